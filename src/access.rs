@@ -0,0 +1,23 @@
+//! `test/access` node with one method per access level, for broker ACL tests.
+//!
+//! Every method returns the grant the broker resolved for the caller, as found in the
+//! request meta. The broker only forwards a request whose grant reaches the level the
+//! method requires, so comparing which methods succeed with the returned grants
+//! verifies access resolution end to end.
+
+use shvproto::rpcvalue::Map;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::Tag;
+use shvrpc::RpcMessage;
+
+pub(crate) const ACCESS_MOUNT: &str = "test/access";
+
+/// `{"required": String, "accessLevel": Int, "access": String}`, the grant keys are Null
+/// when the broker did not set them.
+pub(crate) fn grant(request: &RpcMessage, required: &str) -> RpcValue {
+    let mut map = Map::new();
+    map.insert("required".into(), required.into());
+    map.insert("accessLevel".into(), request.tag(Tag::AccessLevel as i32).cloned().unwrap_or_default());
+    map.insert("access".into(), request.tag(Tag::Access as i32).cloned().unwrap_or_default());
+    map.into()
+}
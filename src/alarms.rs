@@ -0,0 +1,138 @@
+//! Simulated device alarms for testing broker-relayed alerting.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::{DateTime, RpcValue};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+pub(crate) const ALARMS_MOUNT: &str = "alarms";
+
+#[derive(Clone, Copy)]
+enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    fn parse(s: &str) -> Result<Self, RpcError> {
+        match s {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "critical" => Ok(Severity::Critical),
+            _ => Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Unknown severity '{s}', expected info, warning or critical"))),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// Active alarms by name. Raising an active alarm again changes its severity and keeps its start time.
+#[derive(Default)]
+pub(crate) struct Alarms {
+    active: Mutex<BTreeMap<String, (Severity, DateTime)>>,
+}
+
+impl Alarms {
+    pub(crate) fn raise(&self, param: Option<&RpcValue>, now: DateTime) -> Result<(), RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [name, severity]");
+        let Some(Value::List(list)) = param.map(RpcValue::value) else {
+            return Err(invalid());
+        };
+        let [name, severity] = list.as_slice() else {
+            return Err(invalid());
+        };
+        if !name.is_string() || !severity.is_string() {
+            return Err(invalid());
+        }
+        let severity = Severity::parse(severity.as_str())?;
+        self.active.lock().unwrap()
+            .entry(name.as_str().to_string())
+            .and_modify(|(active, _)| *active = severity)
+            .or_insert((severity, now));
+        Ok(())
+    }
+
+    /// Returns whether the alarm was active.
+    pub(crate) fn clear(&self, param: Option<&RpcValue>) -> Result<bool, RpcError> {
+        match param {
+            Some(name) if name.is_string() => Ok(self.active.lock().unwrap().remove(name.as_str()).is_some()),
+            _ => Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected alarm name")),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.active.lock().unwrap().clear();
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let alarms: Map = self.active.lock().unwrap().iter()
+            .map(|(name, (severity, since))| {
+                let mut alarm = Map::new();
+                alarm.insert("severity".into(), severity.as_str().into());
+                alarm.insert("since".into(), (*since).into());
+                (name.clone(), alarm.into())
+            })
+            .collect();
+        alarms.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alarm(name: &str, severity: &str) -> RpcValue {
+        vec![RpcValue::from(name), RpcValue::from(severity)].into()
+    }
+
+    #[test]
+    fn raise_and_clear() {
+        let alarms = Alarms::default();
+        let since = DateTime::from_epoch_msec(1_700_000_000_000);
+        alarms.raise(Some(&alarm("overheat", "critical")), since).unwrap();
+        alarms.raise(Some(&alarm("door", "warning")), since).unwrap();
+        assert!(alarms.clear(Some(&"door".into())).unwrap());
+        assert!(!alarms.clear(Some(&"door".into())).unwrap());
+
+        let mut overheat = Map::new();
+        overheat.insert("severity".into(), "critical".into());
+        overheat.insert("since".into(), since.into());
+        let mut expected = Map::new();
+        expected.insert("overheat".into(), overheat.into());
+        assert_eq!(alarms.value(), RpcValue::from(expected));
+    }
+
+    #[test]
+    fn raising_again_keeps_start_time() {
+        let alarms = Alarms::default();
+        let since = DateTime::from_epoch_msec(1_700_000_000_000);
+        alarms.raise(Some(&alarm("overheat", "warning")), since).unwrap();
+        alarms.raise(Some(&alarm("overheat", "critical")), DateTime::from_epoch_msec(1_700_000_001_000)).unwrap();
+        let value = alarms.value();
+        let Value::Map(active) = value.value() else {
+            panic!("alarms are not a Map");
+        };
+        let Value::Map(overheat) = active.get("overheat").unwrap().value() else {
+            panic!("alarm is not a Map");
+        };
+        assert_eq!(overheat.get("severity"), Some(&RpcValue::from("critical")));
+        assert_eq!(overheat.get("since"), Some(&RpcValue::from(since)));
+    }
+
+    #[test]
+    fn unknown_severity_is_rejected() {
+        let alarms = Alarms::default();
+        assert!(alarms.raise(Some(&alarm("overheat", "fatal")), DateTime::from_epoch_msec(0)).is_err());
+        assert!(alarms.raise(Some(&"overheat".into()), DateTime::from_epoch_msec(0)).is_err());
+        assert_eq!(alarms.value(), RpcValue::from(Map::new()));
+    }
+}
@@ -0,0 +1,83 @@
+//! Node holding a value of any SHV type, for serialization round-trip tests.
+
+use shvproto::rpcvalue::Value;
+use shvproto::RpcValue;
+
+pub(crate) const ANY_VALUE_MOUNT: &str = "test/anyValue";
+
+pub(crate) fn type_name(value: &RpcValue) -> &'static str {
+    match value.value() {
+        Value::Null => "Null",
+        Value::Bool(_) => "Bool",
+        Value::Int(_) => "Int",
+        Value::UInt(_) => "UInt",
+        Value::Double(_) => "Double",
+        Value::Decimal(_) => "Decimal",
+        Value::DateTime(_) => "DateTime",
+        Value::String(_) => "String",
+        Value::Blob(_) => "Blob",
+        Value::List(_) => "List",
+        Value::Map(_) => "Map",
+        Value::IMap(_) => "IMap",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use shvclient::clientnode::SIG_CHNG;
+    use shvproto::decimal::Decimal;
+    use shvproto::rpcvalue::{IMap, Map};
+    use shvproto::DateTime;
+
+    use super::*;
+    use crate::dispatch::finish;
+    use crate::runtime;
+    use crate::signals::Collected;
+
+    fn values() -> Vec<(&'static str, RpcValue)> {
+        let mut map = Map::new();
+        map.insert("key".into(), "value".into());
+        let mut imap = IMap::new();
+        imap.insert(1, 2.into());
+        vec![
+            ("Null", RpcValue::null()),
+            ("Bool", true.into()),
+            ("Int", (-42).into()),
+            ("UInt", 42u64.into()),
+            ("Double", 1.5.into()),
+            ("Decimal", Decimal::new(12345, -2).into()),
+            ("DateTime", DateTime::from_epoch_msec_tz(1_700_000_000_123, 3600).into()),
+            ("String", "text".into()),
+            ("Blob", vec![0u8, 1, 255].into()),
+            ("List", vec![RpcValue::from(1), RpcValue::from("two")].into()),
+            ("Map", map.into()),
+            ("IMap", imap.into()),
+        ]
+    }
+
+    #[test]
+    fn type_names() {
+        for (name, value) in values() {
+            assert_eq!(type_name(&value), name);
+        }
+    }
+
+    #[test]
+    fn every_type_is_stored_and_signalled() {
+        let state = crate::test_state(&[]);
+        let collected = Collected::default();
+        for (name, value) in values() {
+            // Start from a different value, so that setting Null changes the node as well.
+            runtime::block_on(state.set_any_value(&collected, "previous".into())).unwrap();
+            collected.take();
+            runtime::block_on(state.set_any_value(&collected, value.clone())).unwrap();
+            let stored = runtime::block_on(state.any_value.read()).clone();
+            let get = finish(&state, ANY_VALUE_MOUNT, "get", Instant::now(), Some(Ok(stored.clone())), None);
+            assert_eq!(get.unwrap().unwrap(), value, "{name} get");
+            assert_eq!(type_name(&stored), name);
+            assert_eq!(collected.take(), [(ANY_VALUE_MOUNT.to_string(), SIG_CHNG.to_string(), value)], "{name} chng");
+        }
+    }
+}
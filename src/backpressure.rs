@@ -0,0 +1,233 @@
+//! Outgoing rate limits and TCP backpressure, for broker send-queue and slow-consumer tests.
+//!
+//! `--max-send-rate` paces responses and signals to at most that many messages per
+//! second; signals wait in the outbound queue, responses delay their handler.
+//! Responses sent later by deferred handlers (long operations, sequences) are not paced.
+//!
+//! `--shaping-relay` puts a relay on the loopback interface between the client library
+//! and a `tcp://` broker, the same way the TLS relay does. It caps the bytes sent to the
+//! broker to `--max-send-bandwidth` per second (which implies the relay) and implements
+//! `control/backpressure:stall`: the relay stops reading from the broker socket for the
+//! given time, so the receive buffer fills up and the broker's writes block as with a
+//! consumer that does not keep up. `--capture` records the frames passing the relay,
+//! `--connect-stall` and `--login-delay` hold back the handshake in it.
+//! `--disconnect-style abrupt` resets the broker socket of the relay when the client
+//! closes its side.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_std::net::{TcpListener, TcpStream};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::StreamExt;
+use log::*;
+use shvclient::AppState;
+use shvproto::rpcvalue::Map;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use url::Url;
+
+use crate::capture::{Direction, FrameSplitter};
+use crate::connection::DisconnectStyle;
+use crate::{runtime, Opts, State};
+
+pub(crate) const BACKPRESSURE_MOUNT: &str = "control/backpressure";
+const DEFAULT_PORT: u16 = 3755;
+const RELAY_BUFFER_BYTES: usize = 4096;
+
+pub(crate) struct Backpressure {
+    send_interval: Option<Duration>,
+    next_send: Mutex<Option<Instant>>,
+    bandwidth: Option<u64>,
+    relay: bool,
+    relay_port: Mutex<Option<u16>>,
+    disconnect_style: DisconnectStyle,
+    stalled_until: Mutex<Option<Instant>>,
+}
+
+impl Backpressure {
+    pub(crate) fn new(opts: &Opts) -> Result<Self, String> {
+        if opts.max_send_rate.is_some_and(|rate| rate.is_nan() || rate <= 0.) {
+            return Err("Max send rate must be positive".into());
+        }
+        if opts.max_send_bandwidth == Some(0) {
+            return Err("Max send bandwidth must be positive".into());
+        }
+        Ok(Self {
+            send_interval: opts.max_send_rate.map(|rate| Duration::from_secs_f64(1. / rate)),
+            next_send: Default::default(),
+            bandwidth: opts.max_send_bandwidth,
+            relay: opts.shaping_relay || opts.max_send_bandwidth.is_some() || opts.capture.is_some()
+                || opts.connect_stall.is_some() || opts.login_delay.is_some()
+                || opts.disconnect_style == DisconnectStyle::Abrupt,
+            relay_port: Default::default(),
+            disconnect_style: opts.disconnect_style,
+            stalled_until: Default::default(),
+        })
+    }
+
+    /// Waits for the next send slot of `--max-send-rate`, returns at once without it.
+    pub(crate) async fn pace(&self) {
+        let Some(interval) = self.send_interval else {
+            return;
+        };
+        let delay = {
+            let mut next_send = self.next_send.lock().unwrap();
+            let now = Instant::now();
+            let slot = next_send.map_or(now, |next| next.max(now));
+            *next_send = Some(slot + interval);
+            slot - now
+        };
+        runtime::sleep(delay).await;
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("maxSendRate".into(), self.send_interval.map(|interval| RpcValue::from(1. / interval.as_secs_f64())).unwrap_or_default());
+        map.insert("maxSendBandwidth".into(), self.bandwidth.map(|bandwidth| RpcValue::from(bandwidth as i64)).unwrap_or_default());
+        map.insert("relay".into(), self.relay_port.lock().unwrap().is_some().into());
+        map.insert("stalledMs".into(), (self.stall_remaining().as_millis() as i64).into());
+        map.into()
+    }
+
+    fn stall_remaining(&self) -> Duration {
+        self.stalled_until.lock().unwrap().map_or(Duration::ZERO, |until| until.saturating_duration_since(Instant::now()))
+    }
+
+    /// Stops reading from the broker for `duration`, a new stall replaces a running one.
+    pub(crate) fn stall(&self, duration: Duration) -> Result<(), RpcError> {
+        if self.relay_port.lock().unwrap().is_none() {
+            return Err(RpcError::new(RpcErrorCode::MethodCallException, "Stalling requires --shaping-relay and a tcp:// broker URL"));
+        }
+        info!("Not reading from the broker for {duration:?}");
+        *self.stalled_until.lock().unwrap() = Some(Instant::now() + duration);
+        Ok(())
+    }
+
+    /// The URL the client library connects to, `url` pointing at the relay, or `url` as it is without one.
+    pub(crate) fn local_url(&self, url: &str) -> String {
+        let Some(port) = *self.relay_port.lock().unwrap() else {
+            return url.to_string();
+        };
+        let Ok(mut url) = Url::parse(url) else {
+            return url.to_string();
+        };
+        let _ = url.set_host(Some("127.0.0.1"));
+        let _ = url.set_port(Some(port));
+        url.to_string()
+    }
+}
+
+/// Starts the shaping relay to the broker at `url` when configured (`--capture` included), `url` must be a `tcp://` URL.
+pub(crate) async fn start_relay(app_state: &AppState<State>, url: &str) -> Result<(), String> {
+    if !app_state.backpressure.relay {
+        return Ok(());
+    }
+    let url = Url::parse(url).map_err(|err| format!("Invalid broker URL: {err}"))?;
+    if url.scheme() != "tcp" {
+        return Err(format!("The shaping relay (also used by --disconnect-style abrupt) supports tcp:// broker URLs only, not {}://", url.scheme()));
+    }
+    let host = url.host_str().ok_or("Broker URL has no host")?.to_string();
+    let port = url.port().unwrap_or(DEFAULT_PORT);
+    let listener = TcpListener::bind("127.0.0.1:0").await.map_err(|err| format!("Cannot start shaping relay: {err}"))?;
+    let local_port = listener.local_addr().map_err(|err| format!("Cannot start shaping relay: {err}"))?.port();
+    debug!("Shaping relay on 127.0.0.1:{local_port} to {host}:{port}");
+    *app_state.backpressure.relay_port.lock().unwrap() = Some(local_port);
+    let app_state = app_state.clone();
+    runtime::spawn(async move {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(local) => {
+                    runtime::spawn(relay(app_state.clone(), local, host.clone(), port));
+                }
+                Err(err) => warn!("Shaping relay accept failed: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn relay(app_state: AppState<State>, local: TcpStream, host: String, port: u16) {
+    let remote = match TcpStream::connect((host.as_str(), port)).await {
+        Ok(remote) => remote,
+        Err(err) => {
+            warn!("Shaping relay cannot connect to {host}:{port}: {err}");
+            return;
+        }
+    };
+    let backpressure = &app_state.backpressure;
+    backpressure.disconnect_style.prepare(&remote);
+    let capture = &app_state.capture;
+    capture.connected();
+    let slow_login = &app_state.slow_login;
+    let upstream = async {
+        let (mut from, mut to) = (&local, &remote);
+        if let Some(stall) = slow_login.connect_stall {
+            debug!("Connected, sending nothing for {stall:?}");
+            runtime::sleep(stall).await;
+        }
+        let started = Instant::now();
+        let mut sent: u64 = 0;
+        let mut frames = FrameSplitter::default();
+        // Until the login request (the second frame, sent after the hello) is held back by --login-delay.
+        let mut login_delay = slow_login.login_delay;
+        let mut handshake = FrameSplitter::default();
+        let mut handshake_frames = 0;
+        let mut buffer = [0u8; RELAY_BUFFER_BYTES];
+        while let Ok(len @ 1..) = from.read(&mut buffer).await {
+            capture.chunk(&mut frames, Direction::Sent, &buffer[..len]);
+            if let Some(delay) = login_delay {
+                let mut failed = false;
+                for frame in handshake.push(&buffer[..len]) {
+                    handshake_frames += 1;
+                    if handshake_frames == 2 {
+                        debug!("Holding the login request for {delay:?}");
+                        runtime::sleep(delay).await;
+                        login_delay = None;
+                    }
+                    failed |= to.write_all(&frame).await.is_err();
+                }
+                if login_delay.is_none() {
+                    failed |= to.write_all(&handshake.take_rest()).await.is_err();
+                }
+                if failed {
+                    break;
+                }
+                continue;
+            }
+            if to.write_all(&buffer[..len]).await.is_err() {
+                break;
+            }
+            sent += len as u64;
+            if let Some(bandwidth) = backpressure.bandwidth {
+                let due = Duration::from_secs_f64(sent as f64 / bandwidth as f64);
+                runtime::sleep(due.saturating_sub(started.elapsed())).await;
+            }
+        }
+        backpressure.disconnect_style.close(&remote, &mut to).await;
+    };
+    let downstream = async {
+        let (mut from, mut to) = (&remote, &local);
+        let mut frames = FrameSplitter::default();
+        let mut buffer = [0u8; RELAY_BUFFER_BYTES];
+        loop {
+            let stall = backpressure.stall_remaining();
+            if !stall.is_zero() {
+                runtime::sleep(stall).await;
+                continue;
+            }
+            match from.read(&mut buffer).await {
+                Ok(len @ 1..) => {
+                    capture.chunk(&mut frames, Direction::Received, &buffer[..len]);
+                    if to.write_all(&buffer[..len]).await.is_err() {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+        let _ = to.close().await;
+    };
+    futures::future::join(upstream, downstream).await;
+}
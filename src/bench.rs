@@ -0,0 +1,266 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use shvclient::{AppState, ClientCommandSender};
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::signals::{self, emit_chng, MessageSink};
+use crate::{runtime, State};
+
+pub(crate) const EMITTER_MOUNT: &str = "bench/emitter";
+pub(crate) const BURST_MOUNT: &str = "bench/burst";
+pub(crate) const SIGSTORM_MOUNT: &str = "test/sigstorm";
+/// Largest Blob a signal storm carries, the whole payload is allocated up front.
+pub(crate) const MAX_STORM_PAYLOAD_BYTES: usize = 1024 * 1024;
+
+/// Signal generator emitting `chng` at a fixed rate for broker throughput benchmarks.
+#[derive(Default)]
+pub(crate) struct Emitter {
+    running: AtomicBool,
+    stop_requested: AtomicBool,
+    emitted: AtomicU64,
+    stats: Mutex<EmitterStats>,
+}
+
+#[derive(Default, Clone)]
+struct EmitterStats {
+    target_rate: u32,
+    duration_ms: u64,
+    achieved_rate: f64,
+}
+
+impl Emitter {
+    pub(crate) fn value(&self) -> RpcValue {
+        let stats = self.stats.lock().unwrap().clone();
+        let mut map = Map::new();
+        map.insert("running".into(), self.running.load(Ordering::SeqCst).into());
+        map.insert("targetRate".into(), (stats.target_rate as i64).into());
+        map.insert("durationMs".into(), (stats.duration_ms as i64).into());
+        map.insert("emitted".into(), (self.emitted.load(Ordering::SeqCst) as i64).into());
+        map.insert("achievedRate".into(), stats.achieved_rate.into());
+        map.into()
+    }
+
+    pub(crate) fn stop(&self) -> bool {
+        if self.running.load(Ordering::SeqCst) {
+            self.stop_requested.store(true, Ordering::SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub(crate) fn parse_start_param(param: Option<&RpcValue>) -> Result<(u32, u64), RpcError> {
+    let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [ratePerSec, durationMs]");
+    let list = match param.map(RpcValue::value) {
+        Some(Value::List(list)) if list.len() == 2 => list,
+        _ => return Err(invalid()),
+    };
+    if !list[0].is_int() || !list[1].is_int() {
+        return Err(invalid());
+    }
+    let rate = list[0].as_int();
+    let duration_ms = list[1].as_int();
+    if rate <= 0 || rate > u32::MAX as i64 || duration_ms < 0 {
+        return Err(RpcError::new(RpcErrorCode::InvalidParam, "ratePerSec must be positive and durationMs non-negative"));
+    }
+    Ok((rate as u32, duration_ms as u64))
+}
+
+/// Runs the emitter to completion and returns the number of signals emitted.
+///
+/// Send times are computed from the start instant rather than by sleeping a fixed
+/// period after each signal, so the schedule does not drift at high rates. When the
+/// task falls behind, all overdue signals are sent before sleeping again.
+pub(crate) async fn run_emitter(app_state: AppState<State>, client_cmd_tx: ClientCommandSender, rate: u32, duration_ms: u64) -> Result<u64, RpcError> {
+    let emitter = &app_state.bench_emitter;
+    if emitter.running.swap(true, Ordering::SeqCst) {
+        return Err(RpcError::new(RpcErrorCode::MethodCallException, "Emitter is already running"));
+    }
+    emitter.stop_requested.store(false, Ordering::SeqCst);
+    emitter.emitted.store(0, Ordering::SeqCst);
+    *emitter.stats.lock().unwrap() = EmitterStats { target_rate: rate, duration_ms, achieved_rate: 0. };
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_millis(duration_ms);
+    let emitted = run_paced(&app_state, rate, Some(deadline), u64::MAX, &emitter.stop_requested, &emitter.emitted, |n| {
+        emit_chng(&app_state, &client_cmd_tx, EMITTER_MOUNT, (n as i64).into());
+    }).await;
+
+    let elapsed = started.elapsed().as_secs_f64();
+    emitter.stats.lock().unwrap().achieved_rate = if elapsed > 0. { emitted as f64 / elapsed } else { 0. };
+    emitter.running.store(false, Ordering::SeqCst);
+    Ok(emitted)
+}
+
+/// Calls `emit` `rate` times per second until the deadline, `count` calls or a stop request,
+/// keeping `progress` updated. Returns the number of calls made.
+async fn run_paced(state: &State, rate: u32, deadline: Option<Instant>, count: u64, stop: &AtomicBool, progress: &AtomicU64, mut emit: impl FnMut(u64)) -> u64 {
+    let started = Instant::now();
+    let mut emitted: u64 = 0;
+    loop {
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) || emitted >= count || stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let due = (((now - started).as_secs_f64() * rate as f64) as u64 + 1).min(count);
+        while emitted < due {
+            signals::wait_for_room(state).await;
+            emit(emitted);
+            emitted += 1;
+        }
+        progress.store(emitted, Ordering::SeqCst);
+        let next = started + Duration::from_secs_f64(emitted as f64 / rate as f64);
+        let next = deadline.map_or(next, |deadline| next.min(deadline));
+        runtime::sleep(next.saturating_duration_since(Instant::now())).await;
+    }
+    emitted
+}
+
+/// Emits `count` signals back to back and returns the elapsed time in milliseconds.
+///
+/// There is deliberately no pacing, a burst can overwhelm slow consumers and fill
+/// the outbound queue, use it to exercise overflow and drop handling.
+pub(crate) async fn fire_burst(state: &State, client_cmd_tx: &impl MessageSink, count: i32) -> Result<f64, RpcError> {
+    if count < 0 {
+        return Err(RpcError::new(RpcErrorCode::InvalidParam, "count must not be negative"));
+    }
+    let started = Instant::now();
+    for n in 0..count {
+        signals::wait_for_room(state).await;
+        emit_chng(state, client_cmd_tx, BURST_MOUNT, n.into());
+    }
+    Ok(started.elapsed().as_secs_f64() * 1000.)
+}
+
+/// Signal storm of a fixed number of signals carrying Blob payloads, for fan-out tests.
+#[derive(Default)]
+pub(crate) struct Storm {
+    running: AtomicBool,
+    stop_requested: AtomicBool,
+    sent: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Storm {
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("running".into(), self.running.load(Ordering::SeqCst).into());
+        map.insert("count".into(), (self.count.load(Ordering::SeqCst) as i64).into());
+        map.insert("sent".into(), (self.sent.load(Ordering::SeqCst) as i64).into());
+        map.into()
+    }
+
+    pub(crate) fn stop(&self) -> bool {
+        self.running.load(Ordering::SeqCst) && !self.stop_requested.swap(true, Ordering::SeqCst)
+    }
+}
+
+pub(crate) fn parse_storm_param(param: Option<&RpcValue>) -> Result<(u32, u64, usize), RpcError> {
+    let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [rate, count, payloadSize]");
+    let Some(Value::List(list)) = param.map(RpcValue::value) else {
+        return Err(invalid());
+    };
+    let [rate, count, payload_size] = list.as_slice() else {
+        return Err(invalid());
+    };
+    if !rate.is_int() || !count.is_int() || !payload_size.is_int() {
+        return Err(invalid());
+    }
+    let (rate, count, payload_size) = (rate.as_int(), count.as_int(), payload_size.as_int());
+    if rate <= 0 || rate > u32::MAX as i64 || count < 0 || payload_size < 0 {
+        return Err(RpcError::new(RpcErrorCode::InvalidParam, "rate must be positive, count and payloadSize non-negative"));
+    }
+    if payload_size as u64 > MAX_STORM_PAYLOAD_BYTES as u64 {
+        return Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("payloadSize must not exceed {MAX_STORM_PAYLOAD_BYTES} bytes")));
+    }
+    Ok((rate as u32, count as u64, payload_size as usize))
+}
+
+/// Emits `count` signals on test/sigstorm at `rate` per second, each carrying a Blob of
+/// `payload_size` bytes (at most [`MAX_STORM_PAYLOAD_BYTES`]). Returns the number of
+/// signals actually sent, fewer when stopped.
+pub(crate) async fn run_storm(app_state: AppState<State>, client_cmd_tx: impl MessageSink, rate: u32, count: u64, payload_size: usize) -> Result<u64, RpcError> {
+    let storm = &app_state.sigstorm;
+    if storm.running.swap(true, Ordering::SeqCst) {
+        return Err(RpcError::new(RpcErrorCode::MethodCallException, "Signal storm is already running"));
+    }
+    storm.stop_requested.store(false, Ordering::SeqCst);
+    storm.sent.store(0, Ordering::SeqCst);
+    storm.count.store(count, Ordering::SeqCst);
+    let payload = RpcValue::from(vec![0x5Au8; payload_size]);
+    let sent = run_paced(&app_state, rate, None, count, &storm.stop_requested, &storm.sent, |_| {
+        emit_chng(&app_state, &client_cmd_tx, SIGSTORM_MOUNT, payload.clone());
+    }).await;
+    storm.running.store(false, Ordering::SeqCst);
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use shvclient::clientnode::SIG_CHNG;
+
+    use super::*;
+    use crate::signals::Collected;
+
+    fn storm_param(payload_size: i64) -> RpcValue {
+        RpcValue::from(vec![RpcValue::from(1000), RpcValue::from(1), RpcValue::from(payload_size)])
+    }
+
+    #[test]
+    fn storm_payload_is_capped() {
+        let max = MAX_STORM_PAYLOAD_BYTES as i64;
+        assert_eq!(parse_storm_param(Some(&storm_param(max))).unwrap(), (1000, 1, MAX_STORM_PAYLOAD_BYTES));
+        let err = parse_storm_param(Some(&storm_param(max + 1))).unwrap_err();
+        assert_eq!(err.code as i32, RpcErrorCode::InvalidParam as i32);
+    }
+
+    #[test]
+    fn storm_sends_count_signals() {
+        let state = crate::test_state(&[]);
+        let collected = Collected::default();
+        let sent = runtime::block_on(run_storm(state.clone(), &collected, 1000, 5, 3)).unwrap();
+        assert_eq!(sent, 5);
+        let signals = collected.take();
+        assert_eq!(signals.len(), 5);
+        let payload = RpcValue::from(vec![0x5Au8; 3]);
+        assert!(signals.iter().all(|signal| *signal == (SIGSTORM_MOUNT.to_string(), SIG_CHNG.to_string(), payload.clone())));
+        assert_eq!(state.sigstorm.value(), storm_value(false, 5, 5));
+    }
+
+    #[test]
+    fn burst_emits_every_signal_in_order() {
+        let state = crate::test_state(&[]);
+        let collected = Collected::default();
+        runtime::block_on(fire_burst(&state, &collected, 50)).unwrap();
+        let values: Vec<RpcValue> = collected.take().into_iter()
+            .map(|(path, signal, value)| {
+                assert_eq!((path.as_str(), signal.as_str()), (BURST_MOUNT, SIG_CHNG));
+                value
+            })
+            .collect();
+        assert_eq!(values, (0..50).map(RpcValue::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn burst_past_full_queue_drops_per_policy() {
+        let state = crate::test_state(&["--signal-queue-size", "10", "--signal-overflow", "drop-newest"]);
+        let collected = Collected::default();
+        runtime::block_on(fire_burst(&state, &collected, 50)).unwrap();
+        assert!(collected.take().is_empty(), "queued signals bypassed the queue");
+        assert_eq!(state.metrics.signals_dropped.load(Ordering::Relaxed), 40);
+        assert_eq!(state.metrics.signal_queue_depth.load(Ordering::Relaxed), 10);
+    }
+
+    fn storm_value(running: bool, count: i64, sent: i64) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("running".into(), running.into());
+        map.insert("count".into(), count.into());
+        map.insert("sent".into(), sent.into());
+        map.into()
+    }
+}
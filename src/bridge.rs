@@ -0,0 +1,82 @@
+//! Bridging mode: with `--bridge-url` the device connects to a second broker as well,
+//! under `--bridge-mount` (the same mount point by default), for broker federation tests.
+//!
+//! Each side is a device of its own with its own state. With `--bridge-mirror` a property
+//! change on one side is applied to the other side too, which emits its own `chng`, so
+//! a set through either broker shows on both. Mirrored are the nodes control:setMany
+//! writes (state/number, state/text and the typed state nodes), a change that leaves
+//! the other side's value as it is ends the mirroring, so changes do not bounce back.
+
+use std::sync::{Arc, Mutex};
+
+use log::*;
+use shvclient::AppState;
+use shvproto::RpcValue;
+use shvrpc::client::ClientConfig;
+
+use crate::{control, runtime, signals, transport, Custom, Error, Opts, State};
+
+/// The devices of a bridge, both sides of it.
+pub(crate) type Group = Arc<Mutex<Vec<AppState<State>>>>;
+
+#[derive(Default)]
+pub(crate) struct Bridge {
+    mirror: bool,
+    group: Option<Group>,
+}
+
+impl Bridge {
+    pub(crate) fn new(mirror: bool, group: Option<Group>) -> Self {
+        Self { mirror, group }
+    }
+}
+
+pub(crate) fn register(app_state: &AppState<State>) {
+    if let Some(group) = &app_state.bridge.group {
+        group.lock().unwrap().push(app_state.clone());
+    }
+}
+
+/// Applies a change of `path` on `state` to the other side of the bridge.
+pub(crate) fn mirror(state: &State, path: &str, value: &RpcValue) {
+    let (true, Some(group)) = (state.bridge.mirror, &state.bridge.group) else {
+        return;
+    };
+    if !control::settable(path) {
+        return;
+    }
+    let peers: Vec<AppState<State>> = group.lock().unwrap().iter()
+        .filter(|peer| !std::ptr::eq::<State>(&***peer, state))
+        .cloned()
+        .collect();
+    for peer in peers {
+        let path = path.to_string();
+        let value = value.clone();
+        runtime::spawn(async move {
+            match control::set_path(&peer, &path, &value).await {
+                Ok(Some(changed)) => {
+                    if let Some(client_cmd_tx) = peer.connection.client_cmd_tx() {
+                        signals::emit_chng(&peer, &client_cmd_tx, &path, changed);
+                    }
+                }
+                Ok(None) => {}
+                Err(msg) => warn!("Bridge: cannot mirror {path}: {msg}"),
+            }
+        });
+    }
+}
+
+/// Runs both sides of the bridge, returns once both of them exited or one of them failed.
+pub(crate) async fn run(opts: Opts, client_config: ClientConfig, url: &str) -> Result<(), Error> {
+    transport::check_url(url).map_err(|err| Error::Config(format!("Invalid bridge URL: {err}")))?;
+    let mut bridged = client_config.clone();
+    bridged.url = url.to_string();
+    if let Some(mount) = &opts.bridge_mount {
+        bridged.mount = Some(mount.clone());
+    }
+    info!("Bridging {} and {}", crate::connection::redact_url(&client_config.url), crate::connection::redact_url(url));
+    let group = Group::default();
+    let side = |config| crate::run_device(opts.clone(), config, Custom { bridge: Some(group.clone()), ..Default::default() });
+    futures::future::try_join(side(client_config), side(bridged)).await?;
+    Ok(())
+}
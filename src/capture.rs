@@ -0,0 +1,203 @@
+//! `--capture <file>`: every RPC frame exchanged with the broker, byte for byte, for
+//! debugging framing issues. The `replay` subcommand sends the frames the device sent
+//! in the first captured connection to a broker again.
+//!
+//! Frames are taken from the connection through the relay of `control/backpressure`,
+//! which `--capture` turns on, so it needs a `tcp://` broker URL (or `ssl://` with the
+//! `tls` feature, the relay sits behind the TLS relay and sees plain frames).
+//!
+//! File format, all integers little endian:
+//! - header: the 8 bytes `SHVCAP\0` followed by the format version 1
+//! - records: `u64` timestamp in microseconds since the Unix epoch, `u8` direction
+//!   (0 sent by the device, 1 received from the broker, 2 a new connection with
+//!   no frame), `u32` frame length and the frame as on the wire, its length prefix included
+//!
+//! A replayed SHA1 login fails, the broker sends another nonce than when captured,
+//! capture with a PLAIN login to replay a whole session.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::net::TcpStream;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use log::*;
+use url::Url;
+
+use crate::runtime;
+
+const MAGIC: &[u8; 8] = b"SHVCAP\0\x01";
+const DEFAULT_PORT: u16 = 3755;
+const REPLAY_BUFFER_BYTES: usize = 4096;
+/// Time given to the broker to answer the last replayed frame.
+const REPLAY_DRAIN: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Direction {
+    Sent = 0,
+    Received = 1,
+    Connected = 2,
+}
+
+#[derive(Default)]
+pub(crate) struct Capture {
+    file: Option<Mutex<BufWriter<File>>>,
+}
+
+impl Capture {
+    pub(crate) fn new(path: Option<&str>) -> Result<Self, String> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let mut file = BufWriter::new(File::create(path).map_err(|err| format!("Cannot create capture file {path}: {err}"))?);
+        file.write_all(MAGIC).and_then(|_| file.flush()).map_err(|err| format!("Cannot write capture file {path}: {err}"))?;
+        Ok(Self { file: Some(Mutex::new(file)) })
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub(crate) fn connected(&self) {
+        self.record(Direction::Connected, &[]);
+    }
+
+    /// Records the frames completed by `data`, read from the connection in `direction`.
+    pub(crate) fn chunk(&self, frames: &mut FrameSplitter, direction: Direction, data: &[u8]) {
+        if !self.is_enabled() {
+            return;
+        }
+        for frame in frames.push(data) {
+            self.record(direction, &frame);
+        }
+    }
+
+    fn record(&self, direction: Direction, frame: &[u8]) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        let mut file = file.lock().unwrap();
+        let result = file.write_all(&time.to_le_bytes())
+            .and_then(|_| file.write_all(&[direction as u8]))
+            .and_then(|_| file.write_all(&(frame.len() as u32).to_le_bytes()))
+            .and_then(|_| file.write_all(frame))
+            .and_then(|_| file.flush());
+        if let Err(err) = result {
+            warn!("Cannot write capture record: {err}");
+        }
+    }
+}
+
+/// Splits a byte stream into frames: a ChainPack UInt length followed by that many bytes.
+#[derive(Default)]
+pub(crate) struct FrameSplitter {
+    buffer: Vec<u8>,
+}
+
+impl FrameSplitter {
+    pub(crate) fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        while let Some((prefix, len)) = frame_length(&self.buffer) {
+            let Some(end) = prefix.checked_add(len).filter(|end| *end <= self.buffer.len()) else {
+                break;
+            };
+            frames.push(self.buffer.drain(..end).collect());
+        }
+        frames
+    }
+
+    /// The bytes of an incomplete frame, the splitter is empty afterwards.
+    pub(crate) fn take_rest(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+/// Size of the length prefix and the length it encodes, None while incomplete.
+fn frame_length(data: &[u8]) -> Option<(usize, usize)> {
+    let head = *data.first()?;
+    let (extra, mut len) = match head {
+        _ if head & 0x80 == 0 => (0, (head & 0x7f) as u64),
+        _ if head & 0x40 == 0 => (1, (head & 0x3f) as u64),
+        _ if head & 0x20 == 0 => (2, (head & 0x1f) as u64),
+        _ if head & 0x10 == 0 => (3, (head & 0x0f) as u64),
+        _ => ((head & 0x0f) as usize + 4, 0),
+    };
+    let bytes = data.get(1..=extra)?;
+    for byte in bytes {
+        len = (len << 8) | *byte as u64;
+    }
+    Some((extra + 1, usize::try_from(len).ok()?))
+}
+
+struct Record {
+    time_us: u64,
+    direction: u8,
+    frame: Vec<u8>,
+}
+
+fn read_records(path: &str) -> Result<Vec<Record>, String> {
+    let invalid = |msg: &str| format!("Invalid capture file {path}: {msg}");
+    let mut data = Vec::new();
+    File::open(path).and_then(|mut file| file.read_to_end(&mut data)).map_err(|err| format!("Cannot read capture file {path}: {err}"))?;
+    let mut rest = data.strip_prefix(MAGIC.as_slice()).ok_or_else(|| invalid("not a capture file or unsupported version"))?;
+    let mut records = Vec::new();
+    while !rest.is_empty() {
+        let (header, tail) = rest.split_at_checked(13).ok_or_else(|| invalid("truncated record header"))?;
+        let time_us = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+        let (frame, tail) = tail.split_at_checked(len).ok_or_else(|| invalid("truncated frame"))?;
+        records.push(Record { time_us, direction: header[8], frame: frame.to_vec() });
+        rest = tail;
+    }
+    Ok(records)
+}
+
+/// The `replay` subcommand: sends the frames sent by the device in the first captured
+/// connection to the broker at `url`, `fast` skips the captured delays between them.
+pub(crate) async fn replay(path: &str, url: &str, fast: bool) -> Result<(), String> {
+    let records = read_records(path)?;
+    let first_connection = records.iter()
+        .skip_while(|record| record.direction == Direction::Connected as u8)
+        .take_while(|record| record.direction != Direction::Connected as u8);
+    let sent: Vec<&Record> = first_connection.filter(|record| record.direction == Direction::Sent as u8).collect();
+    let url = Url::parse(url).map_err(|err| format!("Invalid broker URL: {err}"))?;
+    if url.scheme() != "tcp" {
+        return Err(format!("Replay supports tcp:// broker URLs only, not {}://", url.scheme()));
+    }
+    let host = url.host_str().ok_or("Broker URL has no host")?.to_string();
+    let port = url.port().unwrap_or(DEFAULT_PORT);
+    let stream = TcpStream::connect((host.as_str(), port)).await.map_err(|err| format!("Cannot connect to {host}:{port}: {err}"))?;
+    info!("Replaying {} frames of {path} to {host}:{port}", sent.len());
+    let received = async {
+        let mut from = &stream;
+        let mut frames = FrameSplitter::default();
+        let mut count = 0;
+        let mut buffer = [0u8; REPLAY_BUFFER_BYTES];
+        while let Ok(len @ 1..) = from.read(&mut buffer).await {
+            count += frames.push(&buffer[..len]).len();
+        }
+        count
+    };
+    let send = async {
+        let mut to = &stream;
+        let first_us = sent.first().map_or(0, |record| record.time_us);
+        let started = std::time::Instant::now();
+        for record in &sent {
+            if !fast {
+                let due = Duration::from_micros(record.time_us.saturating_sub(first_us));
+                runtime::sleep(due.saturating_sub(started.elapsed())).await;
+            }
+            to.write_all(&record.frame).await.map_err(|err| format!("Cannot send frame: {err}"))?;
+        }
+        runtime::sleep(REPLAY_DRAIN).await;
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+        Ok::<(), String>(())
+    };
+    let (sent_result, received) = futures::future::join(send, received).await;
+    sent_result?;
+    info!("Replay finished, {} frames sent, {received} frames received", sent.len());
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+use shvproto::rpcvalue::Map;
+use shvproto::{DateTime, RpcValue};
+
+pub(crate) const DATETIME_MOUNT: &str = "state/datetime";
+pub(crate) const TIME_MOUNT: &str = "status/time";
+
+/// Source of the device's wall-clock time, every DateTime the device produces is
+/// taken from here so that a configured clock offset is applied consistently.
+pub(crate) struct Clock {
+    offset_ms: i64,
+    started: Instant,
+}
+
+impl Clock {
+    pub(crate) fn new(offset_ms: i64) -> Self {
+        Self { offset_ms, started: Instant::now() }
+    }
+
+    pub(crate) fn offset_ms(&self) -> i64 {
+        self.offset_ms
+    }
+
+    pub(crate) fn now(&self) -> DateTime {
+        DateTime::from_epoch_msec(DateTime::now().epoch_msec() + self.offset_ms)
+    }
+
+    /// Milliseconds since device start, unaffected by wall-clock adjustments.
+    pub(crate) fn monotonic_ms(&self) -> i64 {
+        self.started.elapsed().as_millis() as i64
+    }
+
+    /// Both clock domains read together, comparing deltas across calls reveals wall-clock jumps.
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("wallClock".into(), self.now().into());
+        map.insert("monotonicMs".into(), self.monotonic_ms().into());
+        map.into()
+    }
+}
+
+/// Parses an interval with an optional leading sign, e.g. `-1h` or `+30s`, into milliseconds.
+pub(crate) fn parse_signed_interval(s: &str) -> Result<i64, String> {
+    let (sign, interval) = match s.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let duration: Duration = duration_str::parse(interval)?;
+    Ok(sign * duration.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datetime_is_now_plus_offset() {
+        let state = crate::test_state(&["--clock-offset", "-1h"]);
+        let before = DateTime::now().epoch_msec();
+        let now = state.clock.now().epoch_msec();
+        let after = DateTime::now().epoch_msec();
+        assert!((before - 3_600_000..=after - 3_600_000).contains(&now), "{before} {now} {after}");
+    }
+
+    #[test]
+    fn signed_intervals_parse() {
+        assert_eq!(parse_signed_interval("-1h"), Ok(-3_600_000));
+        assert_eq!(parse_signed_interval("+30s"), Ok(30_000));
+        assert_eq!(parse_signed_interval("250ms"), Ok(250));
+        assert!(parse_signed_interval("soon").is_err());
+    }
+
+    #[test]
+    fn monotonic_ms_increases() {
+        let clock = Clock::new(0);
+        let first = clock.monotonic_ms();
+        std::thread::sleep(Duration::from_millis(20));
+        let second = clock.monotonic_ms();
+        assert!(second >= first + 20, "{first} {second}");
+    }
+}
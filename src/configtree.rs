@@ -0,0 +1,144 @@
+//! `state/config` node holding a nested Map, read and written in parts by key path,
+//! the way real devices expose their configuration.
+//!
+//! `get` and `set` take a key path, a List of Map keys such as `["network", "ip"]`; an
+//! empty or missing path addresses the whole tree. `set` creates missing intermediate
+//! Maps. Its `chng` signal carries only the changed subtree, nested under its key path
+//! from the root, e.g. `{"network": {"ip": "10.0.0.2"}}`.
+//!
+//! The initial tree is read from `--config-tree`, a CPON Map, or is a small built-in one.
+
+use std::sync::Mutex;
+
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+pub(crate) const CONFIG_TREE_MOUNT: &str = "state/config";
+
+const DEFAULT_TREE: &str = r#"{
+    "network": {"dhcp": false, "ip": "192.168.1.10", "mask": "255.255.255.0", "gateway": "192.168.1.1"},
+    "logging": {"level": "info", "targets": ["console"]},
+    "device": {"name": "shvbrokertestingdevice", "location": {"site": "lab", "rack": 1}}
+}"#;
+
+pub(crate) struct ConfigTree {
+    initial: Map,
+    tree: Mutex<Map>,
+}
+
+impl ConfigTree {
+    pub(crate) fn new(file: Option<&str>) -> Result<Self, String> {
+        let (name, content) = match file {
+            Some(file) => (file, std::fs::read_to_string(file).map_err(|err| format!("Cannot read config tree {file}: {err}"))?),
+            None => ("built-in", DEFAULT_TREE.to_string()),
+        };
+        let tree = RpcValue::from_cpon(&content).map_err(|err| format!("Invalid config tree {name}: {err}"))?;
+        let Value::Map(tree) = tree.value() else {
+            return Err(format!("Invalid config tree {name}: expected a Map"));
+        };
+        let initial = tree.as_ref().clone();
+        Ok(Self { tree: Mutex::new(initial.clone()), initial })
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        self.tree.lock().unwrap().clone().into()
+    }
+
+    pub(crate) fn reset(&self) {
+        *self.tree.lock().unwrap() = self.initial.clone();
+    }
+
+    pub(crate) fn get(&self, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        let keys = key_path(param)?;
+        let tree = self.tree.lock().unwrap();
+        let mut node = &*tree;
+        for (n, key) in keys.iter().enumerate() {
+            let value = node.get(key).ok_or_else(|| RpcError::new(RpcErrorCode::InvalidParam, &format!("No key {}", keys[..=n].join("/"))))?;
+            if n + 1 == keys.len() {
+                return Ok(value.clone());
+            }
+            let Value::Map(map) = value.value() else {
+                return Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("{} is not a Map", keys[..=n].join("/"))));
+            };
+            node = map.as_ref();
+        }
+        Ok(node.clone().into())
+    }
+
+    /// `param` is `[keyPath, value]`, returns the signal value if the tree changed.
+    /// `commit` runs once the write is known to change the tree, its error rejects the write.
+    pub(crate) fn set(&self, param: Option<&RpcValue>, commit: impl FnOnce() -> Result<(), RpcError>) -> Result<Option<RpcValue>, RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [keyPath, value]");
+        let Some(Value::List(list)) = param.map(RpcValue::value) else {
+            return Err(invalid());
+        };
+        let [keys, value] = list.as_slice() else {
+            return Err(invalid());
+        };
+        let keys = key_path(Some(keys))?;
+        let mut tree = self.tree.lock().unwrap();
+        if keys.is_empty() {
+            let Value::Map(map) = value.value() else {
+                return Err(RpcError::new(RpcErrorCode::InvalidParam, "The whole tree must be a Map"));
+            };
+            if *tree == **map {
+                return Ok(None);
+            }
+            commit()?;
+            *tree = map.as_ref().clone();
+            return Ok(Some(value.clone()));
+        }
+        if lookup(&tree, &keys) == Some(value) {
+            return Ok(None);
+        }
+        let updated = set_at(&tree, &keys, value, 0)?;
+        commit()?;
+        *tree = updated;
+        Ok(Some(keys.iter().rev().fold(value.clone(), |subtree, key| {
+            let mut map = Map::new();
+            map.insert(key.clone(), subtree);
+            map.into()
+        })))
+    }
+}
+
+fn lookup<'a>(tree: &'a Map, keys: &[String]) -> Option<&'a RpcValue> {
+    let (last, parents) = keys.split_last()?;
+    let mut node = tree;
+    for key in parents {
+        let Value::Map(map) = node.get(key)?.value() else {
+            return None;
+        };
+        node = map.as_ref();
+    }
+    node.get(last)
+}
+
+/// Copy of `node` with `value` stored at `keys[depth..]`, creating missing Maps on the way.
+fn set_at(node: &Map, keys: &[String], value: &RpcValue, depth: usize) -> Result<Map, RpcError> {
+    let key = &keys[depth];
+    let mut node = node.clone();
+    if depth + 1 == keys.len() {
+        node.insert(key.clone(), value.clone());
+        return Ok(node);
+    }
+    let child = match node.get(key).map(RpcValue::value) {
+        Some(Value::Map(child)) => set_at(child, keys, value, depth + 1)?,
+        None => set_at(&Map::new(), keys, value, depth + 1)?,
+        Some(_) => return Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("{} is not a Map", keys[..=depth].join("/")))),
+    };
+    node.insert(key.clone(), child.into());
+    Ok(node)
+}
+
+fn key_path(param: Option<&RpcValue>) -> Result<Vec<String>, RpcError> {
+    let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected a key path, e.g. [\"network\", \"ip\"]");
+    match param.map(RpcValue::value) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::List(keys)) => keys.iter()
+            .map(|key| if key.is_string() { Ok(key.as_str().to_string()) } else { Err(invalid()) })
+            .collect(),
+        Some(_) => Err(invalid()),
+    }
+}
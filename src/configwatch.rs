@@ -0,0 +1,210 @@
+//! Reloading of the configuration while the device runs.
+//!
+//! A reload is triggered by SIGHUP, by `control/app:reloadConfig` and, with
+//! `--watch-config`, by a modification of the `--config` file, which is polled.
+//! It reads again:
+//! - the `--options-file`, then parses the command line with it as at startup
+//! - the `--config` file, with the usual command line and environment precedence
+//! - the `--nodes-file`
+//!
+//! and applies, keeping the connection up:
+//! - the reconnect and heartbeat intervals, from the next connection attempt
+//! - the `--latency` models
+//! - the generator intervals (`--flap-interval`, `--counter-auto`, `--sim-clock`, ...),
+//!   the generators are restarted when one of them changed
+//! - the nodes file: a node declared as before keeps its value, a node added or removed
+//!   forces a reconnect, unlike with control/nodes the tree is mounted again as a whole
+//!
+//! Changes of the URL, device id and mount point are ignored with a warning, other
+//! options take effect with the next start only. An invalid file fails the whole reload
+//! before anything is applied.
+
+use std::ffi::OsString;
+use std::time::{Duration, SystemTime};
+
+use log::*;
+use shvclient::AppState;
+use shvproto::RpcValue;
+use shvrpc::client::ClientConfig;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::latency::Latency;
+use crate::synthetic::SyntheticNodes;
+use crate::{lifecycle, load_client_config, reboot, runtime, Opts, State};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+pub(crate) async fn watch(app_state: AppState<State>, path: String) {
+    let mut last_modified = modified(&path);
+    loop {
+        runtime::sleep(POLL_INTERVAL).await;
+        let current = modified(&path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        if let Err(err) = reload(&app_state).await {
+            warn!("Cannot reload config file {path}: {}", err.message);
+        }
+    }
+}
+
+/// Reloads every device of the process on SIGHUP.
+#[cfg(unix)]
+pub(crate) fn handle_sighup() {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            warn!("Cannot install SIGHUP handler: {err}");
+            return;
+        }
+    };
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("SIGHUP, reloading configuration");
+            runtime::block_on(async {
+                for state in lifecycle::devices() {
+                    if let Err(err) = reload(&state).await {
+                        warn!("Cannot reload configuration: {}", err.message);
+                    }
+                }
+            });
+        }
+    });
+}
+
+/// Reads the extra command line options of an options file: one option per line, with
+/// its value separated by whitespace, e.g. `--latency state/number=fixed:20ms`. Empty
+/// lines and lines starting with `#` are skipped.
+pub(crate) fn read_options_file(path: &str) -> Result<Vec<OsString>, String> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("Cannot read options file {path}: {err}"))?;
+    let mut args = Vec::new();
+    for line in content.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        match line.split_once(char::is_whitespace) {
+            Some((option, value)) => args.extend([option.into(), value.trim().into()]),
+            None => args.push(line.into()),
+        }
+    }
+    Ok(args)
+}
+
+/// Performs a reload, returns the list of applied changes.
+pub(crate) async fn reload(app_state: &AppState<State>) -> Result<RpcValue, RpcError> {
+    let failed = |err: String| RpcError::new(RpcErrorCode::MethodCallException, &format!("Reload failed: {err}"));
+    let opts = Opts::reparse(&app_state.opts).map_err(|err| failed(err.to_string()))?;
+    let config = load_client_config(&opts).map_err(|err| failed(err.to_string()))?;
+    let generators = reboot::Generators::new(&opts).map_err(failed)?;
+    let nodes = SyntheticNodes::load(opts.nodes_file.as_deref()).map_err(failed)?;
+    // Parsed up front, so that an invalid model does not leave the reload half applied.
+    Latency::new(&opts.latency, opts.latency_seed).map_err(failed)?;
+    let mut changes = apply(app_state, config);
+    if app_state.latency.set_models(&opts.latency).map_err(failed)? {
+        changes.push("latency".to_string());
+    }
+    if reboot::restart_generators(app_state, generators).await {
+        changes.push("generators".to_string());
+    }
+    if app_state.synthetic.replace(nodes) {
+        changes.push("nodes".to_string());
+        lifecycle::request_reconnect(app_state);
+    }
+    info!("Config reloaded, changed: {changes:?}");
+    let changes: Vec<RpcValue> = changes.into_iter().map(RpcValue::from).collect();
+    Ok(changes.into())
+}
+
+fn apply(state: &State, new: ClientConfig) -> Vec<String> {
+    let mut changes = Vec::new();
+    let mut config = state.client_config.lock().unwrap();
+    if new.url != config.url {
+        warn!("Config reload: url cannot be changed at runtime, ignored");
+    }
+    if new.device_id != config.device_id {
+        warn!("Config reload: device_id cannot be changed at runtime, ignored");
+    }
+    if new.mount != config.mount {
+        warn!("Config reload: mount cannot be changed at runtime, ignored");
+    }
+    if new.reconnect_interval != config.reconnect_interval {
+        match new.reconnect_interval.as_deref().map(duration_str::parse).transpose() {
+            Ok(_) => {
+                info!("Config reload: reconnect_interval {:?} -> {:?}", config.reconnect_interval, new.reconnect_interval);
+                config.reconnect_interval = new.reconnect_interval;
+                changes.push("reconnectInterval".to_string());
+            }
+            Err(err) => warn!("Config reload: invalid reconnect_interval, ignored: {err}"),
+        }
+    }
+    if new.heartbeat_interval != config.heartbeat_interval {
+        match duration_str::parse(&new.heartbeat_interval) {
+            Ok(_) => {
+                info!("Config reload: heartbeat_interval {} -> {}", config.heartbeat_interval, new.heartbeat_interval);
+                config.heartbeat_interval = new.heartbeat_interval;
+                changes.push("heartbeatInterval".to_string());
+            }
+            Err(err) => warn!("Config reload: invalid heartbeat_interval, ignored: {err}"),
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection;
+
+    /// An options file unique to the test, removed again when the test ends.
+    struct OptionsFile(std::path::PathBuf);
+
+    impl OptionsFile {
+        fn new(name: &str, content: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("shvbrokertestingdevice-{}-{name}.opts", std::process::id()));
+            std::fs::write(&path, content).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for OptionsFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn options_file_skips_comments_and_splits_values() {
+        let file = OptionsFile::new("parse", "# comment\n\n--latency state/number=fixed:20ms\n--replay-on-reconnect\n");
+        let args = read_options_file(file.path()).unwrap();
+        assert_eq!(args, ["--latency", "state/number=fixed:20ms", "--replay-on-reconnect"]);
+    }
+
+    #[test]
+    fn watched_file_change_is_applied() {
+        let file = OptionsFile::new("watch", "--reconnect-interval 5s\n");
+        let state = crate::test_state(&["--options-file", file.path()]);
+        let interval = runtime::block_on(async {
+            runtime::spawn(watch(state.clone(), file.path().to_string()));
+            // Past the modification time granularity of coarse file systems.
+            runtime::sleep(Duration::from_millis(1100)).await;
+            std::fs::write(&file.0, "--reconnect-interval 7s\n").unwrap();
+            for _ in 0..50 {
+                if connection::reconnect_interval(&state) == RpcValue::from("7s") {
+                    break;
+                }
+                runtime::sleep(Duration::from_millis(100)).await;
+            }
+            connection::reconnect_interval(&state)
+        });
+        assert_eq!(interval, RpcValue::from("7s"));
+    }
+}
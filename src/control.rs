@@ -0,0 +1,473 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::*;
+use shvclient::{AppState, ClientCommandSender};
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use shvrpc::RpcMessage;
+
+use crate::anyvalue::ANY_VALUE_MOUNT;
+use crate::counter::COUNTER_MOUNT;
+use crate::mapnode::MAP_MOUNT;
+use crate::recording::{self, Step};
+use crate::{rpc, runtime};
+use crate::signals::{self, emit_batch, emit_chng, MessageSink};
+use crate::typednodes::{DECIMAL_MOUNT, INT_LIST_MOUNT, MODE_MOUNT, TIMESTAMP_MOUNT};
+use crate::{tasks, State, NUMBER_MOUNT, TEXT_MOUNT};
+
+pub(crate) const CONTROL_MOUNT: &str = "control";
+
+/// Writes several state nodes in one call, each path as its node's `set` would, see [`settable`].
+///
+/// The writes are not atomic: entries are applied one by one in map order and a
+/// failing entry does not roll back or abort the others. The result maps every
+/// requested path to `true` on success or to an error message string. The `chng`
+/// signals of all successful changes are emitted after the last write, in the
+/// order selected by `--signal-order`.
+pub(crate) async fn set_many(state: &State, client_cmd_tx: &impl MessageSink, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+    let Some(Value::Map(entries)) = param.map(RpcValue::value) else {
+        return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected {path: value, ...}"));
+    };
+    let mut result = Map::new();
+    let mut changes = Vec::new();
+    for (path, value) in entries.iter() {
+        let status = match set_path(state, path, value).await {
+            Ok(changed) => {
+                changes.extend(changed.map(|value| (path.clone(), value)));
+                true.into()
+            }
+            Err(msg) => msg.into(),
+        };
+        result.insert(path.clone(), status);
+    }
+    emit_batch(state, client_cmd_tx, changes);
+    Ok(result.into())
+}
+
+/// Forwards `{"path": String, "method": String, "param": RpcValue}` through the broker
+/// and returns `{"result": RpcValue, "latencyMs": Int}`. An error response of the
+/// called method is returned as the error of this call.
+pub(crate) async fn call(client_cmd_tx: &ClientCommandSender, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+    let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected {path, method, param}");
+    let Some(Value::Map(call)) = param.map(RpcValue::value) else {
+        return Err(invalid());
+    };
+    let (Some(path), Some(method)) = (call.get("path"), call.get("method")) else {
+        return Err(invalid());
+    };
+    if !path.is_string() || !method.is_string() {
+        return Err(invalid());
+    }
+    let started = Instant::now();
+    let result = rpc::call(client_cmd_tx, path.as_str(), method.as_str(), call.get("param").cloned()).await?;
+    let mut map = Map::new();
+    map.insert("result".into(), result);
+    map.insert("latencyMs".into(), (started.elapsed().as_millis() as i64).into());
+    Ok(map.into())
+}
+
+/// Whether [`set_path`] can write the node at `path`.
+pub(crate) fn settable(path: &str) -> bool {
+    matches!(
+        path,
+        NUMBER_MOUNT | TEXT_MOUNT | TIMESTAMP_MOUNT | DECIMAL_MOUNT | INT_LIST_MOUNT | MODE_MOUNT | MAP_MOUNT | ANY_VALUE_MOUNT | COUNTER_MOUNT
+    )
+}
+
+/// Stores the value without emitting, returns the signal value if it changed. Uses the
+/// same State setters as the `set` methods of the nodes, so type checks, constraints and
+/// capacity accounting are the same.
+pub(crate) async fn set_path(state: &State, path: &str, value: &RpcValue) -> Result<Option<RpcValue>, String> {
+    match path {
+        NUMBER_MOUNT => {
+            let value = i32::try_from(value).map_err(|err| format!("Invalid value for {path}: {err}"))?;
+            state.update_number(value).map_err(|err| err.message)
+        }
+        TEXT_MOUNT => {
+            let Value::String(value) = value.value() else {
+                return Err(format!("Invalid value for {path}: expected String"));
+            };
+            state.update_text(value.to_string()).await.map_err(|err| err.message)
+        }
+        TIMESTAMP_MOUNT | DECIMAL_MOUNT | INT_LIST_MOUNT | MODE_MOUNT => {
+            state.typed.set(path, value.clone(), || state.faults.capacity.try_consume()).map_err(|err| err.message)
+        }
+        MAP_MOUNT => state.map.set(Some(value), || state.faults.capacity.try_consume()).map_err(|err| err.message),
+        ANY_VALUE_MOUNT => state.update_any_value(value.clone()).await.map_err(|err| err.message),
+        COUNTER_MOUNT => state.counter.set(value),
+        _ => Err(format!("Unknown path: {path}")),
+    }
+}
+
+/// Paths with a control:playSequence in progress.
+#[derive(Default)]
+pub(crate) struct Sequences {
+    playing: Mutex<BTreeSet<String>>,
+}
+
+/// Marks a path as playing for as long as the sequence task lives, cancelled tasks included.
+/// Holds the deferred response, a task cancelled by control:cancelTask answers it with an error.
+struct Playing<S: MessageSink> {
+    app_state: AppState<State>,
+    path: String,
+    client_cmd_tx: S,
+    response: Option<RpcMessage>,
+}
+
+impl<S: MessageSink> Playing<S> {
+    fn finish(&mut self, result: Result<RpcValue, RpcError>) {
+        if let Some(mut response) = self.response.take() {
+            match result {
+                Ok(value) => response.set_result(value),
+                Err(err) => response.set_error(err),
+            };
+            self.client_cmd_tx.deliver(response);
+        }
+    }
+}
+
+impl<S: MessageSink> Drop for Playing<S> {
+    fn drop(&mut self) {
+        let cancelled = RpcError::new(RpcErrorCode::MethodCallCancelled, &format!("Sequence on {} cancelled", self.path));
+        self.finish(Err(cancelled));
+        self.app_state.sequences.playing.lock().unwrap().remove(&self.path);
+    }
+}
+
+pub(crate) fn parse_sequence(param: Option<&RpcValue>) -> Result<(String, Vec<RpcValue>, Duration), RpcError> {
+    let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [path, values, intervalMs]");
+    let Some(Value::List(list)) = param.map(RpcValue::value) else {
+        return Err(invalid());
+    };
+    match list.as_slice() {
+        [path, values, interval] if path.is_string() && interval.is_int() && interval.as_int() >= 0 => {
+            let Value::List(values) = values.value() else {
+                return Err(invalid());
+            };
+            Ok((path.as_str().to_string(), values.to_vec(), Duration::from_millis(interval.as_int() as u64)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Writes `values` to the state node at `path` one by one, `interval` apart, emitting
+/// `chng` as a set would (a value equal to the current one emits nothing). A second
+/// sequence on a path that is still playing is rejected. The response carrying the
+/// number of played values is sent when the sequence finishes, a sequence cancelled by
+/// control:cancelTask is answered with a MethodCallCancelled error.
+pub(crate) fn play_sequence(
+    app_state: AppState<State>,
+    client_cmd_tx: impl MessageSink + Send + Sync + 'static,
+    response: RpcMessage,
+    path: String,
+    values: Vec<RpcValue>,
+    interval: Duration,
+) -> Result<(), RpcError> {
+    if !app_state.sequences.playing.lock().unwrap().insert(path.clone()) {
+        return Err(RpcError::new(RpcErrorCode::MethodCallException, &format!("A sequence is already playing on {path}")));
+    }
+    let mut playing = Playing { app_state: app_state.clone(), path: path.clone(), client_cmd_tx, response: Some(response) };
+    let name = format!("sequence:{path}");
+    tasks::spawn(&app_state, &name, async move {
+        let mut played: i64 = 0;
+        let mut result = Ok(());
+        for (n, value) in values.iter().enumerate() {
+            if n > 0 {
+                runtime::sleep(interval).await;
+            }
+            match set_path(&playing.app_state, &path, value).await {
+                Ok(changed) => {
+                    if let Some(value) = changed {
+                        emit_chng(&playing.app_state, &playing.client_cmd_tx, &path, value);
+                    }
+                    played += 1;
+                }
+                Err(msg) => {
+                    result = Err(RpcError::new(RpcErrorCode::InvalidParam, &msg));
+                    break;
+                }
+            }
+        }
+        playing.finish(result.map(|()| played.into()));
+    });
+    Ok(())
+}
+
+pub(crate) fn parse_acceleration(param: Option<&RpcValue>) -> Result<(String, Duration, Duration, u32), RpcError> {
+    let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [path, startIntervalMs, endIntervalMs, steps]");
+    let Some(Value::List(list)) = param.map(RpcValue::value) else {
+        return Err(invalid());
+    };
+    let interval = |value: &RpcValue| (value.is_int() && value.as_int() >= 0).then(|| Duration::from_millis(value.as_int() as u64));
+    match list.as_slice() {
+        [path, start, end, steps] if path.is_string() && steps.is_int() && steps.as_int() > 0 => {
+            let (Some(start), Some(end)) = (interval(start), interval(end)) else {
+                return Err(invalid());
+            };
+            let steps = u32::try_from(steps.as_int()).map_err(|_| invalid())?;
+            if path.as_str() != NUMBER_MOUNT {
+                return Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Not a numeric state node: {}", path.as_str())));
+            }
+            Ok((path.as_str().to_string(), start, end, steps))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Changes the numeric node at `path` `steps` times, each change adds the number step
+/// (1 without `--number-step`) and emits `chng`. The interval between two changes ramps
+/// linearly from `start` to `end`. The response carrying the total duration in
+/// milliseconds is sent when the last change is made, a failing change ends it early
+/// with an error and cancelling it by control:cancelTask with a MethodCallCancelled error.
+/// Shares the per-path exclusivity with control:playSequence.
+pub(crate) fn accelerate(
+    app_state: AppState<State>,
+    client_cmd_tx: impl MessageSink + Send + Sync + 'static,
+    response: RpcMessage,
+    path: String,
+    start: Duration,
+    end: Duration,
+    steps: u32,
+) -> Result<(), RpcError> {
+    if !app_state.sequences.playing.lock().unwrap().insert(path.clone()) {
+        return Err(RpcError::new(RpcErrorCode::MethodCallException, &format!("A sequence is already playing on {path}")));
+    }
+    let mut playing = Playing { app_state: app_state.clone(), path: path.clone(), client_cmd_tx, response: Some(response) };
+    let name = format!("accelerate:{path}");
+    tasks::spawn(&app_state, &name, async move {
+        let state = &playing.app_state;
+        let increment = state.number_step.unwrap_or(1);
+        let started = Instant::now();
+        let mut result = Ok(());
+        for n in 0..steps {
+            if n > 0 {
+                // Interval n - 1 of steps - 1, the first one is `start` and the last one `end`.
+                let ratio = if steps > 2 { (n - 1) as f64 / (steps - 2) as f64 } else { 0. };
+                let interval = start.as_secs_f64() + (end.as_secs_f64() - start.as_secs_f64()) * ratio;
+                runtime::sleep(Duration::from_secs_f64(interval)).await;
+            }
+            let value = state.number.load(Ordering::SeqCst).saturating_add(increment);
+            match state.update_number(value) {
+                Ok(changed) => {
+                    if let Some(value) = changed {
+                        emit_chng(state, &playing.client_cmd_tx, &path, value);
+                    }
+                }
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        playing.finish(result.map(|()| (started.elapsed().as_millis() as i64).into()));
+    });
+    Ok(())
+}
+
+/// Replays a trace exported by control:exportRecording with its original relative timing.
+///
+/// Recorded `set` requests on nodes control:setMany can write are applied again and emit
+/// `chng` as they did when recorded, so recorded signals of those nodes are not sent a
+/// second time. All other recorded signals are sent as they are. Other requests are
+/// skipped. Returns the number of trace entries, the replay runs as a cancellable task.
+pub(crate) async fn replay_scenario(app_state: &AppState<State>, client_cmd_tx: impl MessageSink + Send + Sync + 'static, file: &str) -> Result<RpcValue, RpcError> {
+    let steps = recording::load_scenario(file).await?;
+    let count = steps.len() as i64;
+    let state = app_state.clone();
+    tasks::spawn(app_state, "replayScenario", async move {
+        let started = Instant::now();
+        for Step { offset, kind, path, name, value } in steps {
+            runtime::sleep(offset.saturating_sub(started.elapsed())).await;
+            let base_path = state.base_path(&path);
+            let settable = settable(base_path);
+            match kind.as_str() {
+                "request" if name == "set" && settable => match set_path(&state, base_path, &value).await {
+                    Ok(Some(changed)) => emit_chng(&state, &client_cmd_tx, base_path, changed),
+                    Ok(None) => {}
+                    Err(msg) => warn!("Scenario replay: {msg}"),
+                },
+                "signal" if !settable => signals::emit_recorded(&state, &client_cmd_tx, &path, &name, value),
+                _ => {}
+            }
+        }
+        info!("Scenario replay finished");
+    });
+    Ok(count.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use shvclient::clientnode::SIG_CHNG;
+
+    use super::*;
+    use crate::signals::Collected;
+
+    fn chng(path: &str, value: impl Into<RpcValue>) -> (String, String, RpcValue) {
+        (path.to_string(), SIG_CHNG.to_string(), value.into())
+    }
+
+    fn response(method: &str) -> RpcMessage {
+        RpcMessage::new_request("control", method, None).prepare_response().unwrap()
+    }
+
+    /// Waits for the deferred response, returns the signals delivered before it and the response.
+    async fn signals_and_response(collected: &Collected) -> (Vec<(String, String, RpcValue)>, RpcMessage) {
+        let mut messages = Vec::new();
+        for _ in 0..200 {
+            messages.extend(collected.take_messages());
+            if messages.last().is_some_and(RpcMessage::is_response) {
+                let response = messages.pop().unwrap();
+                let signals = messages.iter()
+                    .map(|message| (
+                        message.shv_path().unwrap_or_default().to_string(),
+                        message.method().unwrap_or_default().to_string(),
+                        message.param().cloned().unwrap_or_else(RpcValue::null),
+                    ))
+                    .collect();
+                return (signals, response);
+            }
+            runtime::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("No response delivered");
+    }
+
+    #[test]
+    fn set_many_applies_valid_entries_only() {
+        let state = crate::test_state(&[]);
+        let collected = Collected::default();
+        let text = runtime::block_on(state.text.read()).clone();
+        let param = RpcValue::from_cpon(r#"{
+            "state/number": 5,
+            "state/text": 7,
+            "state/map": {"a": 1},
+            "test/anyValue": [1, "x"],
+            "state/counter": 3,
+            "state/nope": 1
+        }"#).unwrap();
+        let result = runtime::block_on(set_many(&state, &collected, Some(&param))).unwrap();
+        let Value::Map(result) = result.value() else {
+            panic!("setMany result is not a Map");
+        };
+        for path in [NUMBER_MOUNT, MAP_MOUNT, ANY_VALUE_MOUNT, COUNTER_MOUNT] {
+            assert_eq!(result.get(path), Some(&RpcValue::from(true)), "{path}");
+        }
+        for path in [TEXT_MOUNT, "state/nope"] {
+            assert!(result.get(path).is_some_and(RpcValue::is_string), "{path}");
+        }
+        let map = RpcValue::from_cpon(r#"{"a": 1}"#).unwrap();
+        let any_value = RpcValue::from_cpon(r#"[1, "x"]"#).unwrap();
+        assert_eq!(collected.take(), [
+            chng(COUNTER_MOUNT, 3u64),
+            chng(MAP_MOUNT, map),
+            chng(NUMBER_MOUNT, 5),
+            chng(ANY_VALUE_MOUNT, any_value),
+        ]);
+        assert_eq!(state.number.load(Ordering::SeqCst), 5);
+        assert_eq!(*runtime::block_on(state.text.read()), text);
+    }
+
+    #[test]
+    fn sequence_emits_values_in_order() {
+        let state = crate::test_state(&[]);
+        let collected = Arc::new(Collected::default());
+        let values = vec![1.into(), 2.into(), 3.into()];
+        let (signals, response) = runtime::block_on(async {
+            play_sequence(state.clone(), collected.clone(), response("playSequence"), NUMBER_MOUNT.to_string(), values, Duration::ZERO).unwrap();
+            signals_and_response(&collected).await
+        });
+        assert_eq!(signals, [chng(NUMBER_MOUNT, 1), chng(NUMBER_MOUNT, 2), chng(NUMBER_MOUNT, 3)]);
+        assert_eq!(response.result().unwrap(), RpcValue::from(3));
+    }
+
+    #[test]
+    fn cancelled_sequence_responds_with_error() {
+        let state = crate::test_state(&[]);
+        let collected = Arc::new(Collected::default());
+        let values = vec![1.into(), 2.into()];
+        let (signals, response) = runtime::block_on(async {
+            play_sequence(state.clone(), collected.clone(), response("playSequence"), NUMBER_MOUNT.to_string(), values, Duration::from_secs(60)).unwrap();
+            runtime::sleep(Duration::from_millis(50)).await;
+            state.tasks.cancel(&format!("sequence:{NUMBER_MOUNT}")).await.unwrap();
+            signals_and_response(&collected).await
+        });
+        assert_eq!(signals, [chng(NUMBER_MOUNT, 1)]);
+        let err = response.result().unwrap_err();
+        assert_eq!(err.code as i32, RpcErrorCode::MethodCallCancelled as i32);
+        assert!(state.sequences.playing.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn acceleration_emits_once_per_step() {
+        let state = crate::test_state(&[]);
+        let collected = Arc::new(Collected::default());
+        let (signals, response) = runtime::block_on(async {
+            let (start, end) = (Duration::from_millis(1), Duration::from_millis(10));
+            accelerate(state.clone(), collected.clone(), response("accelerate"), NUMBER_MOUNT.to_string(), start, end, 5).unwrap();
+            signals_and_response(&collected).await
+        });
+        assert_eq!(signals, (1..=5).map(|value| chng(NUMBER_MOUNT, value)).collect::<Vec<_>>());
+        assert!(response.result().is_ok());
+        assert_eq!(state.number.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn cancelled_acceleration_responds_with_error() {
+        let state = crate::test_state(&[]);
+        let collected = Arc::new(Collected::default());
+        let (signals, response) = runtime::block_on(async {
+            let interval = Duration::from_secs(60);
+            accelerate(state.clone(), collected.clone(), response("accelerate"), NUMBER_MOUNT.to_string(), interval, interval, 3).unwrap();
+            runtime::sleep(Duration::from_millis(50)).await;
+            state.tasks.cancel(&format!("accelerate:{NUMBER_MOUNT}")).await.unwrap();
+            signals_and_response(&collected).await
+        });
+        assert_eq!(signals, [chng(NUMBER_MOUNT, 1)]);
+        let err = response.result().unwrap_err();
+        assert_eq!(err.code as i32, RpcErrorCode::MethodCallCancelled as i32);
+    }
+
+    #[test]
+    fn replayed_scenario_emits_the_recorded_signals() {
+        let file = std::env::temp_dir().join(format!("shvbrokertestingdevice-{}-scenario.cpon", std::process::id()));
+        let file = file.to_str().unwrap();
+        let state = crate::test_state(&["--recording-file", file]);
+        let recorded = Collected::default();
+        state.recording.start();
+        for value in [1, 2] {
+            let request = RpcMessage::new_request(NUMBER_MOUNT, "set", Some(value.into()));
+            runtime::block_on(crate::dispatch::handle(&state, &request, "set", "Int", async {
+                let changed = state.update_number(value).unwrap().unwrap();
+                emit_chng(&state, &recorded, NUMBER_MOUNT, changed);
+                Some(Ok(true.into()))
+            }));
+        }
+        emit_chng(&state, &recorded, "test/other", "event".into());
+        state.recording.stop();
+        runtime::block_on(state.recording.export()).unwrap();
+        let recorded = recorded.take();
+        state.update_number(0).unwrap();
+
+        let replayed = Arc::new(Collected::default());
+        let signals = runtime::block_on(async {
+            assert_eq!(replay_scenario(&state, replayed.clone(), file).await.unwrap(), RpcValue::from(5));
+            let mut signals = Vec::new();
+            for _ in 0..200 {
+                signals.extend(replayed.take());
+                if signals.len() >= recorded.len() {
+                    break;
+                }
+                runtime::sleep(Duration::from_millis(10)).await;
+            }
+            signals
+        });
+        let _ = std::fs::remove_file(file);
+        assert_eq!(signals, recorded);
+        assert_eq!(state.number.load(Ordering::SeqCst), 2);
+    }
+}
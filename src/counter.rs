@@ -0,0 +1,97 @@
+//! Unsigned counter of configurable width wrapping around at its maximum.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use shvclient::AppState;
+use shvproto::rpcvalue::Value;
+use shvproto::RpcValue;
+
+use crate::signals::{emit_chng, MessageSink};
+use crate::{runtime, State};
+
+pub(crate) const COUNTER_MOUNT: &str = "state/counter";
+
+pub(crate) struct Counter {
+    max: u64,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub(crate) fn new(bits: u32) -> Result<Self, String> {
+        if !matches!(bits, 8 | 16 | 32 | 64) {
+            return Err(format!("Unsupported counter width {bits}, expected 8, 16, 32 or 64"));
+        }
+        Ok(Self { max: u64::MAX >> (64 - bits), value: Default::default() })
+    }
+
+    pub(crate) fn value(&self) -> u64 {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    /// Stores `value` if it fits the width, returns the signal value if it changed.
+    pub(crate) fn set(&self, value: &RpcValue) -> Result<Option<RpcValue>, String> {
+        let value = match value.value() {
+            Value::UInt(value) => Some(*value),
+            Value::Int(value) => u64::try_from(*value).ok(),
+            _ => None,
+        };
+        let value = value.filter(|value| *value <= self.max)
+            .ok_or_else(|| format!("Invalid value for {COUNTER_MOUNT}: expected UInt up to {}", self.max))?;
+        let changed = self.value.swap(value, Ordering::SeqCst) != value;
+        Ok(changed.then(|| value.into()))
+    }
+
+    pub(crate) fn reset(&self) {
+        self.value.store(0, Ordering::SeqCst);
+    }
+
+    fn increment(&self) -> u64 {
+        let max = self.max;
+        let prev = self.value.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some(if v >= max { 0 } else { v + 1 }))
+            .unwrap_or_default();
+        if prev >= max { 0 } else { prev + 1 }
+    }
+}
+
+pub(crate) fn inc(state: &State, client_cmd_tx: &impl MessageSink) -> u64 {
+    let value = state.counter.increment();
+    emit_chng(state, client_cmd_tx, COUNTER_MOUNT, value.into());
+    value
+}
+
+pub(crate) async fn auto_increment(app_state: AppState<State>, interval: Duration) {
+    loop {
+        runtime::sleep(interval).await;
+        inc(&app_state, &app_state.connection);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increment_wraps_to_zero_past_max() {
+        let counter = Counter::new(8).unwrap();
+        for expected in 1..=255 {
+            assert_eq!(counter.increment(), expected);
+        }
+        assert_eq!(counter.increment(), 0);
+        assert_eq!(counter.value(), 0);
+        assert_eq!(counter.increment(), 1);
+    }
+
+    #[test]
+    fn full_width_counter_wraps() {
+        let counter = Counter::new(64).unwrap();
+        counter.value.store(u64::MAX, Ordering::SeqCst);
+        assert_eq!(counter.increment(), 0);
+    }
+
+    #[test]
+    fn rejects_unsupported_width() {
+        assert!(Counter::new(12).is_err());
+        assert!(Counter::new(0).is_err());
+    }
+}
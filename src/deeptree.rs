@@ -0,0 +1,73 @@
+//! Chain of nested nodes `deep/0/1/.../<depth - 1>` for path handling stress tests.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use log::*;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+pub(crate) const DEEP_TREE_MOUNT: &str = "deep";
+
+/// SHV does not limit path length, longer paths than this are known to be
+/// rejected or truncated by some broker and client implementations.
+const PATH_LENGTH_WARN_BYTES: usize = 1024;
+
+/// One i32 value per level, empty without `--deep-tree`.
+pub(crate) struct DeepTree {
+    values: Vec<AtomicI32>,
+}
+
+impl DeepTree {
+    pub(crate) fn new(depth: usize) -> Self {
+        let tree = Self { values: (0..depth).map(|_| AtomicI32::new(0)).collect() };
+        if let Some(leaf) = tree.paths().last() {
+            if leaf.len() > PATH_LENGTH_WARN_BYTES {
+                warn!("Deep tree leaf path is {} bytes long, some brokers may not route it", leaf.len());
+            }
+        }
+        tree
+    }
+
+    /// Mount paths of all levels, from the top one to the leaf.
+    pub(crate) fn paths(&self) -> Vec<String> {
+        (0..self.values.len())
+            .scan(DEEP_TREE_MOUNT.to_string(), |path, level| {
+                path.push_str(&format!("/{level}"));
+                Some(path.clone())
+            })
+            .collect()
+    }
+
+    fn level(&self, path: Option<&str>) -> Result<&AtomicI32, RpcError> {
+        path.and_then(|path| path.strip_prefix(DEEP_TREE_MOUNT))
+            .and_then(|levels| self.values.get(levels.matches('/').count().checked_sub(1)?))
+            .ok_or_else(|| RpcError::new(RpcErrorCode::MethodNotFound, "Not a deep tree node"))
+    }
+
+    pub(crate) fn get(&self, path: Option<&str>) -> Result<RpcValue, RpcError> {
+        Ok(self.level(path)?.load(Ordering::SeqCst).into())
+    }
+
+    /// Returns the signal value if it changed.
+    pub(crate) fn set(&self, path: Option<&str>, value: i32) -> Result<Option<RpcValue>, RpcError> {
+        let changed = self.level(path)?.swap(value, Ordering::SeqCst) != value;
+        Ok(changed.then(|| value.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth_ten_leaf_responds_to_get() {
+        let state = crate::test_state(&["--deep-tree", "10"]);
+        let leaf = "deep/0/1/2/3/4/5/6/7/8/9";
+        assert!(crate::device_nodes(&state).iter().any(|(path, _)| path == leaf));
+        assert_eq!(state.deep_tree.get(Some(leaf)).unwrap(), RpcValue::from(0));
+        assert_eq!(state.deep_tree.set(Some(leaf), 5).unwrap(), Some(RpcValue::from(5)));
+        assert_eq!(state.deep_tree.get(Some(leaf)).unwrap(), RpcValue::from(5));
+        assert_eq!(state.deep_tree.get(Some("deep/0/1")).unwrap(), RpcValue::from(0));
+        assert!(state.deep_tree.get(Some("deep/0/1/2/3/4/5/6/7/8/9/10")).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use std::future::Future;
+use std::time::Instant;
+
+use log::*;
+use shvproto::rpcvalue::Value;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use shvrpc::RpcMessage;
+
+use crate::faults::{self, ResponseFault};
+use crate::{runtime, State};
+
+/// Fixed size of the message envelope (meta tags, request id, framing) used when
+/// estimating message sizes.
+pub(crate) const MESSAGE_OVERHEAD_BYTES: usize = 16;
+
+/// Same as `shvclient::fixed_node!`, every method result additionally passes
+/// through [`finish`] so that per-request bookkeeping stays in one place.
+///
+/// The steps mirror [`handle`] but are expanded inline instead of calling it: a method
+/// body owns the request and may move it, e.g. into a deferred response, while the
+/// future passed to `handle` would have to borrow it for the whole call. Changes to
+/// the request handling go into both.
+macro_rules! device_node {
+    (
+        $fn_name:ident ($request:ident, $client_cmd_tx:ident, $app_state:ident : $T:ty) {
+            $($method:literal [$($flags:ident)|+, $access:ident, $param:literal, $result:literal] $(($param_binding:ident : $param_type:ty))? => $body:block)+
+        }
+    ) => {
+        shvclient::fixed_node!{
+            $fn_name($request, $client_cmd_tx, $app_state: $T) {
+                $(
+                    $method [$($flags)|+, $access, $param, $result] $(($param_binding: $param_type))? => {
+                        let __state = $app_state.clone();
+                        let __path = $request.shv_path().unwrap_or_default().to_string();
+                        let __started = std::time::Instant::now();
+                        __state.history.record(__state.clock.now(), &__path, $method, &$request);
+                        if __state.recording.is_active() {
+                            __state.recording.request(__state.clock.now(), &__path, $method, $request.param().cloned());
+                        }
+                        // Prepared up front as the handler may consume the request, the fault
+                        // itself is only taken in finish once a response is produced.
+                        let __malformed_response = __state.faults.encoding.is_armed()
+                            .then(|| $request.prepare_response().ok())
+                            .flatten();
+                        let __checked = crate::dispatch::check_request_size(&__state, $request.param())
+                            .and_then(|_| crate::params::check($param, $request.param()))
+                            .and_then(|_| __state.faults.error_rate.roll(__state.base_path(&__path)));
+                        let __result = match __checked {
+                            Ok(()) => {
+                                crate::dispatch::blocking_work(&__state).await;
+                                let __result = async { $body }.await;
+                                __state.latency.apply(__state.base_path(&__path)).await;
+                                crate::signals::wait_for_room(&__state).await;
+                                if __result.is_some() {
+                                    __state.backpressure.pace().await;
+                                }
+                                __result
+                            }
+                            Err(err) => Some(Err(err)),
+                        };
+                        crate::dispatch::finish(&__state, &__path, $method, __started, __result, __malformed_response)
+                    }
+                )+
+            }
+        }
+    };
+}
+
+/// The request handling of [`device_node!`] for nodes the client library passes the raw
+/// request to, such as the dynamically mounted declared nodes. `body` runs once the
+/// request passed the checks.
+pub(crate) async fn handle<Fut>(state: &State, request: &RpcMessage, method: &str, param_signature: &str, body: Fut) -> Option<Result<RpcValue, RpcError>>
+where
+    Fut: Future<Output = Option<Result<RpcValue, RpcError>>>,
+{
+    let path = request.shv_path().unwrap_or_default();
+    let started = Instant::now();
+    state.history.record(state.clock.now(), path, method, request);
+    if state.recording.is_active() {
+        state.recording.request(state.clock.now(), path, method, request.param().cloned());
+    }
+    let malformed_response = state.faults.encoding.is_armed()
+        .then(|| request.prepare_response().ok())
+        .flatten();
+    let checked = check_request_size(state, request.param())
+        .and_then(|_| crate::params::check(param_signature, request.param()))
+        .and_then(|_| state.faults.error_rate.roll(state.base_path(path)));
+    let result = match checked {
+        Ok(()) => {
+            blocking_work(state).await;
+            let result = body.await;
+            state.latency.apply(state.base_path(path)).await;
+            crate::signals::wait_for_room(state).await;
+            if result.is_some() {
+                state.backpressure.pace().await;
+            }
+            result
+        }
+        Err(err) => Some(Err(err)),
+    };
+    finish(state, path, method, started, result, malformed_response)
+}
+
+/// Answer to a call of a method the node does not have, see `--unknown-method`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub(crate) enum UnknownMethod {
+    /// MethodNotFound, as the SHV RPC specification requires.
+    #[default]
+    Error,
+    /// A Null result, testing only.
+    Null,
+    /// The method name as the result, testing only.
+    Echo,
+}
+
+impl UnknownMethod {
+    pub(crate) fn respond(self, path: &str, method: &str) -> Result<RpcValue, RpcError> {
+        match self {
+            UnknownMethod::Error => Err(RpcError::new(RpcErrorCode::MethodNotFound, &format!("No method {method} on {path}"))),
+            UnknownMethod::Null => Ok(RpcValue::null()),
+            UnknownMethod::Echo => Ok(method.into()),
+        }
+    }
+}
+
+/// Rejects a request whose ChainPack encoded param exceeds `--max-request-bytes`, before its handler runs.
+pub(crate) fn check_request_size(state: &State, param: Option<&RpcValue>) -> Result<(), RpcError> {
+    let (Some(limit), Some(param)) = (state.max_request_bytes, param) else {
+        return Ok(());
+    };
+    let size = param.to_chainpack().len();
+    if size > limit {
+        return Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Request too large: {size} bytes, limit is {limit}")));
+    }
+    Ok(())
+}
+
+/// Burns CPU for `--blocking-work-ms` on the blocking pool, the async executor keeps serving other handlers meanwhile.
+pub(crate) async fn blocking_work(state: &State) {
+    let Some(duration) = state.blocking_work else {
+        return;
+    };
+    let spent = runtime::spawn_blocking(move || {
+        let started = Instant::now();
+        let mut x: u64 = 0;
+        while started.elapsed() < duration {
+            x = std::hint::black_box(x.wrapping_mul(6364136223846793005).wrapping_add(1));
+        }
+        started.elapsed()
+    }).await;
+    state.metrics.record_blocking_work(spent);
+}
+
+pub(crate) fn finish(
+    state: &State,
+    path: &str,
+    method: &str,
+    started: Instant,
+    mut result: Option<Result<RpcValue, RpcError>>,
+    malformed_response: Option<RpcMessage>,
+) -> Option<Result<RpcValue, RpcError>> {
+    state.latency_histogram.record(started.elapsed());
+    state.request_rate.record();
+    state.method_stats.record(path, method, state.clock.now());
+    if result.is_some() {
+        match state.faults.responses.take() {
+            Some(ResponseFault::Drop) => {
+                debug!("Fault: dropping response of {path}:{method}");
+                return None;
+            }
+            Some(ResponseFault::Error(err)) => result = Some(Err(err)),
+            None => {}
+        }
+    }
+    if let Some(Err(err)) = &result {
+        state.error_counts.record(err);
+    }
+    if method == "get" {
+        if let Some(Ok(value)) = result {
+            let value = if state.faults.availability.is_unavailable(state.base_path(path)) {
+                RpcValue::null()
+            } else {
+                state.node_formats.wrap(state.base_path(path), state.faults.corruption.corrupt(path, value))
+            };
+            result = Some(Ok(value));
+        }
+    }
+    if result.is_some() && state.faults.partition.drops_responses() {
+        debug!("Partition: dropping response of {path}:{method}");
+        return None;
+    }
+    if let Some(Ok(value)) = &result {
+        state.metrics.record_message(estimate_size(value) + MESSAGE_OVERHEAD_BYTES);
+    }
+    // A deferred response (None) leaves an armed encoding fault for the next one.
+    let result = result?;
+    if let Some(response) = malformed_response {
+        if let (Some(fault), Some(client_cmd_tx)) = (state.faults.encoding.take(), state.connection.client_cmd_tx()) {
+            warn!("Sending malformed response of {path}:{method}: {fault:?}");
+            let _ = client_cmd_tx.send_message(faults::malform(fault, response, result));
+            return None;
+        }
+    }
+    Some(result)
+}
+
+/// Approximates the ChainPack encoded size of a value without serializing it.
+pub(crate) fn estimate_size(value: &RpcValue) -> usize {
+    match value.value() {
+        Value::Null | Value::Bool(_) => 1,
+        Value::Int(_) | Value::UInt(_) | Value::Double(_) | Value::DateTime(_) => 9,
+        Value::Decimal(_) => 11,
+        Value::String(s) => 5 + s.len(),
+        Value::Blob(b) => 5 + b.len(),
+        Value::List(list) => 2 + list.iter().map(estimate_size).sum::<usize>(),
+        Value::Map(map) => 2 + map.iter().map(|(k, v)| 5 + k.len() + estimate_size(v)).sum::<usize>(),
+        Value::IMap(map) => 2 + map.values().map(|v| 5 + estimate_size(v)).sum::<usize>(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shvclient::AppState;
+    use shvproto::rpcvalue::IMap;
+
+    use super::*;
+
+    fn armed_state() -> AppState<State> {
+        let state = crate::test_state(&["--enable-encoding-faults"]);
+        state.faults.encoding.set(Some(&"empty".into())).unwrap();
+        state
+    }
+
+    fn response() -> Option<RpcMessage> {
+        RpcMessage::new_request("state/number", "get", None).prepare_response().ok()
+    }
+
+    #[test]
+    fn deferred_response_keeps_encoding_fault() {
+        let state = armed_state();
+        assert!(finish(&state, "state/number", "get", Instant::now(), None, response()).is_none());
+        assert!(state.faults.encoding.is_armed());
+    }
+
+    #[test]
+    fn produced_response_takes_encoding_fault() {
+        let state = armed_state();
+        finish(&state, "state/number", "get", Instant::now(), Some(Ok(1.into())), response());
+        assert!(!state.faults.encoding.is_armed());
+    }
+
+    #[test]
+    fn errors_are_counted_per_code() {
+        let state = crate::test_state(&[]);
+        for _ in 0..2 {
+            let invalid_param = crate::params::check("Int", Some(&"text".into())).unwrap_err();
+            finish(&state, "state/number", "set", Instant::now(), Some(Err(invalid_param)), None);
+        }
+        let not_found = RpcError::new(RpcErrorCode::MethodNotFound, "No method");
+        finish(&state, "state/number", "foo", Instant::now(), Some(Err(not_found)), None);
+        finish(&state, "state/number", "get", Instant::now(), Some(Ok(1.into())), None);
+        let mut expected = IMap::new();
+        expected.insert(RpcErrorCode::InvalidParam as i32, 2.into());
+        expected.insert(RpcErrorCode::MethodNotFound as i32, 1.into());
+        assert_eq!(state.error_counts.value(), RpcValue::from(expected));
+        state.error_counts.reset();
+        assert_eq!(state.error_counts.value(), RpcValue::from(IMap::new()));
+    }
+
+    #[test]
+    fn blocking_work_delays_the_handler() {
+        let state = crate::test_state(&["--blocking-work-ms", "50"]);
+        let request = RpcMessage::new_request("state/number", "get", None);
+        let started = Instant::now();
+        let result = runtime::block_on(handle(&state, &request, "get", "Null", async { Some(Ok(1.into())) }));
+        assert!(started.elapsed() >= std::time::Duration::from_millis(50));
+        assert_eq!(result.unwrap().unwrap(), RpcValue::from(1));
+        let blocking_work_ms = state.metrics.counters().into_iter()
+            .find_map(|(name, value)| (name == "blockingWorkMs").then_some(value));
+        assert!(blocking_work_ms.is_some_and(|ms| ms >= 50), "{blocking_work_ms:?}");
+    }
+
+    #[test]
+    fn request_above_size_limit_is_rejected_before_the_handler() {
+        let state = crate::test_state(&["--max-request-bytes", "100"]);
+        let call = |size: usize| {
+            let request = RpcMessage::new_request("test/blob", "set", Some(vec![0u8; size].into()));
+            let ran = std::cell::Cell::new(false);
+            let result = runtime::block_on(handle(&state, &request, "set", "Blob", async { ran.set(true); Some(Ok(().into())) }));
+            (result.unwrap(), ran.get())
+        };
+        let (result, ran) = call(50);
+        assert!(result.is_ok() && ran);
+        let (result, ran) = call(1000);
+        let err = result.unwrap_err();
+        assert_eq!(err.code as i32, RpcErrorCode::InvalidParam as i32);
+        assert!(err.message.starts_with("Request too large"), "{}", err.message);
+        assert!(!ran);
+    }
+}
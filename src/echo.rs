@@ -0,0 +1,33 @@
+//! Echo node for request timeout and concurrency tests.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+pub(crate) const ECHO_MOUNT: &str = "echo";
+pub(crate) const ECHO_DELAY_MOUNT: &str = "echo/delay";
+
+/// Delay of echo:echo responses in milliseconds.
+#[derive(Default)]
+pub(crate) struct Echo {
+    delay_ms: AtomicU64,
+}
+
+impl Echo {
+    pub(crate) fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay_ms.load(Ordering::SeqCst))
+    }
+
+    pub(crate) fn delay_value(&self) -> RpcValue {
+        (self.delay_ms.load(Ordering::SeqCst) as i64).into()
+    }
+
+    /// Returns the signal value if it changed.
+    pub(crate) fn set_delay(&self, delay_ms: i64) -> Result<Option<RpcValue>, RpcError> {
+        let delay_ms = u64::try_from(delay_ms).map_err(|_| RpcError::new(RpcErrorCode::InvalidParam, "Delay must not be negative"))?;
+        let changed = self.delay_ms.swap(delay_ms, Ordering::SeqCst) != delay_ms;
+        Ok(changed.then(|| (delay_ms as i64).into()))
+    }
+}
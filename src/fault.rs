@@ -0,0 +1,76 @@
+//! Value slowly drifting out of its healthy band, for predictive-maintenance tests.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use shvclient::{AppState, ClientCommandSender};
+use shvproto::rpcvalue::Map;
+use shvproto::RpcValue;
+
+use crate::signals::emit_chng;
+use crate::{runtime, tasks, State};
+
+pub(crate) const FAULT_SIM_MOUNT: &str = "state/fault_sim";
+const TICK: Duration = Duration::from_millis(100);
+
+pub(crate) struct FaultSim {
+    baseline: f64,
+    target: f64,
+    threshold: f64,
+    value: Mutex<f64>,
+    generation: AtomicU64,
+}
+
+impl FaultSim {
+    /// The value is healthy while it deviates from `baseline` by less than `threshold`.
+    pub(crate) fn new(baseline: f64, target: f64, threshold: f64) -> Self {
+        Self { baseline, target, threshold, value: Mutex::new(baseline), generation: Default::default() }
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let value = *self.value.lock().unwrap();
+        let mut map = Map::new();
+        map.insert("value".into(), value.into());
+        map.insert("healthy".into(), ((value - self.baseline).abs() < self.threshold).into());
+        map.into()
+    }
+
+    /// Returns to the baseline immediately, a running ramp stops at its next tick.
+    pub(crate) fn reset(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        *self.value.lock().unwrap() = self.baseline;
+    }
+}
+
+/// Drifts towards the fault target over `duration` and holds there.
+pub(crate) fn trigger(app_state: AppState<State>, client_cmd_tx: ClientCommandSender, duration: Duration) {
+    let target = app_state.fault_sim.target;
+    tasks::spawn(&app_state, "faultSim", ramp(app_state.clone(), client_cmd_tx, target, duration));
+}
+
+/// Ramps back to the baseline over `duration`.
+pub(crate) fn recover(app_state: AppState<State>, client_cmd_tx: ClientCommandSender, duration: Duration) {
+    let baseline = app_state.fault_sim.baseline;
+    tasks::spawn(&app_state, "faultSim", ramp(app_state.clone(), client_cmd_tx, baseline, duration));
+}
+
+/// Moves the value linearly to `to`, a ramp started later supersedes this one.
+async fn ramp(app_state: AppState<State>, client_cmd_tx: ClientCommandSender, to: f64, duration: Duration) {
+    let sim = &app_state.fault_sim;
+    let generation = sim.generation.fetch_add(1, Ordering::SeqCst) + 1;
+    let from = *sim.value.lock().unwrap();
+    let started = Instant::now();
+    loop {
+        let progress = if duration.is_zero() { 1. } else { (started.elapsed().as_secs_f64() / duration.as_secs_f64()).min(1.) };
+        if sim.generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        *sim.value.lock().unwrap() = from + (to - from) * progress;
+        emit_chng(&app_state, &client_cmd_tx, FAULT_SIM_MOUNT, sim.value());
+        if progress >= 1. {
+            return;
+        }
+        runtime::sleep(TICK).await;
+    }
+}
@@ -0,0 +1,783 @@
+//! Fault injection settings shared by all nodes.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use log::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode, Tag};
+use shvrpc::RpcMessage;
+
+pub(crate) struct Faults {
+    pub(crate) corruption: Corruption,
+    pub(crate) leak: Leak,
+    pub(crate) partition: Partition,
+    pub(crate) encoding: EncodingFaults,
+    pub(crate) capacity: Capacity,
+    pub(crate) availability: Availability,
+    pub(crate) responses: ResponseFaults,
+    pub(crate) error_rate: ErrorRate,
+}
+
+pub(crate) const CAPACITY_MOUNT: &str = "status/capacity";
+pub(crate) const FAULTS_MOUNT: &str = "status/faults";
+pub(crate) const AVAILABILITY_MOUNT: &str = "control/availability";
+pub(crate) const TEST_FAULT_MOUNT: &str = "test/fault";
+
+impl Faults {
+    /// Current state of every fault injection, for `status/faults`.
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("corruption".into(), self.corruption.value());
+        map.insert("leak".into(), self.leak.value());
+        map.insert("partition".into(), self.partition.value());
+        map.insert("encoding".into(), self.encoding.value());
+        map.insert("capacity".into(), self.capacity.value());
+        map.insert("availability".into(), self.availability.value());
+        map.insert("responses".into(), self.responses.value());
+        map.insert("errorRate".into(), self.error_rate.value());
+        map.into()
+    }
+
+    /// Disables every fault injection and frees leaked memory.
+    pub(crate) fn clear_all(&self) {
+        let _ = self.corruption.set_active(false);
+        self.leak.release();
+        let _ = self.partition.set(Some(&false.into()));
+        self.encoding.clear();
+        self.capacity.clear();
+        self.availability.clear();
+        self.responses.clear();
+        self.error_rate.clear();
+    }
+}
+
+/// Misbehaving responses requested on test/fault, each applies to a number of upcoming responses.
+#[derive(Default)]
+pub(crate) struct ResponseFaults {
+    drop: Mutex<u32>,
+    error: Mutex<Option<(u32, RpcErrorCode, String)>>,
+}
+
+/// What happens to the next response.
+pub(crate) enum ResponseFault {
+    Drop,
+    Error(RpcError),
+}
+
+impl ResponseFaults {
+    pub(crate) fn drop_next(&self, count: i32) -> Result<(), RpcError> {
+        let count = u32::try_from(count).map_err(|_| RpcError::new(RpcErrorCode::InvalidParam, "Count must not be negative"))?;
+        warn!("Dropping the next {count} responses");
+        *self.drop.lock().unwrap() = count;
+        Ok(())
+    }
+
+    /// Accepts `[count, code, message]`, the code is one of the standard RpcError codes 1 to 9.
+    pub(crate) fn error_next(&self, param: Option<&RpcValue>) -> Result<(), RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [count, code, message]");
+        let Some(Value::List(list)) = param.map(RpcValue::value) else {
+            return Err(invalid());
+        };
+        let [count, code, message] = list.as_slice() else {
+            return Err(invalid());
+        };
+        if !count.is_int() || !code.is_int() || !message.is_string() {
+            return Err(invalid());
+        }
+        let count = u32::try_from(count.as_int()).map_err(|_| invalid())?;
+        let code = error_code(code.as_int()).map_err(|msg| RpcError::new(RpcErrorCode::InvalidParam, &msg))?;
+        warn!("Answering the next {count} requests with error {}", code as i32);
+        *self.error.lock().unwrap() = (count > 0).then(|| (count, code, message.as_str().to_string()));
+        Ok(())
+    }
+
+    /// Returns the fault to apply to the next response, dropping takes precedence.
+    pub(crate) fn take(&self) -> Option<ResponseFault> {
+        let mut drop = self.drop.lock().unwrap();
+        if *drop > 0 {
+            *drop -= 1;
+            return Some(ResponseFault::Drop);
+        }
+        let mut error = self.error.lock().unwrap();
+        let (count, code, message) = error.as_mut()?;
+        let fault = ResponseFault::Error(RpcError::new(*code, message));
+        *count -= 1;
+        if *count == 0 {
+            *error = None;
+        }
+        Some(fault)
+    }
+
+    pub(crate) fn clear(&self) {
+        *self.drop.lock().unwrap() = 0;
+        *self.error.lock().unwrap() = None;
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("drop".into(), (*self.drop.lock().unwrap() as i64).into());
+        let error = self.error.lock().unwrap().clone();
+        map.insert("error".into(), (error.as_ref().map_or(0, |(count, _, _)| *count) as i64).into());
+        map.insert("errorCode".into(), error.map(|(_, code, _)| RpcValue::from(code as i64)).unwrap_or_default());
+        map.into()
+    }
+}
+
+/// One of the standard RpcError codes 1 to 9.
+fn error_code(code: i64) -> Result<RpcErrorCode, String> {
+    match code {
+        1 => Ok(RpcErrorCode::InvalidRequest),
+        2 => Ok(RpcErrorCode::MethodNotFound),
+        3 => Ok(RpcErrorCode::InvalidParam),
+        4 => Ok(RpcErrorCode::InternalError),
+        5 => Ok(RpcErrorCode::ParseError),
+        6 => Ok(RpcErrorCode::MethodCallTimeout),
+        7 => Ok(RpcErrorCode::MethodCallCancelled),
+        8 => Ok(RpcErrorCode::MethodCallException),
+        9 => Ok(RpcErrorCode::Unknown),
+        code => Err(format!("Unsupported error code {code}, expected 1 to 9")),
+    }
+}
+
+/// Requests failed at random before their handler runs, for client retry tests.
+///
+/// A rule fails the given percentage of the requests on its path and the nodes below
+/// it, the rule with the longest matching path applies and the rule of the empty path
+/// covers all nodes. test/fault itself is never failed, so that the rules can be changed.
+pub(crate) struct ErrorRate {
+    rules: Mutex<BTreeMap<String, (f64, RpcErrorCode)>>,
+    rng: Mutex<StdRng>,
+}
+
+impl ErrorRate {
+    /// `specs` are `--error-rate` values, `[path=]percent[:code]` with code 8
+    /// (MethodCallException) by default, e.g. `5` or `state/number=20:6`.
+    pub(crate) fn new(specs: &[String], seed: u64) -> Result<Self, String> {
+        let mut rules = BTreeMap::new();
+        for spec in specs {
+            let (path, rule) = spec.split_once('=').unwrap_or(("", spec));
+            let (percent, code) = rule.split_once(':').unwrap_or((rule, "8"));
+            let percent: f64 = percent.parse().map_err(|err| format!("Invalid error rate '{spec}': {err}"))?;
+            let code: i64 = code.parse().map_err(|err| format!("Invalid error rate '{spec}': {err}"))?;
+            rules.insert(path.to_string(), (check_percent(percent)?, error_code(code)?));
+        }
+        Ok(Self { rules: Mutex::new(rules), rng: Mutex::new(StdRng::seed_from_u64(seed)) })
+    }
+
+    /// Accepts `{"percent": Double, "code": Int, "path": String}`, code and path are
+    /// optional, a percentage of 0 removes the rule of the path.
+    pub(crate) fn set(&self, param: Option<&RpcValue>) -> Result<(), RpcError> {
+        let invalid = |msg: &str| RpcError::new(RpcErrorCode::InvalidParam, msg);
+        let Some(Value::Map(map)) = param.map(RpcValue::value) else {
+            return Err(invalid("Expected {percent, code, path}"));
+        };
+        let percent = match map.get("percent").map(RpcValue::value) {
+            Some(Value::Double(percent)) => *percent,
+            Some(Value::Int(percent)) => *percent as f64,
+            _ => return Err(invalid("percent must be a number")),
+        };
+        let percent = check_percent(percent).map_err(|msg| invalid(&msg))?;
+        let code = match map.get("code") {
+            None => RpcErrorCode::MethodCallException,
+            Some(code) if code.is_int() => error_code(code.as_int()).map_err(|msg| invalid(&msg))?,
+            Some(_) => return Err(invalid("code must be an Int")),
+        };
+        let path = match map.get("path") {
+            None => "",
+            Some(path) if path.is_string() => path.as_str(),
+            Some(_) => return Err(invalid("path must be a String")),
+        };
+        let mut rules = self.rules.lock().unwrap();
+        if percent == 0. {
+            rules.remove(path);
+        } else {
+            warn!("Failing {percent}% of the requests on '{path}' with error {}", code as i32);
+            rules.insert(path.to_string(), (percent, code));
+        }
+        Ok(())
+    }
+
+    /// Decides whether a request on `path` fails.
+    pub(crate) fn roll(&self, path: &str) -> Result<(), RpcError> {
+        if path == TEST_FAULT_MOUNT {
+            return Ok(());
+        }
+        let rule = {
+            let rules = self.rules.lock().unwrap();
+            let covers = |rule: &str| rule.is_empty() || path == rule || path.strip_prefix(rule).is_some_and(|rest| rest.starts_with('/'));
+            rules.iter().filter(|(rule, _)| covers(rule.as_str())).max_by_key(|(rule, _)| rule.len()).map(|(_, rule)| *rule)
+        };
+        let Some((percent, code)) = rule else {
+            return Ok(());
+        };
+        if !self.rng.lock().unwrap().gen_bool(percent / 100.) {
+            return Ok(());
+        }
+        debug!("Fault: failing request on {path} with error {}", code as i32);
+        Err(RpcError::new(code, "Injected error"))
+    }
+
+    pub(crate) fn clear(&self) {
+        self.rules.lock().unwrap().clear();
+    }
+
+    /// `{path: {"percent": Double, "code": Int}}`
+    pub(crate) fn value(&self) -> RpcValue {
+        let map: Map = self.rules.lock().unwrap().iter()
+            .map(|(path, (percent, code))| {
+                let mut rule = Map::new();
+                rule.insert("percent".into(), (*percent).into());
+                rule.insert("code".into(), (*code as i64).into());
+                (path.clone(), rule.into())
+            })
+            .collect();
+        map.into()
+    }
+}
+
+fn check_percent(percent: f64) -> Result<f64, String> {
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("Error rate {percent} is out of range 0..100"));
+    }
+    Ok(percent)
+}
+
+/// Nodes simulating a temporarily unavailable sensor: `get` returns null and no
+/// `chng` is emitted, the stored value is kept and served again once available.
+#[derive(Default)]
+pub(crate) struct Availability {
+    nodes: Mutex<BTreeMap<String, bool>>,
+}
+
+impl Availability {
+    /// Accepts `[path, available]`.
+    pub(crate) fn set(&self, param: Option<&RpcValue>) -> Result<(), RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [path, available]");
+        let Some(Value::List(list)) = param.map(RpcValue::value) else {
+            return Err(invalid());
+        };
+        let [path, available] = list.as_slice() else {
+            return Err(invalid());
+        };
+        if !path.is_string() || !available.is_bool() {
+            return Err(invalid());
+        }
+        info!("Node {} is {}", path.as_str(), if available.as_bool() { "available" } else { "unavailable" });
+        self.nodes.lock().unwrap().insert(path.as_str().to_string(), available.as_bool());
+        Ok(())
+    }
+
+    pub(crate) fn is_unavailable(&self, path: &str) -> bool {
+        self.nodes.lock().unwrap().get(path).is_some_and(|available| !available)
+    }
+
+    pub(crate) fn clear(&self) {
+        self.nodes.lock().unwrap().clear();
+    }
+
+    /// Availability of every node touched by control:setUnavailable.
+    pub(crate) fn value(&self) -> RpcValue {
+        let map: Map = self.nodes.lock().unwrap().iter()
+            .map(|(path, available)| (path.clone(), (*available).into()))
+            .collect();
+        map.into()
+    }
+}
+
+/// Simulated storage exhaustion: after `limit` state writes that changed a value
+/// further changes fail until control:clearCapacity. Writing the current value again
+/// is not accounted.
+pub(crate) struct Capacity {
+    remaining: AtomicU64,
+}
+
+/// Value of [`Capacity::remaining`] without a limit.
+const UNLIMITED: u64 = u64::MAX;
+
+impl Default for Capacity {
+    fn default() -> Self {
+        Self { remaining: AtomicU64::new(UNLIMITED) }
+    }
+}
+
+impl Capacity {
+    pub(crate) fn set(&self, max_entries: i64) -> Result<(), RpcError> {
+        let max_entries = u64::try_from(max_entries).map_err(|_| RpcError::new(RpcErrorCode::InvalidParam, "maxEntries must not be negative"))?;
+        self.remaining.store(max_entries, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub(crate) fn clear(&self) {
+        self.remaining.store(UNLIMITED, Ordering::SeqCst);
+    }
+
+    /// Accounts one write of a changed value, fails with "Device full" once the limit is used up.
+    pub(crate) fn try_consume(&self) -> Result<(), RpcError> {
+        self.remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| match remaining {
+            UNLIMITED => Some(UNLIMITED),
+            0 => None,
+            remaining => Some(remaining - 1),
+        })
+        .map(|_| ())
+        .map_err(|_| RpcError::new(RpcErrorCode::MethodCallException, "Device full"))
+    }
+
+    /// Gives back a write accounted by [`Self::try_consume`] that did not change anything after all.
+    pub(crate) fn release(&self) {
+        let _ = self.remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| {
+            (remaining != UNLIMITED).then(|| remaining + 1)
+        });
+    }
+
+    /// Remaining writes, null when unlimited.
+    pub(crate) fn value(&self) -> RpcValue {
+        match self.remaining.load(Ordering::SeqCst) {
+            UNLIMITED => RpcValue::null(),
+            remaining => (remaining as i64).into(),
+        }
+    }
+}
+
+/// Malformed responses for broker robustness tests, dangerous and for testing only.
+///
+/// The client library serializes every message itself and offers no way to put
+/// raw bytes on the wire, so truncated frames cannot be produced. The closest
+/// approximation is a well-formed frame with invalid RPC content:
+/// - `missingRequestId`: the response has no request id, the broker cannot route it
+/// - `invalidRequestId`: the request id is a String instead of an Int
+/// - `otherRequestId`: the response carries the request id incremented by one, answering a different request
+/// - `empty`: the response carries neither a result nor an error
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum EncodingFault {
+    MissingRequestId,
+    InvalidRequestId,
+    OtherRequestId,
+    Empty,
+}
+
+pub(crate) struct EncodingFaults {
+    allowed: bool,
+    pending: Mutex<Option<(EncodingFault, u32)>>,
+}
+
+impl EncodingFaults {
+    pub(crate) fn new(allowed: bool) -> Self {
+        Self { allowed, pending: Default::default() }
+    }
+
+    /// control:encodingFault, [`Self::arm`] once allowed by `--enable-encoding-faults`.
+    pub(crate) fn set(&self, param: Option<&RpcValue>) -> Result<(), RpcError> {
+        if !self.allowed {
+            return Err(RpcError::new(RpcErrorCode::MethodCallException, "Encoding faults are disabled, start the device with --enable-encoding-faults"));
+        }
+        self.arm(param)
+    }
+
+    /// Accepts `mode` or `[mode, count]`, the next `count` responses (default 1) are malformed.
+    /// test/fault:malformResponses arms the fault directly, that node exists to misbehave.
+    pub(crate) fn arm(&self, param: Option<&RpcValue>) -> Result<(), RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected mode or [mode, count], mode: missingRequestId, invalidRequestId, otherRequestId or empty");
+        let (mode, count) = match param.map(RpcValue::value) {
+            Some(Value::String(mode)) => (mode.as_str(), 1),
+            Some(Value::List(list)) if list.len() == 2 && list[0].is_string() && list[1].is_int() => (list[0].as_str(), list[1].as_int()),
+            _ => return Err(invalid()),
+        };
+        let mode = match mode {
+            "missingRequestId" => EncodingFault::MissingRequestId,
+            "invalidRequestId" => EncodingFault::InvalidRequestId,
+            "otherRequestId" => EncodingFault::OtherRequestId,
+            "empty" => EncodingFault::Empty,
+            _ => return Err(invalid()),
+        };
+        let count = u32::try_from(count).map_err(|_| invalid())?;
+        warn!("Sending the next {count} responses malformed: {mode:?}");
+        *self.pending.lock().unwrap() = (count > 0).then_some((mode, count));
+        Ok(())
+    }
+
+    pub(crate) fn is_armed(&self) -> bool {
+        self.pending.lock().unwrap().is_some()
+    }
+
+    /// Returns the fault to apply to the next response, if any.
+    pub(crate) fn take(&self) -> Option<EncodingFault> {
+        let mut pending = self.pending.lock().unwrap();
+        let (mode, count) = pending.as_mut()?;
+        let mode = *mode;
+        *count -= 1;
+        if *count == 0 {
+            *pending = None;
+        }
+        Some(mode)
+    }
+
+    pub(crate) fn clear(&self) {
+        *self.pending.lock().unwrap() = None;
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("allowed".into(), self.allowed.into());
+        let pending = *self.pending.lock().unwrap();
+        map.insert("mode".into(), pending.map(|(mode, _)| RpcValue::from(format!("{mode:?}"))).unwrap_or_default());
+        map.insert("remaining".into(), (pending.map_or(0, |(_, count)| count) as i64).into());
+        map.into()
+    }
+}
+
+/// Builds the malformed counterpart of a response to be sent instead of it.
+pub(crate) fn malform(mode: EncodingFault, mut response: RpcMessage, result: Result<RpcValue, RpcError>) -> RpcMessage {
+    if !matches!(mode, EncodingFault::Empty) {
+        match result {
+            Ok(value) => response.set_result(value),
+            Err(err) => response.set_error(err),
+        };
+    }
+    match mode {
+        EncodingFault::MissingRequestId => {
+            response.set_tag(Tag::RequestId as i32, None);
+        }
+        EncodingFault::InvalidRequestId => {
+            response.set_tag(Tag::RequestId as i32, Some("invalid".into()));
+        }
+        EncodingFault::OtherRequestId => {
+            let request_id = response.request_id().unwrap_or_default();
+            response.set_tag(Tag::RequestId as i32, Some((request_id + 1).into()));
+        }
+        EncodingFault::Empty => {}
+    }
+    response
+}
+
+/// One-way network partition: requests are still handled, but the selected
+/// outgoing messages are silently dropped.
+#[derive(Default)]
+pub(crate) struct Partition {
+    responses: AtomicBool,
+    signals: AtomicBool,
+}
+
+impl Partition {
+    pub(crate) const DROP_RESPONSES: i64 = 1;
+    pub(crate) const DROP_SIGNALS: i64 = 2;
+
+    /// Accepts `false`, `true` (drop both) or `[bool, directionsMask]`.
+    pub(crate) fn set(&self, param: Option<&RpcValue>) -> Result<(), RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected Bool or [Bool, directionsMask]");
+        let (enabled, mask) = match param.map(RpcValue::value) {
+            Some(Value::Bool(enabled)) => (*enabled, Self::DROP_RESPONSES | Self::DROP_SIGNALS),
+            Some(Value::List(list)) if list.len() == 2 && list[0].is_bool() && list[1].is_int() => (list[0].as_bool(), list[1].as_int()),
+            _ => return Err(invalid()),
+        };
+        if mask & !(Self::DROP_RESPONSES | Self::DROP_SIGNALS) != 0 {
+            return Err(invalid());
+        }
+        let responses = enabled && mask & Self::DROP_RESPONSES != 0;
+        let signals = enabled && mask & Self::DROP_SIGNALS != 0;
+        self.responses.store(responses, Ordering::SeqCst);
+        self.signals.store(signals, Ordering::SeqCst);
+        info!("Partition: dropping responses: {responses}, dropping signals: {signals}");
+        Ok(())
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("dropResponses".into(), self.drops_responses().into());
+        map.insert("dropSignals".into(), self.drops_signals().into());
+        map.into()
+    }
+
+    pub(crate) fn drops_responses(&self) -> bool {
+        self.responses.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn drops_signals(&self) -> bool {
+        self.signals.load(Ordering::SeqCst)
+    }
+}
+
+/// Deliberate memory leak for testing memory monitoring, memory is only
+/// given back by control:releaseLeak or a soft reboot.
+pub(crate) struct Leak {
+    allowed: bool,
+    blocks: Mutex<Vec<Vec<u8>>>,
+}
+
+impl Leak {
+    pub(crate) fn new(allowed: bool) -> Self {
+        Self { allowed, blocks: Default::default() }
+    }
+
+    pub(crate) fn leaked_bytes(&self) -> usize {
+        self.blocks.lock().unwrap().iter().map(Vec::len).sum()
+    }
+
+    /// Allocates and retains `bytes` more bytes, returns the total leaked size.
+    pub(crate) fn leak(&self, bytes: i32) -> Result<usize, RpcError> {
+        if !self.allowed {
+            return Err(RpcError::new(RpcErrorCode::MethodCallException, "Leak method is disabled, start the device with --enable-leak-method"));
+        }
+        let bytes = usize::try_from(bytes).map_err(|_| RpcError::new(RpcErrorCode::InvalidParam, "Size must not be negative"))?;
+        // Filled with non-zero bytes so that the pages are really resident.
+        self.blocks.lock().unwrap().push(vec![0xA5; bytes]);
+        let total = self.leaked_bytes();
+        warn!("Leaking {bytes} bytes on request, {total} bytes leaked in total");
+        Ok(total)
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("allowed".into(), self.allowed.into());
+        map.insert("leakedBytes".into(), (self.leaked_bytes() as i64).into());
+        map.into()
+    }
+
+    /// Frees all leaked memory, returns the freed size.
+    pub(crate) fn release(&self) -> usize {
+        let blocks = std::mem::take(&mut *self.blocks.lock().unwrap());
+        blocks.iter().map(Vec::len).sum()
+    }
+}
+
+/// Makes `get` responses occasionally carry a value of the wrong type.
+/// The stored values are never touched, only the response is corrupted.
+pub(crate) struct Corruption {
+    allowed: bool,
+    active: AtomicBool,
+    rate: f64,
+    rng: Mutex<StdRng>,
+}
+
+impl Corruption {
+    pub(crate) fn new(allowed: bool, rate: f64, seed: u64) -> Result<Self, String> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(format!("Corruption rate {rate} is out of range 0..1"));
+        }
+        Ok(Self { allowed, active: Default::default(), rate, rng: Mutex::new(StdRng::seed_from_u64(seed)) })
+    }
+
+    pub(crate) fn set_active(&self, active: bool) -> Result<(), RpcError> {
+        if active && !self.allowed {
+            return Err(RpcError::new(RpcErrorCode::MethodCallException, "Response corruption is disabled, start the device with --enable-corruption"));
+        }
+        self.active.store(active, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("allowed".into(), self.allowed.into());
+        map.insert("active".into(), self.active.load(Ordering::SeqCst).into());
+        map.insert("rate".into(), self.rate.into());
+        map.into()
+    }
+
+    pub(crate) fn corrupt(&self, path: &str, value: RpcValue) -> RpcValue {
+        if !self.active.load(Ordering::SeqCst) {
+            return value;
+        }
+        if !self.rng.lock().unwrap().gen_bool(self.rate) {
+            return value;
+        }
+        let corrupted: RpcValue = match value.value() {
+            Value::String(s) => (s.len() as i64).into(),
+            Value::Int(_) | Value::UInt(_) | Value::Double(_) | Value::Decimal(_) | Value::Bool(_) => value.to_cpon().into(),
+            _ => "corrupted".into(),
+        };
+        info!("Corrupting response of {path}:get, {} -> {}", value.to_cpon(), corrupted.to_cpon());
+        corrupted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_rate(specs: &[&str]) -> ErrorRate {
+        let specs: Vec<String> = specs.iter().map(|spec| spec.to_string()).collect();
+        ErrorRate::new(&specs, 0).unwrap()
+    }
+
+    fn failure_code(error_rate: &ErrorRate, path: &str) -> Option<i32> {
+        error_rate.roll(path).err().map(|err| err.code as i32)
+    }
+
+    #[test]
+    fn error_rate_parses_specs() {
+        let specs = ["5".to_string(), "state/number=20:6".to_string()];
+        assert!(ErrorRate::new(&specs, 0).is_ok());
+        for invalid in ["x", "101", "-1", "state=5:0", "state=5:x"] {
+            assert!(ErrorRate::new(&[invalid.to_string()], 0).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn error_rate_longest_prefix_applies() {
+        let error_rate = error_rate(&["100:6", "state=0", "state/number=100:3"]);
+        assert_eq!(failure_code(&error_rate, "status/requestRate"), Some(6));
+        assert_eq!(failure_code(&error_rate, "state/text"), None);
+        assert_eq!(failure_code(&error_rate, "state/number"), Some(3));
+        assert_eq!(failure_code(&error_rate, "state/number/child"), Some(3));
+        assert_eq!(failure_code(&error_rate, "statex"), Some(6));
+        assert_eq!(failure_code(&error_rate, TEST_FAULT_MOUNT), None);
+    }
+
+    #[test]
+    fn error_rate_defaults_to_method_call_exception() {
+        let error_rate = error_rate(&["state/number=100"]);
+        assert_eq!(failure_code(&error_rate, "state/number"), Some(RpcErrorCode::MethodCallException as i32));
+        assert_eq!(failure_code(&error_rate, "state/text"), None);
+    }
+
+    #[test]
+    fn capacity_fails_write_past_limit() {
+        let capacity = Capacity::default();
+        capacity.set(3).unwrap();
+        for remaining in [2, 1, 0] {
+            capacity.try_consume().unwrap();
+            assert_eq!(capacity.value(), RpcValue::from(remaining));
+        }
+        assert!(capacity.try_consume().is_err());
+        assert_eq!(capacity.value(), RpcValue::from(0));
+        capacity.clear();
+        assert!(capacity.try_consume().is_ok());
+        assert!(capacity.value().is_null());
+    }
+
+    #[test]
+    fn capacity_accounts_changed_values_only() {
+        let state = crate::test_state(&[]);
+        state.faults.capacity.set(1).unwrap();
+        assert!(state.update_number(5).unwrap().is_some());
+        assert_eq!(state.update_number(5).unwrap(), None);
+        assert_eq!(state.faults.capacity.value(), RpcValue::from(0));
+        assert!(state.update_number(6).is_err());
+        assert_eq!(state.number.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn capacity_holds_under_concurrent_writes() {
+        let capacity = Capacity::default();
+        capacity.set(100).unwrap();
+        let accepted = AtomicU64::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        if capacity.try_consume().is_ok() {
+                            accepted.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(accepted.load(Ordering::SeqCst), 100);
+        assert_eq!(capacity.value(), RpcValue::from(0));
+    }
+
+    #[test]
+    fn encoding_fault_gate_applies_to_set_only() {
+        let faults = EncodingFaults::new(false);
+        assert!(faults.set(Some(&"otherRequestId".into())).is_err());
+        assert!(!faults.is_armed());
+        faults.arm(Some(&"otherRequestId".into())).unwrap();
+        assert!(matches!(faults.take(), Some(EncodingFault::OtherRequestId)));
+        assert!(faults.take().is_none());
+    }
+
+    #[test]
+    fn other_request_id_answers_the_next_request() {
+        let response = RpcMessage::new_request("state/number", "get", None).prepare_response().unwrap();
+        let request_id = response.request_id().unwrap();
+        let response = malform(EncodingFault::OtherRequestId, response, Ok(1.into()));
+        assert_eq!(response.request_id(), Some(request_id + 1));
+    }
+
+    #[test]
+    fn partition_suppresses_responses_while_state_mutates() {
+        let state = crate::test_state(&[]);
+        let collected = crate::signals::Collected::default();
+        let set = |value: i32| {
+            let changed = state.update_number(value).unwrap().unwrap();
+            crate::signals::emit_chng(&state, &collected, crate::NUMBER_MOUNT, changed);
+            crate::dispatch::finish(&state, crate::NUMBER_MOUNT, "set", std::time::Instant::now(), Some(Ok(true.into())), None)
+        };
+        state.faults.partition.set(Some(&true.into())).unwrap();
+        assert!(set(5).is_none());
+        assert_eq!(state.number.load(Ordering::SeqCst), 5);
+        assert!(collected.take().is_empty());
+
+        let signals_only: Vec<RpcValue> = vec![true.into(), Partition::DROP_SIGNALS.into()];
+        state.faults.partition.set(Some(&signals_only.into())).unwrap();
+        assert!(set(6).is_some());
+        assert!(collected.take().is_empty());
+
+        state.faults.partition.set(Some(&false.into())).unwrap();
+        assert!(set(7).is_some());
+        assert_eq!(collected.take().len(), 1);
+    }
+
+    #[test]
+    fn unavailable_node_reads_null_until_available() {
+        let state = crate::test_state(&[]);
+        let collected = crate::signals::Collected::default();
+        let get = || {
+            let value = state.number.load(Ordering::SeqCst).into();
+            crate::dispatch::finish(&state, crate::NUMBER_MOUNT, "get", std::time::Instant::now(), Some(Ok(value)), None)
+                .unwrap()
+                .unwrap()
+        };
+        let availability = |available: bool| RpcValue::from(vec![RpcValue::from(crate::NUMBER_MOUNT), available.into()]);
+        state.update_number(5).unwrap();
+        state.faults.availability.set(Some(&availability(false))).unwrap();
+        assert_eq!(get(), RpcValue::null());
+        crate::signals::emit_chng(&state, &collected, crate::NUMBER_MOUNT, 5.into());
+        assert!(collected.take().is_empty());
+
+        state.faults.availability.set(Some(&availability(true))).unwrap();
+        assert_eq!(get(), RpcValue::from(5));
+        let mut map = Map::new();
+        map.insert(crate::NUMBER_MOUNT.into(), true.into());
+        assert_eq!(state.faults.availability.value(), RpcValue::from(map));
+    }
+
+    #[test]
+    fn clear_all_resets_every_enabled_fault() {
+        let state = crate::test_state(&[]);
+        let faults = &state.faults;
+        let initial = faults.value();
+        faults.partition.set(Some(&true.into())).unwrap();
+        faults.capacity.set(3).unwrap();
+        let value = faults.value();
+        let Value::Map(map) = value.value() else {
+            panic!("status/faults is not a Map");
+        };
+        assert_eq!(map.get("partition"), Some(&faults.partition.value()));
+        assert!(faults.partition.drops_responses());
+        assert_eq!(map.get("capacity"), Some(&RpcValue::from(3)));
+        assert_ne!(value, initial);
+
+        faults.clear_all();
+        assert!(!faults.partition.drops_responses());
+        assert_eq!(faults.value(), initial);
+    }
+
+    #[test]
+    fn leak_grows_tracked_allocation() {
+        assert!(Leak::new(false).leak(1024).is_err());
+        let leak = Leak::new(true);
+        assert_eq!(leak.leak(1 << 20).unwrap(), 1 << 20);
+        assert_eq!(leak.leak(1 << 20).unwrap(), 2 << 20);
+        let Value::Map(resources) = crate::metrics::resources(leak.leaked_bytes()).value().clone() else {
+            panic!("resources is not a Map");
+        };
+        assert_eq!(resources.get("leakedBytes"), Some(&RpcValue::from(2i64 << 20)));
+        assert_eq!(leak.release(), 2 << 20);
+        assert_eq!(leak.leaked_bytes(), 0);
+    }
+}
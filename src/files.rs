@@ -0,0 +1,218 @@
+//! `files/` subtree exposing a local directory through the SHV file node API.
+//!
+//! Every regular file below `--files-root` is mounted as `files/<relative path>` with
+//! `stat`, `size`, `crc`, `read`, `write` and `append`. Directories are listed by the
+//! usual `ls` of the node tree. The tree is scanned on every connect, files created
+//! later appear after a reconnect. Symbolic links are not followed and every access
+//! is checked to stay below the root.
+
+use std::path::{Path, PathBuf};
+
+use async_std::io::prelude::{ReadExt, SeekExt, WriteExt};
+use async_std::io::SeekFrom;
+use log::*;
+use shvproto::rpcvalue::{IMap, Value};
+use shvproto::{DateTime, RpcValue};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+pub(crate) const FILES_MOUNT: &str = "files";
+
+/// Largest chunk returned by a single `read`, advertised in `stat`.
+const MAX_READ_BYTES: u64 = 1 << 20;
+
+const STAT_TYPE: i32 = 0;
+const STAT_SIZE: i32 = 1;
+const STAT_PAGE_SIZE: i32 = 2;
+const STAT_MOD_TIME: i32 = 4;
+const STAT_MAX_WRITE: i32 = 5;
+const TYPE_REGULAR: i32 = 0;
+
+pub(crate) struct Files {
+    root: Option<PathBuf>,
+}
+
+impl Files {
+    pub(crate) fn new(root: Option<&str>) -> Result<Self, String> {
+        let root = root.map(|root| std::fs::canonicalize(root).map_err(|err| format!("Invalid files root {root}: {err}"))).transpose()?;
+        Ok(Self { root })
+    }
+
+    /// Mount paths of all regular files below the root.
+    pub(crate) fn paths(&self) -> Vec<String> {
+        let Some(root) = &self.root else {
+            return Vec::new();
+        };
+        let mut paths = Vec::new();
+        scan(root, root, &mut paths);
+        paths.sort();
+        paths
+    }
+
+    /// Resolves a node path to the file, refusing anything outside the root.
+    fn file(&self, path: Option<&str>) -> Result<PathBuf, RpcError> {
+        let not_found = || RpcError::new(RpcErrorCode::MethodNotFound, "Not a file node");
+        let root = self.root.as_ref().ok_or_else(not_found)?;
+        let relative = path.and_then(|path| path.strip_prefix(FILES_MOUNT)?.strip_prefix('/')).ok_or_else(not_found)?;
+        let file = std::fs::canonicalize(root.join(relative))
+            .map_err(|err| RpcError::new(RpcErrorCode::MethodCallException, &format!("Cannot open {relative}: {err}")))?;
+        if !file.starts_with(root) {
+            return Err(RpcError::new(RpcErrorCode::MethodCallException, "Access outside of the files root"));
+        }
+        Ok(file)
+    }
+
+    pub(crate) async fn stat(&self, path: Option<&str>) -> Result<RpcValue, RpcError> {
+        let metadata = async_std::fs::metadata(self.file(path)?).await.map_err(io_error)?;
+        let mut stat = IMap::new();
+        stat.insert(STAT_TYPE, TYPE_REGULAR.into());
+        stat.insert(STAT_SIZE, (metadata.len() as i64).into());
+        stat.insert(STAT_PAGE_SIZE, (MAX_READ_BYTES as i64).into());
+        if let Ok(modified) = metadata.modified() {
+            let msec = modified.duration_since(std::time::UNIX_EPOCH).map_or(0, |since| since.as_millis() as i64);
+            stat.insert(STAT_MOD_TIME, DateTime::from_epoch_msec(msec).into());
+        }
+        stat.insert(STAT_MAX_WRITE, (MAX_READ_BYTES as i64).into());
+        Ok(stat.into())
+    }
+
+    pub(crate) async fn size(&self, path: Option<&str>) -> Result<RpcValue, RpcError> {
+        let metadata = async_std::fs::metadata(self.file(path)?).await.map_err(io_error)?;
+        Ok((metadata.len() as i64).into())
+    }
+
+    /// `[offset, size]`, at most [`MAX_READ_BYTES`] are returned.
+    pub(crate) async fn read(&self, path: Option<&str>, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        let (offset, size) = parse_range(param)?.ok_or_else(|| RpcError::new(RpcErrorCode::InvalidParam, "Expected [offset, size]"))?;
+        let data = read_range(&self.file(path)?, offset, Some(size.min(MAX_READ_BYTES))).await?;
+        Ok(data.into())
+    }
+
+    /// CRC32 (IEEE 802.3) of the whole file, or of `[offset, size]`.
+    pub(crate) async fn crc(&self, path: Option<&str>, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        let data = match parse_range(param)? {
+            Some((offset, size)) => read_range(&self.file(path)?, offset, Some(size)).await?,
+            None => read_range(&self.file(path)?, 0, None).await?,
+        };
+        Ok(RpcValue::from(crc32(&data) as u64))
+    }
+
+    /// `[offset, Blob]`, the file grows when writing past its end.
+    pub(crate) async fn write(&self, path: Option<&str>, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [offset, Blob]");
+        let Some(Value::List(list)) = param.map(RpcValue::value) else {
+            return Err(invalid());
+        };
+        let [offset, data] = list.as_slice() else {
+            return Err(invalid());
+        };
+        let (true, Value::Blob(data)) = (offset.is_int() && offset.as_int() >= 0, data.value()) else {
+            return Err(invalid());
+        };
+        let mut file = async_std::fs::OpenOptions::new().write(true).open(self.file(path)?).await.map_err(io_error)?;
+        file.seek(SeekFrom::Start(offset.as_int() as u64)).await.map_err(io_error)?;
+        file.write_all(data).await.map_err(io_error)?;
+        file.flush().await.map_err(io_error)?;
+        Ok(().into())
+    }
+
+    pub(crate) async fn append(&self, path: Option<&str>, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        let Some(Value::Blob(data)) = param.map(RpcValue::value) else {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected Blob"));
+        };
+        let mut file = async_std::fs::OpenOptions::new().append(true).open(self.file(path)?).await.map_err(io_error)?;
+        file.write_all(data).await.map_err(io_error)?;
+        file.flush().await.map_err(io_error)?;
+        Ok(().into())
+    }
+}
+
+fn scan(root: &Path, dir: &Path, paths: &mut Vec<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Cannot list {}: {err}", dir.display());
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            scan(root, &path, paths);
+        } else if file_type.is_file() {
+            match path.strip_prefix(root).ok().and_then(Path::to_str) {
+                Some(relative) => paths.push(format!("{FILES_MOUNT}/{relative}")),
+                None => warn!("Skipping file with a non UTF-8 name: {}", path.display()),
+            }
+        }
+    }
+}
+
+fn parse_range(param: Option<&RpcValue>) -> Result<Option<(u64, u64)>, RpcError> {
+    match param.map(RpcValue::value) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::List(list)) => match list.as_slice() {
+            [offset, size] if offset.is_int() && size.is_int() && offset.as_int() >= 0 && size.as_int() >= 0 => {
+                Ok(Some((offset.as_int() as u64, size.as_int() as u64)))
+            }
+            _ => Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected [offset, size]")),
+        },
+        _ => Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected [offset, size]")),
+    }
+}
+
+async fn read_range(file: &Path, offset: u64, size: Option<u64>) -> Result<Vec<u8>, RpcError> {
+    let mut file = async_std::fs::File::open(file).await.map_err(io_error)?;
+    file.seek(SeekFrom::Start(offset)).await.map_err(io_error)?;
+    let mut data = Vec::new();
+    match size {
+        Some(size) => file.take(size).read_to_end(&mut data).await,
+        None => file.read_to_end(&mut data).await,
+    }.map_err(io_error)?;
+    Ok(data)
+}
+
+fn io_error(err: std::io::Error) -> RpcError {
+    RpcError::new(RpcErrorCode::MethodCallException, &format!("File access failed: {err}"))
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(offset: i64, size: i64) -> RpcValue {
+        vec![RpcValue::from(offset), RpcValue::from(size)].into()
+    }
+
+    #[test]
+    fn parse_range_accepts_null_and_offset_size() {
+        assert_eq!(parse_range(None).unwrap(), None);
+        assert_eq!(parse_range(Some(&RpcValue::null())).unwrap(), None);
+        assert_eq!(parse_range(Some(&range(10, 20))).unwrap(), Some((10, 20)));
+        assert_eq!(parse_range(Some(&range(0, 0))).unwrap(), Some((0, 0)));
+    }
+
+    #[test]
+    fn parse_range_rejects_invalid() {
+        let one: RpcValue = vec![RpcValue::from(1)].into();
+        let text: RpcValue = vec![RpcValue::from("0"), RpcValue::from(1)].into();
+        for param in [range(-1, 1), range(0, -1), one, text, "0".into()] {
+            assert!(parse_range(Some(&param)).is_err(), "{}", param.to_cpon());
+        }
+    }
+
+    #[test]
+    fn crc32_matches_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414F_A339);
+    }
+}
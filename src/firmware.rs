@@ -0,0 +1,95 @@
+//! Simulated firmware upgrades for testing fleet tracking of device versions.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::*;
+use shvclient::AppState;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::signals::{emit_chng, MessageSink};
+use crate::{lifecycle, runtime, tasks, State};
+
+pub(crate) const FIRMWARE_MOUNT: &str = "device/firmware";
+
+/// The firmware version survives a soft reboot, as a real upgrade would.
+pub(crate) struct Firmware {
+    version: Mutex<String>,
+    duration: Duration,
+    reconnect: bool,
+    upgrading: AtomicBool,
+}
+
+/// Clears the upgrade in progress flag when the upgrade task ends, cancelled tasks included.
+struct Upgrading(AppState<State>);
+
+impl Drop for Upgrading {
+    fn drop(&mut self) {
+        self.0.firmware.upgrading.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Firmware {
+    pub(crate) fn new(version: String, duration: &str, reconnect: bool) -> Result<Self, String> {
+        let duration = duration_str::parse(duration).map_err(|err| format!("Invalid upgrade duration: {err}"))?;
+        Ok(Self { version: Mutex::new(version), duration, reconnect, upgrading: Default::default() })
+    }
+
+    pub(crate) fn version(&self) -> RpcValue {
+        self.version.lock().unwrap().as_str().into()
+    }
+}
+
+/// Starts an upgrade to `version`, the new version is reported and signalled once
+/// `--upgrade-duration` passes. With `--upgrade-reconnect` the device then reconnects
+/// as if it restarted. Only one upgrade can be in progress.
+pub(crate) fn upgrade(app_state: &AppState<State>, client_cmd_tx: impl MessageSink + Send + Sync + 'static, version: String) -> Result<(), RpcError> {
+    let firmware = &app_state.firmware;
+    if firmware.upgrading.swap(true, Ordering::SeqCst) {
+        return Err(RpcError::new(RpcErrorCode::MethodCallException, "An upgrade is already in progress"));
+    }
+    let upgrading = Upgrading(app_state.clone());
+    tasks::spawn(app_state, "firmwareUpgrade", async move {
+        let state = &upgrading.0;
+        info!("Upgrading firmware to {version}");
+        runtime::sleep(state.firmware.duration).await;
+        *state.firmware.version.lock().unwrap() = version;
+        emit_chng(state, &client_cmd_tx, FIRMWARE_MOUNT, state.firmware.version());
+        if state.firmware.reconnect {
+            lifecycle::request_reconnect(state);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use shvclient::clientnode::SIG_CHNG;
+
+    use super::*;
+    use crate::signals::Collected;
+
+    #[test]
+    fn upgrade_reports_and_signals_new_version() {
+        let state = crate::test_state(&["--firmware-version", "1.0", "--upgrade-duration", "50ms"]);
+        let collected = Arc::new(Collected::default());
+        runtime::block_on(async {
+            upgrade(&state, collected.clone(), "2.0".to_string()).unwrap();
+            let err = upgrade(&state, collected.clone(), "3.0".to_string()).unwrap_err();
+            assert_eq!(err.code as i32, RpcErrorCode::MethodCallException as i32);
+            assert_eq!(state.firmware.version(), RpcValue::from("1.0"));
+            for _ in 0..100 {
+                if !state.firmware.upgrading.load(Ordering::SeqCst) {
+                    break;
+                }
+                runtime::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert_eq!(state.firmware.version(), RpcValue::from("2.0"));
+        assert_eq!(collected.take(), [(FIRMWARE_MOUNT.to_string(), SIG_CHNG.to_string(), RpcValue::from("2.0"))]);
+    }
+}
@@ -0,0 +1,144 @@
+//! Heartbeat misbehavior for testing the broker's idle-disconnect logic.
+//!
+//! Outside of `normal` mode the client library's own ping timer is parked (see
+//! [`connection::SUSPENDED_HEARTBEAT_INTERVAL`]) and the device sends the pings
+//! itself, once per heartbeat interval:
+//! - `skip` sends none, the socket stays open but silent
+//! - `late` sends each ping `lateMs` after it is due
+//! - `burst` sends `burst` pings back to back
+//!
+//! Switching between `normal` and the other modes reconnects once, the library reads
+//! its ping interval only when connecting. Switching among the other modes does not.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::*;
+use shvclient::AppState;
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::{connection, runtime, State};
+
+pub(crate) const HEARTBEAT_MOUNT: &str = "control/heartbeat";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum HeartbeatMode {
+    /// Pings sent by the client library.
+    #[default]
+    Normal,
+    /// No pings at all.
+    Skip,
+    /// Every ping delayed by --heartbeat-late.
+    Late,
+    /// --heartbeat-burst pings at once.
+    Burst,
+}
+
+impl HeartbeatMode {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Skip => "skip",
+            Self::Late => "late",
+            Self::Burst => "burst",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [Self::Normal, Self::Skip, Self::Late, Self::Burst].into_iter().find(|mode| mode.name() == name)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Settings {
+    mode: HeartbeatMode,
+    late: Duration,
+    burst: u32,
+}
+
+pub(crate) struct Heartbeat {
+    settings: Mutex<Settings>,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(mode: HeartbeatMode, late: &str, burst: u32) -> Result<Self, String> {
+        let late = duration_str::parse(late).map_err(|err| format!("Invalid heartbeat delay: {err}"))?;
+        if burst == 0 {
+            return Err("Heartbeat burst must be at least 1".to_string());
+        }
+        Ok(Self { settings: Mutex::new(Settings { mode, late, burst }) })
+    }
+
+    /// True when the device sends the pings instead of the client library.
+    pub(crate) fn overridden(&self) -> bool {
+        self.settings.lock().unwrap().mode != HeartbeatMode::Normal
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let settings = *self.settings.lock().unwrap();
+        let mut map = Map::new();
+        map.insert("mode".into(), settings.mode.name().into());
+        map.insert("lateMs".into(), (settings.late.as_millis() as i64).into());
+        map.insert("burst".into(), (settings.burst as i64).into());
+        map.into()
+    }
+
+    /// Applies the keys present in `{"mode": String, "lateMs": Int, "burst": Int}`.
+    /// Returns true when the caller has to reconnect for the mode to take effect.
+    pub(crate) fn set(&self, param: Option<&RpcValue>) -> Result<bool, RpcError> {
+        let invalid = |msg: &str| RpcError::new(RpcErrorCode::InvalidParam, msg);
+        let Some(Value::Map(map)) = param.map(RpcValue::value) else {
+            return Err(invalid("Expected {mode, lateMs, burst}"));
+        };
+        let mut settings = self.settings.lock().unwrap();
+        let mut new = *settings;
+        if let Some(mode) = map.get("mode") {
+            new.mode = mode.is_string().then(|| HeartbeatMode::parse(mode.as_str())).flatten()
+                .ok_or_else(|| invalid("mode must be one of normal, skip, late, burst"))?;
+        }
+        if let Some(late) = map.get("lateMs") {
+            if !late.is_int() || late.as_int() < 0 {
+                return Err(invalid("lateMs must be a non-negative Int"));
+            }
+            new.late = Duration::from_millis(late.as_int() as u64);
+        }
+        if let Some(burst) = map.get("burst") {
+            new.burst = Some(burst).filter(|burst| burst.is_int() && burst.as_int() > 0)
+                .and_then(|burst| u32::try_from(burst.as_int()).ok())
+                .ok_or_else(|| invalid("burst must be a positive Int"))?;
+        }
+        let reconnect = (new.mode == HeartbeatMode::Normal) != (settings.mode == HeartbeatMode::Normal);
+        info!("Heartbeat mode {}, late {:?}, burst {}", new.mode.name(), new.late, new.burst);
+        *settings = new;
+        Ok(reconnect)
+    }
+}
+
+/// Sends the device driven pings, runs for the whole device lifetime and idles in `normal` mode.
+pub(crate) async fn run(app_state: AppState<State>) {
+    loop {
+        let interval = duration_str::parse(&app_state.client_config.lock().unwrap().heartbeat_interval).unwrap_or(Duration::from_secs(60));
+        runtime::sleep(interval).await;
+        let settings = *app_state.heartbeat.settings.lock().unwrap();
+        let (delay, count) = match settings.mode {
+            HeartbeatMode::Normal | HeartbeatMode::Skip => continue,
+            HeartbeatMode::Late => (settings.late, 1),
+            HeartbeatMode::Burst => (Duration::ZERO, settings.burst),
+        };
+        if app_state.connection.heartbeat_suspended() {
+            continue;
+        }
+        runtime::sleep(delay).await;
+        let Some(client_cmd_tx) = app_state.connection.client_cmd_tx() else {
+            continue;
+        };
+        for _ in 0..count {
+            if let Err(err) = connection::send_heartbeat(&client_cmd_tx).await {
+                warn!("Heartbeat ping failed: {err}");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Ring buffer of incoming requests, so that test harnesses can assert what the broker forwarded.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::{DateTime, RpcValue};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode, Tag};
+use shvrpc::RpcMessage;
+
+pub(crate) const HISTORY_MOUNT: &str = "history/requests";
+
+pub(crate) struct History {
+    capacity: usize,
+    entries: Mutex<(u64, VecDeque<RpcValue>)>,
+}
+
+impl History {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self { capacity, entries: Default::default() }
+    }
+
+    /// Every entry gets a sequence number, counting continues across `clear`.
+    pub(crate) fn record(&self, time: DateTime, path: &str, method: &str, request: &RpcMessage) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let (next_seq, entries) = &mut *entries;
+        let mut entry = Map::new();
+        entry.insert("seq".into(), (*next_seq as i64).into());
+        entry.insert("time".into(), time.into());
+        entry.insert("path".into(), path.into());
+        entry.insert("method".into(), method.into());
+        entry.insert("param".into(), request.param().cloned().unwrap_or_default());
+        entry.insert("callerIds".into(), request.tag(Tag::CallerIds as i32).cloned().unwrap_or_default());
+        *next_seq += 1;
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry.into());
+    }
+
+    /// Accepts `[since, count]`, returns up to `count` entries with a sequence number of at least `since`, oldest first.
+    pub(crate) fn read(&self, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [since, count]");
+        let Some(Value::List(list)) = param.map(RpcValue::value) else {
+            return Err(invalid());
+        };
+        let [since, count] = list.as_slice() else {
+            return Err(invalid());
+        };
+        if !since.is_int() || !count.is_int() || count.as_int() < 0 {
+            return Err(invalid());
+        }
+        let entries = self.entries.lock().unwrap();
+        let (next_seq, entries) = &*entries;
+        let first_seq = *next_seq as i64 - entries.len() as i64;
+        let skip = (since.as_int() - first_seq).max(0) as usize;
+        let entries: Vec<RpcValue> = entries.iter().skip(skip).take(count.as_int() as usize).cloned().collect();
+        Ok(entries.into())
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().1.clear();
+    }
+}
@@ -0,0 +1,99 @@
+//! External commands spawned on connection state changes.
+//!
+//! The commands run with the privileges of the device process, so only configure
+//! them from a trusted source. A command is the path of a program run without a
+//! shell, it cannot carry arguments of its own. The broker URL is passed as the
+//! single argument, with any `password` query parameter stripped to avoid leaking
+//! it into the process list of the host.
+//!
+//! The commands run one at a time in the order of the state changes, a disconnect
+//! command waits for a still running connect command to finish and vice versa.
+
+use async_process::Command;
+use log::*;
+
+use crate::connection::redact_url;
+use crate::runtime;
+
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionHooks {
+    on_connect: Option<String>,
+    on_disconnect: Option<String>,
+    queue: Option<async_std::channel::Sender<(&'static str, String)>>,
+}
+
+impl ConnectionHooks {
+    pub(crate) fn new(on_connect: Option<String>, on_disconnect: Option<String>, url: &str) -> Self {
+        let queue = (on_connect.is_some() || on_disconnect.is_some()).then(|| {
+            let (tx, rx) = async_std::channel::unbounded();
+            runtime::spawn(run_queued(rx, redact_url(url)));
+            tx
+        });
+        Self { on_connect, on_disconnect, queue }
+    }
+
+    pub(crate) fn connected(&self) {
+        self.spawn("on-connect", self.on_connect.as_deref());
+    }
+
+    pub(crate) fn disconnected(&self) {
+        self.spawn("on-disconnect", self.on_disconnect.as_deref());
+    }
+
+    fn spawn(&self, name: &'static str, cmd: Option<&str>) {
+        let (Some(cmd), Some(queue)) = (cmd, &self.queue) else {
+            return;
+        };
+        let _ = queue.try_send((name, cmd.to_string()));
+    }
+}
+
+async fn run_queued(rx: async_std::channel::Receiver<(&'static str, String)>, url: String) {
+    while let Ok((name, cmd)) = rx.recv().await {
+        debug!("Running {name} command: {cmd} {url}");
+        match Command::new(&cmd).arg(&url).status().await {
+            Ok(status) if !status.success() => warn!("{name} command {cmd} exited with {status}"),
+            Ok(_) => {}
+            Err(err) => error!("Cannot spawn {name} command {cmd}: {err}"),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("shvbrokertestingdevice-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn connect_command_runs_with_redacted_url() {
+        let marker = temp_path("hook.marker");
+        let script = temp_path("hook.sh");
+        std::fs::write(&script, format!("#!/bin/sh\necho \"$1\" > '{}'\n", marker.display())).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let content = runtime::block_on(async {
+            let hooks = ConnectionHooks::new(Some(script.to_str().unwrap().to_string()), None, "tcp://localhost:3755?user=test&password=secret");
+            hooks.disconnected();
+            hooks.connected();
+            for _ in 0..200 {
+                if let Ok(content) = std::fs::read_to_string(&marker) {
+                    if !content.is_empty() {
+                        return content;
+                    }
+                }
+                runtime::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("on-connect command did not run");
+        });
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&marker);
+        assert!(content.contains("localhost:3755"), "{content}");
+        assert!(!content.contains("secret"), "{content}");
+    }
+}
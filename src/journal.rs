@@ -0,0 +1,122 @@
+//! In-memory journal of property value changes, served by `.history:getLog`.
+//!
+//! Every `chng` the device emits is journaled with its plain value (before node formats
+//! and signal shaping). `getLog` accepts the usual SHV log parameters `since`, `until`
+//! (DateTime), `pathPattern` (`*` matches within a path segment, `**` any number of
+//! segments) and `recordCountLimit`, and returns the rows in the SHV log format
+//! `[timestamp, path, value, shortTime, domain, valueFlags, userId]`, oldest first,
+//! with `shortTime` and `userId` Null.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::{DateTime, RpcValue};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+pub(crate) const JOURNAL_MOUNT: &str = ".history";
+const DOMAIN_CHNG: &str = "chng";
+const DEFAULT_RECORD_COUNT_LIMIT: usize = 1000;
+
+struct Entry {
+    time: DateTime,
+    path: String,
+    value: RpcValue,
+}
+
+pub(crate) struct Journal {
+    capacity: usize,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl Journal {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self { capacity, entries: Default::default() }
+    }
+
+    pub(crate) fn record(&self, time: DateTime, path: &str, value: &RpcValue) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(Entry { time, path: path.to_string(), value: value.clone() });
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub(crate) fn get_log(&self, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        let empty = Map::new();
+        let params = match param.map(RpcValue::value) {
+            None | Some(Value::Null) => &empty,
+            Some(Value::Map(params)) => params.as_ref(),
+            _ => return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected {since, until, pathPattern, recordCountLimit}")),
+        };
+        let time = |key: &str| match params.get(key).map(RpcValue::value) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::DateTime(time)) => Ok(Some(time.epoch_msec())),
+            _ => Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("{key} must be a DateTime"))),
+        };
+        let (since, until) = (time("since")?, time("until")?);
+        let pattern = match params.get("pathPattern").map(RpcValue::value) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(pattern)) => Some(pattern.as_str()),
+            _ => return Err(RpcError::new(RpcErrorCode::InvalidParam, "pathPattern must be a String")),
+        };
+        let limit = match params.get("recordCountLimit") {
+            None => DEFAULT_RECORD_COUNT_LIMIT,
+            Some(limit) if limit.is_int() && limit.as_int() >= 0 => limit.as_int() as usize,
+            Some(_) => return Err(RpcError::new(RpcErrorCode::InvalidParam, "recordCountLimit must be a non-negative Int")),
+        };
+        let rows: Vec<RpcValue> = self.entries.lock().unwrap().iter()
+            .filter(|entry| since.is_none_or(|since| entry.time.epoch_msec() >= since))
+            .filter(|entry| until.is_none_or(|until| entry.time.epoch_msec() < until))
+            .filter(|entry| pattern.is_none_or(|pattern| path_matches(pattern, &entry.path)))
+            .take(limit)
+            .map(|entry| {
+                let row: Vec<RpcValue> = vec![
+                    entry.time.into(),
+                    entry.path.as_str().into(),
+                    entry.value.clone(),
+                    RpcValue::null(),
+                    DOMAIN_CHNG.into(),
+                    0.into(),
+                    RpcValue::null(),
+                ];
+                row.into()
+            })
+            .collect();
+        Ok(rows.into())
+    }
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+    fn matches(pattern: &[&str], path: &[&str]) -> bool {
+        match (pattern.first(), path.first()) {
+            (None, None) => true,
+            (Some(&"**"), _) => matches(&pattern[1..], path) || (!path.is_empty() && matches(pattern, &path[1..])),
+            (Some(segment), Some(name)) => segment_matches(segment, name) && matches(&pattern[1..], &path[1..]),
+            _ => false,
+        }
+    }
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    matches(&pattern, &path)
+}
+
+/// `*` matches any run of characters within the segment.
+fn segment_matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len()).filter(|n| name.is_char_boundary(*n)).any(|n| segment_matches(rest, &name[n..]))
+        }
+    }
+}
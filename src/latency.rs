@@ -0,0 +1,109 @@
+//! Response latency models assigned to nodes with `--latency <path>=<model>`.
+//!
+//! Models, all durations as e.g. `20ms` or `1s`:
+//! - `fixed:<delay>`
+//! - `uniform:<min>:<max>`
+//! - `normal:<mean>:<stddev>`, negative samples are clamped to zero
+//! - `pareto:<scale>:<shape>`, the scale is the minimum delay and the shape (alpha > 0)
+//!   controls the tail, smaller values give heavier tails
+//!
+//! The delay is applied to every method of the node after its handler finished and
+//! before the response is sent, so it shows in `status/latencyHistogram`.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::runtime;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Model {
+    Fixed(Duration),
+    Uniform(Duration, Duration),
+    Normal(f64, f64),
+    Pareto(f64, f64),
+}
+
+pub(crate) struct Latency {
+    models: Mutex<BTreeMap<String, Model>>,
+    rng: Mutex<StdRng>,
+}
+
+impl Latency {
+    pub(crate) fn new(specs: &[String], seed: u64) -> Result<Self, String> {
+        Ok(Self { models: Mutex::new(parse_specs(specs)?), rng: Mutex::new(StdRng::seed_from_u64(seed)) })
+    }
+
+    /// Replaces all models, returns false if they are the same as before. The random
+    /// generator keeps its state.
+    pub(crate) fn set_models(&self, specs: &[String]) -> Result<bool, String> {
+        let models = parse_specs(specs)?;
+        let mut current = self.models.lock().unwrap();
+        if *current == models {
+            return Ok(false);
+        }
+        *current = models;
+        Ok(true)
+    }
+
+    /// Sleeps for a delay sampled from the model of `path`, if it has one.
+    pub(crate) async fn apply(&self, path: &str) {
+        let Some(model) = self.models.lock().unwrap().get(path).copied() else {
+            return;
+        };
+        let delay = self.sample(model);
+        runtime::sleep(delay).await;
+    }
+
+    fn sample(&self, model: Model) -> Duration {
+        let mut rng = self.rng.lock().unwrap();
+        let secs = match model {
+            Model::Fixed(delay) => return delay,
+            Model::Uniform(min, max) => return rng.gen_range(min..=max),
+            Model::Normal(mean, stddev) => {
+                // Box-Muller transform, 1 - u keeps the logarithm argument in (0, 1].
+                let u1: f64 = 1. - rng.gen::<f64>();
+                let u2: f64 = rng.gen();
+                mean + stddev * (-2. * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+            }
+            Model::Pareto(scale, shape) => scale / (1. - rng.gen::<f64>()).powf(1. / shape),
+        };
+        Duration::try_from_secs_f64(secs.max(0.)).unwrap_or(Duration::MAX)
+    }
+}
+
+fn parse_specs(specs: &[String]) -> Result<BTreeMap<String, Model>, String> {
+    specs.iter()
+        .map(|spec| {
+            let (path, model) = spec.split_once('=').ok_or_else(|| format!("Invalid latency '{spec}', expected path=model"))?;
+            Ok((path.to_string(), parse_model(model).map_err(|err| format!("Invalid latency model for {path}: {err}"))?))
+        })
+        .collect()
+}
+
+fn parse_model(model: &str) -> Result<Model, String> {
+    let duration = |s: &str| duration_str::parse(s).map_err(|err| err.to_string());
+    let parts: Vec<&str> = model.split(':').collect();
+    match parts.as_slice() {
+        ["fixed", delay] => Ok(Model::Fixed(duration(delay)?)),
+        ["uniform", min, max] => {
+            let (min, max) = (duration(min)?, duration(max)?);
+            if min > max {
+                return Err("uniform minimum exceeds maximum".to_string());
+            }
+            Ok(Model::Uniform(min, max))
+        }
+        ["normal", mean, stddev] => Ok(Model::Normal(duration(mean)?.as_secs_f64(), duration(stddev)?.as_secs_f64())),
+        ["pareto", scale, shape] => {
+            let shape: f64 = shape.parse().map_err(|_| format!("invalid pareto shape '{shape}'"))?;
+            if shape.is_nan() || shape <= 0. {
+                return Err("pareto shape must be positive".to_string());
+            }
+            Ok(Model::Pareto(duration(scale)?.as_secs_f64(), shape))
+        }
+        _ => Err(format!("unknown model '{model}', expected fixed:<d>, uniform:<min>:<max>, normal:<mean>:<stddev> or pareto:<scale>:<shape>")),
+    }
+}
@@ -0,0 +1,2593 @@
+//! SHV device for testing brokers, the binary is a thin wrapper around [`run`].
+//!
+//! [`TestingDevice`] runs the same device embedded in another program, e.g. broker
+//! integration tests.
+
+use std::pin::pin;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+use async_std::sync::RwLock;
+
+use clap::Parser;
+use futures::future::Either;
+use log::*;
+use shvrpc::client::ClientConfig;
+use shvproto::rpcvalue::Map;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use shvclient::appnodes::{DotAppNode, DotDeviceNode};
+use shvclient::clientnode::ClientNode;
+use shvclient::{AppState, ClientCommandSender, ClientEvent, ClientEventsReceiver};
+
+#[macro_use]
+mod dispatch;
+mod access;
+mod alarms;
+mod anyvalue;
+mod backpressure;
+mod bench;
+mod bridge;
+mod capture;
+mod clock;
+mod configtree;
+mod configwatch;
+mod connection;
+mod control;
+mod counter;
+mod deeptree;
+mod echo;
+mod fault;
+mod faults;
+mod files;
+mod heartbeat;
+mod firmware;
+mod history;
+mod hooks;
+mod journal;
+mod latency;
+mod lifecycle;
+mod loadgen;
+mod logging;
+mod loginprobe;
+mod longop;
+mod lsanomaly;
+mod mapnode;
+mod metrics;
+#[cfg(feature = "metrics-http")]
+mod metricshttp;
+mod mountconflict;
+mod mirror;
+mod nodeformat;
+mod params;
+mod payload;
+mod reboot;
+mod recording;
+mod rpc;
+mod runtime;
+mod scenario;
+mod scripting;
+mod selftest;
+mod sensors;
+mod signals;
+mod sigtypes;
+mod sim;
+mod slowlogin;
+mod stats;
+mod subscriptions;
+mod synthetic;
+mod table;
+mod tasks;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
+mod typednodes;
+
+#[derive(Parser, Debug, Clone)]
+//#[structopt(name = "device", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "SHV call")]
+#[command(args_override_self = true)]
+struct Opts {
+    /// Config file path
+    #[arg(long)]
+    config: Option<String>,
+    /// File with additional command line options, one per line, e.g. `--latency state/number=fixed:20ms`.
+    /// Read again on a config reload (SIGHUP, control/app:reloadConfig), a repeated option overrides the command line.
+    #[arg(long)]
+    options_file: Option<String>,
+    /// The command line the options are parsed from, for reloading the --options-file.
+    #[arg(skip)]
+    command_line: Vec<std::ffi::OsString>,
+    #[command(subcommand)]
+    command: Option<SubCommand>,
+    /// Create default config file if one specified by --config is not found
+    #[arg(short, long)]
+    create_default_config: bool,
+    ///Url to connect to, example tcp://localhost:3755?user=admin&password=dj4j5HHb, localsocket:path/to/socket,
+    /// ws://localhost:3777 or wss://broker.example.com (websocket feature), ssl://localhost:3756 (tls feature)
+    #[arg(short = 's', long = "url", env = "SHV_URL")]
+    url: Option<String>,
+    /// CA bundle (PEM) verifying the broker certificate of ssl:// URLs, the system roots are used as well.
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "SHV_TLS_CA")]
+    tls_ca: Option<String>,
+    /// Client certificate (PEM) presented to ssl:// brokers, requires --tls-key.
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "SHV_TLS_CERT")]
+    tls_cert: Option<String>,
+    /// Private key (PKCS#8 PEM) of --tls-cert.
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "SHV_TLS_KEY")]
+    tls_key: Option<String>,
+    /// Accept any broker certificate and host name, for self-signed test brokers.
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    insecure: bool,
+    #[arg(short = 'i', long, env = "SHV_DEVICE_ID")]
+    device_id: Option<String>,
+    /// Mount point on broker connected to, note that broker might not accept any path.
+    #[arg(short, long, env = "SHV_MOUNT")]
+    mount: Option<String>,
+    /// What to do when the broker does not accept the mount point.
+    /// fail: exit, retry: reconnect with backoff, ignore: stay connected unmounted.
+    #[arg(long, value_enum, default_value_t = connection::MountReject::Fail)]
+    mount_reject: connection::MountReject,
+    /// How the device closes the broker connection on reconnect, disconnect and shutdown.
+    /// graceful: orderly close, abrupt: TCP reset, needs a tcp:// or ssl:// broker URL.
+    #[arg(long, value_enum, default_value_t = connection::DisconnectStyle::Graceful)]
+    disconnect_style: connection::DisconnectStyle,
+    /// Open a second connection competing for the mount point or device id this long
+    /// after connecting, see history/mountConflicts.
+    #[arg(long)]
+    mount_conflict: Option<String>,
+    /// What the second connection of --mount-conflict competes for.
+    #[arg(long, value_enum, default_value_t = mountconflict::ConflictBy::Mount)]
+    mount_conflict_by: mountconflict::ConflictBy,
+    /// Login variant to try on a separate connection at startup, results in test/login. Can be repeated.
+    #[arg(long, value_enum)]
+    login_probe: Vec<loginprobe::LoginVariant>,
+    /// Field added to the login options of the test/login probes, as key=cpon. Can be repeated.
+    #[arg(long)]
+    login_option: Vec<String>,
+    /// Size of the padding in the options of the oversized-options login probe.
+    #[arg(long, default_value_t = 1024 * 1024)]
+    login_oversized_bytes: usize,
+    /// Connect, check the broker (mount, signal echo, access levels), print a JSON report
+    /// and exit with 0 when all checks passed, 1 otherwise.
+    #[arg(long)]
+    selftest: bool,
+    /// How long --selftest waits for the connection before it fails.
+    #[arg(long, default_value = "30s")]
+    selftest_timeout: String,
+    /// Derive the mount point from a template, overrides --mount. Placeholders: {device_id}, {pid}.
+    /// Example: test/devices/{device_id}
+    #[arg(long)]
+    mount_template: Option<String>,
+    /// Device tries to reconnect to broker after this interval, if connection to broker is lost.
+    /// Example values: 1s, 1h, etc.
+    #[arg(short, long, env = "SHV_RECONNECT_INTERVAL")]
+    reconnect_interval: Option<String>,
+    /// Client should ping broker with this interval. Broker will disconnect device, if ping is not received twice.
+    /// Example values: 1s, 1h, etc.
+    #[arg(long, default_value = "1m", env = "SHV_HEARTBEAT_INTERVAL")]
+    heartbeat_interval: String,
+    /// Program executed when the device connects to the broker, the broker URL is passed as its only argument.
+    /// A path to the program only, it runs without a shell so arguments cannot be added, wrap them in a script.
+    /// The program runs with the privileges of the device process.
+    #[arg(long)]
+    on_connect_cmd: Option<String>,
+    /// Program executed when the device loses connection to the broker, the broker URL is passed as its only argument.
+    /// A path to the program only, it runs without a shell so arguments cannot be added, wrap them in a script.
+    /// The program runs with the privileges of the device process.
+    #[arg(long)]
+    on_disconnect_cmd: Option<String>,
+    /// Mirror a remote broker node locally, get and set are forwarded to the remote path.
+    /// Format: <local path>=<remote path>, can be repeated.
+    #[arg(long)]
+    mirror: Vec<String>,
+    /// Serve mirror node reads from a cache for this interval after a successful remote get.
+    /// Example values: 1s, 1h, etc.
+    #[arg(long)]
+    mirror_cache_ttl: Option<String>,
+    /// How errors of the remote node are returned by mirror nodes.
+    /// passthrough: the remote code and message, wrap: the remote code with the failing mirror named in the message.
+    #[arg(long, value_enum, default_value_t = mirror::MirrorErrorMode::Passthrough)]
+    mirror_error_mode: mirror::MirrorErrorMode,
+    /// Healthy value of the state/fault_sim node.
+    #[arg(long, default_value_t = 20.0)]
+    fault_baseline: f64,
+    /// Value the state/fault_sim node drifts to when a fault is triggered.
+    #[arg(long, default_value_t = 80.0)]
+    fault_target: f64,
+    /// The state/fault_sim value is reported as healthy while its distance from the baseline is below this threshold.
+    #[arg(long, default_value_t = 30.0)]
+    fault_threshold: f64,
+    /// Log a warning for every outgoing signal or response larger than this (estimated) size in bytes.
+    #[arg(long)]
+    large_message_warn_bytes: Option<usize>,
+    /// Shift every DateTime the device produces by this offset to simulate a misconfigured clock.
+    /// Example values: 5s, -1h, etc.
+    #[arg(long, allow_hyphen_values = true)]
+    clock_offset: Option<String>,
+    /// Round values written to state/number to the nearest multiple of this step, ties away from zero.
+    #[arg(long)]
+    number_step: Option<i32>,
+    /// Smallest value state/number accepts, advertised by its constraints method.
+    #[arg(long, allow_hyphen_values = true)]
+    number_min: Option<i32>,
+    /// Largest value state/number accepts, advertised by its constraints method.
+    #[arg(long, allow_hyphen_values = true)]
+    number_max: Option<i32>,
+    /// Width of the state/counter node in bits (8, 16, 32 or 64), the counter wraps to zero past its maximum.
+    #[arg(long, default_value_t = 16)]
+    counter_bits: u32,
+    /// Increment state/counter automatically with this interval.
+    /// Example values: 100ms, 1s, etc.
+    #[arg(long)]
+    counter_auto: Option<String>,
+    /// Advance sim/clock and emit its chng with this period, e.g. 1s.
+    #[arg(long)]
+    sim_clock: Option<String>,
+    /// Advance sim/ramp by one step and emit its chng with this period, e.g. 100ms.
+    #[arg(long)]
+    sim_ramp: Option<String>,
+    /// Waveform of sim/ramp.
+    #[arg(long, value_enum, default_value_t)]
+    sim_ramp_shape: sim::RampShape,
+    /// Number of sim/ramp steps per waveform period.
+    #[arg(long, default_value_t = 100)]
+    sim_ramp_steps: u64,
+    /// Fault injection: state/text set emits a truncated value first and the full value after --text-tear-delay.
+    /// The stored value is always the full string.
+    #[arg(long)]
+    text_tear: bool,
+    /// Delay between the partial and the full chng signal in --text-tear mode.
+    #[arg(long, default_value = "100ms")]
+    text_tear_delay: String,
+    /// Emit the current value of every stateful node, including running generators, after each (re)connect.
+    #[arg(long)]
+    emit_snapshot_on_connect: bool,
+    /// Buffer signals emitted while disconnected and send them after reconnect.
+    #[arg(long)]
+    replay_on_reconnect: bool,
+    /// Maximum number of signals kept for --replay-on-reconnect, the oldest are dropped first.
+    #[arg(long, default_value_t = 1000)]
+    replay_buffer_size: usize,
+    /// Flaky network simulation: drop and re-establish the broker connection with this interval.
+    /// Example values: 30s, 5m, etc.
+    #[arg(long)]
+    flaky_drop_every: Option<String>,
+    /// Random deviation applied to each --flaky-drop-every interval.
+    #[arg(long, default_value = "0s")]
+    flaky_drop_jitter: String,
+    /// Drop and immediately re-establish the broker connection with this period, see also control/connection:flap.
+    #[arg(long)]
+    flap_interval: Option<String>,
+    /// Heartbeat misbehavior: skip pings, send them late or in bursts, see also control/heartbeat.
+    #[arg(long, value_enum, default_value_t, env = "SHV_HEARTBEAT_MODE")]
+    heartbeat_mode: heartbeat::HeartbeatMode,
+    /// Delay of each ping in --heartbeat-mode late.
+    #[arg(long, default_value = "5s", env = "SHV_HEARTBEAT_LATE")]
+    heartbeat_late: String,
+    /// Number of pings sent at once in --heartbeat-mode burst.
+    #[arg(long, default_value_t = 10, env = "SHV_HEARTBEAT_BURST")]
+    heartbeat_burst: u32,
+    /// Allow control:corruptResponses to make get methods return values of the wrong type.
+    #[arg(long)]
+    enable_corruption: bool,
+    /// Probability (0..1) that a get response is corrupted while corruption is active.
+    #[arg(long, default_value_t = 0.1)]
+    corrupt_rate: f64,
+    /// Seed of the corruption random generator.
+    #[arg(long, default_value_t = 0)]
+    corrupt_seed: u64,
+    /// Fail a percentage of the requests with an RpcError code as `[path=]percent[:code]`, e.g. `5` or
+    /// `state/number=20:6`. The code defaults to 8 (MethodCallException). Can be repeated, see test/fault:setErrorRate.
+    #[arg(long)]
+    error_rate: Vec<String>,
+    /// Seed of the --error-rate random generator.
+    #[arg(long, default_value_t = 0)]
+    error_rate_seed: u64,
+    /// Order of signals emitted together by control:setMany and connect snapshots.
+    /// path: sorted by node path, insertion: in the order the changes were applied.
+    #[arg(long, value_enum, default_value_t = signals::SignalOrder::Insertion)]
+    signal_order: signals::SignalOrder,
+    /// Structure of chng signal parameters.
+    /// scalar: the bare value, valueChange: {"value": <value>} with optional "meta" Map.
+    #[arg(long, value_enum, default_value_t = signals::SignalShape::Scalar)]
+    signal_shape: signals::SignalShape,
+    /// Upper bound of the test/adaptivePayload blob size, the blob grows by 1 KiB per unit of control:setLoad.
+    #[arg(long, default_value_t = 1024 * 1024)]
+    max_payload_bytes: usize,
+    /// Probability (0..1) that an emitted chng signal is sent twice, for consumer idempotency tests.
+    #[arg(long, default_value_t = 0.)]
+    signal_duplicate_rate: f64,
+    /// Seed of the signal duplication random generator.
+    #[arg(long, default_value_t = 0)]
+    signal_duplicate_seed: u64,
+    /// Response latency model of a node as `path=model`, can be repeated. Models: fixed:<delay>,
+    /// uniform:<min>:<max>, normal:<mean>:<stddev>, pareto:<scale>:<shape>, e.g. state/number=uniform:10ms:50ms.
+    #[arg(long)]
+    latency: Vec<String>,
+    /// Seed of the latency model random generator.
+    #[arg(long, default_value_t = 0)]
+    latency_seed: u64,
+    /// Collapse rapid changes of a state/* node into one chng signal carrying the last value,
+    /// emitted once the node has not changed for this interval. get returns new values immediately.
+    /// Example values: 50ms, 1s, etc.
+    #[arg(long)]
+    coalesce_window: Option<String>,
+    /// Send signals through a bounded outbound queue of this size, for broker backpressure tests.
+    /// Dropped signals are counted in status/metrics.
+    #[arg(long)]
+    signal_queue_size: Option<usize>,
+    /// What happens to a new signal when the --signal-queue-size queue is full.
+    #[arg(long, value_enum, default_value_t = signals::SignalOverflow::DropOldest)]
+    signal_overflow: signals::SignalOverflow,
+    /// Send at most this many signals per second, the rest wait in the outbound queue
+    /// (unbounded unless --signal-queue-size is given), simulating a slow consumer.
+    #[arg(long)]
+    consumer_rate: Option<f64>,
+    /// Send at most this many messages (responses and signals) per second, see control/backpressure.
+    #[arg(long)]
+    max_send_rate: Option<f64>,
+    /// Send at most this many bytes per second to the broker, through the shaping relay.
+    #[arg(long)]
+    max_send_bandwidth: Option<u64>,
+    /// Connect to a tcp:// broker through a local relay that can stall reading with control/backpressure:stall.
+    #[arg(long)]
+    shaping_relay: bool,
+    /// Wait this long before the first connection attempt, e.g. 1500ms.
+    #[arg(long)]
+    startup_delay: Option<String>,
+    /// After the TCP connect send nothing for this long, e.g. 250ms. Uses the relay of --shaping-relay.
+    #[arg(long)]
+    connect_stall: Option<String>,
+    /// Hold the login request this long after the broker answered the hello, e.g. 250ms.
+    /// Uses the relay of --shaping-relay.
+    #[arg(long)]
+    login_delay: Option<String>,
+    /// Write every RPC frame sent to and received from the broker to this file, see the `replay` subcommand.
+    /// Uses the relay of --shaping-relay.
+    #[arg(long)]
+    capture: Option<String>,
+    /// Exit with code 3 after this many consecutive failed connection attempts, unlimited by default.
+    #[arg(long)]
+    max_reconnect_attempts: Option<u64>,
+    /// CPON file declaring additional property nodes to mount, see the synthetic module for the format.
+    #[arg(long)]
+    nodes_file: Option<String>,
+    /// test/blob:get refuses blobs larger than this many bytes, test/blob:stream sends chunks of this size.
+    #[arg(long)]
+    blob_stream_threshold: Option<usize>,
+    /// Run this many independent simulated devices, each with its own connection and state.
+    #[arg(long, env = "SHV_DEVICES", default_value_t = 1)]
+    devices: usize,
+    /// Connect to this broker too, see the bridge module.
+    #[arg(long)]
+    bridge_url: Option<String>,
+    /// Mount point on the --bridge-url broker, the same as on the first broker by default.
+    #[arg(long)]
+    bridge_mount: Option<String>,
+    /// Apply property changes made on one side of the bridge to the other side.
+    #[arg(long)]
+    bridge_mirror: bool,
+    /// Mount point of each device with --devices, `{}` is replaced by the device index.
+    /// A configured device id gets the index appended.
+    #[arg(long, default_value = "test/device{}")]
+    device_template: String,
+    /// Number of requests kept in history/requests, 0 disables the history.
+    #[arg(long, default_value_t = 1000)]
+    history_size: usize,
+    /// Number of value changes kept in the .history journal, 0 disables the journal.
+    #[arg(long, default_value_t = 10000)]
+    journal_size: usize,
+    /// Mount this many children under test/ls/wide, for long ls results.
+    #[arg(long, default_value_t = 0)]
+    ls_wide: usize,
+    /// Mount children with unusual characters in their names under test/ls/odd.
+    #[arg(long)]
+    ls_odd_names: bool,
+    /// Upper bound of the memory test/load:allocate keeps allocated in total.
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    max_allocate_bytes: usize,
+    /// Mount a node handled by a Rhai script, see the scripting module for the script interface.
+    /// Format: <path>=<script file>, can be repeated.
+    #[arg(long)]
+    script: Vec<String>,
+    /// Expose the files of this directory under files/ through the SHV file node API.
+    #[arg(long)]
+    files_root: Option<String>,
+    /// Subscribe to signals on the broker matching this RPC RI, e.g. test/**:*:chng, can be repeated.
+    /// Received signals are counted in history/signals, see also control/subscriptions.
+    #[arg(long)]
+    subscribe: Vec<String>,
+    /// Subscribe to the signals of the own state nodes and report in status/drift the nodes
+    /// whose last emitted value differs from the last value received back through the broker.
+    #[arg(long)]
+    self_subscribe: bool,
+    /// Testing only: allow control:leak to allocate memory that is never freed until control:releaseLeak.
+    #[arg(long)]
+    enable_leak_method: bool,
+    /// Mount correlated sensors/temperature, sensors/humidity and sensors/pressure generator nodes.
+    #[arg(long)]
+    sensor_suite: bool,
+    /// control:exportRecording also writes the recorded trace to this file as CPON.
+    #[arg(long)]
+    recording_file: Option<String>,
+    /// Emit connected, disconnected and reconnecting signals on this path for fleet monitoring over SHV.
+    #[arg(long)]
+    lifecycle_signal_path: Option<String>,
+    /// Reload the configuration when the --config file changes, as on SIGHUP, see the configwatch module.
+    #[arg(long)]
+    watch_config: bool,
+    /// Dangerous, testing only: allow control:encodingFault to send malformed responses to the broker.
+    /// test/fault:malformResponses works without it.
+    #[arg(long)]
+    enable_encoding_faults: bool,
+    /// Window over which status/requestRate averages handled requests.
+    #[arg(long, default_value = "10s")]
+    rps_window: String,
+    /// Emit chng on status/requestRate with this interval.
+    /// Example values: 1s, 5s, etc.
+    #[arg(long)]
+    rps_emit_interval: Option<String>,
+    /// Sample the .stats nodes with this interval, a changed value emits chng.
+    #[arg(long, default_value = "1s")]
+    stats_interval: String,
+    /// Attach the emission time as a "ts" DateTime meta tag to every chng signal, for end-to-end latency measurement.
+    #[arg(long)]
+    timestamp_signals: bool,
+    /// Mount the whole node tree once more under this path, sharing the same state, can be repeated.
+    /// Every change is signalled under each mount path.
+    #[arg(long)]
+    extra_mount: Vec<String>,
+    /// Busy-loop this many milliseconds on the blocking thread pool in every handler before it responds.
+    #[arg(long)]
+    blocking_work_ms: Option<u64>,
+    /// Re-emit the last signalled value of every node after each (re)connect, emulating retained values.
+    #[arg(long)]
+    retain_last_value: bool,
+    /// Firmware version reported on device/firmware at startup.
+    #[arg(long, default_value = "0.1")]
+    firmware_version: String,
+    /// Time a device/firmware:upgrade takes until the new version is reported.
+    #[arg(long, default_value = "5s")]
+    upgrade_duration: String,
+    /// Reconnect to the broker after a firmware upgrade, simulating a restart.
+    #[arg(long)]
+    upgrade_reconnect: bool,
+    /// CPON file with a Map of key to type name, state/map rejects writes not matching it.
+    #[arg(long)]
+    map_schema: Option<String>,
+    /// Comma separated values state/mode accepts, the first one is the initial value.
+    #[arg(long, default_value = "off,auto,manual")]
+    mode_values: String,
+    /// CPON Map holding the initial state/config tree, a small built-in tree by default.
+    #[arg(long)]
+    config_tree: Option<String>,
+    /// Typed column of state/table as `name=Type`, can be repeated. Without columns rows are untyped Maps.
+    #[arg(long)]
+    table_column: Vec<String>,
+    /// Serve Prometheus metrics on http://<addr>/metrics, e.g. 127.0.0.1:9100.
+    #[cfg(feature = "metrics-http")]
+    #[arg(long)]
+    metrics_listen: Option<String>,
+    /// Reject requests whose param is larger than this many bytes when encoded, unlimited by default.
+    #[arg(long)]
+    max_request_bytes: Option<usize>,
+    /// Answer to a call of a method the node does not have, null and echo are for testing only.
+    /// error: MethodNotFound, null: a Null result, echo: the method name as the result.
+    /// Applies to the nodes declared with control/nodes and --node-file. Calls of undeclared
+    /// methods on the built-in nodes are rejected with MethodNotFound by the client library
+    /// before they reach the device.
+    #[arg(long, value_enum, default_value_t = dispatch::UnknownMethod::Error)]
+    unknown_method: dispatch::UnknownMethod,
+    /// Signal name emitted instead of `chng` when the node at the mount changes, as `mount=name`, can be repeated.
+    #[arg(long)]
+    signal_name: Vec<String>,
+    /// Mount a chain of i32 nodes deep/0/1/.../<depth - 1> of this depth.
+    #[arg(long)]
+    deep_tree: Option<usize>,
+    /// Verbose mode (module, .)
+    #[arg(short, long)]
+    verbose: Option<String>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum SubCommand {
+    /// Send the frames the device sent in the first connection of a --capture file to the
+    /// broker of --url again, instead of running the device.
+    Replay {
+        /// Capture file
+        file: String,
+        /// Send the frames right after each other instead of with the captured timing.
+        #[arg(long)]
+        fast: bool,
+    },
+}
+
+impl Opts {
+    /// Parses the command line (program name included) followed by the --options-file options.
+    fn parse_args<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let command_line: Vec<std::ffi::OsString> = args.into_iter().map(Into::into).collect();
+        let mut opts = Opts::try_parse_from(&command_line)?;
+        if let Some(file) = &opts.options_file {
+            let extra = configwatch::read_options_file(file)
+                .map_err(|err| clap::Error::raw(clap::error::ErrorKind::Io, format!("{err}\n")))?;
+            opts = Opts::try_parse_from(command_line.iter().cloned().chain(extra))?;
+        }
+        opts.command_line = command_line;
+        Ok(opts)
+    }
+
+    /// The options as they are parsed now, with the current content of the --options-file.
+    fn reparse(&self) -> Result<Self, clap::Error> {
+        Self::parse_args(self.command_line.clone())
+    }
+}
+
+/// Connection options are resolved with precedence: command line, then `SHV_*`
+/// environment variable, then config file, then built-in default.
+fn load_client_config(cli_opts: &Opts) -> shvrpc::Result<ClientConfig> {
+    let mut config = if let Some(config_file) = &cli_opts.config {
+        ClientConfig::from_file_or_default(config_file, cli_opts.create_default_config)?
+    } else {
+        Default::default()
+    };
+    config.url = cli_opts.url.clone().unwrap_or(config.url);
+    config.device_id = cli_opts.device_id.clone().or(config.device_id);
+    config.mount = cli_opts.mount.clone().or(config.mount);
+    if let Some(template) = &cli_opts.mount_template {
+        config.mount = Some(expand_mount_template(template, config.device_id.as_deref())?);
+    }
+    config.reconnect_interval = cli_opts.reconnect_interval.clone().or(config.reconnect_interval);
+    config.heartbeat_interval.clone_from(&cli_opts.heartbeat_interval);
+    Ok(config)
+}
+
+/// Expands `{device_id}` and `{pid}` in a mount template and checks that the result is a valid SHV path.
+fn expand_mount_template(template: &str, device_id: Option<&str>) -> Result<String, String> {
+    let mut mount = template.replace("{pid}", &std::process::id().to_string());
+    if mount.contains("{device_id}") {
+        let device_id = device_id.ok_or("Mount template uses {device_id}, but no device id is configured")?;
+        mount = mount.replace("{device_id}", device_id);
+    }
+    if mount.is_empty() || mount.split('/').any(|dir| dir.is_empty() || dir.contains(|c: char| c.is_whitespace() || c == '{' || c == '}')) {
+        return Err(format!("Mount template '{template}' expands to invalid SHV path '{mount}'"));
+    }
+    Ok(mount)
+}
+
+/// State of one device, the handlers of nodes added by [`TestingDevice::add_node`] get it as their app state.
+pub struct State {
+    number: AtomicI32,
+    text: RwLock<String>,
+    any_value: RwLock<RpcValue>,
+    map: mapnode::MapNode,
+    typed: typednodes::TypedNodes,
+    config_tree: configtree::ConfigTree,
+    table: table::Table,
+    bench_emitter: bench::Emitter,
+    sigstorm: bench::Storm,
+    echo: echo::Echo,
+    fault_sim: fault::FaultSim,
+    mirrors: mirror::Mirrors,
+    metrics: metrics::Metrics,
+    clock: clock::Clock,
+    text_tear_delay: Option<Duration>,
+    blocking_work: Option<Duration>,
+    max_request_bytes: Option<usize>,
+    unknown_method: dispatch::UnknownMethod,
+    number_step: Option<i32>,
+    number_min: Option<i32>,
+    number_max: Option<i32>,
+    counter: counter::Counter,
+    faults: faults::Faults,
+    tasks: tasks::Tasks,
+    adaptive_payload: payload::AdaptivePayload,
+    blob: payload::Blob,
+    long_ops: longop::LongOps,
+    transport: transport::Transport,
+    signals: signals::Signals,
+    connection: connection::Connection,
+    latency_histogram: metrics::LatencyHistogram,
+    latency: latency::Latency,
+    error_counts: metrics::ErrorCounts,
+    request_rate: metrics::RequestRate,
+    method_stats: metrics::MethodStats,
+    stats: stats::Stats,
+    generators: std::sync::Mutex<reboot::Generators>,
+    /// Options the device was started with, reparsed by a config reload.
+    opts: Opts,
+    sensors: Option<sensors::SensorSuite>,
+    recording: recording::Recording,
+    history: history::History,
+    lifecycle: lifecycle::Lifecycle,
+    client_config: std::sync::Mutex<ClientConfig>,
+    extra_mounts: Vec<String>,
+    mount_reject: connection::MountReject,
+    sequences: control::Sequences,
+    alarms: alarms::Alarms,
+    firmware: firmware::Firmware,
+    deep_tree: deeptree::DeepTree,
+    node_formats: nodeformat::NodeFormats,
+    synthetic: synthetic::SyntheticNodes,
+    scripts: scripting::Scripts,
+    files: files::Files,
+    subscriptions: subscriptions::Subscriptions,
+    sim: sim::Sim,
+    journal: journal::Journal,
+    ls_anomalies: lsanomaly::LsAnomalies,
+    load_generator: loadgen::LoadGenerator,
+    scenario: scenario::Scenario,
+    mount_conflicts: mountconflict::MountConflicts,
+    selftest: selftest::SelfTest,
+    login_probes: loginprobe::LoginProbes,
+    backpressure: backpressure::Backpressure,
+    capture: capture::Capture,
+    slow_login: slowlogin::SlowLogin,
+    heartbeat: heartbeat::Heartbeat,
+    custom_nodes: Vec<(String, NodeFactory)>,
+    bridge: bridge::Bridge,
+}
+
+impl State {
+    /// Current value of state/number.
+    pub fn number(&self) -> i32 {
+        self.number.load(Ordering::SeqCst)
+    }
+
+    /// Current value of state/text.
+    pub async fn text(&self) -> String {
+        self.text.read().await.clone()
+    }
+
+    /// Stores a new state/number value, returns the signal value if it changed.
+    /// The range check applies to the value after quantization, as that is what gets stored.
+    fn update_number(&self, value: i32) -> Result<Option<RpcValue>, RpcError> {
+        let value = match self.number_step {
+            Some(step) => quantize(value, step),
+            None => value,
+        };
+        if self.number_min.is_some_and(|min| value < min) || self.number_max.is_some_and(|max| value > max) {
+            let msg = format!("Value {value} is out of range {}", self.number_constraints().to_cpon());
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, &msg));
+        }
+        if self.number.load(Ordering::SeqCst) == value {
+            return Ok(None);
+        }
+        self.faults.capacity.try_consume()?;
+        if self.number.swap(value, Ordering::SeqCst) == value {
+            // A concurrent write of the same value got there first.
+            self.faults.capacity.release();
+            return Ok(None);
+        }
+        Ok(Some(value.into()))
+    }
+
+    fn set_number(&self, client_cmd_tx: &ClientCommandSender, value: i32) -> Result<(), RpcError> {
+        if let Some(value) = self.update_number(value)? {
+            signals::emit_chng(self, client_cmd_tx, NUMBER_MOUNT, value);
+        }
+        Ok(())
+    }
+
+    /// Constraints enforced by state/number:set. The dir metadata of fixed nodes can
+    /// only hold static strings, so they are published by a method instead.
+    fn number_constraints(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("type".into(), "Int".into());
+        map.insert("min".into(), self.number_min.unwrap_or(i32::MIN).into());
+        map.insert("max".into(), self.number_max.unwrap_or(i32::MAX).into());
+        if let Some(step) = self.number_step {
+            map.insert("step".into(), step.into());
+        }
+        map.into()
+    }
+
+    /// Stores a new state/text value, returns the signal value if it changed.
+    async fn update_text(&self, value: String) -> Result<Option<RpcValue>, RpcError> {
+        let mut writer = self.text.write().await;
+        if *writer == value {
+            return Ok(None);
+        }
+        self.faults.capacity.try_consume()?;
+        *writer = value;
+        Ok(Some(writer.as_str().into()))
+    }
+
+    /// With `--text-tear` the response does not wait for the final value, the request
+    /// completes while observers still see the torn update.
+    async fn set_text(
+        app_state: &AppState<Self>,
+        client_cmd_tx: &(impl signals::MessageSink + Clone + Send + Sync + 'static),
+        value: String,
+    ) -> Result<(), RpcError> {
+        let Some(value) = app_state.update_text(value).await? else {
+            return Ok(());
+        };
+        let Some(delay) = app_state.text_tear_delay else {
+            signals::emit_chng(app_state, client_cmd_tx, TEXT_MOUNT, value);
+            return Ok(());
+        };
+        // Deliberate fault injection: observers see a torn update before the final value.
+        let text = value.as_str();
+        let partial: String = text.chars().take(text.chars().count() / 2).collect();
+        signals::emit_chng(app_state, client_cmd_tx, TEXT_MOUNT, partial.into());
+        let state = app_state.clone();
+        let client_cmd_tx = client_cmd_tx.clone();
+        tasks::spawn(app_state, "textTear", async move {
+            runtime::sleep(delay).await;
+            // A newer write emits its own value, this one is stale by now.
+            if *state.text.read().await == value.as_str() {
+                signals::emit_chng(&state, &client_cmd_tx, TEXT_MOUNT, value);
+            }
+        });
+        Ok(())
+    }
+
+    /// Stores a new test/anyValue value, returns the signal value if it changed.
+    async fn update_any_value(&self, value: RpcValue) -> Result<Option<RpcValue>, RpcError> {
+        let mut writer = self.any_value.write().await;
+        if *writer == value {
+            return Ok(None);
+        }
+        self.faults.capacity.try_consume()?;
+        *writer = value.clone();
+        Ok(Some(value))
+    }
+
+    async fn set_any_value(&self, client_cmd_tx: &impl signals::MessageSink, value: RpcValue) -> Result<(), RpcError> {
+        if let Some(value) = self.update_any_value(value).await? {
+            signals::emit_chng(self, client_cmd_tx, anyvalue::ANY_VALUE_MOUNT, value);
+        }
+        Ok(())
+    }
+
+    /// Applies a change of state/map with capacity accounting and emits its signal.
+    /// `update` passes the commit hook on to the node, which accounts the write once it
+    /// is known to change the map.
+    fn set_map(
+        &self,
+        client_cmd_tx: &ClientCommandSender,
+        update: impl FnOnce(&mapnode::MapNode, &dyn Fn() -> Result<(), RpcError>) -> Result<Option<RpcValue>, RpcError>,
+    ) -> Result<RpcValue, RpcError> {
+        if let Some(value) = update(&self.map, &|| self.faults.capacity.try_consume())? {
+            signals::emit_chng(self, client_cmd_tx, mapnode::MAP_MOUNT, value);
+        }
+        Ok(().into())
+    }
+
+    /// Like [`Self::set_map`] for the nodes of the typednodes module.
+    fn set_typed(&self, client_cmd_tx: &ClientCommandSender, path: &str, value: RpcValue) -> Result<RpcValue, RpcError> {
+        if let Some(value) = self.typed.set(path, value, || self.faults.capacity.try_consume())? {
+            signals::emit_chng(self, client_cmd_tx, path, value);
+        }
+        Ok(().into())
+    }
+
+    /// Like [`Self::set_map`] for state/config, the signal carries the changed subtree only.
+    fn set_config_tree(&self, client_cmd_tx: &ClientCommandSender, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        if let Some(subtree) = self.config_tree.set(param, || self.faults.capacity.try_consume())? {
+            signals::emit_chng(self, client_cmd_tx, configtree::CONFIG_TREE_MOUNT, subtree);
+        }
+        Ok(().into())
+    }
+
+    /// Like [`Self::set_map`], `change` returns the method result and the row signal to emit.
+    fn change_table(
+        &self,
+        client_cmd_tx: &ClientCommandSender,
+        change: impl FnOnce(&table::Table, &dyn Fn() -> Result<(), RpcError>) -> Result<(RpcValue, Option<(String, RpcValue)>), RpcError>,
+    ) -> Result<RpcValue, RpcError> {
+        let (result, changed) = change(&self.table, &|| self.faults.capacity.try_consume())?;
+        if let Some((path, value)) = changed {
+            signals::emit_chng(self, client_cmd_tx, &path, value);
+        }
+        Ok(result)
+    }
+
+    /// Restores the startup values of every stateful node without emitting.
+    async fn reset_values(&self) {
+        self.number.store(0, Ordering::SeqCst);
+        self.text.write().await.clear();
+        *self.any_value.write().await = RpcValue::null();
+        self.map.reset();
+        self.typed.reset();
+        self.config_tree.reset();
+        self.table.reset();
+        self.synthetic.reset();
+    }
+
+    /// Emits the current value of every stateful node.
+    async fn emit_snapshot(&self, client_cmd_tx: &impl signals::MessageSink) {
+        let text = self.text.read().await.clone();
+        let mut batch = vec![
+            (NUMBER_MOUNT.to_string(), self.number.load(Ordering::SeqCst).into()),
+            (TEXT_MOUNT.to_string(), text.into()),
+            (anyvalue::ANY_VALUE_MOUNT.to_string(), self.any_value.read().await.clone()),
+            (mapnode::MAP_MOUNT.to_string(), self.map.value()),
+            (configtree::CONFIG_TREE_MOUNT.to_string(), self.config_tree.value()),
+            (counter::COUNTER_MOUNT.to_string(), self.counter.value().into()),
+            (fault::FAULT_SIM_MOUNT.to_string(), self.fault_sim.value()),
+            (alarms::ALARMS_MOUNT.to_string(), self.alarms.value()),
+            (sim::SIM_CLOCK_MOUNT.to_string(), self.sim.clock_value()),
+            (sim::SIM_RAMP_MOUNT.to_string(), self.sim.ramp_value()),
+        ];
+        if let Some(sensors) = &self.sensors {
+            batch.extend(sensors.values());
+        }
+        batch.extend(self.typed.values());
+        batch.extend(self.synthetic.values());
+        signals::emit_batch(self, client_cmd_tx, batch);
+    }
+
+    /// Strips an `--extra-mount` prefix, so that path-dependent nodes resolve the same under every mount.
+    fn base_path<'a>(&self, path: &'a str) -> &'a str {
+        self.extra_mounts.iter()
+            .find_map(|mount| path.strip_prefix(mount.as_str())?.strip_prefix('/'))
+            .unwrap_or(path)
+    }
+
+    /// Complete device dump for control:snapshot. Values are read one after another
+    /// in a fixed order, the dump is not atomic across nodes.
+    async fn dump(&self) -> RpcValue {
+        let mut nodes = Map::new();
+        nodes.insert(NUMBER_MOUNT.into(), self.number.load(Ordering::SeqCst).into());
+        nodes.insert(TEXT_MOUNT.into(), self.text.read().await.as_str().into());
+        nodes.insert(anyvalue::ANY_VALUE_MOUNT.into(), self.any_value.read().await.clone());
+        nodes.insert(mapnode::MAP_MOUNT.into(), self.map.value());
+        nodes.extend(self.typed.values());
+        nodes.insert(configtree::CONFIG_TREE_MOUNT.into(), self.config_tree.value());
+        nodes.insert(table::TABLE_MOUNT.into(), self.table.rows());
+        nodes.insert(counter::COUNTER_MOUNT.into(), self.counter.value().into());
+        nodes.insert(fault::FAULT_SIM_MOUNT.into(), self.fault_sim.value());
+        nodes.insert(payload::LOAD_MOUNT.into(), self.adaptive_payload.load().into());
+        nodes.insert(alarms::ALARMS_MOUNT.into(), self.alarms.value());
+        nodes.insert(sim::SIM_CLOCK_MOUNT.into(), self.sim.clock_value());
+        nodes.insert(sim::SIM_RAMP_MOUNT.into(), self.sim.ramp_value());
+        if let Some(sensors) = &self.sensors {
+            nodes.extend(sensors.values());
+        }
+        nodes.extend(self.synthetic.values());
+        let mut map = Map::new();
+        map.insert("nodes".into(), nodes.into());
+        map.insert("tasks".into(), self.tasks.names());
+        map.insert("benchEmitter".into(), self.bench_emitter.value());
+        map.insert("faults".into(), self.faults.value());
+        map.insert("metrics".into(), self.metrics.value());
+        map.insert("errors".into(), self.error_counts.value());
+        map.insert("connection".into(), self.connection.value());
+        map.insert("config".into(), self.config_value());
+        map.into()
+    }
+
+    /// Runtime configuration reported by `status/config`.
+    fn config_value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("clockOffsetMs".into(), self.clock.offset_ms().into());
+        map.insert("textTear".into(), self.text_tear_delay.is_some().into());
+        map.insert("numberStep".into(), self.number_step.map(RpcValue::from).unwrap_or_default());
+        map.into()
+    }
+}
+
+/// Rounds `value` to the nearest multiple of `step`, ties away from zero. Near the ends
+/// of the i32 range the result saturates to the outermost multiple, so it stays on the grid.
+fn quantize(value: i32, step: i32) -> i32 {
+    let (value, step) = (value as i64, step as i64);
+    let quantized = value.signum() * ((value.abs() + step / 2) / step) * step;
+    let (min, max) = (i32::MIN as i64 / step * step, i32::MAX as i64 / step * step);
+    quantized.clamp(min, max) as i32
+}
+
+async fn handle_client_events(app_state: AppState<State>, url: String, client_cmd_tx: ClientCommandSender, mut client_evt_rx: ClientEventsReceiver, hooks: hooks::ConnectionHooks) {
+    while let Ok(event) = client_evt_rx.wait_for_event().await {
+        match event {
+            ClientEvent::Connected(_) => {
+                app_state.connection.attach(client_cmd_tx.clone());
+                app_state.connection.connected();
+                app_state.stats.on_connected();
+                let mount = app_state.client_config.lock().unwrap().mount.clone();
+                if let Some(mount) = mount {
+                    connection::verify_mount(&app_state, &client_cmd_tx, &mount, app_state.mount_reject).await;
+                }
+                app_state.transport.refresh(&url).await;
+                lifecycle::connected(&app_state, &client_cmd_tx);
+                signals::on_connected(&app_state, &client_cmd_tx).await;
+                subscriptions::on_connected(&app_state, &client_cmd_tx).await;
+                mountconflict::on_connected(&app_state);
+                selftest::on_connected(&app_state, &client_cmd_tx);
+                hooks.connected();
+            }
+            ClientEvent::Disconnected => {
+                app_state.connection.detach();
+                signals::on_disconnected(&app_state);
+                lifecycle::disconnected(&app_state);
+                mountconflict::on_disconnected(&app_state);
+                hooks.disconnected();
+            }
+        }
+    }
+}
+
+const NUMBER_MOUNT: &str = "state/number";
+const TEXT_MOUNT: &str = "state/text";
+const CONFIG_MOUNT: &str = "status/config";
+const FEATURES_MOUNT: &str = "status/features";
+
+/// Cargo features and build configuration recorded by `build.rs`.
+fn build_features() -> RpcValue {
+    let features: Vec<RpcValue> = env!("BUILD_FEATURES").split(',')
+        .filter(|feature| !feature.is_empty())
+        .map(RpcValue::from)
+        .collect();
+    let mut map = Map::new();
+    map.insert("features".into(), features.into());
+    map.insert("profile".into(), env!("BUILD_PROFILE").into());
+    map.insert("target".into(), env!("BUILD_TARGET").into());
+    map.insert("version".into(), env!("CARGO_PKG_VERSION").into());
+    map.into()
+}
+/// Builds the nodes of the device tree, every client instance needs its own set.
+fn device_nodes(state: &State) -> Vec<(String, ClientNode<State>)> {
+    let number_node = device_node!{
+        number_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Int"] => {
+                    Some(Ok(app_state.number.load(Ordering::SeqCst).into()))
+            }
+            "set" [IsSetter, Write, "Int", "Null"] (param: i32) => {
+                Some(app_state.set_number(&client_cmd_tx, param).map(|_| ().into()))
+            }
+            "constraints" [None, Read, "Null", "Map"] => {
+                Some(Ok(app_state.number_constraints()))
+            }
+       }
+    };
+    let text_node = device_node!{
+        text_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "String"] => {
+                let s = &*app_state.text.read().await;
+                Some(Ok(s.into()))
+            }
+            "set" [IsSetter, Write, "String", "Null"] (param: String) => {
+                Some(State::set_text(&app_state, &client_cmd_tx, param).await.map(|_| ().into()))
+            }
+       }
+    };
+
+    let timestamp_node = device_node!{
+        timestamp_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "DateTime"] => {
+                Some(Ok(app_state.typed.get(typednodes::TIMESTAMP_MOUNT)))
+            }
+            "set" [IsSetter, Write, "DateTime", "Null"] => {
+                Some(app_state.set_typed(&client_cmd_tx, typednodes::TIMESTAMP_MOUNT, request.param().cloned().unwrap_or_default()))
+            }
+       }
+    };
+    let decimal_node = device_node!{
+        decimal_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Decimal"] => {
+                Some(Ok(app_state.typed.get(typednodes::DECIMAL_MOUNT)))
+            }
+            "set" [IsSetter, Write, "Decimal", "Null"] => {
+                Some(app_state.set_typed(&client_cmd_tx, typednodes::DECIMAL_MOUNT, request.param().cloned().unwrap_or_default()))
+            }
+       }
+    };
+    let int_list_node = device_node!{
+        int_list_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "List"] => {
+                Some(Ok(app_state.typed.get(typednodes::INT_LIST_MOUNT)))
+            }
+            "set" [IsSetter, Write, "List", "Null"] => {
+                Some(app_state.set_typed(&client_cmd_tx, typednodes::INT_LIST_MOUNT, request.param().cloned().unwrap_or_default()))
+            }
+       }
+    };
+    let mode_node = device_node!{
+        mode_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "String"] => {
+                Some(Ok(app_state.typed.get(typednodes::MODE_MOUNT)))
+            }
+            "set" [IsSetter, Write, "String", "Null"] => {
+                Some(app_state.set_typed(&client_cmd_tx, typednodes::MODE_MOUNT, request.param().cloned().unwrap_or_default()))
+            }
+            "values" [None, Read, "Null", "List"] => {
+                Some(Ok(app_state.typed.modes()))
+            }
+       }
+    };
+    let any_value_node = device_node!{
+        any_value_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "RpcValue"] => {
+                Some(Ok(app_state.any_value.read().await.clone()))
+            }
+            "set" [IsSetter, Write, "RpcValue", "Null"] => {
+                Some(app_state.set_any_value(&client_cmd_tx, request.param().cloned().unwrap_or_default()).await.map(|_| ().into()))
+            }
+            "typeName" [None, Read, "Null", "String"] => {
+                Some(Ok(anyvalue::type_name(&*app_state.any_value.read().await).into()))
+            }
+       }
+    };
+    let map_node = device_node!{
+        map_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.map.value()))
+            }
+            "set" [IsSetter, Write, "Map", "Null"] => {
+                Some(app_state.set_map(&client_cmd_tx, |map, commit| map.set(request.param(), commit)))
+            }
+            "setKey" [None, Write, "[String, RpcValue]", "Null"] => {
+                Some(app_state.set_map(&client_cmd_tx, |map, commit| map.set_key(request.param(), commit)))
+            }
+       }
+    };
+    let config_tree_node = device_node!{
+        config_tree_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null|List", "RpcValue"] => {
+                Some(app_state.config_tree.get(request.param()))
+            }
+            "set" [IsSetter, Write, "[List, RpcValue]", "Null"] => {
+                Some(app_state.set_config_tree(&client_cmd_tx, request.param()))
+            }
+       }
+    };
+    let table_node = device_node!{
+        table_node_handler(request, client_cmd_tx, app_state: State) {
+            "rows" [None, Read, "Null", "List"] => {
+                Some(Ok(app_state.table.rows()))
+            }
+            "appendRow" [None, Write, "Map", "Int"] => {
+                Some(app_state.change_table(&client_cmd_tx, |table, commit| {
+                    table.append_row(request.param(), commit).map(|(id, path, value)| (id.into(), Some((path, value))))
+                }))
+            }
+            "updateRow" [None, Write, "[Int, Map]", "Null"] => {
+                Some(app_state.change_table(&client_cmd_tx, |table, commit| table.update_row(request.param(), commit).map(|changed| (().into(), changed))))
+            }
+            "deleteRow" [None, Write, "Int", "Null"] (param: i64) => {
+                Some(app_state.change_table(&client_cmd_tx, |table, commit| {
+                    table.delete_row(param, commit).map(|path| (().into(), Some((path, RpcValue::null()))))
+                }))
+            }
+       }
+    };
+
+    let bench_emitter_node = device_node!{
+        bench_emitter_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.bench_emitter.value()))
+            }
+            "start" [None, Command, "[Int, Int]", "Int"] => {
+                match bench::parse_start_param(request.param()) {
+                    Ok((rate, duration_ms)) => {
+                        let mut resp = request.prepare_response().unwrap_or_default();
+                        runtime::spawn(async move {
+                            match bench::run_emitter(app_state, client_cmd_tx.clone(), rate, duration_ms).await {
+                                Ok(emitted) => resp.set_result((emitted as i64).into()),
+                                Err(err) => resp.set_error(err),
+                            };
+                            let _ = client_cmd_tx.send_message(resp);
+                        });
+                        None
+                    }
+                    Err(err) => Some(Err(err)),
+                }
+            }
+            "stop" [None, Command, "Null", "Bool"] => {
+                Some(Ok(app_state.bench_emitter.stop().into()))
+            }
+       }
+    };
+
+    let sigstorm_node = device_node!{
+        sigstorm_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.sigstorm.value()))
+            }
+            "start" [None, Command, "[Int, Int, Int]", "Int"] => {
+                match bench::parse_storm_param(request.param()) {
+                    Ok((rate, count, payload_size)) => {
+                        let mut resp = request.prepare_response().unwrap_or_default();
+                        runtime::spawn(async move {
+                            match bench::run_storm(app_state, client_cmd_tx.clone(), rate, count, payload_size).await {
+                                Ok(sent) => resp.set_result((sent as i64).into()),
+                                Err(err) => resp.set_error(err),
+                            };
+                            let _ = client_cmd_tx.send_message(resp);
+                        });
+                        None
+                    }
+                    Err(err) => Some(Err(err)),
+                }
+            }
+            "stop" [None, Command, "Null", "Bool"] => {
+                Some(Ok(app_state.sigstorm.stop().into()))
+            }
+       }
+    };
+
+    let access_node = device_node!{
+        access_node_handler(request, client_cmd_tx, app_state: State) {
+            "browse" [None, Browse, "Null", "Map"] => {
+                Some(Ok(access::grant(&request, "Browse")))
+            }
+            "read" [None, Read, "Null", "Map"] => {
+                Some(Ok(access::grant(&request, "Read")))
+            }
+            "write" [None, Write, "Null", "Map"] => {
+                Some(Ok(access::grant(&request, "Write")))
+            }
+            "command" [None, Command, "Null", "Map"] => {
+                Some(Ok(access::grant(&request, "Command")))
+            }
+            "config" [None, Config, "Null", "Map"] => {
+                Some(Ok(access::grant(&request, "Config")))
+            }
+            "service" [None, Service, "Null", "Map"] => {
+                Some(Ok(access::grant(&request, "Service")))
+            }
+            "superService" [None, SuperService, "Null", "Map"] => {
+                Some(Ok(access::grant(&request, "SuperService")))
+            }
+            "devel" [None, Devel, "Null", "Map"] => {
+                Some(Ok(access::grant(&request, "Devel")))
+            }
+       }
+    };
+    let sim_clock_node = device_node!{
+        sim_clock_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Int"] => {
+                Some(Ok(app_state.sim.clock_value()))
+            }
+       }
+    };
+    let sim_ramp_node = device_node!{
+        sim_ramp_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Double"] => {
+                Some(Ok(app_state.sim.ramp_value()))
+            }
+       }
+    };
+    let load_generator_node = device_node!{
+        load_generator_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.load_generator.value()))
+            }
+            "allocate" [None, Command, "Int", "Map"] (param: i64) => {
+                Some(app_state.load_generator.allocate(param))
+            }
+            "free" [None, Command, "Null", "Int"] => {
+                Some(Ok((app_state.load_generator.free() as i64).into()))
+            }
+            "cpuBurn" [None, Command, "[Int, Int]", "Map"] => {
+                match loadgen::parse_burn_param(request.param()) {
+                    Ok((threads, duration)) => {
+                        let mut resp = request.prepare_response().unwrap_or_default();
+                        runtime::spawn(async move {
+                            resp.set_result(app_state.load_generator.cpu_burn(threads, duration).await);
+                            let _ = client_cmd_tx.send_message(resp);
+                        });
+                        None
+                    }
+                    Err(err) => Some(Err(err)),
+                }
+            }
+       }
+    };
+    let echo_node = device_node!{
+        echo_node_handler(request, client_cmd_tx, app_state: State) {
+            "echo" [None, Read, "RpcValue", "RpcValue"] => {
+                let param = request.param().cloned().unwrap_or_default();
+                let delay = app_state.echo.delay();
+                if delay.is_zero() {
+                    return Some(Ok(param));
+                }
+                let mut resp = request.prepare_response().unwrap_or_default();
+                runtime::spawn(async move {
+                    runtime::sleep(delay).await;
+                    resp.set_result(param);
+                    let _ = client_cmd_tx.send_message(resp);
+                });
+                None
+            }
+       }
+    };
+    let echo_delay_node = device_node!{
+        echo_delay_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Int"] => {
+                Some(Ok(app_state.echo.delay_value()))
+            }
+            "set" [IsSetter, Write, "Int", "Null"] (param: i64) => {
+                match app_state.echo.set_delay(param) {
+                    Ok(changed) => {
+                        if let Some(value) = changed {
+                            signals::emit_chng(&app_state, &client_cmd_tx, echo::ECHO_DELAY_MOUNT, value);
+                        }
+                        Some(Ok(().into()))
+                    }
+                    Err(err) => Some(Err(err)),
+                }
+            }
+       }
+    };
+
+    let bench_burst_node = device_node!{
+        bench_burst_node_handler(request, client_cmd_tx, app_state: State) {
+            "fire" [None, Command, "Int", "Double"] (param: i32) => {
+                Some(bench::fire_burst(&app_state, &client_cmd_tx, param).await.map(RpcValue::from))
+            }
+       }
+    };
+
+    let counter_node = device_node!{
+        counter_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "UInt"] => {
+                Some(Ok(app_state.counter.value().into()))
+            }
+            "inc" [None, Write, "Null", "UInt"] => {
+                Some(Ok(counter::inc(&app_state, &client_cmd_tx).into()))
+            }
+       }
+    };
+    let fault_sim_node = device_node!{
+        fault_sim_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.fault_sim.value()))
+            }
+            "trigger" [None, Command, "Int", "Null"] (param: i32) => {
+                if param < 0 {
+                    return Some(Err(RpcError::new(RpcErrorCode::InvalidParam, "durationMs must not be negative")));
+                }
+                fault::trigger(app_state, client_cmd_tx, Duration::from_millis(param as u64));
+                Some(Ok(().into()))
+            }
+            "recover" [None, Command, "Int", "Null"] (param: i32) => {
+                if param < 0 {
+                    return Some(Err(RpcError::new(RpcErrorCode::InvalidParam, "durationMs must not be negative")));
+                }
+                fault::recover(app_state, client_cmd_tx, Duration::from_millis(param as u64));
+                Some(Ok(().into()))
+            }
+       }
+    };
+
+    let control_node = device_node!{
+        control_node_handler(request, client_cmd_tx, app_state: State) {
+            "setMany" [None, Write, "Map", "Map"] => {
+                Some(control::set_many(&app_state, &client_cmd_tx, request.param()).await)
+            }
+            "corruptResponses" [None, Command, "Bool", "Null"] (param: bool) => {
+                Some(app_state.faults.corruption.set_active(param).map(|_| ().into()))
+            }
+            "cancelTask" [None, Command, "String", "Null"] (param: String) => {
+                Some(app_state.tasks.cancel(&param).await.map(|_| ().into()))
+            }
+            "sendHeartbeat" [None, Command, "Null", "Bool"] => {
+                Some(connection::send_heartbeat(&client_cmd_tx).await.map(|_| true.into()))
+            }
+            "suspendHeartbeat" [None, Command, "Bool", "Null"] (param: bool) => {
+                if app_state.connection.suspend_heartbeat(param) {
+                    lifecycle::request_reconnect(&app_state);
+                }
+                Some(Ok(().into()))
+            }
+            "setLoad" [None, Write, "Int", "Null"] (param: i32) => {
+                Some(app_state.adaptive_payload.set_load(param).map(|_| ().into()))
+            }
+            "leak" [None, Command, "Int", "Int"] (param: i32) => {
+                Some(app_state.faults.leak.leak(param).map(|total| (total as i64).into()))
+            }
+            "releaseLeak" [None, Command, "Null", "Int"] => {
+                Some(Ok((app_state.faults.leak.release() as i64).into()))
+            }
+            "startRecording" [None, Command, "Null", "Null"] => {
+                app_state.recording.start();
+                Some(Ok(().into()))
+            }
+            "stopRecording" [None, Command, "Null", "Int"] => {
+                Some(Ok((app_state.recording.stop() as i64).into()))
+            }
+            "exportRecording" [None, Read, "Null", "List"] => {
+                Some(app_state.recording.export().await)
+            }
+            "partition" [None, Command, "Bool|[Bool, Int]", "Null"] => {
+                Some(app_state.faults.partition.set(request.param()).map(|_| ().into()))
+            }
+            "encodingFault" [None, Command, "String|[String, Int]", "Null"] => {
+                Some(app_state.faults.encoding.set(request.param()).map(|_| ().into()))
+            }
+            "setCapacity" [None, Command, "Int", "Null"] (param: i64) => {
+                Some(app_state.faults.capacity.set(param).map(|_| ().into()))
+            }
+            "clearCapacity" [None, Command, "Null", "Null"] => {
+                app_state.faults.capacity.clear();
+                Some(Ok(().into()))
+            }
+            "playSequence" [None, Command, "[String, List, Int]", "Int"] => {
+                let (path, values, interval) = match control::parse_sequence(request.param()) {
+                    Ok(sequence) => sequence,
+                    Err(err) => return Some(Err(err)),
+                };
+                let response = request.prepare_response().unwrap_or_default();
+                match control::play_sequence(app_state, client_cmd_tx, response, path, values, interval) {
+                    Ok(()) => None,
+                    Err(err) => Some(Err(err)),
+                }
+            }
+            "accelerate" [None, Command, "[String, Int, Int, Int]", "Int"] => {
+                let (path, start, end, steps) = match control::parse_acceleration(request.param()) {
+                    Ok(acceleration) => acceleration,
+                    Err(err) => return Some(Err(err)),
+                };
+                let response = request.prepare_response().unwrap_or_default();
+                match control::accelerate(app_state, client_cmd_tx, response, path, start, end, steps) {
+                    Ok(()) => None,
+                    Err(err) => Some(Err(err)),
+                }
+            }
+            "replayScenario" [None, Command, "String", "Int"] (param: String) => {
+                Some(control::replay_scenario(&app_state, client_cmd_tx, &param).await)
+            }
+            "setNodeFormat" [None, Command, "[String, String]", "Null"] => {
+                Some(app_state.node_formats.set(request.param()).map(|_| ().into()))
+            }
+            "call" [None, Command, "Map", "Map"] => {
+                Some(control::call(&client_cmd_tx, request.param()).await)
+            }
+            "setUnavailable" [None, Command, "[String, Bool]", "Null"] => {
+                Some(app_state.faults.availability.set(request.param()).map(|_| ().into()))
+            }
+            "snapshot" [None, Read, "Null", "Map"] => {
+                Some(Ok(app_state.dump().await))
+            }
+            "softReboot" [None, Command, "Null", "Null"] => {
+                reboot::soft_reboot(&app_state, &client_cmd_tx).await;
+                Some(Ok(().into()))
+            }
+            "reconnect" [None, Command, "Null", "Bool"] => {
+                Some(Ok(lifecycle::request_reconnect(&app_state).into()))
+            }
+       }
+    };
+
+    let datetime_node = device_node!{
+        datetime_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "DateTime"] => {
+                Some(Ok(app_state.clock.now().into()))
+            }
+       }
+    };
+    let time_node = device_node!{
+        time_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.clock.value()))
+            }
+       }
+    };
+    let config_node = device_node!{
+        config_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.config_value()))
+            }
+       }
+    };
+    let transport_node = device_node!{
+        transport_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.transport.value()))
+            }
+       }
+    };
+    let connection_node = device_node!{
+        connection_node_handler(request, client_cmd_tx, app_state: State) {
+            "disconnect" [None, Command, "Null|Int", "Bool"] => {
+                let offline_ms = request.param().map_or(0, RpcValue::as_int);
+                if offline_ms < 0 {
+                    return Some(Err(RpcError::new(RpcErrorCode::InvalidParam, "offlineMs must not be negative")));
+                }
+                Some(Ok(connection::disconnect(&app_state, Duration::from_millis(offline_ms as u64)).into()))
+            }
+            "reconnect" [None, Command, "Null", "Bool"] => {
+                Some(Ok(lifecycle::request_reconnect(&app_state).into()))
+            }
+            "flap" [None, Command, "Int", "Null"] (param: i64) => {
+                if param < 0 {
+                    return Some(Err(RpcError::new(RpcErrorCode::InvalidParam, "periodMs must not be negative")));
+                }
+                connection::set_flap(&app_state, Duration::from_millis(param as u64)).await;
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let sigtypes_node = device_node!{
+        sigtypes_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(sigtypes::value()))
+            }
+            "emit" [None, Command, "Null|String", "Int"] => {
+                Some(sigtypes::emit(&app_state, &client_cmd_tx, request.param()))
+            }
+       }
+    };
+    let backpressure_node = device_node!{
+        backpressure_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.backpressure.value()))
+            }
+            "stall" [None, Command, "Int", "Null"] (param: i64) => {
+                if param < 0 {
+                    return Some(Err(RpcError::new(RpcErrorCode::InvalidParam, "durationMs must not be negative")));
+                }
+                Some(app_state.backpressure.stall(Duration::from_millis(param as u64)).map(|_| ().into()))
+            }
+       }
+    };
+    let login_probes_node = device_node!{
+        login_probes_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "List"] => {
+                Some(Ok(app_state.login_probes.value()))
+            }
+            "run" [None, Command, "String", "Map"] => {
+                Some(loginprobe::run(&app_state, request.param()).await)
+            }
+       }
+    };
+    let mount_conflicts_node = device_node!{
+        mount_conflicts_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "List"] => {
+                Some(Ok(app_state.mount_conflicts.value()))
+            }
+            "clear" [None, Command, "Null", "Null"] => {
+                app_state.mount_conflicts.clear();
+                Some(Ok(().into()))
+            }
+            "start" [None, Command, "Null|Int", "Null"] => {
+                let delay_ms = request.param().map_or(0, RpcValue::as_int);
+                if delay_ms < 0 {
+                    return Some(Err(RpcError::new(RpcErrorCode::InvalidParam, "delayMs must not be negative")));
+                }
+                Some(mountconflict::start(&app_state, Duration::from_millis(delay_ms as u64)).map(|_| ().into()))
+            }
+       }
+    };
+    let scenario_node = device_node!{
+        scenario_node_handler(request, client_cmd_tx, app_state: State) {
+            "status" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.scenario.value()))
+            }
+            "load" [None, Command, "String", "Int"] (param: String) => {
+                Some(scenario::load(&app_state, &param).await)
+            }
+            "start" [None, Command, "Null", "Null"] => {
+                Some(scenario::start(&app_state).map(|_| ().into()))
+            }
+            "pause" [None, Command, "Null", "Null"] => {
+                Some(scenario::pause(&app_state).await.map(|_| ().into()))
+            }
+       }
+    };
+    let heartbeat_node = device_node!{
+        heartbeat_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.heartbeat.value()))
+            }
+            "set" [IsSetter, Write, "Map", "Null"] => {
+                match app_state.heartbeat.set(request.param()) {
+                    Ok(reconnect) => {
+                        if reconnect {
+                            lifecycle::request_reconnect(&app_state);
+                        }
+                        Some(Ok(().into()))
+                    }
+                    Err(err) => Some(Err(err)),
+                }
+            }
+       }
+    };
+    let subscriptions_node = device_node!{
+        subscriptions_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "List"] => {
+                Some(Ok(app_state.subscriptions.patterns()))
+            }
+            "add" [None, Command, "String", "Bool"] (param: String) => {
+                Some(subscriptions::add(&app_state, &client_cmd_tx, param).await.map(RpcValue::from))
+            }
+            "remove" [None, Command, "String", "Bool"] (param: String) => {
+                Some(Ok(subscriptions::remove(&app_state, &param).await.into()))
+            }
+       }
+    };
+    let signal_history_node = device_node!{
+        signal_history_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "List"] => {
+                Some(Ok(app_state.subscriptions.history()))
+            }
+            "clear" [None, Write, "Null", "Null"] => {
+                app_state.subscriptions.clear_history();
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let drift_node = device_node!{
+        drift_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.subscriptions.drift()))
+            }
+            "reset" [None, Write, "Null", "Null"] => {
+                app_state.subscriptions.reset_drift();
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let reconnects_node = device_node!{
+        reconnects_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.connection.value()))
+            }
+       }
+    };
+    let blob_node = device_node!{
+        blob_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Blob"] => {
+                Some(app_state.blob.value())
+            }
+            "size" [None, Write, "Int", "Null"] (param: i64) => {
+                Some(app_state.blob.set_size(param).map(|_| ().into()))
+            }
+            "stream" [None, Read, "Null", "Int"] => {
+                Some(Ok(app_state.blob.stream(&client_cmd_tx)))
+            }
+       }
+    };
+    let adaptive_payload_node = device_node!{
+        adaptive_payload_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Blob"] => {
+                Some(Ok(app_state.adaptive_payload.value()))
+            }
+       }
+    };
+    let load_node = device_node!{
+        load_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Int"] => {
+                Some(Ok(app_state.adaptive_payload.load().into()))
+            }
+       }
+    };
+    let long_op_node = device_node!{
+        long_op_node_handler(request, client_cmd_tx, app_state: State) {
+            "start" [None, Command, "Int", "Int"] (param: i32) => {
+                if param < 0 {
+                    return Some(Err(RpcError::new(RpcErrorCode::InvalidParam, "durationMs must not be negative")));
+                }
+                let id = longop::start(app_state, client_cmd_tx, Duration::from_millis(param as u64));
+                Some(Ok((id as i64).into()))
+            }
+            "progress" [None, Read, "Int", "Int"] (param: i64) => {
+                Some(app_state.long_ops.progress(param))
+            }
+       }
+    };
+    let test_long_op_node = device_node!{
+        test_long_op_node_handler(request, client_cmd_tx, app_state: State) {
+            "run" [None, Command, "Int", "Map"] (param: i64) => {
+                if param < 0 {
+                    return Some(Err(RpcError::new(RpcErrorCode::InvalidParam, "durationMs must not be negative")));
+                }
+                Some(Ok(longop::run_detached(app_state, client_cmd_tx, Duration::from_millis(param as u64))))
+            }
+            "runDeferred" [None, Command, "Int", "Map"] (param: i64) => {
+                if param < 0 {
+                    return Some(Err(RpcError::new(RpcErrorCode::InvalidParam, "durationMs must not be negative")));
+                }
+                if longop::abort(&app_state, &request).await {
+                    return None;
+                }
+                longop::run_deferred(app_state, client_cmd_tx, &request, Duration::from_millis(param as u64));
+                None
+            }
+            "cancel" [None, Command, "Int", "Bool"] (param: i64) => {
+                Some(Ok(longop::cancel(&app_state, param).await.into()))
+            }
+            "progress" [None, Read, "Int", "Int"] (param: i64) => {
+                Some(app_state.long_ops.progress(param))
+            }
+       }
+    };
+    let tasks_node = device_node!{
+        tasks_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "List"] => {
+                Some(Ok(app_state.tasks.names()))
+            }
+       }
+    };
+    let features_node = device_node!{
+        features_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(build_features()))
+            }
+       }
+    };
+    let metrics_node = device_node!{
+        metrics_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.metrics.value()))
+            }
+            "export" [None, Read, "Null|String", "RpcValue"] => {
+                Some(app_state.metrics.export(request.param()))
+            }
+       }
+    };
+    let latency_histogram_node = device_node!{
+        latency_histogram_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.latency_histogram.value()))
+            }
+            "reset" [None, Write, "Null", "Null"] => {
+                app_state.latency_histogram.reset();
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let log_spec_node = device_node!{
+        log_spec_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "String"] => {
+                Some(Ok(logging::spec().into()))
+            }
+            "set" [IsSetter, Write, "String", "Null"] (param: String) => {
+                Some(logging::set_spec(&param)
+                    .map(|_| ().into())
+                    .map_err(|err| RpcError::new(RpcErrorCode::InvalidParam, &err)))
+            }
+       }
+    };
+    let resources_node = device_node!{
+        resources_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(metrics::resources(app_state.faults.leak.leaked_bytes())))
+            }
+       }
+    };
+    let stats_requests_node = device_node!{
+        stats_requests_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(stats::value(&app_state, stats::REQUESTS_MOUNT)))
+            }
+       }
+    };
+    let stats_signals_node = device_node!{
+        stats_signals_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Int"] => {
+                Some(Ok(stats::value(&app_state, stats::SIGNALS_MOUNT)))
+            }
+       }
+    };
+    let stats_reconnects_node = device_node!{
+        stats_reconnects_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Int"] => {
+                Some(Ok(stats::value(&app_state, stats::RECONNECTS_MOUNT)))
+            }
+       }
+    };
+    let stats_avg_latency_node = device_node!{
+        stats_avg_latency_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Double"] => {
+                Some(Ok(stats::value(&app_state, stats::AVG_LATENCY_MOUNT)))
+            }
+       }
+    };
+    let stats_rss_node = device_node!{
+        stats_rss_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Int"] => {
+                Some(Ok(stats::value(&app_state, stats::RSS_MOUNT)))
+            }
+       }
+    };
+    let request_rate_node = device_node!{
+        request_rate_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Double"] => {
+                Some(Ok(app_state.request_rate.value().into()))
+            }
+       }
+    };
+    let capacity_node = device_node!{
+        capacity_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Int"] => {
+                Some(Ok(app_state.faults.capacity.value()))
+            }
+       }
+    };
+    let faults_node = device_node!{
+        faults_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.faults.value()))
+            }
+            "clearAll" [None, Command, "Null", "Null"] => {
+                app_state.faults.clear_all();
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let method_stats_node = device_node!{
+        method_stats_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "List"] => {
+                Some(Ok(app_state.method_stats.value()))
+            }
+            "reset" [None, Write, "Null", "Null"] => {
+                app_state.method_stats.reset();
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let availability_node = device_node!{
+        availability_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.faults.availability.value()))
+            }
+       }
+    };
+    let reconnect_interval_node = device_node!{
+        reconnect_interval_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "String"] => {
+                Some(Ok(connection::reconnect_interval(&app_state)))
+            }
+            "set" [IsSetter, Write, "String", "Null"] (param: String) => {
+                Some(connection::set_reconnect_interval(&app_state, param).map(|_| ().into()))
+            }
+       }
+    };
+    let firmware_node = device_node!{
+        firmware_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "String"] => {
+                Some(Ok(app_state.firmware.version()))
+            }
+            "upgrade" [None, Command, "String", "Null"] (param: String) => {
+                Some(firmware::upgrade(&app_state, client_cmd_tx, param).map(|_| ().into()))
+            }
+       }
+    };
+    let node_formats_node = device_node!{
+        node_formats_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.node_formats.value()))
+            }
+       }
+    };
+    let nodes_node = device_node!{
+        nodes_node_handler(request, client_cmd_tx, app_state: State) {
+            "list" [None, Read, "Null", "List"] => {
+                Some(Ok(app_state.synthetic.list()))
+            }
+            "create" [None, Command, "Map", "Bool"] => {
+                Some(app_state.synthetic.create(request.param()).map(|change| {
+                    synthetic::announce(&app_state, &client_cmd_tx, change);
+                    true.into()
+                }))
+            }
+            "remove" [None, Command, "String", "Bool"] => {
+                Some(app_state.synthetic.remove(request.param()).map(|change| {
+                    synthetic::announce(&app_state, &client_cmd_tx, change);
+                    true.into()
+                }))
+            }
+       }
+    };
+    let test_fault_node = device_node!{
+        test_fault_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.faults.responses.value()))
+            }
+            "dropResponses" [None, Command, "Int", "Null"] (param: i32) => {
+                Some(app_state.faults.responses.drop_next(param).map(|_| ().into()))
+            }
+            "errorResponses" [None, Command, "[Int, Int, String]", "Null"] => {
+                Some(app_state.faults.responses.error_next(request.param()).map(|_| ().into()))
+            }
+            "malformResponses" [None, Command, "RpcValue", "Null"] => {
+                Some(app_state.faults.encoding.arm(request.param()).map(|_| ().into()))
+            }
+            "setErrorRate" [None, Command, "Map", "Null"] => {
+                Some(app_state.faults.error_rate.set(request.param()).map(|_| ().into()))
+            }
+            "clear" [None, Command, "Null", "Null"] => {
+                app_state.faults.responses.clear();
+                app_state.faults.encoding.clear();
+                app_state.faults.error_rate.clear();
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let journal_node = device_node!{
+        journal_node_handler(request, client_cmd_tx, app_state: State) {
+            "getLog" [None, Read, "Null|Map", "List"] => {
+                Some(app_state.journal.get_log(request.param()))
+            }
+            "clear" [None, Write, "Null", "Null"] => {
+                app_state.journal.clear();
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let history_node = device_node!{
+        history_node_handler(request, client_cmd_tx, app_state: State) {
+            "read" [None, Read, "[Int, Int]", "List"] => {
+                Some(app_state.history.read(request.param()))
+            }
+            "clear" [None, Command, "Null", "Null"] => {
+                app_state.history.clear();
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let session_node = device_node!{
+        session_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "String"] => {
+                Some(Ok(app_state.lifecycle.session_id()))
+            }
+       }
+    };
+    let app_node = device_node!{
+        app_node_handler(request, client_cmd_tx, app_state: State) {
+            "quit" [None, Command, "Null", "Null"] => {
+                lifecycle::quit();
+                Some(Ok(().into()))
+            }
+            "restart" [None, Command, "Null", "Null"] => {
+                lifecycle::restart();
+                Some(Ok(().into()))
+            }
+            "reloadConfig" [None, Command, "Null", "List"] => {
+                Some(configwatch::reload(&app_state).await)
+            }
+            "uptime" [None, Read, "Null", "Int"] => {
+                Some(Ok(app_state.clock.monotonic_ms().into()))
+            }
+       }
+    };
+    let alarms_node = device_node!{
+        alarms_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Map"] => {
+                Some(Ok(app_state.alarms.value()))
+            }
+            "raise" [None, Command, "[String, String]", "Null"] => {
+                if let Err(err) = app_state.alarms.raise(request.param(), app_state.clock.now()) {
+                    return Some(Err(err));
+                }
+                signals::emit_chng(&app_state, &client_cmd_tx, alarms::ALARMS_MOUNT, app_state.alarms.value());
+                Some(Ok(().into()))
+            }
+            "clear" [None, Command, "String", "Bool"] => {
+                let cleared = match app_state.alarms.clear(request.param()) {
+                    Ok(cleared) => cleared,
+                    Err(err) => return Some(Err(err)),
+                };
+                if cleared {
+                    signals::emit_chng(&app_state, &client_cmd_tx, alarms::ALARMS_MOUNT, app_state.alarms.value());
+                }
+                Some(Ok(cleared.into()))
+            }
+       }
+    };
+    let errors_node = device_node!{
+        errors_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "IMap"] => {
+                Some(Ok(app_state.error_counts.value()))
+            }
+            "reset" [None, Write, "Null", "Null"] => {
+                app_state.error_counts.reset();
+                Some(Ok(().into()))
+            }
+       }
+    };
+    let sensor_node = || device_node!{
+        sensor_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Double"] => {
+                let sensors = app_state.sensors.as_ref()
+                    .ok_or_else(|| RpcError::new(RpcErrorCode::MethodNotFound, "Sensor suite is disabled"));
+                Some(sensors.and_then(|sensors| sensors.value(request.shv_path().map(|path| app_state.base_path(path)))))
+            }
+            "unit" [None, Read, "Null", "String"] => {
+                Some(sensors::SensorSuite::unit(request.shv_path().map(|path| app_state.base_path(path))))
+            }
+       }
+    };
+    let mirror_node = || device_node!{
+        mirror_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "RpcValue"] => {
+                Some(mirror::get(&app_state, &client_cmd_tx, request.shv_path().map(|path| app_state.base_path(path))).await)
+            }
+            "set" [IsSetter, Write, "RpcValue", "Null"] => {
+                Some(mirror::set(&app_state, &client_cmd_tx, request.shv_path().map(|path| app_state.base_path(path)), request.param()).await)
+            }
+       }
+    };
+    let deep_tree_node = || device_node!{
+        deep_tree_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "Int"] => {
+                Some(app_state.deep_tree.get(request.shv_path().map(|path| app_state.base_path(path))))
+            }
+            "set" [IsSetter, Write, "Int", "Null"] (param: i32) => {
+                let path = request.shv_path().map(|path| app_state.base_path(path)).unwrap_or_default();
+                match app_state.deep_tree.set(Some(path), param) {
+                    Ok(changed) => {
+                        if let Some(value) = changed {
+                            signals::emit_chng(&app_state, &client_cmd_tx, path, value);
+                        }
+                        Some(Ok(().into()))
+                    }
+                    Err(err) => Some(Err(err)),
+                }
+            }
+       }
+    };
+    let script_node = || device_node!{
+        script_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "RpcValue"] => {
+                Some(scripting::handle(&app_state, &client_cmd_tx, &request, "get"))
+            }
+            "set" [IsSetter, Write, "RpcValue", "RpcValue"] => {
+                Some(scripting::handle(&app_state, &client_cmd_tx, &request, "set"))
+            }
+            "call" [None, Command, "RpcValue", "RpcValue"] => {
+                Some(scripting::handle(&app_state, &client_cmd_tx, &request, "call"))
+            }
+       }
+    };
+    let file_node = || device_node!{
+        file_node_handler(request, client_cmd_tx, app_state: State) {
+            "stat" [None, Read, "Null", "IMap"] => {
+                Some(app_state.files.stat(request.shv_path().map(|path| app_state.base_path(path))).await)
+            }
+            "size" [None, Read, "Null", "Int"] => {
+                Some(app_state.files.size(request.shv_path().map(|path| app_state.base_path(path))).await)
+            }
+            "crc" [None, Read, "Null|[Int, Int]", "UInt"] => {
+                Some(app_state.files.crc(request.shv_path().map(|path| app_state.base_path(path)), request.param()).await)
+            }
+            "read" [None, Read, "[Int, Int]", "Blob"] => {
+                Some(app_state.files.read(request.shv_path().map(|path| app_state.base_path(path)), request.param()).await)
+            }
+            "write" [None, Write, "[Int, Blob]", "Null"] => {
+                Some(app_state.files.write(request.shv_path().map(|path| app_state.base_path(path)), request.param()).await)
+            }
+            "append" [None, Write, "Blob", "Null"] => {
+                Some(app_state.files.append(request.shv_path().map(|path| app_state.base_path(path)), request.param()).await)
+            }
+       }
+    };
+    let ls_leaf_node = || device_node!{
+        ls_leaf_node_handler(request, client_cmd_tx, app_state: State) {
+            "get" [IsGetter, Read, "Null", "String"] => {
+                let path = request.shv_path().map(|path| app_state.base_path(path)).unwrap_or_default();
+                Some(Ok(lsanomaly::leaf_name(path).into()))
+            }
+       }
+    };
+
+    let mut nodes = vec![
+        (NUMBER_MOUNT.to_string(), number_node),
+        (TEXT_MOUNT.to_string(), text_node),
+        (anyvalue::ANY_VALUE_MOUNT.to_string(), any_value_node),
+        (typednodes::TIMESTAMP_MOUNT.to_string(), timestamp_node),
+        (typednodes::DECIMAL_MOUNT.to_string(), decimal_node),
+        (typednodes::INT_LIST_MOUNT.to_string(), int_list_node),
+        (typednodes::MODE_MOUNT.to_string(), mode_node),
+        (mapnode::MAP_MOUNT.to_string(), map_node),
+        (configtree::CONFIG_TREE_MOUNT.to_string(), config_tree_node),
+        (table::TABLE_MOUNT.to_string(), table_node),
+        (counter::COUNTER_MOUNT.to_string(), counter_node),
+        (fault::FAULT_SIM_MOUNT.to_string(), fault_sim_node),
+        (bench::EMITTER_MOUNT.to_string(), bench_emitter_node),
+        (bench::BURST_MOUNT.to_string(), bench_burst_node),
+        (bench::SIGSTORM_MOUNT.to_string(), sigstorm_node),
+        (access::ACCESS_MOUNT.to_string(), access_node),
+        (loadgen::LOAD_GEN_MOUNT.to_string(), load_generator_node),
+        (sigtypes::SIGTYPES_MOUNT.to_string(), sigtypes_node),
+        (loginprobe::LOGIN_PROBE_MOUNT.to_string(), login_probes_node),
+        (sim::SIM_CLOCK_MOUNT.to_string(), sim_clock_node),
+        (sim::SIM_RAMP_MOUNT.to_string(), sim_ramp_node),
+        (echo::ECHO_MOUNT.to_string(), echo_node),
+        (echo::ECHO_DELAY_MOUNT.to_string(), echo_delay_node),
+        (control::CONTROL_MOUNT.to_string(), control_node),
+        (metrics::METRICS_MOUNT.to_string(), metrics_node),
+        (metrics::LATENCY_HISTOGRAM_MOUNT.to_string(), latency_histogram_node),
+        (metrics::ERRORS_MOUNT.to_string(), errors_node),
+        (faults::CAPACITY_MOUNT.to_string(), capacity_node),
+        (faults::FAULTS_MOUNT.to_string(), faults_node),
+        (faults::AVAILABILITY_MOUNT.to_string(), availability_node),
+        (faults::TEST_FAULT_MOUNT.to_string(), test_fault_node),
+        (metrics::REQUEST_RATE_MOUNT.to_string(), request_rate_node),
+        (metrics::METHOD_STATS_MOUNT.to_string(), method_stats_node),
+        (metrics::RESOURCES_MOUNT.to_string(), resources_node),
+        (stats::REQUESTS_MOUNT.to_string(), stats_requests_node),
+        (stats::SIGNALS_MOUNT.to_string(), stats_signals_node),
+        (stats::RECONNECTS_MOUNT.to_string(), stats_reconnects_node),
+        (stats::AVG_LATENCY_MOUNT.to_string(), stats_avg_latency_node),
+        (stats::RSS_MOUNT.to_string(), stats_rss_node),
+        (logging::LOG_SPEC_MOUNT.to_string(), log_spec_node),
+        (clock::DATETIME_MOUNT.to_string(), datetime_node),
+        (clock::TIME_MOUNT.to_string(), time_node),
+        (CONFIG_MOUNT.to_string(), config_node),
+        (FEATURES_MOUNT.to_string(), features_node),
+        (tasks::TASKS_MOUNT.to_string(), tasks_node),
+        (payload::ADAPTIVE_PAYLOAD_MOUNT.to_string(), adaptive_payload_node),
+        (payload::LOAD_MOUNT.to_string(), load_node),
+        (payload::BLOB_MOUNT.to_string(), blob_node),
+        (longop::LONG_OP_MOUNT.to_string(), long_op_node),
+        (longop::TEST_LONG_OP_MOUNT.to_string(), test_long_op_node),
+        (transport::TRANSPORT_MOUNT.to_string(), transport_node),
+        (connection::RECONNECTS_MOUNT.to_string(), reconnects_node),
+        (connection::RECONNECT_INTERVAL_MOUNT.to_string(), reconnect_interval_node),
+        (connection::CONNECTION_MOUNT.to_string(), connection_node),
+        (scenario::SCENARIO_MOUNT.to_string(), scenario_node),
+        (backpressure::BACKPRESSURE_MOUNT.to_string(), backpressure_node),
+        (heartbeat::HEARTBEAT_MOUNT.to_string(), heartbeat_node),
+        (subscriptions::SUBSCRIPTIONS_MOUNT.to_string(), subscriptions_node),
+        (subscriptions::SIGNAL_HISTORY_MOUNT.to_string(), signal_history_node),
+        (subscriptions::DRIFT_MOUNT.to_string(), drift_node),
+        (lifecycle::SESSION_MOUNT.to_string(), session_node),
+        (lifecycle::APP_MOUNT.to_string(), app_node),
+        (history::HISTORY_MOUNT.to_string(), history_node),
+        (mountconflict::MOUNT_CONFLICTS_MOUNT.to_string(), mount_conflicts_node),
+        (journal::JOURNAL_MOUNT.to_string(), journal_node),
+        (alarms::ALARMS_MOUNT.to_string(), alarms_node),
+        (nodeformat::NODE_FORMATS_MOUNT.to_string(), node_formats_node),
+        (synthetic::NODES_MOUNT.to_string(), nodes_node),
+        (firmware::FIRMWARE_MOUNT.to_string(), firmware_node),
+    ];
+    if state.sensors.is_some() {
+        nodes.extend(sensors::SENSORS.iter().map(|sensor| (sensor.path.to_string(), sensor_node())));
+    }
+    nodes.extend(state.mirrors.local_paths().map(|path| (path.clone(), mirror_node())));
+    nodes.extend(state.deep_tree.paths().into_iter().map(|path| (path, deep_tree_node())));
+    nodes.extend(state.scripts.paths().map(|path| (path.clone(), script_node())));
+    nodes.extend(state.files.paths().into_iter().map(|path| (path, file_node())));
+    nodes.extend(state.ls_anomalies.paths().into_iter().map(|path| (path, ls_leaf_node())));
+    nodes.extend(state.custom_nodes.iter().map(|(path, factory)| (path.clone(), factory(state))));
+    nodes
+}
+
+type NodeFactory = Box<dyn Fn(&State) -> ClientNode<State> + Send + Sync>;
+
+/// Why a device stopped with an error, the binary maps it to its exit code.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid command line, `--help` and `--version` end up here as well.
+    Args(clap::Error),
+    /// Invalid option, config file or broker URL.
+    Config(String),
+    /// The broker did not mount the device and `--mount-reject fail` is set.
+    MountRejected(String),
+    /// `--max-reconnect-attempts` connection attempts failed in a row.
+    ReconnectLimit(u64),
+    /// `--selftest` ran and at least one check failed.
+    SelfTestFailed,
+    /// The connection ended with an error and no reconnect is configured.
+    Connection(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Args(err) => write!(f, "{err}"),
+            Error::Config(msg) => write!(f, "{msg}"),
+            Error::MountRejected(msg) => write!(f, "Mount point rejected: {msg}"),
+            Error::ReconnectLimit(attempts) => write!(f, "Giving up after {attempts} failed connection attempts"),
+            Error::SelfTestFailed => write!(f, "Selftest failed"),
+            Error::Connection(msg) => write!(f, "Connection to broker failed: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Maps the error of an option to [`Error::Config`] reading "Invalid <what>: <error>".
+fn invalid<E: std::fmt::Display>(what: &'static str) -> impl FnOnce(E) -> Error {
+    move |err| Error::Config(format!("Invalid {what}: {err}"))
+}
+
+/// Builder of a device running inside the calling program.
+///
+/// The device is configured by the same options as the binary, it runs on the runtime
+/// selected by the `runtime-*` feature and stops when its connection ends for good.
+/// `--devices` is ignored, build one `TestingDevice` per device instead.
+pub struct TestingDevice {
+    opts: Opts,
+    client_config: Option<ClientConfig>,
+    number: Option<i32>,
+    text: Option<String>,
+    nodes: Vec<(String, NodeFactory)>,
+}
+
+impl TestingDevice {
+    /// `args` are the command line options without the program name, e.g. `["--sensor-suite"]`.
+    /// As on the command line, `SHV_*` environment variables fill in the connection options.
+    pub fn from_args<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let args = std::iter::once(std::ffi::OsString::from(env!("CARGO_PKG_NAME"))).chain(args.into_iter().map(Into::into));
+        Ok(Self {
+            opts: Opts::parse_args(args)?,
+            client_config: None,
+            number: None,
+            text: None,
+            nodes: Vec::new(),
+        })
+    }
+
+    /// Connects with `config` instead of the connection options.
+    pub fn with_client_config(mut self, config: ClientConfig) -> Self {
+        self.client_config = Some(config);
+        self
+    }
+
+    /// Initial value of state/number, a reboot resets it to 0 as usual.
+    pub fn with_number(mut self, number: i32) -> Self {
+        self.number = Some(number);
+        self
+    }
+
+    /// Initial value of state/text, a reboot resets it to an empty string as usual.
+    pub fn with_text(mut self, text: impl Into<String>) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+
+    /// Mounts a node at `path` next to the built-in ones. As every connection needs its
+    /// own node instances, `factory` is called each time the device connects.
+    pub fn add_node<F>(mut self, path: impl Into<String>, factory: F) -> Self
+    where
+        F: Fn(&State) -> ClientNode<State> + Send + Sync + 'static,
+    {
+        self.nodes.push((path.into(), Box::new(factory)));
+        self
+    }
+
+    /// Runs the device until its connection ends and no reconnect is configured.
+    pub async fn run(self) -> Result<(), Error> {
+        let client_config = match self.client_config {
+            Some(config) => config,
+            None => load_client_config(&self.opts).map_err(invalid("config"))?,
+        };
+        transport::check_url(&client_config.url).map_err(invalid("broker URL"))?;
+        let custom = Custom { number: self.number, text: self.text, nodes: self.nodes, bridge: None };
+        run_device(self.opts, client_config, custom).await
+    }
+}
+
+/// What a [`TestingDevice`] sets up beyond the options.
+#[derive(Default)]
+struct Custom {
+    number: Option<i32>,
+    text: Option<String>,
+    nodes: Vec<(String, NodeFactory)>,
+    bridge: Option<bridge::Group>,
+}
+
+/// Runs the devices configured by the command line of the process, with logging and
+/// the SIGINT/SIGTERM handler set up.
+pub async fn run() -> Result<(), Error> {
+    let cli_opts = Opts::parse_args(std::env::args_os()).map_err(Error::Args)?;
+    logging::init(cli_opts.verbose.as_deref()).map_err(invalid("verbosity spec"))?;
+    lifecycle::handle_termination();
+    #[cfg(unix)]
+    configwatch::handle_sighup();
+
+    log::info!("=====================================================");
+    log::info!("{} starting", std::module_path!());
+    log::info!("=====================================================");
+
+    let client_config = load_client_config(&cli_opts).map_err(invalid("config"))?;
+    transport::check_url(&client_config.url).map_err(invalid("broker URL"))?;
+    if let Some(SubCommand::Replay { file, fast }) = &cli_opts.command {
+        return capture::replay(file, &client_config.url, *fast).await.map_err(Error::Connection);
+    }
+    if cli_opts.devices == 0 {
+        return Err(Error::Config("Number of devices must be positive".into()));
+    }
+    if let Some(url) = cli_opts.bridge_url.clone() {
+        if cli_opts.devices != 1 {
+            return Err(Error::Config("--bridge-url cannot be combined with --devices".into()));
+        }
+        return bridge::run(cli_opts, client_config, &url).await;
+    }
+    if cli_opts.devices == 1 {
+        return run_device(cli_opts, client_config, Default::default()).await;
+    }
+    if !cli_opts.device_template.contains("{}") {
+        return Err(Error::Config(format!("Device template '{}' does not contain {{}}", cli_opts.device_template)));
+    }
+    info!("Running {} devices", cli_opts.devices);
+    let devices = (0..cli_opts.devices).map(|n| {
+        let mut config = client_config.clone();
+        config.mount = Some(cli_opts.device_template.replace("{}", &n.to_string()));
+        config.device_id = config.device_id.map(|device_id| format!("{device_id}{n}"));
+        Box::pin(run_device(cli_opts.clone(), config, Default::default()))
+    });
+    // The first error ends all devices.
+    futures::future::try_join_all(devices).await?;
+    Ok(())
+}
+
+/// The state of a device at startup, validating the options.
+fn new_state(cli_opts: &Opts, client_config: &ClientConfig, custom: Custom) -> Result<AppState<State>, Error> {
+    let mirror_cache_ttl = cli_opts.mirror_cache_ttl.as_deref()
+        .map(|ttl| duration_str::parse(ttl).map_err(invalid("mirror cache TTL")))
+        .transpose()?;
+    let mirrors = mirror::Mirrors::new(&cli_opts.mirror, mirror_cache_ttl, cli_opts.mirror_error_mode).map_err(invalid("mirror config"))?;
+    let clock_offset_ms = cli_opts.clock_offset.as_deref()
+        .map(|offset| clock::parse_signed_interval(offset).map_err(invalid("clock offset")))
+        .transpose()?
+        .unwrap_or_default();
+    if cli_opts.number_step.is_some_and(|step| step <= 0) {
+        return Err(Error::Config("Number step must be positive".into()));
+    }
+    if let (Some(min), Some(max)) = (cli_opts.number_min, cli_opts.number_max) {
+        if min > max {
+            return Err(Error::Config(format!("Number min {min} is greater than max {max}")));
+        }
+    }
+    let text_tear_delay = duration_str::parse(&cli_opts.text_tear_delay).map_err(invalid("text tear delay"))?;
+    let rps_window = duration_str::parse(&cli_opts.rps_window).map_err(invalid("request rate window"))?;
+    if rps_window.is_zero() {
+        return Err(Error::Config("Request rate window must be positive".into()));
+    }
+
+    Ok(AppState::new(State {
+        number: custom.number.unwrap_or_default().into(),
+        text: custom.text.unwrap_or_default().into(),
+        any_value: Default::default(),
+        map: mapnode::MapNode::new(cli_opts.map_schema.as_deref()).map_err(invalid("map schema"))?,
+        typed: typednodes::TypedNodes::new(&cli_opts.mode_values).map_err(invalid("mode values"))?,
+        config_tree: configtree::ConfigTree::new(cli_opts.config_tree.as_deref()).map_err(invalid("config tree"))?,
+        table: table::Table::new(&cli_opts.table_column).map_err(invalid("table config"))?,
+        bench_emitter: Default::default(),
+        sigstorm: Default::default(),
+        echo: Default::default(),
+        fault_sim: fault::FaultSim::new(cli_opts.fault_baseline, cli_opts.fault_target, cli_opts.fault_threshold),
+        mirrors,
+        metrics: metrics::Metrics::new(cli_opts.large_message_warn_bytes),
+        clock: clock::Clock::new(clock_offset_ms),
+        text_tear_delay: cli_opts.text_tear.then_some(text_tear_delay),
+        blocking_work: cli_opts.blocking_work_ms.map(Duration::from_millis),
+        max_request_bytes: cli_opts.max_request_bytes,
+        unknown_method: cli_opts.unknown_method,
+        number_step: cli_opts.number_step,
+        number_min: cli_opts.number_min,
+        number_max: cli_opts.number_max,
+        counter: counter::Counter::new(cli_opts.counter_bits).map_err(invalid("counter config"))?,
+        tasks: Default::default(),
+        adaptive_payload: payload::AdaptivePayload::new(cli_opts.max_payload_bytes),
+        blob: payload::Blob::new(cli_opts.blob_stream_threshold).map_err(invalid("blob config"))?,
+        long_ops: Default::default(),
+        faults: faults::Faults {
+            corruption: faults::Corruption::new(cli_opts.enable_corruption, cli_opts.corrupt_rate, cli_opts.corrupt_seed).map_err(invalid("corruption config"))?,
+            leak: faults::Leak::new(cli_opts.enable_leak_method),
+            partition: Default::default(),
+            encoding: faults::EncodingFaults::new(cli_opts.enable_encoding_faults),
+            capacity: Default::default(),
+            availability: Default::default(),
+            responses: Default::default(),
+            error_rate: faults::ErrorRate::new(&cli_opts.error_rate, cli_opts.error_rate_seed).map_err(invalid("error rate config"))?,
+        },
+        transport: Default::default(),
+        latency_histogram: Default::default(),
+        latency: latency::Latency::new(&cli_opts.latency, cli_opts.latency_seed).map_err(invalid("latency config"))?,
+        error_counts: Default::default(),
+        request_rate: metrics::RequestRate::new(rps_window),
+        method_stats: Default::default(),
+        stats: Default::default(),
+        generators: std::sync::Mutex::new(reboot::Generators::new(cli_opts).map_err(invalid("generator config"))?),
+        opts: cli_opts.clone(),
+        sensors: cli_opts.sensor_suite.then(Default::default),
+        recording: recording::Recording::new(cli_opts.recording_file.clone()),
+        history: history::History::new(cli_opts.history_size),
+        lifecycle: lifecycle::Lifecycle::new(cli_opts.lifecycle_signal_path.clone(), client_config.device_id.clone()),
+        client_config: client_config.clone().into(),
+        extra_mounts: cli_opts.extra_mount.clone(),
+        mount_reject: cli_opts.mount_reject,
+        sequences: Default::default(),
+        alarms: Default::default(),
+        firmware: firmware::Firmware::new(cli_opts.firmware_version.clone(), &cli_opts.upgrade_duration, cli_opts.upgrade_reconnect)
+            .map_err(invalid("firmware config"))?,
+        deep_tree: deeptree::DeepTree::new(cli_opts.deep_tree.unwrap_or_default()),
+        node_formats: Default::default(),
+        synthetic: synthetic::SyntheticNodes::load(cli_opts.nodes_file.as_deref()).map_err(invalid("nodes file"))?,
+        scripts: scripting::Scripts::new(&cli_opts.script).map_err(invalid("script config"))?,
+        files: files::Files::new(cli_opts.files_root.as_deref()).map_err(invalid("files config"))?,
+        subscriptions: subscriptions::Subscriptions::new(&cli_opts.subscribe, cli_opts.self_subscribe).map_err(invalid("subscription config"))?,
+        sim: sim::Sim::new(cli_opts.sim_ramp_shape, cli_opts.sim_ramp_steps).map_err(invalid("sim config"))?,
+        journal: journal::Journal::new(cli_opts.journal_size),
+        ls_anomalies: lsanomaly::LsAnomalies::new(cli_opts.ls_wide, cli_opts.ls_odd_names),
+        load_generator: loadgen::LoadGenerator::new(cli_opts.max_allocate_bytes),
+        scenario: Default::default(),
+        login_probes: loginprobe::LoginProbes::new(&cli_opts.login_probe, &cli_opts.login_option, cli_opts.login_oversized_bytes)
+            .map_err(invalid("login probe config"))?,
+        backpressure: backpressure::Backpressure::new(cli_opts).map_err(invalid("backpressure config"))?,
+        capture: capture::Capture::new(cli_opts.capture.as_deref()).map_err(invalid("capture config"))?,
+        slow_login: slowlogin::SlowLogin::new(cli_opts).map_err(invalid("slow login config"))?,
+        selftest: selftest::SelfTest::new(cli_opts.selftest, &cli_opts.selftest_timeout).map_err(invalid("selftest config"))?,
+        mount_conflicts: mountconflict::MountConflicts::new(cli_opts.mount_conflict.as_deref(), cli_opts.mount_conflict_by)
+            .map_err(invalid("mount conflict config"))?,
+        heartbeat: heartbeat::Heartbeat::new(cli_opts.heartbeat_mode, &cli_opts.heartbeat_late, cli_opts.heartbeat_burst)
+            .map_err(invalid("heartbeat config"))?,
+        connection: Default::default(),
+        signals: signals::Signals::new(cli_opts).map_err(invalid("signal config"))?,
+        custom_nodes: custom.nodes,
+        bridge: bridge::Bridge::new(cli_opts.bridge_mirror, custom.bridge),
+    }))
+}
+
+/// The state of a device started with `args`, for unit tests.
+#[cfg(test)]
+fn test_state(args: &[&str]) -> AppState<State> {
+    let opts = Opts::parse_args(std::iter::once(env!("CARGO_PKG_NAME")).chain(args.iter().copied())).unwrap();
+    new_state(&opts, &ClientConfig::default(), Default::default()).unwrap()
+}
+
+/// Runs one simulated device until it exits. With `--devices` several of them share
+/// the process, a fatal error of one of them (e.g. `--mount-reject fail`) ends all.
+async fn run_device(cli_opts: Opts, client_config: ClientConfig, custom: Custom) -> Result<(), Error> {
+    let hooks = hooks::ConnectionHooks::new(cli_opts.on_connect_cmd.clone(), cli_opts.on_disconnect_cmd.clone(), &client_config.url);
+    let state = new_state(&cli_opts, &client_config, custom)?;
+
+    let parse_reconnect_interval = |config: &ClientConfig| config.reconnect_interval.as_deref()
+        .map(|interval| duration_str::parse(interval).map_err(invalid("reconnect interval")))
+        .transpose();
+    parse_reconnect_interval(&client_config)?;
+
+    if cli_opts.watch_config {
+        match &cli_opts.config {
+            Some(path) => {
+                runtime::spawn(configwatch::watch(state.clone(), path.clone()));
+            }
+            None => warn!("--watch-config has no effect without --config"),
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    let tls_relay = tls::Relay::start(&client_config.url, &cli_opts).await.map_err(invalid("TLS config"))?;
+
+    #[cfg(feature = "tls")]
+    let relayed_url = tls_relay.as_ref().map_or(client_config.url.clone(), |relay| relay.local_url(&client_config.url));
+    #[cfg(not(feature = "tls"))]
+    let relayed_url = client_config.url.clone();
+    backpressure::start_relay(&state, &relayed_url).await.map_err(invalid("backpressure config"))?;
+
+    lifecycle::register(&state);
+    bridge::register(&state);
+    reboot::spawn_generators(&state);
+    runtime::spawn(loginprobe::run_startup(state.clone()));
+    selftest::start(&state);
+    #[cfg(feature = "metrics-http")]
+    if let Some(address) = &cli_opts.metrics_listen {
+        runtime::spawn(metricshttp::serve(state.clone(), address.clone()));
+    }
+
+    state.slow_login.before_startup().await;
+    let connect_loop = async {
+        loop {
+            let init_state = state.clone();
+            let url = client_config.url.clone();
+            let hooks = hooks.clone();
+            let init_task = move |client_cmd_tx: ClientCommandSender, client_evt_rx| {
+                init_state.connection.attach(client_cmd_tx.clone());
+                runtime::spawn(handle_client_events(init_state, url, client_cmd_tx, client_evt_rx, hooks));
+            };
+
+            let mut client = shvclient::Client::new_device(DotAppNode::new("shvbrokertestingdevice"), DotDeviceNode::new("shvbrokertestingdevice", "0.1", Some("00000".into())));
+            let nodes = device_nodes(&state);
+            let roots = state.synthetic.mount_roots(nodes.iter().map(|(path, _)| path.clone()).collect());
+            for (path, node) in nodes {
+                client = client.mount(&path, node);
+            }
+            for mount in &state.extra_mounts {
+                for (path, node) in device_nodes(&state) {
+                    client = client.mount(&format!("{mount}/{path}"), node);
+                }
+            }
+            for mount in std::iter::once(None).chain(state.extra_mounts.iter().map(Some)) {
+                for root in &roots {
+                    let path = mount.map_or(root.clone(), |mount| format!("{mount}/{root}"));
+                    let app_state = state.clone();
+                    client = client.mount_dynamic(&path, move |request, client_cmd_tx| {
+                        Box::pin(synthetic::handle(app_state.clone(), request, client_cmd_tx))
+                    });
+                }
+            }
+            let mut config = state.client_config.lock().unwrap().clone();
+            config.reconnect_interval = None;
+            #[cfg(feature = "tls")]
+            if let Some(relay) = &tls_relay {
+                config.url = relay.local_url(&config.url);
+            }
+            config.url = state.backpressure.local_url(&config.url);
+            if state.connection.heartbeat_suspended() || state.heartbeat.overridden() {
+                config.heartbeat_interval = connection::SUSPENDED_HEARTBEAT_INTERVAL.to_string();
+            }
+            state.connection.begin_attempt(&config.url);
+            let result = client
+                .with_app_state(state.clone())
+                .run_with_init(&config, init_task)
+                .await;
+
+            signals::on_disconnected(&state);
+            if state.connection.take_reconnect_request() {
+                if let Some(offline) = state.connection.take_offline() {
+                    runtime::sleep(offline).await;
+                }
+                info!("Reconnecting to broker");
+                continue;
+            }
+            // Read after the connection ends, so that control/reconnectInterval changes apply to this wait.
+            let reconnect_interval = parse_reconnect_interval(&state.client_config.lock().unwrap())?;
+            let Some(reconnect_interval) = reconnect_interval else {
+                return result.map_err(|err| Error::Connection(err.to_string()));
+            };
+            if let Err(err) = result {
+                warn!("Connection to broker failed: {err}");
+            }
+            if let Some(max_attempts) = cli_opts.max_reconnect_attempts {
+                if state.connection.failed_attempts() >= max_attempts {
+                    return Err(Error::ReconnectLimit(max_attempts));
+                }
+            }
+            runtime::sleep(reconnect_interval).await;
+        }
+    };
+    // Dropping the connect loop closes the connection of a device stopped from a handler.
+    let stopped = state.connection.stopped();
+    match futures::future::select(pin!(connect_loop), pin!(stopped)).await {
+        Either::Left((result, _)) | Either::Right((result, _)) => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_template_expands_placeholders() {
+        assert_eq!(expand_mount_template("test/devices/{device_id}", Some("abc")).unwrap(), "test/devices/abc");
+        assert_eq!(expand_mount_template("test/{pid}", None).unwrap(), format!("test/{}", std::process::id()));
+        assert_eq!(expand_mount_template("test/fixed", None).unwrap(), "test/fixed");
+    }
+
+    #[test]
+    fn mount_template_needs_device_id() {
+        assert!(expand_mount_template("test/devices/{device_id}", None).is_err());
+    }
+
+    #[test]
+    fn mount_template_rejects_invalid_paths() {
+        for template in ["", "test//{pid}", "test/{device_id}", "test/{other}", "/test"] {
+            assert!(expand_mount_template(template, Some("")).is_err(), "{template}");
+        }
+        assert!(expand_mount_template("test/{device_id}", Some("a b")).is_err());
+    }
+
+    #[test]
+    fn env_fills_in_connection_options_below_command_line() {
+        // No other test reads the device id, so setting it does not race with them.
+        std::env::set_var("SHV_DEVICE_ID", "from-env");
+        let from_env = Opts::parse_args(["shvbrokertestingdevice"]).unwrap();
+        let from_cli = Opts::parse_args(["shvbrokertestingdevice", "--device-id", "from-cli"]).unwrap();
+        std::env::remove_var("SHV_DEVICE_ID");
+        assert_eq!(load_client_config(&from_env).unwrap().device_id.as_deref(), Some("from-env"));
+        assert_eq!(load_client_config(&from_cli).unwrap().device_id.as_deref(), Some("from-cli"));
+    }
+
+    /// Calls `method` on a declared node through the dynamic node handler, returns the delivered messages.
+    fn call_declared(state: &AppState<State>, path: &str, method: &str, param: Option<RpcValue>) -> Vec<RpcMessage> {
+        let collected = signals::Collected::default();
+        let request = RpcMessage::new_request(path, method, param);
+        runtime::block_on(synthetic::handle(state.clone(), request, &collected));
+        collected.take_messages()
+    }
+
+    #[test]
+    fn write_via_extra_mount_reads_back_via_main_mount() {
+        let state = test_state(&["--extra-mount", "alt"]);
+        let node = RpcValue::from_cpon(r#"{"path": "test/declared", "type": "Int", "value": 1, "writable": true}"#).unwrap();
+        state.synthetic.create(Some(&node)).unwrap();
+
+        let messages = call_declared(&state, "alt/test/declared", "set", Some(5.into()));
+        let signals: Vec<&str> = messages.iter().filter(|message| message.is_signal())
+            .map(|message| message.shv_path().unwrap_or_default())
+            .collect();
+        assert_eq!(signals, ["alt/test/declared", "test/declared"]);
+
+        let [response] = call_declared(&state, "test/declared", "get", None).try_into().expect("one response");
+        assert_eq!(response.result().unwrap(), RpcValue::from(5));
+        let [response] = call_declared(&state, "alt/test/declared", "get", None).try_into().expect("one response");
+        assert_eq!(response.result().unwrap(), RpcValue::from(5));
+    }
+
+    #[test]
+    fn snapshot_has_every_top_level_key() {
+        let state = test_state(&[]);
+        let dump = runtime::block_on(state.dump());
+        let shvproto::rpcvalue::Value::Map(dump) = dump.value() else {
+            panic!("snapshot is not a Map");
+        };
+        let keys: Vec<&str> = dump.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["benchEmitter", "config", "connection", "errors", "faults", "metrics", "nodes", "tasks"]);
+        let shvproto::rpcvalue::Value::Map(nodes) = dump["nodes"].value() else {
+            panic!("snapshot nodes is not a Map");
+        };
+        for path in [NUMBER_MOUNT, TEXT_MOUNT, counter::COUNTER_MOUNT, sim::SIM_CLOCK_MOUNT] {
+            assert!(nodes.contains_key(path), "{path}");
+        }
+    }
+
+    #[test]
+    fn number_rejects_values_out_of_advertised_range() {
+        let state = test_state(&["--number-min", "-10", "--number-max", "10"]);
+        let constraints = RpcValue::from_cpon(r#"{"type": "Int", "min": -10, "max": 10}"#).unwrap();
+        assert_eq!(state.number_constraints(), constraints);
+        for value in [-11, 11] {
+            let err = state.update_number(value).unwrap_err();
+            assert_eq!(err.code as i32, RpcErrorCode::InvalidParam as i32, "{value}");
+        }
+        assert_eq!(state.number.load(Ordering::SeqCst), 0);
+        assert_eq!(state.update_number(10).unwrap(), Some(10.into()));
+        assert_eq!(state.update_number(-10).unwrap(), Some((-10).into()));
+    }
+
+    #[test]
+    fn quantize_rounds_to_nearest_step() {
+        assert_eq!(quantize(14, 10), 10);
+        assert_eq!(quantize(16, 10), 20);
+        assert_eq!(quantize(-14, 10), -10);
+        assert_eq!(quantize(-16, 10), -20);
+        assert_eq!(quantize(30, 10), 30);
+    }
+
+    #[test]
+    fn quantize_ties_away_from_zero() {
+        assert_eq!(quantize(15, 10), 20);
+        assert_eq!(quantize(-15, 10), -20);
+        assert_eq!(quantize(1, 2), 2);
+        assert_eq!(quantize(-1, 2), -2);
+    }
+
+    #[test]
+    fn quantize_saturates() {
+        assert_eq!(quantize(i32::MAX, 1000), 2_147_483_000);
+        assert_eq!(quantize(i32::MIN, 1000), -2_147_483_000);
+        assert_eq!(quantize(i32::MAX, 1), i32::MAX);
+        assert_eq!(quantize(i32::MIN, 1), i32::MIN);
+    }
+
+    #[test]
+    fn torn_text_write_responds_before_final_value() {
+        let state = test_state(&["--text-tear", "--text-tear-delay", "100ms"]);
+        let collected = std::sync::Arc::new(signals::Collected::default());
+        let chng = |value: &str| (TEXT_MOUNT.to_string(), shvclient::clientnode::SIG_CHNG.to_string(), RpcValue::from(value));
+        let signals = runtime::block_on(async {
+            let started = std::time::Instant::now();
+            State::set_text(&state, &collected, "abcdef".to_string()).await.unwrap();
+            assert!(started.elapsed() < Duration::from_millis(100), "set waited for the final value");
+            assert_eq!(collected.take(), [chng("abc")]);
+            assert_eq!(*state.text.read().await, "abcdef");
+            let mut signals = Vec::new();
+            for _ in 0..100 {
+                signals.extend(collected.take());
+                if !signals.is_empty() {
+                    break;
+                }
+                runtime::sleep(Duration::from_millis(10)).await;
+            }
+            signals
+        });
+        assert_eq!(signals, [chng("abcdef")]);
+    }
+}
@@ -0,0 +1,220 @@
+//! Lifecycle events published as SHV signals, so that a collector can track
+//! devices through subscriptions only.
+//!
+//! With `--lifecycle-signal-path` the device emits the `connected`, `disconnected`
+//! and `reconnecting` signals on that path. The parameter is an IMap with the
+//! keys listed below. `disconnected` cannot be delivered while the connection is
+//! down, it is sent right before the next `connected`, with the time of the
+//! disconnect. `shutdown` is sent when the device exits gracefully, on SIGINT or
+//! SIGTERM and by `control/app:quit` and `control/app:restart`.
+//!
+//! Every successful connect mints a new session id, readable on `status/session`
+//! and carried by the events: `disconnected` has the id of the session that ended.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::*;
+use shvclient::AppState;
+use shvproto::rpcvalue::IMap;
+use shvproto::{DateTime, RpcValue};
+use shvrpc::RpcMessage;
+
+use crate::signals::MessageSink;
+use crate::{runtime, State};
+
+pub(crate) const EVENT_KEY: i32 = 1;
+pub(crate) const TIME_KEY: i32 = 2;
+pub(crate) const DEVICE_ID_KEY: i32 = 3;
+pub(crate) const SESSION_ID_KEY: i32 = 4;
+
+pub(crate) const SESSION_MOUNT: &str = "status/session";
+pub(crate) const APP_MOUNT: &str = "control/app";
+
+/// Time given to queued messages (the `shutdown` event, the response of control/app:quit)
+/// to be sent, and to the clients to close their connections.
+const SHUTDOWN_FLUSH: Duration = Duration::from_millis(200);
+
+/// Devices of this process, all of them are shut down together.
+static DEVICES: Mutex<Vec<AppState<State>>> = Mutex::new(Vec::new());
+
+#[derive(Default)]
+pub(crate) struct Lifecycle {
+    path: Option<String>,
+    device_id: Option<String>,
+    disconnected_at: Mutex<Option<DateTime>>,
+    session_id: Mutex<Option<String>>,
+}
+
+impl Lifecycle {
+    pub(crate) fn new(path: Option<String>, device_id: Option<String>) -> Self {
+        Self { path, device_id, disconnected_at: Default::default(), session_id: Default::default() }
+    }
+
+    /// Id of the current or last connection session, Null before the first connect.
+    pub(crate) fn session_id(&self) -> RpcValue {
+        self.session_id.lock().unwrap().as_deref().map(RpcValue::from).unwrap_or_default()
+    }
+
+    fn send(&self, client_cmd_tx: &impl MessageSink, event: &str, time: DateTime) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut param = IMap::new();
+        param.insert(EVENT_KEY, event.into());
+        param.insert(TIME_KEY, time.into());
+        param.insert(DEVICE_ID_KEY, self.device_id.as_deref().map(RpcValue::from).unwrap_or_default());
+        param.insert(SESSION_ID_KEY, self.session_id());
+        client_cmd_tx.deliver(RpcMessage::new_signal(path, event, Some(param.into())));
+    }
+}
+
+pub(crate) fn connected(state: &State, client_cmd_tx: &impl MessageSink) {
+    let lifecycle = &state.lifecycle;
+    if let Some(time) = lifecycle.disconnected_at.lock().unwrap().take() {
+        lifecycle.send(client_cmd_tx, "disconnected", time);
+    }
+    *lifecycle.session_id.lock().unwrap() = Some(format!("{:016x}", rand::random::<u64>()));
+    lifecycle.send(client_cmd_tx, "connected", state.clock.now());
+}
+
+pub(crate) fn disconnected(state: &State) {
+    *state.lifecycle.disconnected_at.lock().unwrap() = Some(state.clock.now());
+}
+
+/// Forces a reconnect, announcing it with `reconnecting` first.
+pub(crate) fn request_reconnect(state: &State) -> bool {
+    state.connection.request_reconnect(|client_cmd_tx| {
+        state.lifecycle.send(client_cmd_tx, "reconnecting", state.clock.now());
+    })
+}
+
+pub(crate) fn register(app_state: &AppState<State>) {
+    DEVICES.lock().unwrap().push(app_state.clone());
+}
+
+pub(crate) fn devices() -> Vec<AppState<State>> {
+    DEVICES.lock().unwrap().clone()
+}
+
+/// Installs the SIGINT and SIGTERM handler shutting the devices down gracefully.
+pub(crate) fn handle_termination() {
+    let result = ctrlc::set_handler(|| {
+        info!("Termination requested");
+        runtime::block_on(close_all());
+        std::process::exit(0);
+    });
+    if let Err(err) = result {
+        warn!("Cannot install termination handler: {err}");
+    }
+}
+
+/// Sends `shutdown` on every device connection and closes the connections.
+pub(crate) async fn close_all() {
+    let devices = devices();
+    for state in &devices {
+        if let Some(client_cmd_tx) = state.connection.client_cmd_tx() {
+            state.lifecycle.send(&client_cmd_tx, "shutdown", state.clock.now());
+        }
+    }
+    runtime::sleep(SHUTDOWN_FLUSH).await;
+    for state in &devices {
+        if let Some(client_cmd_tx) = state.connection.client_cmd_tx() {
+            client_cmd_tx.terminate_client();
+        }
+    }
+    runtime::sleep(SHUTDOWN_FLUSH).await;
+}
+
+/// control/app:quit, exits the process once the response is on its way.
+pub(crate) fn quit() {
+    runtime::spawn(async {
+        info!("Quitting on request");
+        close_all().await;
+        std::process::exit(0);
+    });
+}
+
+/// control/app:restart, replaces the process with a fresh instance started with the
+/// same command line, the process id stays the same on Unix.
+pub(crate) fn restart() {
+    runtime::spawn(async {
+        info!("Restarting on request");
+        close_all().await;
+        let exe = std::env::current_exe();
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        match exe {
+            Ok(exe) => {
+                let mut command = std::process::Command::new(exe);
+                command.args(args);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::process::CommandExt;
+                    let err = command.exec();
+                    error!("Cannot restart: {err}");
+                }
+                #[cfg(not(unix))]
+                if let Err(err) = command.spawn() {
+                    error!("Cannot restart: {err}");
+                }
+            }
+            Err(err) => error!("Cannot restart, executable not found: {err}"),
+        }
+        std::process::exit(0);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signals::Collected;
+
+    /// Event name and session id of the lifecycle signals delivered since the last call.
+    fn events(collected: &Collected) -> Vec<(String, RpcValue)> {
+        collected.take().into_iter()
+            .map(|(path, event, param)| {
+                assert_eq!(path, "test/lifecycle");
+                let shvproto::rpcvalue::Value::IMap(param) = param.value() else {
+                    panic!("{event} param is not an IMap");
+                };
+                assert_eq!(param.get(&EVENT_KEY), Some(&RpcValue::from(event.as_str())));
+                (event, param.get(&SESSION_ID_KEY).cloned().unwrap_or_default())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reconnect_emits_disconnected_then_connected() {
+        let state = crate::test_state(&["--lifecycle-signal-path", "test/lifecycle"]);
+        let collected = Collected::default();
+        connected(&state, &collected);
+        let first = state.lifecycle.session_id();
+        assert_eq!(events(&collected), [("connected".to_string(), first.clone())]);
+
+        disconnected(&state);
+        assert!(collected.take().is_empty());
+        connected(&state, &collected);
+        let names: Vec<String> = events(&collected).into_iter().map(|(event, _)| event).collect();
+        assert_eq!(names, ["disconnected", "connected"]);
+    }
+
+    #[test]
+    fn reconnect_mints_new_session_id() {
+        let state = crate::test_state(&["--lifecycle-signal-path", "test/lifecycle"]);
+        let collected = Collected::default();
+        assert_eq!(state.lifecycle.session_id(), RpcValue::null());
+        connected(&state, &collected);
+        let first = state.lifecycle.session_id();
+        assert!(first.is_string());
+        collected.take();
+
+        disconnected(&state);
+        connected(&state, &collected);
+        let second = state.lifecycle.session_id();
+        assert_ne!(second, first);
+        assert_eq!(events(&collected), [
+            ("disconnected".to_string(), first),
+            ("connected".to_string(), second),
+        ]);
+    }
+}
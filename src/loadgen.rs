@@ -0,0 +1,125 @@
+//! `test/load` node making the device consume memory and CPU on request, to verify
+//! that the broker keeps serving other clients meanwhile.
+//!
+//! Unlike control:leak the memory is meant to be freed again and the node is always
+//! available, the total allocation is capped by `--max-allocate-bytes`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::*;
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::{metrics, runtime};
+
+pub(crate) const LOAD_GEN_MOUNT: &str = "test/load";
+const MAX_BURN_THREADS: i64 = 256;
+
+pub(crate) struct LoadGenerator {
+    max_bytes: usize,
+    blocks: Mutex<Vec<Vec<u8>>>,
+    burning_threads: AtomicU64,
+    burn_ms_total: AtomicU64,
+}
+
+impl LoadGenerator {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self { max_bytes, blocks: Default::default(), burning_threads: Default::default(), burn_ms_total: Default::default() }
+    }
+
+    fn allocated_bytes(&self) -> usize {
+        self.blocks.lock().unwrap().iter().map(Vec::len).sum()
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let mut map = Map::new();
+        map.insert("allocatedBytes".into(), (self.allocated_bytes() as i64).into());
+        map.insert("maxBytes".into(), (self.max_bytes as i64).into());
+        map.insert("burningThreads".into(), (self.burning_threads.load(Ordering::SeqCst) as i64).into());
+        map.insert("burnMsTotal".into(), (self.burn_ms_total.load(Ordering::SeqCst) as i64).into());
+        map.into()
+    }
+
+    /// Allocates and keeps `bytes` more bytes, returns `{"allocatedBytes", "rssBytes"}` after the allocation.
+    pub(crate) fn allocate(&self, bytes: i64) -> Result<RpcValue, RpcError> {
+        let bytes = usize::try_from(bytes).map_err(|_| RpcError::new(RpcErrorCode::InvalidParam, "Size must not be negative"))?;
+        let mut blocks = self.blocks.lock().unwrap();
+        let allocated: usize = blocks.iter().map(Vec::len).sum();
+        if allocated.saturating_add(bytes) > self.max_bytes {
+            return Err(RpcError::new(RpcErrorCode::MethodCallException,
+                &format!("Allocation of {bytes} bytes exceeds the limit of {} bytes, {allocated} bytes allocated", self.max_bytes)));
+        }
+        // Filled with non-zero bytes so that the pages are really resident.
+        blocks.push(vec![0x5A; bytes]);
+        drop(blocks);
+        info!("Allocated {bytes} bytes on request");
+        Ok(self.allocation())
+    }
+
+    /// Frees everything allocated, returns the freed size.
+    pub(crate) fn free(&self) -> usize {
+        let blocks = std::mem::take(&mut *self.blocks.lock().unwrap());
+        blocks.iter().map(Vec::len).sum()
+    }
+
+    fn allocation(&self) -> RpcValue {
+        let allocated = self.allocated_bytes();
+        let mut map = Map::new();
+        map.insert("allocatedBytes".into(), (allocated as i64).into());
+        if let Value::Map(resources) = metrics::resources(allocated).value() {
+            map.insert("rssBytes".into(), resources.get("rssBytes").cloned().unwrap_or_default());
+        }
+        map.into()
+    }
+
+    /// Keeps `threads` OS threads busy for `duration`, returns `{"threads", "durationMs",
+    /// "iterations", "cpuMs"}` with `cpuMs` summed over the threads.
+    pub(crate) async fn cpu_burn(&self, threads: usize, duration: Duration) -> RpcValue {
+        self.burning_threads.fetch_add(threads as u64, Ordering::SeqCst);
+        let started = Instant::now();
+        let (iterations, busy) = runtime::spawn_blocking(move || {
+            let handles: Vec<_> = (0..threads).map(|_| std::thread::spawn(move || burn(duration))).collect();
+            handles.into_iter()
+                .filter_map(|handle| handle.join().ok())
+                .fold((0u64, Duration::ZERO), |(iterations, busy), (n, spent)| (iterations + n, busy + spent))
+        }).await;
+        self.burning_threads.fetch_sub(threads as u64, Ordering::SeqCst);
+        self.burn_ms_total.fetch_add(busy.as_millis() as u64, Ordering::SeqCst);
+        let mut map = Map::new();
+        map.insert("threads".into(), (threads as i64).into());
+        map.insert("durationMs".into(), (started.elapsed().as_millis() as i64).into());
+        map.insert("iterations".into(), (iterations as i64).into());
+        map.insert("cpuMs".into(), (busy.as_millis() as i64).into());
+        map.into()
+    }
+}
+
+pub(crate) fn parse_burn_param(param: Option<&RpcValue>) -> Result<(usize, Duration), RpcError> {
+    let invalid = || RpcError::new(RpcErrorCode::InvalidParam, &format!("Expected [threads 1..{MAX_BURN_THREADS}, durationMs >= 0]"));
+    let Some(Value::List(list)) = param.map(RpcValue::value) else {
+        return Err(invalid());
+    };
+    match list.as_slice() {
+        [threads, duration] if threads.is_int() && (1..=MAX_BURN_THREADS).contains(&threads.as_int())
+            && duration.is_int() && duration.as_int() >= 0 => {
+            Ok((threads.as_int() as usize, Duration::from_millis(duration.as_int() as u64)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn burn(duration: Duration) -> (u64, Duration) {
+    let started = Instant::now();
+    let mut iterations: u64 = 0;
+    let mut x: u64 = 1;
+    while started.elapsed() < duration {
+        for _ in 0..1000 {
+            x = std::hint::black_box(x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407));
+        }
+        iterations += 1000;
+    }
+    (iterations, started.elapsed())
+}
@@ -0,0 +1,97 @@
+//! Logger whose per-module levels can be changed at runtime through `status/logSpec`.
+
+use std::sync::RwLock;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use shvrpc::util::parse_log_verbosity;
+use simple_logger::SimpleLogger;
+
+pub(crate) const LOG_SPEC_MOUNT: &str = "status/logSpec";
+/// Module the `.` placeholder of a verbosity spec stands for.
+const ROOT_MODULE: &str = env!("CARGO_CRATE_NAME");
+
+struct LogSpec {
+    spec: String,
+    module_levels: Vec<(String, LevelFilter)>,
+}
+
+static LOG_SPEC: RwLock<LogSpec> = RwLock::new(LogSpec { spec: String::new(), module_levels: Vec::new() });
+
+/// Filters records by the current spec and leaves formatting to a fully enabled [`SimpleLogger`].
+struct DynamicLogger {
+    inner: SimpleLogger,
+}
+
+impl DynamicLogger {
+    fn level(target: &str) -> LevelFilter {
+        let spec = LOG_SPEC.read().unwrap();
+        spec.module_levels.iter()
+            .filter(|(module, _)| target == module || target.strip_prefix(module.as_str()).is_some_and(|rest| rest.starts_with("::")))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(LevelFilter::Info, |(_, level)| *level)
+    }
+}
+
+impl Log for DynamicLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Self::level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+pub(crate) fn init(spec: Option<&str>) -> Result<(), String> {
+    if let Some(spec) = spec {
+        set_spec(spec)?;
+    }
+    log::set_boxed_logger(Box::new(DynamicLogger { inner: SimpleLogger::new().with_level(LevelFilter::Trace) }))
+        .expect("Logger already initialized");
+    log::set_max_level(LevelFilter::Trace);
+    Ok(())
+}
+
+pub(crate) fn spec() -> String {
+    LOG_SPEC.read().unwrap().spec.clone()
+}
+
+/// Applies a `--verbose` style spec, e.g. `rpcmsg:W,.:D`, the current levels are kept if it is invalid.
+pub(crate) fn set_spec(spec: &str) -> Result<(), String> {
+    for item in spec.split(',') {
+        let (module, level) = item.split_once(':').unwrap_or((item, "T"));
+        if module.is_empty() || !matches!(level, "E" | "W" | "I" | "D" | "T") {
+            return Err(format!("Invalid verbosity spec item '{item}', expected <module>[:E|W|I|D|T]"));
+        }
+    }
+    let module_levels = parse_log_verbosity(spec, ROOT_MODULE).into_iter()
+        .map(|(module, level)| (module.to_string(), level))
+        .collect();
+    *LOG_SPEC.write().unwrap() = LogSpec { spec: spec.to_string(), module_levels };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_round_trips() {
+        set_spec("rpcmsg:W,.:D").unwrap();
+        assert_eq!(spec(), "rpcmsg:W,.:D");
+        assert_eq!(DynamicLogger::level("rpcmsg"), LevelFilter::Warn);
+        assert_eq!(DynamicLogger::level("rpcmsg::frame"), LevelFilter::Warn);
+        assert_eq!(DynamicLogger::level("rpcmsgx"), LevelFilter::Info);
+
+        assert!(set_spec("rpcmsg:X").is_err());
+        assert!(set_spec("rpcmsg:W,").is_err());
+        assert_eq!(spec(), "rpcmsg:W,.:D");
+        assert_eq!(DynamicLogger::level("rpcmsg"), LevelFilter::Warn);
+    }
+}
@@ -0,0 +1,229 @@
+//! `test/login` runs login variants against the broker on separate connections and
+//! reports which of them succeeded, for broker authentication tests.
+//!
+//! The client library logs in one fixed way, so the probes speak the handshake
+//! (`hello`, then `login`) themselves over their own TCP connection to a `tcp://`
+//! broker, with the user and password of the broker URL, and close it afterwards.
+//! They announce no mount point, the device's own connection is not affected.
+//!
+//! Variants (`--login-probe`, repeatable, run once at startup; `test/login:run` runs
+//! one on demand):
+//! - `plain`, `sha1`: login type PLAIN or SHA1
+//! - `wrong-then-right`: a wrong password first, the right one on a new connection;
+//!   succeeds when the first attempt is rejected and the second accepted
+//! - `oversized-options`: an options Map padded to `--login-oversized-bytes`
+//! - `custom-options`: the `--login-option key=cpon` fields added to the options
+//!
+//! The `--login-option` fields are sent with every variant. Each result is a Map with
+//! `variant`, `success`, `error` of a failed variant and `durationMs`.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use async_std::net::TcpStream;
+use futures::io::{AsyncReadExt, BufReader};
+use log::*;
+use sha1::{Digest, Sha1};
+use shvclient::AppState;
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::framerw::{FrameReader, FrameWriter};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use shvrpc::streamrwframe::{StreamFrameReader, StreamFrameWriter};
+use shvrpc::RpcMessage;
+use url::Url;
+
+use crate::State;
+
+pub(crate) const LOGIN_PROBE_MOUNT: &str = "test/login";
+const DEFAULT_PORT: u16 = 3755;
+const MAX_RESULTS: usize = 100;
+const IDLE_WATCHDOG_TIMEOUT_S: i64 = 60;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LoginVariant {
+    Plain,
+    Sha1,
+    WrongThenRight,
+    OversizedOptions,
+    CustomOptions,
+}
+
+impl LoginVariant {
+    fn as_str(self) -> &'static str {
+        match self {
+            LoginVariant::Plain => "plain",
+            LoginVariant::Sha1 => "sha1",
+            LoginVariant::WrongThenRight => "wrong-then-right",
+            LoginVariant::OversizedOptions => "oversized-options",
+            LoginVariant::CustomOptions => "custom-options",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [Self::Plain, Self::Sha1, Self::WrongThenRight, Self::OversizedOptions, Self::CustomOptions]
+            .into_iter()
+            .find(|variant| variant.as_str() == name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LoginType {
+    Plain,
+    Sha1,
+}
+
+pub(crate) struct LoginProbes {
+    startup: Vec<LoginVariant>,
+    options: Map,
+    oversized_bytes: usize,
+    results: Mutex<VecDeque<RpcValue>>,
+}
+
+impl LoginProbes {
+    pub(crate) fn new(startup: &[LoginVariant], options: &[String], oversized_bytes: usize) -> Result<Self, String> {
+        let options = options.iter()
+            .map(|option| {
+                let (key, value) = option.split_once('=').ok_or_else(|| format!("Invalid login option '{option}', expected key=cpon"))?;
+                let value = RpcValue::from_cpon(value).map_err(|err| format!("Invalid value of login option {key}: {err}"))?;
+                Ok((key.to_string(), value))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { startup: startup.to_vec(), options, oversized_bytes, results: Default::default() })
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let results: Vec<RpcValue> = self.results.lock().unwrap().iter().cloned().collect();
+        results.into()
+    }
+
+    fn record(&self, result: Map) {
+        let mut results = self.results.lock().unwrap();
+        if results.len() == MAX_RESULTS {
+            results.pop_front();
+        }
+        results.push_back(result.into());
+    }
+
+    fn options(&self, variant: LoginVariant) -> Map {
+        let mut options = Map::new();
+        options.insert("idleWatchDogTimeOut".into(), IDLE_WATCHDOG_TIMEOUT_S.into());
+        if variant == LoginVariant::OversizedOptions {
+            options.insert("padding".into(), "x".repeat(self.oversized_bytes).into());
+        }
+        options.extend(self.options.clone());
+        options
+    }
+}
+
+/// Runs the `--login-probe` variants one after another.
+pub(crate) async fn run_startup(app_state: AppState<State>) {
+    for variant in app_state.login_probes.startup.clone() {
+        probe(&app_state, variant).await;
+    }
+}
+
+/// test/login:run
+pub(crate) async fn run(app_state: &AppState<State>, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+    let variant = param.filter(|param| param.is_string()).and_then(|param| LoginVariant::parse(param.as_str()))
+        .ok_or_else(|| RpcError::new(RpcErrorCode::InvalidParam, "Expected plain, sha1, wrong-then-right, oversized-options or custom-options"))?;
+    Ok(probe(app_state, variant).await)
+}
+
+async fn probe(app_state: &AppState<State>, variant: LoginVariant) -> RpcValue {
+    let started = Instant::now();
+    let url = app_state.client_config.lock().unwrap().url.clone();
+    let probes = &app_state.login_probes;
+    let options = probes.options(variant);
+    let outcome = match Broker::parse(&url) {
+        Err(err) => Err(err),
+        Ok(broker) => match variant {
+            LoginVariant::Sha1 => broker.login(LoginType::Sha1, &broker.password, options).await,
+            LoginVariant::WrongThenRight => {
+                let wrong = format!("{}-wrong", broker.password);
+                match broker.login(LoginType::Plain, &wrong, options.clone()).await {
+                    Ok(()) => Err("The wrong password was accepted".to_string()),
+                    Err(err) => {
+                        debug!("Login probe: wrong password rejected: {err}");
+                        broker.login(LoginType::Plain, &broker.password, options).await
+                            .map_err(|err| format!("Retry with the right password failed: {err}"))
+                    }
+                }
+            }
+            _ => broker.login(LoginType::Plain, &broker.password, options).await,
+        },
+    };
+    let mut result = Map::new();
+    result.insert("variant".into(), variant.as_str().into());
+    result.insert("success".into(), outcome.is_ok().into());
+    match &outcome {
+        Ok(()) => info!("Login probe {} succeeded", variant.as_str()),
+        Err(err) => {
+            info!("Login probe {} failed: {err}", variant.as_str());
+            result.insert("error".into(), err.as_str().into());
+        }
+    }
+    result.insert("durationMs".into(), (started.elapsed().as_millis() as i64).into());
+    probes.record(result.clone());
+    result.into()
+}
+
+struct Broker {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+}
+
+impl Broker {
+    fn parse(url: &str) -> Result<Self, String> {
+        let url = Url::parse(url).map_err(|err| format!("Invalid broker URL: {err}"))?;
+        if url.scheme() != "tcp" {
+            return Err(format!("Login probes support tcp:// broker URLs only, not {}://", url.scheme()));
+        }
+        let query = |key: &str| url.query_pairs().find(|(k, _)| k == key).map(|(_, v)| v.into_owned()).unwrap_or_default();
+        Ok(Self {
+            host: url.host_str().ok_or("Broker URL has no host")?.to_string(),
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            user: query("user"),
+            password: query("password"),
+        })
+    }
+
+    async fn login(&self, login_type: LoginType, password: &str, options: Map) -> Result<(), String> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await
+            .map_err(|err| format!("Cannot connect to {}:{}: {err}", self.host, self.port))?;
+        let (reader, writer) = stream.split();
+        let mut frame_reader = StreamFrameReader::new(BufReader::new(reader));
+        let mut frame_writer = StreamFrameWriter::new(writer);
+        let hello = call(&mut frame_reader, &mut frame_writer, "hello", None).await?;
+        let nonce = match hello.value() {
+            Value::Map(hello) => hello.get("nonce").map(|nonce| nonce.as_str().to_string()).unwrap_or_default(),
+            _ => String::new(),
+        };
+        let (password, type_name) = match login_type {
+            LoginType::Plain => (password.to_string(), "PLAIN"),
+            LoginType::Sha1 => (sha1_hex(&format!("{nonce}{}", sha1_hex(password))), "SHA1"),
+        };
+        let mut login = Map::new();
+        login.insert("user".into(), self.user.as_str().into());
+        login.insert("password".into(), password.into());
+        login.insert("type".into(), type_name.into());
+        let mut param = Map::new();
+        param.insert("login".into(), login.into());
+        param.insert("options".into(), options.into());
+        call(&mut frame_reader, &mut frame_writer, "login", Some(param.into())).await.map(|_| ())
+    }
+}
+
+async fn call(frame_reader: &mut impl FrameReader, frame_writer: &mut impl FrameWriter, method: &str, param: Option<RpcValue>) -> Result<RpcValue, String> {
+    frame_writer.send_message(RpcMessage::new_request("", method, param)).await.map_err(|err| format!("Cannot send {method}: {err}"))?;
+    let frame = frame_reader.receive_frame().await.map_err(|err| format!("No response to {method}: {err}"))?;
+    let response = frame.to_rpcmesage().map_err(|err| format!("Invalid response to {method}: {err}"))?;
+    response.result().cloned().map_err(|err| format!("{method} failed: {}", err.message))
+}
+
+fn sha1_hex(data: &str) -> String {
+    format!("{:x}", Sha1::digest(data.as_bytes()))
+}
@@ -0,0 +1,252 @@
+//! Long-running operations with pollable progress, modelled after firmware updates.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use shvclient::{AppState, ClientCommandSender};
+use shvproto::rpcvalue::Map;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode, Tag};
+use shvrpc::RpcMessage;
+
+use crate::signals::emit_chng;
+use crate::{runtime, tasks, State};
+
+pub(crate) const LONG_OP_MOUNT: &str = "control/longOp";
+pub(crate) const TEST_LONG_OP_MOUNT: &str = "test/longop";
+/// Signal of test/longop sent when an operation started by test/longop:run finishes or is cancelled.
+const SIG_DONE: &str = "done";
+const TICK: Duration = Duration::from_millis(100);
+/// Finished operations kept for progress queries, the oldest are forgotten first.
+const MAX_FINISHED_OPS: usize = 100;
+
+/// How a test/longop operation reports its end.
+enum Completion {
+    Signal(ClientCommandSender),
+    Response(ClientCommandSender, RpcMessage),
+}
+
+struct Pending {
+    /// Request id and caller ids of a deferred request.
+    request: Option<(i64, String)>,
+    completion: Completion,
+}
+
+#[derive(Default)]
+pub(crate) struct LongOps {
+    next_id: AtomicU64,
+    progress: Mutex<BTreeMap<u64, u8>>,
+    /// test/longop operations that did not report their end yet.
+    pending: Mutex<BTreeMap<u64, Pending>>,
+}
+
+impl LongOps {
+    pub(crate) fn progress(&self, id: i64) -> Result<RpcValue, RpcError> {
+        let progress = u64::try_from(id).ok().and_then(|id| self.progress.lock().unwrap().get(&id).copied());
+        match progress {
+            Some(progress) => Ok((progress as i64).into()),
+            None => Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Unknown operation id {id}"))),
+        }
+    }
+
+    /// Forgets all operations, ids keep increasing so that stale ids stay unknown.
+    /// Pending test/longop operations are reported as cancelled.
+    pub(crate) fn clear(&self) {
+        self.progress.lock().unwrap().clear();
+        let pending: Vec<u64> = self.pending.lock().unwrap().keys().copied().collect();
+        for id in pending {
+            self.complete(id, true);
+        }
+    }
+
+    /// Reports the end of a test/longop operation, only the first call for an id does.
+    fn complete(&self, id: u64, cancelled: bool) -> bool {
+        let Some(pending) = self.pending.lock().unwrap().remove(&id) else {
+            return false;
+        };
+        match pending.completion {
+            Completion::Signal(client_cmd_tx) => {
+                let status = status(id, if cancelled { "cancelled" } else { "done" });
+                let _ = client_cmd_tx.send_message(RpcMessage::new_signal(TEST_LONG_OP_MOUNT, SIG_DONE, Some(status)));
+            }
+            Completion::Response(client_cmd_tx, mut response) => {
+                if cancelled {
+                    response.set_error(RpcError::new(RpcErrorCode::MethodCallCancelled, &format!("Operation {id} cancelled")));
+                } else {
+                    response.set_result(status(id, "done"));
+                }
+                let _ = client_cmd_tx.send_message(response);
+            }
+        }
+        true
+    }
+
+    fn update(&self, id: u64, progress: u8) {
+        let mut ops = self.progress.lock().unwrap();
+        ops.insert(id, progress);
+        while ops.len() > MAX_FINISHED_OPS {
+            let Some(oldest) = ops.iter().find(|(_, progress)| **progress == 100).map(|(id, _)| *id) else {
+                break;
+            };
+            ops.remove(&oldest);
+        }
+    }
+}
+
+/// Starts an operation lasting `duration` and returns its id.
+pub(crate) fn start(app_state: AppState<State>, client_cmd_tx: ClientCommandSender, duration: Duration) -> u64 {
+    spawn(app_state, duration, move |app_state, id| {
+        let mut map = Map::new();
+        map.insert("id".into(), (id as i64).into());
+        map.insert("progress".into(), 100.into());
+        emit_chng(&app_state, &client_cmd_tx, LONG_OP_MOUNT, map.into());
+    })
+}
+
+/// Starts an operation for test/longop:run and returns the immediate `started` response,
+/// the `done` signal follows once the operation finished. Both carry the operation id,
+/// so that the caller can match them.
+pub(crate) fn run_detached(app_state: AppState<State>, client_cmd_tx: ClientCommandSender, duration: Duration) -> RpcValue {
+    let id = spawn_pending(app_state, duration, None, Completion::Signal(client_cmd_tx));
+    status(id, "started")
+}
+
+/// Starts an operation for test/longop:runDeferred, the response to the original request
+/// is held back until the operation finished.
+pub(crate) fn run_deferred(app_state: AppState<State>, client_cmd_tx: ClientCommandSender, request: &RpcMessage, duration: Duration) {
+    let response = request.prepare_response().unwrap_or_default();
+    spawn_pending(app_state, duration, Some(request_key(request)), Completion::Response(client_cmd_tx, response));
+}
+
+/// Cancels a test/longop operation: a deferred request gets a MethodCallCancelled error,
+/// a detached operation sends `done` with status `cancelled`. Returns false when the
+/// operation is unknown or already finished.
+pub(crate) async fn cancel(app_state: &AppState<State>, id: i64) -> bool {
+    let Ok(id) = u64::try_from(id) else {
+        return false;
+    };
+    if !app_state.long_ops.complete(id, true) {
+        return false;
+    }
+    let _ = app_state.tasks.cancel(&task_name(id)).await;
+    true
+}
+
+/// A repeated runDeferred request with the request id and caller ids of a pending one aborts it.
+/// The client library does not expose the SHV abort meta tag, so the repetition itself is the abort.
+/// The pending request is answered with the cancellation error, the abort request gets no other response.
+pub(crate) async fn abort(app_state: &AppState<State>, request: &RpcMessage) -> bool {
+    let key = request_key(request);
+    let id = app_state.long_ops.pending.lock().unwrap().iter()
+        .find(|(_, pending)| pending.request.as_ref() == Some(&key))
+        .map(|(id, _)| *id);
+    match id {
+        Some(id) => cancel(app_state, id as i64).await,
+        None => false,
+    }
+}
+
+fn status(id: u64, status: &str) -> RpcValue {
+    let mut map = Map::new();
+    map.insert("id".into(), (id as i64).into());
+    map.insert("status".into(), status.into());
+    map.into()
+}
+
+fn request_key(request: &RpcMessage) -> (i64, String) {
+    let caller_ids = request.tag(Tag::CallerIds as i32).map(RpcValue::to_cpon).unwrap_or_default();
+    (request.request_id().unwrap_or_default(), caller_ids)
+}
+
+fn task_name(id: u64) -> String {
+    format!("longOp{id}")
+}
+
+/// Runs a test/longop operation whose end is reported through `completion`.
+fn spawn_pending(app_state: AppState<State>, duration: Duration, request: Option<(i64, String)>, completion: Completion) -> u64 {
+    let id = app_state.long_ops.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+    app_state.long_ops.pending.lock().unwrap().insert(id, Pending { request, completion });
+    spawn_with_id(app_state, id, duration, |app_state, id| {
+        app_state.long_ops.complete(id, false);
+    });
+    id
+}
+
+/// Runs an operation as a task, `finished` is called once its progress reached 100.
+fn spawn(app_state: AppState<State>, duration: Duration, finished: impl FnOnce(AppState<State>, u64) + Send + 'static) -> u64 {
+    let id = app_state.long_ops.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+    spawn_with_id(app_state, id, duration, finished);
+    id
+}
+
+fn spawn_with_id(app_state: AppState<State>, id: u64, duration: Duration, finished: impl FnOnce(AppState<State>, u64) + Send + 'static) {
+    app_state.long_ops.update(id, 0);
+    let state = app_state.clone();
+    tasks::spawn(&app_state, &task_name(id), async move {
+        run(&state, id, duration).await;
+        finished(state, id);
+    });
+}
+
+async fn run(app_state: &State, id: u64, duration: Duration) {
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        let progress = (started.elapsed().as_secs_f64() / duration.as_secs_f64() * 100.) as u8;
+        app_state.long_ops.update(id, progress.min(99));
+        runtime::sleep(TICK.min(duration.saturating_sub(started.elapsed()))).await;
+    }
+    app_state.long_ops.update(id, 100);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress_of(state: &State, id: u64) -> i64 {
+        state.long_ops.progress(id as i64).unwrap().as_int()
+    }
+
+    #[test]
+    fn progress_runs_to_completion() {
+        let state = crate::test_state(&[]);
+        state.long_ops.update(1, 0);
+        let samples = runtime::block_on(async {
+            let op = run(&state, 1, Duration::from_millis(600));
+            let sample = async {
+                let mut samples = Vec::new();
+                for _ in 0..4 {
+                    runtime::sleep(Duration::from_millis(100)).await;
+                    samples.push(progress_of(&state, 1));
+                }
+                samples
+            };
+            futures::join!(op, sample).1
+        });
+        assert!(samples.windows(2).all(|pair| pair[0] <= pair[1]), "{samples:?}");
+        assert!(samples.iter().all(|progress| *progress < 100), "{samples:?}");
+        assert!(samples.last().is_some_and(|progress| *progress > 0), "{samples:?}");
+        assert_eq!(progress_of(&state, 1), 100);
+    }
+
+    #[test]
+    fn unknown_operation_is_rejected() {
+        let state = crate::test_state(&[]);
+        assert!(state.long_ops.progress(1).is_err());
+        assert!(state.long_ops.progress(-1).is_err());
+    }
+
+    #[test]
+    fn oldest_finished_operations_are_forgotten() {
+        let long_ops = LongOps::default();
+        long_ops.update(1, 50);
+        for id in 2..=MAX_FINISHED_OPS as u64 + 2 {
+            long_ops.update(id, 100);
+        }
+        assert_eq!(long_ops.progress(1).unwrap(), RpcValue::from(50));
+        assert!(long_ops.progress(2).is_err());
+        assert!(long_ops.progress(3).is_err());
+        assert_eq!(long_ops.progress(4).unwrap(), RpcValue::from(100));
+    }
+}
@@ -0,0 +1,65 @@
+//! Adversarial node trees under `test/ls` for broker tree browser and `ls` cache tests.
+//!
+//! - `--ls-wide N` mounts N children `test/ls/wide/0` .. `test/ls/wide/<N - 1>`, 10000 and
+//!   more give the extremely long `ls` results
+//! - `--ls-odd-names` mounts children of `test/ls/odd` whose names contain spaces, dots,
+//!   quotes, control and non-ASCII characters, glob characters, or are very long
+//!
+//! The method lists of the intermediate nodes (`test/ls`, `test/ls/wide`, ...) are empty
+//! apart from the mandatory `dir` and `ls` the client library adds. The client library
+//! keys mounted nodes by path, so duplicate child names cannot be produced.
+
+pub(crate) const LS_MOUNT: &str = "test/ls";
+
+fn odd_names() -> Vec<String> {
+    [
+        "with space",
+        " leading-space",
+        "trailing-space ",
+        "dot.ted",
+        ".hidden",
+        "..",
+        "quote\"d",
+        "apostrophe's",
+        "back\\slash",
+        "tab\tname",
+        "new\nline",
+        "star*",
+        "question?",
+        "colon:name",
+        "percent%2Fencoded",
+        "ünïcödé",
+        "名前",
+        "emoji-🔥",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .chain(["x".repeat(1024)])
+    .collect()
+}
+
+pub(crate) struct LsAnomalies {
+    wide: usize,
+    odd_names: bool,
+}
+
+impl LsAnomalies {
+    pub(crate) fn new(wide: usize, odd_names: bool) -> Self {
+        Self { wide, odd_names }
+    }
+
+    /// Leaf paths to mount.
+    pub(crate) fn paths(&self) -> Vec<String> {
+        let wide = (0..self.wide).map(|n| format!("{LS_MOUNT}/wide/{n}"));
+        let odd = self.odd_names.then(odd_names).unwrap_or_default().into_iter().map(|name| format!("{LS_MOUNT}/odd/{name}"));
+        wide.chain(odd).collect()
+    }
+}
+
+/// Name of the leaf node at `path`, returned by its `get`.
+pub(crate) fn leaf_name(path: &str) -> &str {
+    path.strip_prefix(LS_MOUNT)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .and_then(|rest| rest.split_once('/'))
+        .map_or(path, |(_, name)| name)
+}
@@ -0,0 +1,147 @@
+//! `state/map` node holding a Map, optionally validated against a `--map-schema`.
+//!
+//! The schema file is a CPON Map of key to SHV type name, e.g. `{"name": "String", "count": "Int"}`.
+//! Every listed key is required and must hold a value of that type, other keys are allowed.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::anyvalue::type_name;
+
+pub(crate) const MAP_MOUNT: &str = "state/map";
+
+pub(crate) const TYPE_NAMES: &[&str] = &[
+    "Null", "Bool", "Int", "UInt", "Double", "Decimal", "DateTime", "String", "Blob", "List", "Map", "IMap",
+];
+
+pub(crate) struct MapNode {
+    value: Mutex<Map>,
+    schema: Option<BTreeMap<String, String>>,
+}
+
+impl MapNode {
+    pub(crate) fn new(schema_file: Option<&str>) -> Result<Self, String> {
+        let schema = schema_file.map(load_schema).transpose()?;
+        Ok(Self { value: Default::default(), schema })
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        self.value.lock().unwrap().clone().into()
+    }
+
+    pub(crate) fn reset(&self) {
+        self.value.lock().unwrap().clear();
+    }
+
+    /// Replaces the whole map, returns the signal value if it changed.
+    /// `commit` runs once the write is known to change the map, its error rejects the write.
+    pub(crate) fn set(&self, param: Option<&RpcValue>, commit: impl FnOnce() -> Result<(), RpcError>) -> Result<Option<RpcValue>, RpcError> {
+        let Some(Value::Map(map)) = param.map(RpcValue::value) else {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected Map"));
+        };
+        self.store(|_| map.as_ref().clone(), commit)
+    }
+
+    /// Sets a single key, returns the signal value if the map changed. `commit` as in [`Self::set`].
+    pub(crate) fn set_key(&self, param: Option<&RpcValue>, commit: impl FnOnce() -> Result<(), RpcError>) -> Result<Option<RpcValue>, RpcError> {
+        let Some(Value::List(list)) = param.map(RpcValue::value) else {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected [key, value]"));
+        };
+        let [key, value] = list.as_slice() else {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected [key, value]"));
+        };
+        if !key.is_string() {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected [key, value]"));
+        }
+        self.store(|current| {
+            let mut map = current.clone();
+            map.insert(key.as_str().to_string(), value.clone());
+            map
+        }, commit)
+    }
+
+    /// The map resulting from `update` is validated as a whole, a rejected write leaves the node unchanged.
+    fn store(&self, update: impl FnOnce(&Map) -> Map, commit: impl FnOnce() -> Result<(), RpcError>) -> Result<Option<RpcValue>, RpcError> {
+        let mut current = self.value.lock().unwrap();
+        let map = update(&current);
+        self.validate(&map)?;
+        if *current == map {
+            return Ok(None);
+        }
+        commit()?;
+        *current = map.clone();
+        Ok(Some(map.into()))
+    }
+
+    fn validate(&self, map: &Map) -> Result<(), RpcError> {
+        let Some(schema) = &self.schema else {
+            return Ok(());
+        };
+        let errors: Vec<String> = schema.iter()
+            .filter_map(|(key, expected)| match map.get(key) {
+                None => Some(format!("missing key '{key}'")),
+                Some(value) if type_name(value) != expected.as_str() => {
+                    Some(format!("key '{key}' is {}, expected {expected}", type_name(value)))
+                }
+                Some(_) => None,
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Map does not match schema: {}", errors.join(", "))))
+        }
+    }
+}
+
+fn load_schema(path: &str) -> Result<BTreeMap<String, String>, String> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("Cannot read map schema {path}: {err}"))?;
+    let schema = RpcValue::from_cpon(&content).map_err(|err| format!("Invalid map schema {path}: {err}"))?;
+    let Value::Map(schema) = schema.value() else {
+        return Err(format!("Invalid map schema {path}: expected a Map of key to type name"));
+    };
+    schema.iter()
+        .map(|(key, ty)| match ty.value() {
+            Value::String(ty) if TYPE_NAMES.contains(&ty.as_str()) => Ok((key.clone(), ty.to_string())),
+            _ => Err(format!("Invalid map schema {path}: unknown type for key '{key}': {}", ty.to_cpon())),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema_node() -> MapNode {
+        let schema = [("name", "String"), ("count", "Int")].into_iter()
+            .map(|(key, ty)| (key.to_string(), ty.to_string()))
+            .collect();
+        MapNode { value: Default::default(), schema: Some(schema) }
+    }
+
+    fn map(entries: &[(&str, RpcValue)]) -> RpcValue {
+        entries.iter().map(|(key, value)| (key.to_string(), value.clone())).collect::<Map>().into()
+    }
+
+    #[test]
+    fn conforming_map_is_stored() {
+        let node = schema_node();
+        let value = map(&[("name", "pump".into()), ("count", 3.into()), ("extra", true.into())]);
+        assert_eq!(node.set(Some(&value), || Ok(())).unwrap(), Some(value.clone()));
+        assert_eq!(node.value(), value);
+    }
+
+    #[test]
+    fn non_conforming_map_is_rejected() {
+        let node = schema_node();
+        let value = map(&[("count", "three".into())]);
+        let err = node.set(Some(&value), || Ok(())).unwrap_err();
+        assert_eq!(err.code as i32, RpcErrorCode::InvalidParam as i32);
+        assert_eq!(err.message, "Map does not match schema: key 'count' is String, expected Int, missing key 'name'");
+        assert_eq!(node.value(), RpcValue::from(Map::new()));
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::*;
+use shvclient::AppState;
+use shvproto::rpcvalue::{IMap, Map, Value};
+use shvproto::{DateTime, RpcValue};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::signals::emit_chng;
+use crate::{runtime, State};
+
+pub(crate) const METRICS_MOUNT: &str = "status/metrics";
+pub(crate) const LATENCY_HISTOGRAM_MOUNT: &str = "status/latencyHistogram";
+pub(crate) const ERRORS_MOUNT: &str = "status/errors";
+pub(crate) const RESOURCES_MOUNT: &str = "status/resources";
+pub(crate) const REQUEST_RATE_MOUNT: &str = "status/requestRate";
+pub(crate) const METHOD_STATS_MOUNT: &str = "status/methodStats";
+/// Prefix of the metric names in the Prometheus text exposition format.
+pub(crate) const PROMETHEUS_PREFIX: &str = "shvbrokertestingdevice";
+
+/// Upper bounds of the latency histogram buckets, the last bucket is unbounded.
+const LATENCY_BUCKETS: [(Duration, &str); 4] = [
+    (Duration::from_millis(1), "<1ms"),
+    (Duration::from_millis(10), "<10ms"),
+    (Duration::from_millis(100), "<100ms"),
+    (Duration::from_secs(1), "<1s"),
+];
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    large_message_warn_bytes: Option<usize>,
+    pub(crate) mirror_cache_hits: AtomicU64,
+    pub(crate) mirror_cache_misses: AtomicU64,
+    pub(crate) signals_dropped: AtomicU64,
+    pub(crate) signals_sent: AtomicU64,
+    blocking_work_us: AtomicU64,
+    pub(crate) signal_queue_depth: AtomicU64,
+    max_message_bytes: AtomicU64,
+    total_bytes_sent: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn new(large_message_warn_bytes: Option<usize>) -> Self {
+        Self { large_message_warn_bytes, ..Default::default() }
+    }
+
+    pub(crate) fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Accounts an outgoing signal or response of the given (estimated) size.
+    pub(crate) fn record_message(&self, size: usize) {
+        self.total_bytes_sent.fetch_add(size as u64, Ordering::Relaxed);
+        self.max_message_bytes.fetch_max(size as u64, Ordering::Relaxed);
+        if self.large_message_warn_bytes.is_some_and(|limit| size > limit) {
+            warn!("Sending large message of about {size} bytes");
+        }
+    }
+
+    pub(crate) fn record_blocking_work(&self, spent: Duration) {
+        self.blocking_work_us.fetch_add(spent.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn counters(&self) -> [(&'static str, i64); 8] {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as i64;
+        [
+            ("mirrorCacheHits", load(&self.mirror_cache_hits)),
+            ("mirrorCacheMisses", load(&self.mirror_cache_misses)),
+            ("signalsDropped", load(&self.signals_dropped)),
+            ("signalsSent", load(&self.signals_sent)),
+            ("maxMessageBytes", load(&self.max_message_bytes)),
+            ("totalBytesSent", load(&self.total_bytes_sent)),
+            ("blockingWorkMs", load(&self.blocking_work_us) / 1000),
+            ("signalQueueDepth", load(&self.signal_queue_depth)),
+        ]
+    }
+
+    /// The counters in the Prometheus text exposition format, shared by `export` and
+    /// the `metrics-http` endpoint.
+    pub(crate) fn prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in self.counters() {
+            let (kind, help) = describe(name);
+            let name = format!("{PROMETHEUS_PREFIX}_{}", snake_case(name));
+            write_prometheus_header(&mut out, &name, kind, help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+        out
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let map: Map = self.counters().into_iter().map(|(name, value)| (name.to_string(), value.into())).collect();
+        map.into()
+    }
+
+    /// Metrics in the requested format: `shv` (the Map of `get`, default), `json` or
+    /// `prometheus` (text exposition format), the latter two as a String.
+    pub(crate) fn export(&self, format: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        let format = match format.map(RpcValue::value) {
+            None | Some(Value::Null) => "shv",
+            Some(Value::String(format)) => format.as_str(),
+            _ => return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected format name")),
+        };
+        match format {
+            "shv" => Ok(self.value()),
+            "json" => {
+                let fields: Vec<String> = self.counters().iter().map(|(name, value)| format!("\"{name}\":{value}")).collect();
+                Ok(format!("{{{}}}", fields.join(",")).into())
+            }
+            "prometheus" => Ok(self.prometheus().into()),
+            _ => Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Unknown metrics format '{format}', expected shv, json or prometheus"))),
+        }
+    }
+}
+
+/// Prometheus metric type and help text of a counter of [`Metrics::counters`].
+fn describe(name: &str) -> (&'static str, &'static str) {
+    match name {
+        "mirrorCacheHits" => ("counter", "Mirrored values served from the cache."),
+        "mirrorCacheMisses" => ("counter", "Mirrored values fetched from the remote node."),
+        "signalsDropped" => ("counter", "Signals dropped by a full queue or a lost connection."),
+        "signalsSent" => ("counter", "Signals sent."),
+        "maxMessageBytes" => ("gauge", "Size of the largest message sent."),
+        "totalBytesSent" => ("counter", "Bytes of all messages sent."),
+        "blockingWorkMs" => ("counter", "Milliseconds spent in blocking work."),
+        "signalQueueDepth" => ("gauge", "Signals waiting in the queue."),
+        _ => ("untyped", ""),
+    }
+}
+
+/// Writes the `# HELP` and `# TYPE` lines preceding the samples of metric `name`.
+pub(crate) fn write_prometheus_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn snake_case(name: &str) -> String {
+    name.chars().fold(String::new(), |mut out, c| {
+        if c.is_ascii_uppercase() {
+            out.push('_');
+        }
+        out.push(c.to_ascii_lowercase());
+        out
+    })
+}
+
+/// Handler execution times, covers artificial delays as well as real processing.
+/// Delayed responses sent from a spawned task are accounted up to the spawn only.
+#[derive(Default)]
+pub(crate) struct LatencyHistogram {
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    total_us: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let bucket = LATENCY_BUCKETS.iter()
+            .position(|(bound, _)| elapsed < *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        for count in &self.counts {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.total_us.store(0, Ordering::Relaxed);
+    }
+
+    /// Count per bucket upper bound, the unbounded last bucket has None.
+    pub(crate) fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        let bounds = LATENCY_BUCKETS.iter().map(|(bound, _)| Some(*bound)).chain([None]);
+        bounds.zip(&self.counts).map(|(bound, count)| (bound, count.load(Ordering::Relaxed))).collect()
+    }
+
+    /// Sum of all recorded latencies.
+    pub(crate) fn total(&self) -> Duration {
+        Duration::from_micros(self.total_us.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let labels = LATENCY_BUCKETS.iter().map(|(_, label)| *label).chain([">=1s"]);
+        let map: Map = labels.zip(&self.counts)
+            .map(|(label, count)| (label.to_string(), (count.load(Ordering::Relaxed) as i64).into()))
+            .collect();
+        map.into()
+    }
+}
+
+/// Number of error responses per RpcError code. Errors of responses sent from a
+/// spawned task (e.g. bench/emitter:start) are not counted.
+#[derive(Default)]
+pub(crate) struct ErrorCounts {
+    counts: Mutex<BTreeMap<i32, u64>>,
+}
+
+impl ErrorCounts {
+    pub(crate) fn record(&self, err: &RpcError) {
+        *self.counts.lock().unwrap().entry(err.code as i32).or_default() += 1;
+    }
+
+    pub(crate) fn reset(&self) {
+        self.counts.lock().unwrap().clear();
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let map: IMap = self.counts.lock().unwrap().iter()
+            .map(|(code, count)| (*code, (*count as i64).into()))
+            .collect();
+        map.into()
+    }
+}
+
+/// Process resource usage. The resident set size is read from `/proc` assuming
+/// 4 KiB pages and is null on other platforms.
+pub(crate) fn resources(leaked_bytes: usize) -> RpcValue {
+    let rss_bytes = std::fs::read_to_string("/proc/self/statm").ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<i64>().ok())
+        .map(|pages| RpcValue::from(pages * 4096))
+        .unwrap_or_default();
+    let mut map = Map::new();
+    map.insert("rssBytes".into(), rss_bytes);
+    map.insert("leakedBytes".into(), (leaked_bytes as i64).into());
+    map.into()
+}
+
+/// Handled requests per second, averaged over a sliding window. Until the window
+/// has passed since the start the average is over the time elapsed so far.
+pub(crate) struct RequestRate {
+    window: Duration,
+    started: Instant,
+    requests: Mutex<VecDeque<Instant>>,
+}
+
+impl RequestRate {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self { window, started: Instant::now(), requests: Default::default() }
+    }
+
+    pub(crate) fn record(&self) {
+        self.record_at(Instant::now());
+    }
+
+    fn record_at(&self, now: Instant) {
+        let mut requests = self.requests.lock().unwrap();
+        self.expire(&mut requests, now);
+        requests.push_back(now);
+    }
+
+    fn expire(&self, requests: &mut VecDeque<Instant>, now: Instant) {
+        while requests.front().is_some_and(|time| now.duration_since(*time) > self.window) {
+            requests.pop_front();
+        }
+    }
+
+    pub(crate) fn value(&self) -> f64 {
+        self.value_at(Instant::now())
+    }
+
+    fn value_at(&self, now: Instant) -> f64 {
+        let mut requests = self.requests.lock().unwrap();
+        self.expire(&mut requests, now);
+        let span = self.window.min(now.duration_since(self.started));
+        if requests.is_empty() || span.is_zero() {
+            return 0.;
+        }
+        requests.len() as f64 / span.as_secs_f64()
+    }
+}
+
+pub(crate) async fn emit_request_rate(app_state: AppState<State>, interval: Duration) {
+    loop {
+        runtime::sleep(interval).await;
+        if let Some(client_cmd_tx) = app_state.connection.client_cmd_tx() {
+            emit_chng(&app_state, &client_cmd_tx, REQUEST_RATE_MOUNT, app_state.request_rate.value().into());
+        }
+    }
+}
+
+/// Call count and time of the last call per path and method, sorted by path and method.
+#[derive(Default)]
+pub(crate) struct MethodStats {
+    calls: Mutex<BTreeMap<(String, String), (u64, DateTime)>>,
+}
+
+impl MethodStats {
+    pub(crate) fn record(&self, path: &str, method: &str, time: DateTime) {
+        let mut calls = self.calls.lock().unwrap();
+        let entry = calls.entry((path.to_string(), method.to_string())).or_insert((0, time));
+        entry.0 += 1;
+        entry.1 = time;
+    }
+
+    pub(crate) fn reset(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+
+    /// `(path, method, count)` of every called method.
+    pub(crate) fn counts(&self) -> Vec<(String, String, u64)> {
+        self.calls.lock().unwrap().iter().map(|((path, method), (count, _))| (path.clone(), method.clone(), *count)).collect()
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let list: Vec<RpcValue> = self.calls.lock().unwrap().iter()
+            .map(|((path, method), (count, last))| {
+                let mut map = Map::new();
+                map.insert("path".into(), path.as_str().into());
+                map.insert("method".into(), method.as_str().into());
+                map.insert("count".into(), (*count as i64).into());
+                map.insert("lastTs".into(), (*last).into());
+                RpcValue::from(map)
+            })
+            .collect();
+        list.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_export_describes_every_counter() {
+        let metrics = Metrics::default();
+        Metrics::inc(&metrics.signals_sent);
+        metrics.record_message(42);
+        let exported = metrics.export(Some(&"prometheus".into())).unwrap();
+        let lines: Vec<&str> = exported.as_str().lines().collect();
+        for expected in [
+            "# HELP shvbrokertestingdevice_signals_sent Signals sent.",
+            "# TYPE shvbrokertestingdevice_signals_sent counter",
+            "shvbrokertestingdevice_signals_sent 1",
+            "# TYPE shvbrokertestingdevice_max_message_bytes gauge",
+            "shvbrokertestingdevice_max_message_bytes 42",
+            "shvbrokertestingdevice_total_bytes_sent 42",
+            "shvbrokertestingdevice_signals_dropped 0",
+        ] {
+            assert!(lines.contains(&expected), "{expected}");
+        }
+        assert_eq!(lines.len(), 3 * metrics.counters().len());
+    }
+
+    #[test]
+    fn unknown_export_format_is_rejected() {
+        assert!(Metrics::default().export(Some(&"xml".into())).is_err());
+    }
+
+    #[test]
+    fn method_stats_count_each_method() {
+        let state = crate::test_state(&[]);
+        let call = |method: &str| {
+            crate::dispatch::finish(&state, crate::NUMBER_MOUNT, method, Instant::now(), Some(Ok(().into())), None);
+        };
+        call("get");
+        call("set");
+        call("get");
+        let calls = |path: &str, method: &str, count: u64| (path.to_string(), method.to_string(), count);
+        assert_eq!(state.method_stats.counts(), [calls(crate::NUMBER_MOUNT, "get", 2), calls(crate::NUMBER_MOUNT, "set", 1)]);
+        let Value::List(list) = state.method_stats.value().value().clone() else {
+            panic!("status/methodStats is not a List");
+        };
+        let Value::Map(first) = list[0].value() else {
+            panic!("method stats entry is not a Map");
+        };
+        assert_eq!(first.get("count"), Some(&RpcValue::from(2)));
+        assert!(matches!(first.get("lastTs").map(RpcValue::value), Some(Value::DateTime(_))));
+        state.method_stats.reset();
+        assert!(state.method_stats.counts().is_empty());
+    }
+
+    #[test]
+    fn request_rate_averages_over_uptime_until_window_passed() {
+        let rate = RequestRate::new(Duration::from_secs(10));
+        let start = rate.started;
+        for ms in [100, 500, 900, 1500] {
+            rate.record_at(start + Duration::from_millis(ms));
+        }
+        assert_eq!(rate.value_at(start + Duration::from_secs(2)), 2.);
+        assert_eq!(rate.value_at(start + Duration::from_secs(10)), 0.4);
+    }
+
+    #[test]
+    fn request_rate_forgets_requests_outside_window() {
+        let rate = RequestRate::new(Duration::from_secs(1));
+        let start = rate.started;
+        for ms in [100, 200, 2100, 2500] {
+            rate.record_at(start + Duration::from_millis(ms));
+        }
+        assert_eq!(rate.value_at(start + Duration::from_secs(3)), 2.);
+        assert_eq!(rate.value_at(start + Duration::from_secs(5)), 0.);
+    }
+}
@@ -0,0 +1,95 @@
+//! HTTP listener serving device statistics in the Prometheus text exposition format
+//! on `GET /metrics`, enabled by the `metrics-http` feature and `--metrics-listen`.
+//!
+//! Only as much HTTP as a scraper needs is implemented: one request per connection,
+//! the request body is ignored and the connection is closed after the response.
+
+use std::fmt::Write;
+
+use async_std::io::prelude::{ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use futures::StreamExt;
+use log::*;
+use shvclient::AppState;
+
+use crate::metrics::{write_prometheus_header as header, PROMETHEUS_PREFIX as PREFIX};
+use crate::{runtime, State};
+
+const MAX_REQUEST_HEAD_BYTES: usize = 8192;
+
+pub(crate) async fn serve(app_state: AppState<State>, address: String) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Cannot listen for metrics on {address}: {err}");
+            return;
+        }
+    };
+    info!("Serving metrics on http://{address}/metrics");
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                runtime::spawn(respond(app_state.clone(), stream));
+            }
+            Err(err) => warn!("Metrics connection failed: {err}"),
+        }
+    }
+}
+
+async fn respond(app_state: AppState<State>, mut stream: TcpStream) {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+        if head.len() > MAX_REQUEST_HEAD_BYTES {
+            return;
+        }
+    }
+    let request_line = String::from_utf8_lossy(&head).lines().next().unwrap_or_default().to_string();
+    let (status, body) = match request_line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["GET", "/metrics", ..] => ("200 OK", exposition(&app_state)),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        debug!("Cannot send metrics response: {err}");
+    }
+}
+
+/// Counters of `status/metrics`, the reconnect count, the handler latency histogram
+/// of `status/latencyHistogram` and the per-method call counts of `status/methodStats`.
+fn exposition(state: &State) -> String {
+    let mut out = state.metrics.prometheus();
+    header(&mut out, &format!("{PREFIX}_reconnects_total"), "counter", "Reconnects to the broker.");
+    let _ = writeln!(out, "{PREFIX}_reconnects_total {}", state.connection.reconnects());
+
+    let calls = state.method_stats.counts();
+    header(&mut out, &format!("{PREFIX}_requests_total"), "counter", "Requests handled.");
+    let _ = writeln!(out, "{PREFIX}_requests_total {}", calls.iter().map(|(_, _, count)| count).sum::<u64>());
+    header(&mut out, &format!("{PREFIX}_calls_total"), "counter", "Requests handled per path and method.");
+    for (path, method, count) in &calls {
+        let _ = writeln!(out, "{PREFIX}_calls_total{{path=\"{}\",method=\"{}\"}} {count}", escape(path), escape(method));
+    }
+
+    header(&mut out, &format!("{PREFIX}_response_latency_seconds"), "histogram", "Request handler execution time.");
+    let mut cumulative = 0;
+    for (bound, count) in state.latency_histogram.buckets() {
+        cumulative += count;
+        let le = bound.map_or("+Inf".to_string(), |bound| bound.as_secs_f64().to_string());
+        let _ = writeln!(out, "{PREFIX}_response_latency_seconds_bucket{{le=\"{le}\"}} {cumulative}");
+    }
+    let _ = writeln!(out, "{PREFIX}_response_latency_seconds_sum {}", state.latency_histogram.total().as_secs_f64());
+    let _ = writeln!(out, "{PREFIX}_response_latency_seconds_count {cumulative}");
+    out
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,137 @@
+//! Local nodes forwarding `get` and `set` to other paths on the broker.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use shvclient::ClientCommandSender;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::metrics::Metrics;
+use crate::{rpc, State};
+
+#[derive(Default)]
+pub(crate) struct Mirrors {
+    remotes: BTreeMap<String, String>,
+    cache_ttl: Option<Duration>,
+    cache: Mutex<HashMap<String, (Instant, RpcValue)>>,
+    error_mode: MirrorErrorMode,
+}
+
+/// How errors returned by the remote node are relayed to the local caller.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub(crate) enum MirrorErrorMode {
+    /// Unchanged code and message.
+    #[default]
+    Passthrough,
+    /// Unchanged code, the message names the mirror that failed.
+    Wrap,
+}
+
+impl Mirrors {
+    /// Parses `--mirror` specs in the `<local path>=<remote path>` form.
+    pub(crate) fn new(specs: &[String], cache_ttl: Option<Duration>, error_mode: MirrorErrorMode) -> Result<Self, String> {
+        let mut remotes = BTreeMap::new();
+        for spec in specs {
+            let Some((local, remote)) = spec.split_once('=') else {
+                return Err(format!("Invalid mirror spec '{spec}', expected <local path>=<remote path>"));
+            };
+            remotes.insert(local.to_string(), remote.to_string());
+        }
+        Ok(Self { remotes, cache_ttl, cache: Default::default(), error_mode })
+    }
+
+    pub(crate) fn local_paths(&self) -> impl Iterator<Item = &String> {
+        self.remotes.keys()
+    }
+
+    pub(crate) fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    fn remote(&self, local: Option<&str>) -> Result<&str, RpcError> {
+        local.and_then(|local| self.remotes.get(local))
+            .map(String::as_str)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::MethodNotFound, "Not a mirror node"))
+    }
+
+    fn relay_error(&self, local: &str, remote: &str, err: RpcError) -> RpcError {
+        match self.error_mode {
+            MirrorErrorMode::Passthrough => err,
+            MirrorErrorMode::Wrap => RpcError::new(err.code, &format!("Mirror {local} -> {remote} failed: {}", err.message)),
+        }
+    }
+
+    fn cached(&self, local: &str) -> Option<RpcValue> {
+        let ttl = self.cache_ttl?;
+        let cache = self.cache.lock().unwrap();
+        cache.get(local)
+            .filter(|(fetched, _)| fetched.elapsed() < ttl)
+            .map(|(_, value)| value.clone())
+    }
+}
+
+pub(crate) async fn get(state: &State, client_cmd_tx: &ClientCommandSender, local: Option<&str>) -> Result<RpcValue, RpcError> {
+    let mirrors = &state.mirrors;
+    let remote = mirrors.remote(local)?;
+    let local = local.unwrap_or_default();
+    if let Some(value) = mirrors.cached(local) {
+        Metrics::inc(&state.metrics.mirror_cache_hits);
+        return Ok(value);
+    }
+    if mirrors.cache_ttl.is_some() {
+        Metrics::inc(&state.metrics.mirror_cache_misses);
+    }
+    let value = rpc::call(client_cmd_tx, remote, "get", None).await
+        .map_err(|err| mirrors.relay_error(local, remote, err))?;
+    if mirrors.cache_ttl.is_some() {
+        mirrors.cache.lock().unwrap().insert(local.to_string(), (Instant::now(), value.clone()));
+    }
+    Ok(value)
+}
+
+pub(crate) async fn set(state: &State, client_cmd_tx: &ClientCommandSender, local: Option<&str>, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+    let mirrors = &state.mirrors;
+    let remote = mirrors.remote(local)?;
+    let local = local.unwrap_or_default();
+    mirrors.cache.lock().unwrap().remove(local);
+    rpc::call(client_cmd_tx, remote, "set", param.cloned()).await
+        .map_err(|err| mirrors.relay_error(local, remote, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirrors(error_mode: MirrorErrorMode) -> Mirrors {
+        Mirrors::new(&["mirror/number=remote/number".to_string()], None, error_mode).unwrap()
+    }
+
+    fn remote_error() -> RpcError {
+        RpcError::new(RpcErrorCode::PermissionDenied, "No write access")
+    }
+
+    #[test]
+    fn passthrough_relays_remote_error_unchanged() {
+        let mirrors = mirrors(MirrorErrorMode::Passthrough);
+        let err = mirrors.relay_error("mirror/number", "remote/number", remote_error());
+        assert_eq!(err.code as i32, RpcErrorCode::PermissionDenied as i32);
+        assert_eq!(err.message, "No write access");
+    }
+
+    #[test]
+    fn wrap_keeps_code_and_names_mirror() {
+        let mirrors = mirrors(MirrorErrorMode::Wrap);
+        let err = mirrors.relay_error("mirror/number", "remote/number", remote_error());
+        assert_eq!(err.code as i32, RpcErrorCode::PermissionDenied as i32);
+        assert_eq!(err.message, "Mirror mirror/number -> remote/number failed: No write access");
+    }
+
+    #[test]
+    fn unknown_local_path_is_not_a_mirror() {
+        let mirrors = mirrors(MirrorErrorMode::Passthrough);
+        assert_eq!(mirrors.remote(Some("mirror/number")).unwrap(), "remote/number");
+        assert_eq!(mirrors.remote(Some("mirror/other")).unwrap_err().code as i32, RpcErrorCode::MethodNotFound as i32);
+    }
+}
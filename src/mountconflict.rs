@@ -0,0 +1,212 @@
+//! Second connection competing for the mount point or device id of the device, to
+//! test how the broker resolves the conflict.
+//!
+//! With `--mount-conflict <delay>` the device opens the competing connection once,
+//! `delay` after it first connected; `history/mountConflicts:start` opens another one.
+//! The competitor logs in with the same options, either with the same mount point and
+//! no device id or with the same device id and no mount point (`--mount-conflict-by`),
+//! and mounts no nodes. It stays connected for [`OBSERVE`] and is then closed.
+//!
+//! Each outcome is a Map with the `time`, `by` (`mount` or `deviceId`) and the requested
+//! `target`, the `competitor` result (`connected`, `dropped` when the broker closed it
+//! while observed, `rejected` or `timeout`), `error` of a rejected competitor, its
+//! `mountPoint` as reported by `.broker/currentClient:info`, and `firstEvicted`, true
+//! when the device's own connection was lost meanwhile.
+
+use std::collections::VecDeque;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::{select, Either};
+use log::*;
+use shvclient::appnodes::{DotAppNode, DotDeviceNode};
+use shvclient::{AppState, ClientCommandSender, ClientEvent};
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::{rpc, runtime, tasks, State};
+
+pub(crate) const MOUNT_CONFLICTS_MOUNT: &str = "history/mountConflicts";
+const MOUNT_CONFLICT_TASK: &str = "mountConflict";
+const MAX_OUTCOMES: usize = 100;
+/// How long the competing connection may take to log in.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long both connections are kept up before the competitor is closed.
+const OBSERVE: Duration = Duration::from_secs(3);
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub(crate) enum ConflictBy {
+    /// Same mount point, no device id.
+    #[default]
+    Mount,
+    /// Same device id, no mount point.
+    DeviceId,
+}
+
+impl ConflictBy {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConflictBy::Mount => "mount",
+            ConflictBy::DeviceId => "deviceId",
+        }
+    }
+}
+
+pub(crate) struct MountConflicts {
+    delay: Option<Duration>,
+    by: ConflictBy,
+    started: AtomicBool,
+    observing: AtomicBool,
+    evicted: AtomicBool,
+    outcomes: Mutex<VecDeque<RpcValue>>,
+}
+
+enum Competitor {
+    Connected(ClientCommandSender),
+    Ended(Result<(), String>),
+}
+
+impl MountConflicts {
+    pub(crate) fn new(delay: Option<&str>, by: ConflictBy) -> Result<Self, String> {
+        let delay = delay.map(|delay| duration_str::parse(delay).map_err(|err| format!("Invalid mount conflict delay: {err}"))).transpose()?;
+        Ok(Self {
+            delay,
+            by,
+            started: Default::default(),
+            observing: Default::default(),
+            evicted: Default::default(),
+            outcomes: Default::default(),
+        })
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let outcomes: Vec<RpcValue> = self.outcomes.lock().unwrap().iter().cloned().collect();
+        outcomes.into()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.outcomes.lock().unwrap().clear();
+    }
+
+    fn record(&self, outcome: Map) {
+        let mut outcomes = self.outcomes.lock().unwrap();
+        if outcomes.len() == MAX_OUTCOMES {
+            outcomes.pop_front();
+        }
+        outcomes.push_back(outcome.into());
+    }
+}
+
+/// Starts the `--mount-conflict` competitor after the first successful connect.
+pub(crate) fn on_connected(app_state: &AppState<State>) {
+    let conflicts = &app_state.mount_conflicts;
+    if let Some(delay) = conflicts.delay {
+        if !conflicts.started.swap(true, Ordering::SeqCst) {
+            tasks::spawn(app_state, MOUNT_CONFLICT_TASK, compete(app_state.clone(), delay));
+        }
+    }
+}
+
+pub(crate) fn on_disconnected(state: &State) {
+    if state.mount_conflicts.observing.load(Ordering::SeqCst) {
+        state.mount_conflicts.evicted.store(true, Ordering::SeqCst);
+    }
+}
+
+/// history/mountConflicts:start, opens a competing connection after `delay`.
+pub(crate) fn start(app_state: &AppState<State>, delay: Duration) -> Result<(), RpcError> {
+    if app_state.mount_conflicts.observing.load(Ordering::SeqCst) {
+        return Err(RpcError::new(RpcErrorCode::MethodCallException, "A competing connection is already open"));
+    }
+    tasks::spawn(app_state, MOUNT_CONFLICT_TASK, compete(app_state.clone(), delay));
+    Ok(())
+}
+
+async fn compete(app_state: AppState<State>, delay: Duration) {
+    runtime::sleep(delay).await;
+    let conflicts = &app_state.mount_conflicts;
+    let by = conflicts.by;
+    let mut config = app_state.client_config.lock().unwrap().clone();
+    config.reconnect_interval = None;
+    let target = match by {
+        ConflictBy::Mount => {
+            config.device_id = None;
+            config.mount.clone()
+        }
+        ConflictBy::DeviceId => {
+            config.mount = None;
+            config.device_id.clone()
+        }
+    };
+    let mut outcome = Map::new();
+    outcome.insert("time".into(), app_state.clock.now().into());
+    outcome.insert("by".into(), by.as_str().into());
+    outcome.insert("target".into(), target.as_deref().map(RpcValue::from).unwrap_or_default());
+    let Some(target) = target else {
+        warn!("Mount conflict: no {} configured to compete for", by.as_str());
+        outcome.insert("competitor".into(), "rejected".into());
+        outcome.insert("error".into(), format!("No {} configured", by.as_str()).into());
+        conflicts.record(outcome);
+        return;
+    };
+    info!("Mount conflict: opening a second connection with {} {target}", by.as_str());
+    conflicts.evicted.store(false, Ordering::SeqCst);
+    conflicts.observing.store(true, Ordering::SeqCst);
+
+    let (events_tx, events_rx) = async_std::channel::unbounded();
+    let init_events_tx = events_tx.clone();
+    let init_task = move |client_cmd_tx: ClientCommandSender, mut client_evt_rx: shvclient::ClientEventsReceiver| {
+        runtime::spawn(async move {
+            if let Ok(ClientEvent::Connected(_)) = client_evt_rx.wait_for_event().await {
+                let _ = init_events_tx.send(Competitor::Connected(client_cmd_tx)).await;
+            }
+        });
+    };
+    let client = shvclient::Client::new_device(DotAppNode::new("shvbrokertestingdevice"), DotDeviceNode::new("shvbrokertestingdevice", "0.1", Some("00000".into())))
+        .with_app_state(app_state.clone());
+    let client_task = runtime::spawn(async move {
+        let result = client.run_with_init(&config, init_task).await.map_err(|err| err.to_string());
+        let _ = events_tx.send(Competitor::Ended(result)).await;
+    });
+
+    let first = match select(pin!(events_rx.recv()), pin!(runtime::sleep(CONNECT_TIMEOUT))).await {
+        Either::Left((Ok(event), _)) => Some(event),
+        _ => None,
+    };
+    let competitor = match first {
+        Some(Competitor::Connected(client_cmd_tx)) => {
+            let mount_point = match rpc::call(&client_cmd_tx, ".broker/currentClient", "info", None).await {
+                Ok(info) => match info.value() {
+                    Value::Map(info) => info.get("mountPoint").cloned().unwrap_or_default(),
+                    _ => RpcValue::null(),
+                },
+                Err(err) => {
+                    warn!("Mount conflict: cannot read the competitor's client info: {err}");
+                    RpcValue::null()
+                }
+            };
+            outcome.insert("mountPoint".into(), mount_point);
+            let ended = select(pin!(events_rx.recv()), pin!(runtime::sleep(OBSERVE))).await;
+            client_cmd_tx.terminate_client();
+            match ended {
+                Either::Left(_) => "dropped",
+                Either::Right(_) => "connected",
+            }
+        }
+        Some(Competitor::Ended(result)) => {
+            outcome.insert("error".into(), result.err().unwrap_or_else(|| "Connection closed".to_string()).into());
+            "rejected"
+        }
+        None => "timeout",
+    };
+    client_task.cancel().await;
+    conflicts.observing.store(false, Ordering::SeqCst);
+    let evicted = conflicts.evicted.load(Ordering::SeqCst);
+    info!("Mount conflict: competitor {competitor}, first connection {}", if evicted { "evicted" } else { "kept" });
+    outcome.insert("competitor".into(), competitor.into());
+    outcome.insert("firstEvicted".into(), evicted.into());
+    conflicts.record(outcome);
+}
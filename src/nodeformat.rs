@@ -0,0 +1,133 @@
+//! Per-node serialization override for mixed-format tests.
+//!
+//! The client library encodes every message of a connection in the same format, so
+//! a node cannot answer in a different one. As the nearest approximation, values of
+//! a node with a format set are sent as `{"format": <format>, "data": Blob}` with the
+//! value encoded in that format. This applies to `get` responses and to signals.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+pub(crate) const NODE_FORMATS_MOUNT: &str = "status/nodeFormats";
+
+#[derive(Clone, Copy)]
+enum Format {
+    Cpon,
+    ChainPack,
+}
+
+impl Format {
+    fn as_str(self) -> &'static str {
+        match self {
+            Format::Cpon => "cpon",
+            Format::ChainPack => "chainpack",
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct NodeFormats {
+    formats: Mutex<BTreeMap<String, Format>>,
+}
+
+impl NodeFormats {
+    /// Sets the format of a node, `default` removes the override.
+    pub(crate) fn set(&self, param: Option<&RpcValue>) -> Result<(), RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [path, format]");
+        let Some(Value::List(list)) = param.map(RpcValue::value) else {
+            return Err(invalid());
+        };
+        let [path, format] = list.as_slice() else {
+            return Err(invalid());
+        };
+        if !path.is_string() || !format.is_string() {
+            return Err(invalid());
+        }
+        let format = match format.as_str() {
+            "default" => None,
+            "cpon" => Some(Format::Cpon),
+            "chainpack" => Some(Format::ChainPack),
+            format => {
+                return Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Unknown format '{format}', expected default, cpon or chainpack")));
+            }
+        };
+        let mut formats = self.formats.lock().unwrap();
+        match format {
+            Some(format) => formats.insert(path.as_str().to_string(), format),
+            None => formats.remove(path.as_str()),
+        };
+        Ok(())
+    }
+
+    pub(crate) fn clear(&self) {
+        self.formats.lock().unwrap().clear();
+    }
+
+    pub(crate) fn value(&self) -> RpcValue {
+        let map: Map = self.formats.lock().unwrap().iter()
+            .map(|(path, format)| (path.clone(), format.as_str().into()))
+            .collect();
+        map.into()
+    }
+
+    pub(crate) fn wrap(&self, path: &str, value: RpcValue) -> RpcValue {
+        let Some(format) = self.formats.lock().unwrap().get(path).copied() else {
+            return value;
+        };
+        let data = match format {
+            Format::Cpon => value.to_cpon().into_bytes(),
+            Format::ChainPack => value.to_chainpack(),
+        };
+        let mut map = Map::new();
+        map.insert("format".into(), format.as_str().into());
+        map.insert("data".into(), RpcValue::from(data));
+        map.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(formats: &NodeFormats, path: &str, format: &str) -> Result<(), RpcError> {
+        let param: RpcValue = vec![RpcValue::from(path), RpcValue::from(format)].into();
+        formats.set(Some(&param))
+    }
+
+    fn reported(entries: &[(&str, &str)]) -> RpcValue {
+        entries.iter().map(|(path, format)| (path.to_string(), RpcValue::from(*format))).collect::<Map>().into()
+    }
+
+    #[test]
+    fn toggled_format_is_reported() {
+        let formats = NodeFormats::default();
+        set(&formats, "state/number", "cpon").unwrap();
+        assert_eq!(formats.value(), reported(&[("state/number", "cpon")]));
+        set(&formats, "state/number", "chainpack").unwrap();
+        set(&formats, "state/text", "cpon").unwrap();
+        assert_eq!(formats.value(), reported(&[("state/number", "chainpack"), ("state/text", "cpon")]));
+        set(&formats, "state/number", "default").unwrap();
+        assert_eq!(formats.value(), reported(&[("state/text", "cpon")]));
+        assert!(set(&formats, "state/number", "json").is_err());
+    }
+
+    #[test]
+    fn wrapped_value_decodes_back() {
+        let formats = NodeFormats::default();
+        assert_eq!(formats.wrap("state/number", 42.into()), RpcValue::from(42));
+        set(&formats, "state/number", "cpon").unwrap();
+        let wrapped = formats.wrap("state/number", 42.into());
+        let Value::Map(map) = wrapped.value() else {
+            panic!("wrapped value is not a Map");
+        };
+        assert_eq!(map.get("format"), Some(&RpcValue::from("cpon")));
+        let Some(Value::Blob(data)) = map.get("data").map(RpcValue::value) else {
+            panic!("wrapped data is not a Blob");
+        };
+        assert_eq!(RpcValue::from_cpon(std::str::from_utf8(data).unwrap()).unwrap(), RpcValue::from(42));
+    }
+}
@@ -0,0 +1,124 @@
+//! Checks request parameters against the parameter signature a method declares in
+//! `device_node!`, before its handler runs.
+//!
+//! A signature is a type name (`Int`, `String`, `Map`, ...), `RpcValue` for any value,
+//! a fixed size list of signatures such as `[String, Int]`, or alternatives separated
+//! by `|` such as `Bool|[Bool, Int]`. An `Int` parameter also
+//! accepts a UInt that fits and a `Double` accepts integers. A missing parameter is the
+//! same as Null, it only passes a signature accepting Null, so an optional parameter is
+//! declared as `Null|Int`. The parameter of a `Null` signature is ignored, SHV getters
+//! may be passed a `maxAge`. Value ranges stay with the handlers.
+
+use shvproto::rpcvalue::Value;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::anyvalue::type_name;
+
+pub(crate) fn check(signature: &str, param: Option<&RpcValue>) -> Result<(), RpcError> {
+    if signature == "Null" {
+        return Ok(());
+    }
+    let null = RpcValue::null();
+    matches(signature, param.unwrap_or(&null))
+        .map_err(|mismatch| RpcError::new(RpcErrorCode::InvalidParam, &format!("Invalid param, expected {signature}: {mismatch}")))
+}
+
+/// Returns a description of the first mismatch.
+fn matches(signature: &str, value: &RpcValue) -> Result<(), String> {
+    let signature = signature.trim();
+    let alternatives = split_top_level(signature, '|');
+    if alternatives.len() > 1 {
+        if alternatives.iter().any(|alternative| matches(alternative, value).is_ok()) {
+            return Ok(());
+        }
+        return Err(format!("got {}", type_name(value)));
+    }
+    if let Some(elements) = signature.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        let Value::List(list) = value.value() else {
+            return Err(format!("got {}", type_name(value)));
+        };
+        let elements = split_top_level(elements, ',');
+        if list.len() != elements.len() {
+            return Err(format!("got a List of {} items, expected {}", list.len(), elements.len()));
+        }
+        return elements.iter().zip(list.iter()).enumerate()
+            .try_for_each(|(n, (element, value))| matches(element, value).map_err(|mismatch| format!("item {n}: {mismatch}")));
+    }
+    let ok = match (signature, value.value()) {
+        ("RpcValue", _) => true,
+        ("Int", Value::UInt(value)) => i64::try_from(*value).is_ok(),
+        ("UInt", Value::Int(value)) => *value >= 0,
+        ("Double", Value::Int(_) | Value::UInt(_)) => true,
+        (signature, _) => type_name(value) == signature,
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("got {}", type_name(value)))
+    }
+}
+
+/// Splits at `separator` outside of nested brackets.
+fn split_top_level(elements: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (n, c) in elements.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                parts.push(&elements[start..n]);
+                start = n + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&elements[start..]);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(items: Vec<RpcValue>) -> RpcValue {
+        items.into()
+    }
+
+    #[test]
+    fn missing_param_needs_null_signature() {
+        assert!(check("Null", None).is_ok());
+        assert!(check("Null|Int", None).is_ok());
+        assert!(check("RpcValue", None).is_ok());
+        assert!(check("String", None).is_err());
+        assert!(check("Int", Some(&RpcValue::null())).is_err());
+    }
+
+    #[test]
+    fn null_signature_ignores_param() {
+        assert!(check("Null", Some(&1.into())).is_ok());
+    }
+
+    #[test]
+    fn scalar_types() {
+        assert!(check("String", Some(&"text".into())).is_ok());
+        assert!(check("String", Some(&1.into())).is_err());
+        assert!(check("Int", Some(&RpcValue::from(7u64))).is_ok());
+        assert!(check("Int", Some(&RpcValue::from(u64::MAX))).is_err());
+        assert!(check("UInt", Some(&RpcValue::from(-1))).is_err());
+        assert!(check("Double", Some(&3.into())).is_ok());
+    }
+
+    #[test]
+    fn lists_and_alternatives() {
+        assert!(check("[String, Int]", Some(&list(vec!["a".into(), 1.into()]))).is_ok());
+        assert!(check("[String, Int]", Some(&list(vec!["a".into()]))).is_err());
+        assert!(check("[String, Int]", Some(&list(vec![1.into(), 1.into()]))).is_err());
+        assert!(check("Bool|[Bool, Int]", Some(&true.into())).is_ok());
+        assert!(check("Bool|[Bool, Int]", Some(&list(vec![true.into(), 5.into()]))).is_ok());
+        assert!(check("Bool|[Bool, Int]", Some(&"yes".into())).is_err());
+        assert!(check("[List, RpcValue]", Some(&list(vec![list(vec![]), RpcValue::null()]))).is_ok());
+    }
+}
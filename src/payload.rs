@@ -0,0 +1,105 @@
+//! Responses whose size follows a simulated device load, and a Blob node of any size.
+
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+use shvclient::ClientCommandSender;
+use shvproto::rpcvalue::Map;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use shvrpc::RpcMessage;
+
+pub(crate) const ADAPTIVE_PAYLOAD_MOUNT: &str = "test/adaptivePayload";
+pub(crate) const LOAD_MOUNT: &str = "control/load";
+pub(crate) const BLOB_MOUNT: &str = "test/blob";
+const BYTES_PER_LOAD_UNIT: usize = 1024;
+const MAX_BLOB_BYTES: usize = 1 << 30;
+
+pub(crate) struct AdaptivePayload {
+    load: AtomicI32,
+    max_bytes: usize,
+}
+
+impl AdaptivePayload {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self { load: Default::default(), max_bytes }
+    }
+
+    pub(crate) fn load(&self) -> i32 {
+        self.load.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_load(&self, load: i32) -> Result<(), RpcError> {
+        if load < 0 {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, "Load must not be negative"));
+        }
+        self.load.store(load, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Blob of `load` KiB, capped at `--max-payload-bytes`.
+    pub(crate) fn value(&self) -> RpcValue {
+        let size = (self.load() as usize).saturating_mul(BYTES_PER_LOAD_UNIT).min(self.max_bytes);
+        RpcValue::from(vec![0xA5u8; size])
+    }
+}
+
+/// test/blob content, only the size is stored and the bytes are generated on each read.
+/// Byte `n` is `n % 251`, so that misplaced or lost chunks show up in the content.
+pub(crate) struct Blob {
+    size: AtomicUsize,
+    stream_threshold: Option<usize>,
+}
+
+impl Blob {
+    pub(crate) fn new(stream_threshold: Option<usize>) -> Result<Self, String> {
+        if stream_threshold == Some(0) {
+            return Err("Blob stream threshold must be positive".into());
+        }
+        Ok(Self { size: Default::default(), stream_threshold })
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_size(&self, size: i64) -> Result<(), RpcError> {
+        match usize::try_from(size) {
+            Ok(size) if size <= MAX_BLOB_BYTES => {
+                self.size.store(size, Ordering::SeqCst);
+                Ok(())
+            }
+            _ => Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Blob size must be between 0 and {MAX_BLOB_BYTES}"))),
+        }
+    }
+
+    /// The whole content, refused above `--blob-stream-threshold` where test/blob:stream has to be used.
+    pub(crate) fn value(&self) -> Result<RpcValue, RpcError> {
+        let size = self.size();
+        if self.stream_threshold.is_some_and(|threshold| size > threshold) {
+            return Err(RpcError::new(RpcErrorCode::MethodCallException, &format!("Blob of {size} bytes exceeds the stream threshold, use stream")));
+        }
+        Ok(content(0, size).into())
+    }
+
+    /// Sends the content as `chunk` signals of `{"offset": Int, "data": Blob}` on test/blob,
+    /// chunks are `--blob-stream-threshold` bytes long (the whole blob without it). Returns the blob size.
+    pub(crate) fn stream(&self, client_cmd_tx: &ClientCommandSender) -> RpcValue {
+        let size = self.size();
+        let chunk_size = self.stream_threshold.unwrap_or(size).max(1);
+        for offset in (0..size).step_by(chunk_size) {
+            let mut chunk = Map::new();
+            chunk.insert("offset".into(), (offset as i64).into());
+            chunk.insert("data".into(), content(offset, chunk_size.min(size - offset)).into());
+            let _ = client_cmd_tx.send_message(RpcMessage::new_signal(BLOB_MOUNT, "chunk", Some(chunk.into())));
+        }
+        (size as i64).into()
+    }
+
+    pub(crate) fn reset(&self) {
+        self.size.store(0, Ordering::SeqCst);
+    }
+}
+
+fn content(offset: usize, len: usize) -> Vec<u8> {
+    (offset..offset + len).map(|n| (n % 251) as u8).collect()
+}
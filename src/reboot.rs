@@ -0,0 +1,212 @@
+//! Simulated device reboot keeping the broker connection up.
+//!
+//! `control:softReboot` brings the application back to its startup state:
+//! - every task listed in `status/tasks` is cancelled (generators, fault ramps, long operations),
+//!   the bench emitter and signal storm are stopped, a control/scenario being played is rewound, and the startup generators are spawned again
+//! - the broker subscriptions of `control/subscriptions` are renewed, `history/signals` is kept
+//! - state/number, state/text, state/timestamp, state/decimal, state/intList, state/mode, state/counter, state/fault_sim, sim/clock, sim/ramp, state/map, state/config, state/table (row ids included), test/anyValue, sensors/* and --nodes-file nodes return to their initial values,
+//!   active alarms are cleared
+//! - the simulated load, test/load allocations, the test/blob size, all fault injections listed in `status/faults`, long operation progress, mirror cache,
+//!   latency histogram (and with it the .stats average latency), error counters, node format overrides and buffered (replay, coalesced, queued) signals are cleared
+//!
+//! Kept as they are: the broker connection with its reconnect and heartbeat settings (control/heartbeat included),
+//! `status/metrics` byte counters, the monotonic clock, the firmware version and the command line configuration.
+//! A snapshot of all state nodes is emitted once the reboot is complete.
+
+use std::time::Duration;
+
+use shvclient::{AppState, ClientCommandSender};
+
+use crate::{connection, counter, heartbeat, metrics, sensors, signals, sim, stats, subscriptions, tasks, Opts, State};
+
+/// Background generators started with the device, restarted by a soft reboot.
+#[derive(Clone, PartialEq)]
+pub(crate) struct Generators {
+    flaky_drops: Option<(Duration, Duration)>,
+    flap: Option<Duration>,
+    counter_auto: Option<Duration>,
+    sim_clock: Option<Duration>,
+    sim_ramp: Option<Duration>,
+    coalesce: bool,
+    signal_queue: bool,
+    sensor_suite: bool,
+    request_rate: Option<Duration>,
+    stats: Duration,
+}
+
+impl Generators {
+    pub(crate) fn new(opts: &Opts) -> Result<Self, String> {
+        let flaky_drops = match &opts.flaky_drop_every {
+            Some(every) => {
+                let every = duration_str::parse(every).map_err(|err| format!("Invalid flaky drop interval: {err}"))?;
+                let jitter = duration_str::parse(&opts.flaky_drop_jitter).map_err(|err| format!("Invalid flaky drop jitter: {err}"))?;
+                Some((every, jitter))
+            }
+            None => None,
+        };
+        let flap = opts.flap_interval.as_deref()
+            .map(|interval| duration_str::parse(interval).map_err(|err| format!("Invalid flap interval: {err}")))
+            .transpose()?;
+        let counter_auto = opts.counter_auto.as_deref()
+            .map(|interval| duration_str::parse(interval).map_err(|err| format!("Invalid counter auto-increment interval: {err}")))
+            .transpose()?;
+        let sim_clock = opts.sim_clock.as_deref()
+            .map(|period| duration_str::parse(period).map_err(|err| format!("Invalid sim clock period: {err}")))
+            .transpose()?;
+        let sim_ramp = opts.sim_ramp.as_deref()
+            .map(|period| duration_str::parse(period).map_err(|err| format!("Invalid sim ramp period: {err}")))
+            .transpose()?;
+        let request_rate = opts.rps_emit_interval.as_deref()
+            .map(|interval| duration_str::parse(interval).map_err(|err| format!("Invalid request rate emit interval: {err}")))
+            .transpose()?;
+        let stats = duration_str::parse(&opts.stats_interval).map_err(|err| format!("Invalid stats interval: {err}"))?;
+        Ok(Self {
+            flaky_drops,
+            flap,
+            counter_auto,
+            sim_clock,
+            sim_ramp,
+            coalesce: opts.coalesce_window.is_some(),
+            signal_queue: opts.signal_queue_size.is_some() || opts.consumer_rate.is_some() || opts.max_send_rate.is_some(),
+            sensor_suite: opts.sensor_suite,
+            request_rate,
+            stats,
+        })
+    }
+}
+
+/// Tasks spawned by [`spawn_generators`].
+const GENERATOR_TASKS: &[&str] = &[
+    "flakyDrops", "flap", "heartbeat", "signalQueue", "coalesce", "sensorSuite",
+    "requestRate", "stats", "counterAuto", "simClock", "simRamp",
+];
+
+pub(crate) fn spawn_generators(app_state: &AppState<State>) {
+    let generators = app_state.generators.lock().unwrap().clone();
+    if let Some((every, jitter)) = generators.flaky_drops {
+        tasks::spawn(app_state, "flakyDrops", connection::flaky_drops(app_state.clone(), every, jitter));
+    }
+    if let Some(period) = generators.flap {
+        tasks::spawn(app_state, "flap", connection::flap(app_state.clone(), period));
+    }
+    tasks::spawn(app_state, "heartbeat", heartbeat::run(app_state.clone()));
+    if generators.signal_queue {
+        tasks::spawn(app_state, "signalQueue", signals::drain_queue(app_state.clone()));
+    }
+    if generators.coalesce {
+        tasks::spawn(app_state, "coalesce", signals::flush_coalesced(app_state.clone()));
+    }
+    if generators.sensor_suite {
+        tasks::spawn(app_state, "sensorSuite", sensors::run(app_state.clone()));
+    }
+    if let Some(interval) = generators.request_rate {
+        tasks::spawn(app_state, "requestRate", metrics::emit_request_rate(app_state.clone(), interval));
+    }
+    tasks::spawn(app_state, "stats", stats::emit_changes(app_state.clone(), generators.stats));
+    if let Some(interval) = generators.counter_auto {
+        tasks::spawn(app_state, "counterAuto", counter::auto_increment(app_state.clone(), interval));
+    }
+    if let Some(period) = generators.sim_clock {
+        tasks::spawn(app_state, "simClock", sim::run_clock(app_state.clone(), period));
+    }
+    if let Some(period) = generators.sim_ramp {
+        tasks::spawn(app_state, "simRamp", sim::run_ramp(app_state.clone(), period));
+    }
+}
+
+/// Replaces the generator configuration and restarts the generators, returns false if
+/// it did not change. The signal queue and coalescing are set up at startup and stay as they are.
+pub(crate) async fn restart_generators(app_state: &AppState<State>, mut generators: Generators) -> bool {
+    {
+        let mut current = app_state.generators.lock().unwrap();
+        generators.coalesce = current.coalesce;
+        generators.signal_queue = current.signal_queue;
+        if *current == generators {
+            return false;
+        }
+        *current = generators;
+    }
+    for name in GENERATOR_TASKS {
+        let _ = app_state.tasks.cancel(name).await;
+    }
+    spawn_generators(app_state);
+    true
+}
+
+pub(crate) async fn soft_reboot(app_state: &AppState<State>, client_cmd_tx: &ClientCommandSender) {
+    restart(app_state).await;
+    subscriptions::on_connected(app_state, client_cmd_tx).await;
+    app_state.emit_snapshot(client_cmd_tx).await;
+}
+
+/// The part of a soft reboot that does not need the broker connection.
+async fn restart(app_state: &AppState<State>) {
+    app_state.tasks.cancel_all().await;
+    app_state.bench_emitter.stop();
+    app_state.sigstorm.stop();
+
+    app_state.reset_values().await;
+    app_state.fault_sim.reset();
+    app_state.counter.reset();
+    app_state.alarms.reset();
+    app_state.sim.reset();
+    if let Some(sensors) = &app_state.sensors {
+        sensors.reset();
+    }
+    app_state.long_ops.clear();
+    let _ = app_state.adaptive_payload.set_load(0);
+    app_state.load_generator.free();
+    app_state.scenario.rewind();
+    app_state.blob.reset();
+    app_state.faults.clear_all();
+    app_state.mirrors.clear_cache();
+    app_state.latency_histogram.reset();
+    app_state.error_counts.reset();
+    app_state.stats.clear();
+    app_state.signals.clear();
+    app_state.node_formats.clear();
+
+    spawn_generators(app_state);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use shvproto::rpcvalue::Value;
+
+    use super::*;
+    use crate::runtime;
+
+    fn task_names(app_state: &AppState<State>) -> Vec<String> {
+        let names = app_state.tasks.names();
+        let Value::List(names) = names.value() else {
+            panic!("status/tasks is not a List");
+        };
+        names.iter().map(|name| name.as_str().to_string()).collect()
+    }
+
+    #[test]
+    fn restart_respawns_generators_and_resets_values() {
+        let state = crate::test_state(&["--counter-auto", "1h"]);
+        runtime::block_on(async {
+            spawn_generators(&state);
+            state.tasks.cancel("counterAuto").await.unwrap();
+            tasks::spawn(&state, "longOp", futures::future::pending());
+            state.number.store(42, Ordering::SeqCst);
+            state.text.write().await.push_str("changed");
+            *state.any_value.write().await = 1.into();
+
+            restart(&state).await;
+
+            let names = task_names(&state);
+            for generator in ["counterAuto", "heartbeat", "stats"] {
+                assert!(names.iter().any(|name| name == generator), "{generator} not running: {names:?}");
+            }
+            assert!(!names.iter().any(|name| name == "longOp"), "{names:?}");
+            assert_eq!(state.number.load(Ordering::SeqCst), 0);
+            assert!(state.text.read().await.is_empty());
+            assert!(state.any_value.read().await.is_null());
+        });
+    }
+}
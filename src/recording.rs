@@ -0,0 +1,192 @@
+//! Timestamped trace of handled requests and emitted signals for bug reports.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::*;
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::{DateTime, RpcValue};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+/// Entries kept per recording, the oldest are dropped first.
+const MAX_RECORDING_ENTRIES: usize = 10000;
+
+#[derive(Default)]
+pub(crate) struct Recording {
+    active: AtomicBool,
+    file: Option<String>,
+    entries: Mutex<VecDeque<RpcValue>>,
+}
+
+impl Recording {
+    pub(crate) fn new(file: Option<String>) -> Self {
+        Self { file, ..Default::default() }
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Starts a new recording, entries of a previous one are discarded.
+    pub(crate) fn start(&self) {
+        self.entries.lock().unwrap().clear();
+        self.active.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns the number of recorded entries.
+    pub(crate) fn stop(&self) -> usize {
+        self.active.store(false, Ordering::SeqCst);
+        self.entries.lock().unwrap().len()
+    }
+
+    pub(crate) fn request(&self, time: DateTime, path: &str, method: &str, param: Option<RpcValue>) {
+        self.push(time, "request", path, method, param.unwrap_or_default());
+    }
+
+    pub(crate) fn signal(&self, time: DateTime, path: &str, signal: &str, value: &RpcValue) {
+        self.push(time, "signal", path, signal, value.clone());
+    }
+
+    fn push(&self, time: DateTime, kind: &str, path: &str, name: &str, value: RpcValue) {
+        if !self.is_active() {
+            return;
+        }
+        let mut entry = Map::new();
+        entry.insert("time".into(), time.into());
+        entry.insert("kind".into(), kind.into());
+        entry.insert("path".into(), path.into());
+        entry.insert("name".into(), name.into());
+        entry.insert("value".into(), value);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == MAX_RECORDING_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry.into());
+    }
+
+    /// Returns the recorded entries, with `--recording-file` they are also written there as CPON.
+    pub(crate) async fn export(&self) -> Result<RpcValue, RpcError> {
+        let entries: Vec<RpcValue> = self.entries.lock().unwrap().iter().cloned().collect();
+        let trace = RpcValue::from(entries);
+        if let Some(file) = &self.file {
+            async_std::fs::write(file, trace.to_cpon()).await
+                .map_err(|err| RpcError::new(RpcErrorCode::MethodCallException, &format!("Cannot write recording to {file}: {err}")))?;
+            info!("Recording exported to {file}");
+        }
+        Ok(trace)
+    }
+}
+
+/// An entry of a recorded trace, `offset` is the time from the first entry.
+pub(crate) struct Step {
+    pub(crate) offset: Duration,
+    pub(crate) kind: String,
+    pub(crate) path: String,
+    pub(crate) name: String,
+    pub(crate) value: RpcValue,
+}
+
+/// Reads a trace written by control:exportRecording.
+pub(crate) async fn load_scenario(file: &str) -> Result<Vec<Step>, RpcError> {
+    let content = async_std::fs::read_to_string(file).await
+        .map_err(|err| RpcError::new(RpcErrorCode::MethodCallException, &format!("Cannot read scenario {file}: {err}")))?;
+    let malformed = |msg: &str| RpcError::new(RpcErrorCode::InvalidParam, &format!("Malformed scenario {file}: {msg}"));
+    let trace = RpcValue::from_cpon(&content).map_err(|err| malformed(&err.to_string()))?;
+    let Value::List(entries) = trace.value() else {
+        return Err(malformed("expected a List of entries"));
+    };
+    let mut start = None;
+    entries.iter().enumerate()
+        .map(|(n, entry)| {
+            let Value::Map(entry) = entry.value() else {
+                return Err(malformed(&format!("entry {n} is not a Map")));
+            };
+            let field = |key: &str| match entry.get(key).map(RpcValue::value) {
+                Some(Value::String(field)) => Ok(field.to_string()),
+                _ => Err(malformed(&format!("entry {n} has no {key}"))),
+            };
+            let Some(Value::DateTime(time)) = entry.get("time").map(RpcValue::value) else {
+                return Err(malformed(&format!("entry {n} has no time")));
+            };
+            let start = *start.get_or_insert(time.epoch_msec());
+            Ok(Step {
+                offset: Duration::from_millis((time.epoch_msec() - start).max(0) as u64),
+                kind: field("kind")?,
+                path: field("path")?,
+                name: field("name")?,
+                value: entry.get("value").cloned().unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use shvclient::clientnode::SIG_CHNG;
+    use shvrpc::RpcMessage;
+
+    use super::*;
+    use crate::signals::{emit_chng, Collected};
+    use crate::{dispatch, runtime, NUMBER_MOUNT};
+
+    fn field<'a>(entry: &'a RpcValue, key: &str) -> &'a RpcValue {
+        let Value::Map(entry) = entry.value() else {
+            panic!("entry is not a Map");
+        };
+        entry.get(key).unwrap_or_else(|| panic!("entry has no {key}"))
+    }
+
+    #[test]
+    fn trace_holds_sets_and_their_signals() {
+        let state = crate::test_state(&[]);
+        let collected = Collected::default();
+        state.recording.start();
+        for value in [1, 2] {
+            let request = RpcMessage::new_request(NUMBER_MOUNT, "set", Some(value.into()));
+            runtime::block_on(dispatch::handle(&state, &request, "set", "Int", async {
+                let changed = state.update_number(value).unwrap().unwrap();
+                emit_chng(&state, &collected, NUMBER_MOUNT, changed);
+                Some(Ok(true.into()))
+            }));
+        }
+        assert_eq!(state.recording.stop(), 4);
+        let trace = runtime::block_on(state.recording.export()).unwrap();
+        let Value::List(entries) = trace.value() else {
+            panic!("trace is not a List");
+        };
+        let summary: Vec<(String, String, String, RpcValue)> = entries.iter()
+            .map(|entry| (
+                field(entry, "kind").as_str().to_string(),
+                field(entry, "path").as_str().to_string(),
+                field(entry, "name").as_str().to_string(),
+                field(entry, "value").clone(),
+            ))
+            .collect();
+        let entry = |kind: &str, name: &str, value: i32| (kind.to_string(), NUMBER_MOUNT.to_string(), name.to_string(), RpcValue::from(value));
+        assert_eq!(summary, [
+            entry("request", "set", 1),
+            entry("signal", SIG_CHNG, 1),
+            entry("request", "set", 2),
+            entry("signal", SIG_CHNG, 2),
+        ]);
+        assert!(entries.iter().all(|entry| matches!(field(entry, "time").value(), Value::DateTime(_))));
+    }
+
+    #[test]
+    fn nothing_is_recorded_when_stopped() {
+        let recording = Recording::default();
+        recording.request(DateTime::now(), NUMBER_MOUNT, "set", Some(1.into()));
+        recording.start();
+        recording.request(DateTime::now(), NUMBER_MOUNT, "set", Some(2.into()));
+        assert_eq!(recording.stop(), 1);
+        recording.signal(DateTime::now(), NUMBER_MOUNT, SIG_CHNG, &3.into());
+        let trace = runtime::block_on(recording.export()).unwrap();
+        let Value::List(entries) = trace.value() else {
+            panic!("trace is not a List");
+        };
+        assert_eq!(entries.len(), 1);
+        assert_eq!(field(&entries[0], "value"), &RpcValue::from(2));
+    }
+}
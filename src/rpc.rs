@@ -0,0 +1,14 @@
+use shvclient::ClientCommandSender;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+/// Calls a method on the broker and waits for its result.
+pub(crate) async fn call(client_cmd_tx: &ClientCommandSender, path: &str, method: &str, param: Option<RpcValue>) -> Result<RpcValue, RpcError> {
+    let response_rx = client_cmd_tx.do_rpc_call_param(path, method, param)
+        .map_err(|err| RpcError::new(RpcErrorCode::InternalError, &format!("Cannot send request to {path}:{method}: {err}")))?;
+    let frame = response_rx.recv().await
+        .map_err(|err| RpcError::new(RpcErrorCode::InternalError, &format!("No response from {path}:{method}: {err}")))?;
+    let response = frame.to_rpcmesage()
+        .map_err(|err| RpcError::new(RpcErrorCode::InternalError, &format!("Invalid response from {path}:{method}: {err}")))?;
+    response.result().cloned()
+}
@@ -0,0 +1,96 @@
+//! The async runtime the device runs on, async-std by default or tokio with the
+//! `runtime-tokio` feature (build with `--no-default-features --features runtime-tokio`).
+//! Features are additive, with both enabled (e.g. `--all-features`) tokio is used.
+//!
+//! Tasks, timers and blocking work go through here so that under tokio they run on the
+//! embedding runtime. Socket, file and channel I/O keep using async-std, its reactor runs
+//! on a thread of its own and serves futures polled by either runtime.
+
+#[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+compile_error!("One of the features runtime-async-std or runtime-tokio is required");
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+pub(crate) use async_std_rt::*;
+#[cfg(feature = "runtime-tokio")]
+pub(crate) use tokio_rt::*;
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+mod async_std_rt {
+    use std::future::Future;
+
+    pub(crate) use async_std::task::{sleep, yield_now};
+
+    pub(crate) struct JoinHandle<T>(async_std::task::JoinHandle<T>);
+
+    impl<T> JoinHandle<T> {
+        /// Stops the task, returns once it does not run anymore.
+        pub(crate) async fn cancel(self) {
+            self.0.cancel().await;
+        }
+    }
+
+    pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        JoinHandle(async_std::task::spawn(future))
+    }
+
+    pub(crate) async fn spawn_blocking<F, T>(f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        async_std::task::spawn_blocking(f).await
+    }
+
+    /// Runs `future` to completion on the calling thread, which must not be a runtime thread.
+    pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+        async_std::task::block_on(future)
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+mod tokio_rt {
+    use std::future::Future;
+
+    pub(crate) use tokio::task::yield_now;
+    pub(crate) use tokio::time::sleep;
+
+    pub(crate) struct JoinHandle<T>(tokio::task::JoinHandle<T>);
+
+    impl<T> JoinHandle<T> {
+        /// Stops the task, returns once it does not run anymore.
+        pub(crate) async fn cancel(self) {
+            self.0.abort();
+            let _ = self.0.await;
+        }
+    }
+
+    pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        JoinHandle(tokio::task::spawn(future))
+    }
+
+    /// A panic in `f` is propagated to the caller as with async-std.
+    pub(crate) async fn spawn_blocking<F, T>(f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::task::spawn_blocking(f).await.unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+
+    /// Runs `future` to completion on the calling thread, which must not be a runtime thread.
+    pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Cannot create runtime")
+            .block_on(future)
+    }
+}
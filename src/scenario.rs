@@ -0,0 +1,209 @@
+//! `control/scenario` plays a scripted timeline of device actions, so that broker
+//! regression tests see the same device behaviour on every run.
+//!
+//! A scenario file is a CPON List of actions, each a Map with the time `at` as an
+//! interval from the start of the scenario (e.g. `"5s"`, `"1m30s"`) and an `action`:
+//! - `{"at": "5s", "action": "set", "path": "state/number", "value": 42}` writes a state
+//!   node as control:setMany does, emitting `chng` when the value changes
+//! - `{"at": "10s", "action": "signal", "path": "state/text", "signal": "chng", "value": "x"}`
+//!   sends a signal as it is, `signal` defaults to `chng`
+//! - `{"at": "30s", "action": "disconnect", "offline": "5s"}` drops the connection and
+//!   stays offline for `offline` (0 by default), the timeline keeps running meanwhile
+//!
+//! Actions run in the order of `at`, actions with the same time in file order. Signals of
+//! actions due while the device is offline are dropped. Pausing stops the timeline,
+//! starting again resumes it where it stopped, a finished scenario starts from the beginning.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::*;
+use shvclient::AppState;
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::signals::{self, emit_chng};
+use crate::{connection, control, runtime, tasks, State};
+
+pub(crate) const SCENARIO_MOUNT: &str = "control/scenario";
+const SCENARIO_TASK: &str = "scenario";
+
+#[derive(Clone)]
+enum Action {
+    Set { path: String, value: RpcValue },
+    Signal { path: String, signal: String, value: RpcValue },
+    Disconnect { offline: Duration },
+}
+
+#[derive(Default)]
+struct Player {
+    file: Option<String>,
+    actions: Vec<(Duration, Action)>,
+    next: usize,
+    /// Timeline position when the scenario was paused, or when it was resumed while running.
+    elapsed: Duration,
+    resumed: Option<Instant>,
+}
+
+impl Player {
+    fn position(&self) -> Duration {
+        self.elapsed + self.resumed.map_or(Duration::ZERO, |resumed| resumed.elapsed())
+    }
+
+    fn rewind(&mut self) {
+        self.next = 0;
+        self.elapsed = Duration::ZERO;
+        self.resumed = None;
+    }
+
+    fn status(&self) -> &'static str {
+        if self.resumed.is_some() {
+            "running"
+        } else if self.file.is_none() {
+            "empty"
+        } else if self.next >= self.actions.len() {
+            "finished"
+        } else if self.next == 0 && self.elapsed.is_zero() {
+            "loaded"
+        } else {
+            "paused"
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Scenario {
+    player: Mutex<Player>,
+}
+
+impl Scenario {
+    pub(crate) fn value(&self) -> RpcValue {
+        let player = self.player.lock().unwrap();
+        let mut map = Map::new();
+        map.insert("status".into(), player.status().into());
+        map.insert("file".into(), player.file.as_deref().map(RpcValue::from).unwrap_or_default());
+        map.insert("position".into(), (player.next as i64).into());
+        map.insert("actions".into(), (player.actions.len() as i64).into());
+        map.insert("elapsedMs".into(), (player.position().as_millis() as i64).into());
+        map.into()
+    }
+
+    /// Stops at the beginning, the task itself is cancelled by the reboot.
+    pub(crate) fn rewind(&self) {
+        self.player.lock().unwrap().rewind();
+    }
+}
+
+fn parse(file: &str, content: &str) -> Result<Vec<(Duration, Action)>, RpcError> {
+    let malformed = |msg: &str| RpcError::new(RpcErrorCode::InvalidParam, &format!("Malformed scenario {file}: {msg}"));
+    let scenario = RpcValue::from_cpon(content).map_err(|err| malformed(&err.to_string()))?;
+    let Value::List(entries) = scenario.value() else {
+        return Err(malformed("expected a List of actions"));
+    };
+    let mut actions = entries.iter().enumerate()
+        .map(|(n, entry)| {
+            let Value::Map(entry) = entry.value() else {
+                return Err(malformed(&format!("action {n} is not a Map")));
+            };
+            let string = |key: &str| match entry.get(key).map(RpcValue::value) {
+                Some(Value::String(field)) => Ok(Some(field.to_string())),
+                None => Ok(None),
+                _ => Err(malformed(&format!("{key} of action {n} is not a String"))),
+            };
+            let interval = |key: &str| string(key)?
+                .map(|interval| duration_str::parse(&interval).map_err(|err| malformed(&format!("{key} of action {n}: {err}"))))
+                .transpose();
+            let at = interval("at")?.ok_or_else(|| malformed(&format!("action {n} has no at")))?;
+            let path = || string("path")?.ok_or_else(|| malformed(&format!("action {n} has no path")));
+            let value = entry.get("value").cloned().unwrap_or_default();
+            let action = match string("action")?.as_deref() {
+                Some("set") => Action::Set { path: path()?, value },
+                Some("signal") => Action::Signal { path: path()?, signal: string("signal")?.unwrap_or_else(|| "chng".to_string()), value },
+                Some("disconnect") => Action::Disconnect { offline: interval("offline")?.unwrap_or_default() },
+                Some(action) => return Err(malformed(&format!("unknown action {action}"))),
+                None => return Err(malformed(&format!("action {n} has no action"))),
+            };
+            Ok((at, action))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    actions.sort_by_key(|(at, _)| *at);
+    Ok(actions)
+}
+
+/// Loads a scenario file in place of the current scenario, which is stopped. Returns the number of actions.
+pub(crate) async fn load(app_state: &AppState<State>, file: &str) -> Result<RpcValue, RpcError> {
+    let content = async_std::fs::read_to_string(file).await
+        .map_err(|err| RpcError::new(RpcErrorCode::MethodCallException, &format!("Cannot read scenario {file}: {err}")))?;
+    let actions = parse(file, &content)?;
+    let _ = app_state.tasks.cancel(SCENARIO_TASK).await;
+    let count = actions.len() as i64;
+    *app_state.scenario.player.lock().unwrap() = Player { file: Some(file.to_string()), actions, ..Default::default() };
+    info!("Scenario {file} loaded with {count} actions");
+    Ok(count.into())
+}
+
+pub(crate) fn start(app_state: &AppState<State>) -> Result<(), RpcError> {
+    let mut player = app_state.scenario.player.lock().unwrap();
+    match player.status() {
+        "empty" => return Err(RpcError::new(RpcErrorCode::MethodCallException, "No scenario loaded")),
+        "running" => return Err(RpcError::new(RpcErrorCode::MethodCallException, "Scenario is already running")),
+        "finished" => player.rewind(),
+        _ => {}
+    }
+    player.resumed = Some(Instant::now());
+    drop(player);
+    tasks::spawn(app_state, SCENARIO_TASK, play(app_state.clone()));
+    Ok(())
+}
+
+pub(crate) async fn pause(app_state: &AppState<State>) -> Result<(), RpcError> {
+    if app_state.scenario.player.lock().unwrap().resumed.is_none() {
+        return Err(RpcError::new(RpcErrorCode::MethodCallException, "Scenario is not running"));
+    }
+    let _ = app_state.tasks.cancel(SCENARIO_TASK).await;
+    let mut player = app_state.scenario.player.lock().unwrap();
+    player.elapsed = player.position();
+    player.resumed = None;
+    Ok(())
+}
+
+async fn play(app_state: AppState<State>) {
+    loop {
+        let (wait, action) = {
+            let player = app_state.scenario.player.lock().unwrap();
+            let Some((at, action)) = player.actions.get(player.next) else {
+                break;
+            };
+            (at.saturating_sub(player.position()), action.clone())
+        };
+        runtime::sleep(wait).await;
+        perform(&app_state, action).await;
+        app_state.scenario.player.lock().unwrap().next += 1;
+    }
+    let mut player = app_state.scenario.player.lock().unwrap();
+    player.elapsed = player.position();
+    player.resumed = None;
+    info!("Scenario finished");
+}
+
+async fn perform(app_state: &AppState<State>, action: Action) {
+    match action {
+        Action::Set { path, value } => match control::set_path(app_state, &path, &value).await {
+            Ok(Some(changed)) => {
+                if let Some(client_cmd_tx) = app_state.connection.client_cmd_tx() {
+                    emit_chng(app_state, &client_cmd_tx, &path, changed);
+                }
+            }
+            Ok(None) => {}
+            Err(msg) => warn!("Scenario: {msg}"),
+        },
+        Action::Signal { path, signal, value } => match app_state.connection.client_cmd_tx() {
+            Some(client_cmd_tx) => signals::emit_recorded(app_state, &client_cmd_tx, &path, &signal, value),
+            None => warn!("Scenario: offline, dropping signal {path}:{signal}"),
+        },
+        Action::Disconnect { offline } => {
+            connection::disconnect(app_state, offline);
+        }
+    }
+}
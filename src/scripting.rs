@@ -0,0 +1,134 @@
+//! Nodes whose behaviour is a Rhai script, attached with `--script <path>=<file>`.
+//!
+//! A script node has `get`, `set` and `call`. The script runs on every request with
+//! these variables in scope:
+//! - `method`: name of the called method
+//! - `param`: request parameter, `()` for none
+//! - `state`: value kept between calls of the same node, `()` initially
+//! - `signal`: assign a value to emit it as `chng` of the node after the call
+//!
+//! The value of the last expression is the result, a thrown error becomes an RpcError.
+//! SHV values map to the closest Rhai type: List to Array, Map and IMap to object maps,
+//! Blob to Blob; DateTime and Decimal are passed as CPON strings.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use rhai::{Dynamic, Engine, AST};
+use shvclient::ClientCommandSender;
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use shvrpc::RpcMessage;
+
+use crate::signals::emit_chng;
+use crate::State;
+
+/// Operation limit of a single script run, stops runaway loops.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+struct Script {
+    ast: AST,
+    state: Mutex<Dynamic>,
+}
+
+pub(crate) struct Scripts {
+    engine: Engine,
+    scripts: BTreeMap<String, Script>,
+}
+
+impl Scripts {
+    pub(crate) fn new(specs: &[String]) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let mut scripts = BTreeMap::new();
+        for spec in specs {
+            let Some((path, file)) = spec.split_once('=') else {
+                return Err(format!("Invalid script spec '{spec}', expected <path>=<file>"));
+            };
+            let source = std::fs::read_to_string(file).map_err(|err| format!("Cannot read script {file}: {err}"))?;
+            let ast = engine.compile(source).map_err(|err| format!("Invalid script {file}: {err}"))?;
+            scripts.insert(path.to_string(), Script { ast, state: Mutex::new(Dynamic::UNIT) });
+        }
+        Ok(Self { engine, scripts })
+    }
+
+    pub(crate) fn paths(&self) -> impl Iterator<Item = &String> {
+        self.scripts.keys()
+    }
+
+    /// Runs the script of the node at `path`, returns the result and the signal value to emit.
+    pub(crate) fn call(&self, path: Option<&str>, method: &str, param: Option<&RpcValue>) -> Result<(RpcValue, Option<RpcValue>), RpcError> {
+        let script = path.and_then(|path| self.scripts.get(path))
+            .ok_or_else(|| RpcError::new(RpcErrorCode::MethodNotFound, "Not a script node"))?;
+        let mut state = script.state.lock().unwrap();
+        let mut scope = rhai::Scope::new();
+        scope.push("method", method.to_string());
+        scope.push_dynamic("param", param.map_or(Dynamic::UNIT, to_dynamic));
+        scope.push_dynamic("state", state.clone());
+        scope.push_dynamic("signal", Dynamic::UNIT);
+        let result = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &script.ast)
+            .map_err(|err| RpcError::new(RpcErrorCode::MethodCallException, &format!("Script error: {err}")))?;
+        *state = scope.get_value::<Dynamic>("state").unwrap_or_default();
+        let signal = scope.get_value::<Dynamic>("signal").filter(|signal| !signal.is_unit());
+        Ok((from_dynamic(result), signal.map(from_dynamic)))
+    }
+}
+
+/// Handles a request of a script node, emitting the signal the script asked for.
+pub(crate) fn handle(state: &State, client_cmd_tx: &ClientCommandSender, request: &RpcMessage, method: &str) -> Result<RpcValue, RpcError> {
+    let path = request.shv_path().map(|path| state.base_path(path));
+    let (result, signal) = state.scripts.call(path, method, request.param())?;
+    if let (Some(path), Some(signal)) = (path, signal) {
+        emit_chng(state, client_cmd_tx, path, signal);
+    }
+    Ok(result)
+}
+
+fn to_dynamic(value: &RpcValue) -> Dynamic {
+    match value.value() {
+        Value::Null => Dynamic::UNIT,
+        Value::Bool(value) => (*value).into(),
+        Value::Int(value) => (*value).into(),
+        Value::UInt(value) => (*value as i64).into(),
+        Value::Double(value) => (*value).into(),
+        Value::Decimal(_) | Value::DateTime(_) => value.to_cpon().into(),
+        Value::String(value) => value.as_str().into(),
+        Value::Blob(value) => Dynamic::from_blob(value.to_vec()),
+        Value::List(list) => list.iter().map(to_dynamic).collect::<rhai::Array>().into(),
+        Value::Map(map) => map.iter()
+            .map(|(key, value)| (key.as_str().into(), to_dynamic(value)))
+            .collect::<rhai::Map>()
+            .into(),
+        Value::IMap(map) => map.iter()
+            .map(|(key, value)| (key.to_string().into(), to_dynamic(value)))
+            .collect::<rhai::Map>()
+            .into(),
+    }
+}
+
+fn from_dynamic(value: Dynamic) -> RpcValue {
+    if value.is_unit() {
+        RpcValue::null()
+    } else if value.is::<bool>() {
+        value.cast::<bool>().into()
+    } else if value.is::<i64>() {
+        value.cast::<i64>().into()
+    } else if value.is::<f64>() {
+        value.cast::<f64>().into()
+    } else if value.is_string() {
+        value.into_string().unwrap_or_default().into()
+    } else if value.is_blob() {
+        RpcValue::from(value.cast::<rhai::Blob>())
+    } else if value.is_array() {
+        let list: Vec<RpcValue> = value.cast::<rhai::Array>().into_iter().map(from_dynamic).collect();
+        list.into()
+    } else if value.is_map() {
+        let map: Map = value.cast::<rhai::Map>().into_iter()
+            .map(|(key, value)| (key.to_string(), from_dynamic(value)))
+            .collect();
+        map.into()
+    } else {
+        value.to_string().into()
+    }
+}
@@ -0,0 +1,211 @@
+//! `--selftest`: connect, check the broker behaves, print a JSON report and exit 0 when
+//! every check passed, 1 otherwise. This makes the device a standalone broker smoke test.
+//!
+//! Checks, made through the broker over the device's own connection:
+//! - `mount`: `.broker/currentClient:info` reports a mount point and `ls` on it lists
+//!   the device's `state` node
+//! - `signalEcho`: a `chng` of state/number, set through the broker, comes back to a
+//!   subscription of the device
+//! - `accessLevels`: every `test/access` method that the broker lets through was granted
+//!   at least the access level it requires
+//!
+//! Without a connection within `--selftest-timeout` the report has a failed `connect`
+//! check. The report is a single line on stdout:
+//! `{"success": true, "durationMs": 123, "checks": [{"name": "mount", "passed": true, "detail": "..."}]}`
+
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::future::{select, Either};
+use futures::StreamExt;
+use shvclient::{AppState, ClientCommandSender};
+use shvproto::rpcvalue::Value;
+use shvproto::RpcValue;
+use shvrpc::ShvRI;
+
+use crate::{rpc, runtime, Error, State};
+
+const SIGNAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Methods of test/access with the access level they require.
+const ACCESS_LEVELS: &[(&str, i64)] = &[
+    ("browse", 1), ("read", 8), ("write", 16), ("command", 24),
+    ("config", 32), ("service", 40), ("superService", 48), ("devel", 56),
+];
+
+pub(crate) struct SelfTest {
+    enabled: bool,
+    timeout: Duration,
+    started: Instant,
+    running: AtomicBool,
+}
+
+struct Check {
+    name: &'static str,
+    result: Result<String, String>,
+}
+
+impl SelfTest {
+    pub(crate) fn new(enabled: bool, timeout: &str) -> Result<Self, String> {
+        let timeout = duration_str::parse(timeout).map_err(|err| format!("Invalid selftest timeout: {err}"))?;
+        Ok(Self { enabled, timeout, started: Instant::now(), running: Default::default() })
+    }
+}
+
+/// Starts the connect timeout of `--selftest`.
+pub(crate) fn start(app_state: &AppState<State>) {
+    let selftest = &app_state.selftest;
+    if !selftest.enabled {
+        return;
+    }
+    let timeout = selftest.timeout;
+    let app_state = app_state.clone();
+    runtime::spawn(async move {
+        runtime::sleep(timeout).await;
+        if !app_state.selftest.running.load(Ordering::SeqCst) {
+            let check = Check { name: "connect", result: Err(format!("Not connected within {timeout:?}")) };
+            finish(&app_state, vec![check]);
+        }
+    });
+}
+
+/// Runs the checks on the first connect.
+pub(crate) fn on_connected(app_state: &AppState<State>, client_cmd_tx: &ClientCommandSender) {
+    let selftest = &app_state.selftest;
+    if !selftest.enabled || selftest.running.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let app_state = app_state.clone();
+    let client_cmd_tx = client_cmd_tx.clone();
+    runtime::spawn(async move {
+        let mut checks = vec![Check { name: "connect", result: Ok("Connected".to_string()) }];
+        let mount = check_mount(&client_cmd_tx).await;
+        let mount_point = mount.as_ref().ok().cloned();
+        checks.push(Check { name: "mount", result: mount.map(|mount| format!("Mounted at {mount}")) });
+        match mount_point {
+            Some(mount) => {
+                checks.push(Check { name: "signalEcho", result: check_signal_echo(&app_state, &client_cmd_tx, &mount).await });
+                checks.push(Check { name: "accessLevels", result: check_access_levels(&client_cmd_tx, &mount).await });
+            }
+            None => {
+                for name in ["signalEcho", "accessLevels"] {
+                    checks.push(Check { name, result: Err("Skipped, the device is not mounted".to_string()) });
+                }
+            }
+        }
+        finish(&app_state, checks);
+    });
+}
+
+async fn check_mount(client_cmd_tx: &ClientCommandSender) -> Result<String, String> {
+    let info = rpc::call(client_cmd_tx, ".broker/currentClient", "info", None).await
+        .map_err(|err| format!(".broker/currentClient:info failed: {}", err.message))?;
+    let mount = match info.value() {
+        Value::Map(info) => info.get("mountPoint").filter(|mount| mount.is_string()).map(|mount| mount.as_str().to_string()),
+        _ => None,
+    };
+    let mount = mount.ok_or("The broker reports no mount point")?;
+    let ls = rpc::call(client_cmd_tx, &mount, "ls", None).await
+        .map_err(|err| format!("ls of {mount} failed: {}", err.message))?;
+    let listed = match ls.value() {
+        Value::List(children) => children.iter().any(|child| child.is_string() && child.as_str() == "state"),
+        _ => false,
+    };
+    if !listed {
+        return Err(format!("ls of {mount} does not list the device's nodes"));
+    }
+    Ok(mount)
+}
+
+async fn check_signal_echo(app_state: &AppState<State>, client_cmd_tx: &ClientCommandSender, mount: &str) -> Result<String, String> {
+    let path = format!("{mount}/{}", crate::NUMBER_MOUNT);
+    let pattern = format!("{path}:*:chng");
+    let ri = ShvRI::try_from(pattern.as_str()).map_err(|err| format!("Invalid subscription {pattern}: {err}"))?;
+    let mut subscriber = client_cmd_tx.subscribe(ri).await.map_err(|err| format!("Cannot subscribe {pattern}: {err}"))?;
+    let value = app_state.number.load(Ordering::SeqCst).wrapping_add(1);
+    rpc::call(client_cmd_tx, &path, "set", Some(value.into())).await
+        .map_err(|err| format!("{path}:set failed: {}", err.message))?;
+    // Only this set changes the number while the check waits, any chng of it is the echo.
+    let received = async {
+        while let Some(frame) = subscriber.next().await {
+            if frame.to_rpcmesage().is_ok() {
+                return true;
+            }
+        }
+        false
+    };
+    match select(pin!(received), pin!(runtime::sleep(SIGNAL_TIMEOUT))).await {
+        Either::Left((true, _)) => Ok(format!("chng of {path} received")),
+        Either::Left((false, _)) => Err("The subscription ended".to_string()),
+        Either::Right(_) => Err(format!("No chng of {path} within {SIGNAL_TIMEOUT:?}")),
+    }
+}
+
+async fn check_access_levels(client_cmd_tx: &ClientCommandSender, mount: &str) -> Result<String, String> {
+    let path = format!("{mount}/{}", crate::access::ACCESS_MOUNT);
+    let mut allowed = Vec::new();
+    for (method, required) in ACCESS_LEVELS {
+        let Ok(grant) = rpc::call(client_cmd_tx, &path, method, None).await else {
+            continue;
+        };
+        let granted = match grant.value() {
+            Value::Map(grant) => grant.get("accessLevel").filter(|level| level.is_int()).map(RpcValue::as_int),
+            _ => None,
+        };
+        match granted {
+            Some(granted) if granted >= *required => allowed.push(*method),
+            Some(granted) => return Err(format!("{method} requires level {required} but was called with level {granted}")),
+            None => return Err(format!("The broker did not pass the access level to {method}")),
+        }
+    }
+    if allowed.is_empty() {
+        return Err(format!("No method of {path} could be called"));
+    }
+    Ok(format!("Allowed: {}", allowed.join(", ")))
+}
+
+/// Prints the report and stops the device, [`crate::run`] then returns
+/// [`Error::SelfTestFailed`] unless every check passed.
+fn finish(app_state: &AppState<State>, checks: Vec<Check>) {
+    let success = checks.iter().all(|check| check.result.is_ok());
+    let checks: Vec<String> = checks.iter()
+        .map(|check| {
+            let (passed, detail) = match &check.result {
+                Ok(detail) => (true, detail),
+                Err(detail) => (false, detail),
+            };
+            format!(r#"{{"name": {}, "passed": {passed}, "detail": {}}}"#, json_string(check.name), json_string(detail))
+        })
+        .collect();
+    println!(r#"{{"success": {success}, "durationMs": {}, "checks": [{}]}}"#, app_state.selftest.started.elapsed().as_millis(), checks.join(", "));
+    app_state.connection.stop(if success { Ok(()) } else { Err(Error::SelfTestFailed) });
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_string_escapes_quotes_backslashes_and_control_chars() {
+        assert_eq!(json_string("plain ü"), r#""plain ü""#);
+        assert_eq!(json_string(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_string(r"C:\dir"), r#""C:\\dir""#);
+        assert_eq!(json_string("a\nb\tc\u{1}\u{7f}"), r#""a\nb\u0009c\u0001\u007f""#);
+    }
+}
@@ -0,0 +1,142 @@
+//! Temperature, humidity and pressure sensors driven by one simulated weather.
+//!
+//! A slow random walk stands for the weather, all three values follow it with
+//! some independent noise, so that warmer means drier and lower pressure. The
+//! generator is seeded with a constant, every run produces the same series.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use shvclient::AppState;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::signals::{emit_chng, MessageSink};
+use crate::{runtime, State};
+
+const TICK: Duration = Duration::from_secs(1);
+const SEED: u64 = 0;
+
+/// Mount path, unit, value at weather 0, change per unit of weather, noise amplitude, valid range.
+pub(crate) const SENSORS: [Sensor; 3] = [
+    Sensor { path: "sensors/temperature", unit: "°C", base: 22.5, weather_gain: 7., noise: 0.2, range: (15., 30.) },
+    Sensor { path: "sensors/humidity", unit: "%", base: 55., weather_gain: -25., noise: 1., range: (20., 90.) },
+    Sensor { path: "sensors/pressure", unit: "hPa", base: 1013., weather_gain: -10., noise: 0.3, range: (990., 1035.) },
+];
+
+pub(crate) struct Sensor {
+    pub(crate) path: &'static str,
+    pub(crate) unit: &'static str,
+    base: f64,
+    weather_gain: f64,
+    noise: f64,
+    range: (f64, f64),
+}
+
+pub(crate) struct SensorSuite {
+    values: Mutex<[f64; SENSORS.len()]>,
+}
+
+impl Default for SensorSuite {
+    fn default() -> Self {
+        Self { values: Mutex::new(SENSORS.map(|sensor| sensor.base)) }
+    }
+}
+
+impl SensorSuite {
+    fn index(path: Option<&str>) -> Result<usize, RpcError> {
+        SENSORS.iter().position(|sensor| Some(sensor.path) == path)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::MethodNotFound, "Not a sensor node"))
+    }
+
+    pub(crate) fn value(&self, path: Option<&str>) -> Result<RpcValue, RpcError> {
+        let index = Self::index(path)?;
+        Ok(self.values.lock().unwrap()[index].into())
+    }
+
+    pub(crate) fn unit(path: Option<&str>) -> Result<RpcValue, RpcError> {
+        Ok(SENSORS[Self::index(path)?].unit.into())
+    }
+
+    pub(crate) fn values(&self) -> Vec<(String, RpcValue)> {
+        let values = *self.values.lock().unwrap();
+        SENSORS.iter().zip(values).map(|(sensor, value)| (sensor.path.to_string(), value.into())).collect()
+    }
+
+    pub(crate) fn reset(&self) {
+        *self.values.lock().unwrap() = SENSORS.map(|sensor| sensor.base);
+    }
+}
+
+/// Updates all sensors on a shared timer and emits `chng` for each of them.
+pub(crate) async fn run(app_state: AppState<State>) {
+    let Some(suite) = &app_state.sensors else {
+        return;
+    };
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut weather: f64 = 0.;
+    loop {
+        runtime::sleep(TICK).await;
+        weather = tick(&app_state, suite, &app_state.connection, &mut rng, weather);
+    }
+}
+
+/// Moves the weather on by one step, updates the sensors and returns the new weather.
+fn tick(state: &State, suite: &SensorSuite, client_cmd_tx: &impl MessageSink, rng: &mut StdRng, weather: f64) -> f64 {
+    let weather = (weather + rng.gen_range(-0.05..=0.05)).clamp(-1., 1.);
+    let values = SENSORS.map(|sensor| {
+        let value = sensor.base + sensor.weather_gain * weather + rng.gen_range(-sensor.noise..=sensor.noise);
+        (value.clamp(sensor.range.0, sensor.range.1) * 10.).round() / 10.
+    });
+    *suite.values.lock().unwrap() = values;
+    for (sensor, value) in SENSORS.iter().zip(values) {
+        emit_chng(state, client_cmd_tx, sensor.path, value.into());
+    }
+    weather
+}
+
+#[cfg(test)]
+mod tests {
+    use shvclient::clientnode::SIG_CHNG;
+
+    use super::*;
+    use crate::signals::Collected;
+
+    fn sensor_paths() -> Vec<&'static str> {
+        SENSORS.iter().map(|sensor| sensor.path).collect()
+    }
+
+    #[test]
+    fn suite_mounts_all_sensors() {
+        let mounted = |args: &[&str]| -> Vec<String> {
+            crate::device_nodes(&crate::test_state(args)).into_iter().map(|(path, _)| path).collect()
+        };
+        let with_suite = mounted(&["--sensor-suite"]);
+        let without_suite = mounted(&[]);
+        for path in sensor_paths() {
+            assert!(with_suite.iter().any(|mounted| mounted == path), "{path}");
+            assert!(!without_suite.iter().any(|mounted| mounted == path), "{path}");
+        }
+    }
+
+    #[test]
+    fn tick_emits_every_sensor_in_range() {
+        let state = crate::test_state(&["--sensor-suite"]);
+        let suite = state.sensors.as_ref().unwrap();
+        let collected = Collected::default();
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let mut weather = 0.;
+        for _ in 0..10 {
+            weather = tick(&state, suite, &collected, &mut rng, weather);
+            let signals = collected.take();
+            let paths: Vec<&str> = signals.iter().map(|(path, _, _)| path.as_str()).collect();
+            assert_eq!(paths, sensor_paths());
+            for ((_, method, value), sensor) in signals.iter().zip(&SENSORS) {
+                assert_eq!(method, SIG_CHNG);
+                assert!((sensor.range.0..=sensor.range.1).contains(&value.as_f64()), "{} {}", sensor.path, value.as_f64());
+            }
+        }
+    }
+}
@@ -0,0 +1,825 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use shvclient::clientnode::SIG_CHNG;
+use shvclient::{AppState, ClientCommandSender};
+use shvproto::rpcvalue::Map;
+use shvproto::RpcValue;
+use shvrpc::RpcMessage;
+
+use crate::dispatch::{estimate_size, MESSAGE_OVERHEAD_BYTES};
+use crate::metrics::Metrics;
+use crate::{bridge, runtime, Opts, State};
+
+/// Meta tag holding the emission time of a signal with `--timestamp-signals`,
+/// a DateTime taken from the device clock (clock offset applied).
+pub(crate) const TIMESTAMP_TAG: &str = "ts";
+const SIG_LSMOD: &str = "lsmod";
+
+/// Receiver of the messages a node emits, the client in the device and a collecting sink in tests.
+pub(crate) trait MessageSink {
+    fn deliver(&self, message: RpcMessage);
+}
+
+impl MessageSink for ClientCommandSender {
+    fn deliver(&self, message: RpcMessage) {
+        let _ = self.send_message(message);
+    }
+}
+
+impl<S: MessageSink + ?Sized> MessageSink for &S {
+    fn deliver(&self, message: RpcMessage) {
+        (**self).deliver(message);
+    }
+}
+
+impl<S: MessageSink + ?Sized> MessageSink for std::sync::Arc<S> {
+    fn deliver(&self, message: RpcMessage) {
+        (**self).deliver(message);
+    }
+}
+
+/// Keeps every delivered message, for tests to check the signals a node emitted.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct Collected(Mutex<Vec<RpcMessage>>);
+
+#[cfg(test)]
+impl Collected {
+    /// The messages delivered since the last call.
+    pub(crate) fn take_messages(&self) -> Vec<RpcMessage> {
+        self.0.lock().unwrap().drain(..).collect()
+    }
+
+    /// Path, method and param of the messages delivered since the last call.
+    pub(crate) fn take(&self) -> Vec<(String, String, RpcValue)> {
+        self.take_messages().into_iter()
+            .map(|message| (
+                message.shv_path().unwrap_or_default().to_string(),
+                message.method().unwrap_or_default().to_string(),
+                message.param().cloned().unwrap_or_else(RpcValue::null),
+            ))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl MessageSink for Collected {
+    fn deliver(&self, message: RpcMessage) {
+        self.0.lock().unwrap().push(message);
+    }
+}
+
+/// Signal emission settings and bookkeeping shared by all nodes.
+pub(crate) struct Signals {
+    connected: AtomicBool,
+    snapshot_on_connect: bool,
+    replay: Option<ReplayBuffer>,
+    order: SignalOrder,
+    shape: SignalShape,
+    duplicate_rate: f64,
+    rng: Mutex<StdRng>,
+    coalesce: Option<Coalesce>,
+    queue: Option<SignalQueue>,
+    timestamp: bool,
+    last_values: Option<Mutex<BTreeMap<String, RpcValue>>>,
+    names: BTreeMap<String, String>,
+}
+
+/// Order in which a batch of signals emitted together goes out.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub(crate) enum SignalOrder {
+    /// Sorted by node path.
+    Path,
+    /// In the order the changes were made.
+    #[default]
+    Insertion,
+}
+
+impl SignalOrder {
+    fn arrange(self, batch: &mut [(String, RpcValue)]) {
+        if let SignalOrder::Path = self {
+            batch.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+    }
+}
+
+/// Structure of the `chng` signal parameter.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub(crate) enum SignalShape {
+    /// The bare node value.
+    #[default]
+    Scalar,
+    /// `{"value": <node value>}`, with an optional `"meta"` Map of additional
+    /// information, following the newer SHV property-change convention.
+    #[value(name = "valueChange")]
+    ValueChange,
+}
+
+impl SignalShape {
+    fn wrap(self, value: RpcValue) -> RpcValue {
+        match self {
+            SignalShape::Scalar => value,
+            SignalShape::ValueChange => {
+                let mut map = Map::new();
+                map.insert("value".into(), value);
+                map.into()
+            }
+        }
+    }
+}
+
+/// Policy applied to a new signal when the outbound queue is full.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub(crate) enum SignalOverflow {
+    /// Discard the oldest queued signal.
+    #[default]
+    DropOldest,
+    /// Discard the new signal.
+    DropNewest,
+    /// Queue the new signal, the emitting request handler or generator then waits in
+    /// [`wait_for_room`] until the queue has room again.
+    Block,
+}
+
+/// Bounded queue between signal emission and the client, drained by [`drain_queue`]
+/// no faster than `interval` apart with `--consumer-rate`.
+struct SignalQueue {
+    capacity: usize,
+    interval: Option<Duration>,
+    overflow: SignalOverflow,
+    messages: Mutex<VecDeque<RpcMessage>>,
+    space: event_listener::Event,
+    wakeup: (async_std::channel::Sender<()>, async_std::channel::Receiver<()>),
+}
+
+impl SignalQueue {
+    fn new(capacity: usize, interval: Option<Duration>, overflow: SignalOverflow) -> Self {
+        Self {
+            capacity,
+            interval,
+            overflow,
+            messages: Default::default(),
+            space: event_listener::Event::new(),
+            wakeup: async_std::channel::bounded(1),
+        }
+    }
+
+    /// Signals are emitted from synchronous code, so with [`SignalOverflow::Block`] the
+    /// message is always queued and the queue may exceed its capacity by the signals
+    /// emitted before the emitter reaches [`Self::wait_for_room`].
+    fn push(&self, metrics: &Metrics, message: RpcMessage) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= self.capacity {
+            match self.overflow {
+                SignalOverflow::DropOldest => {
+                    messages.pop_front();
+                    Metrics::inc(&metrics.signals_dropped);
+                }
+                SignalOverflow::DropNewest => {
+                    Metrics::inc(&metrics.signals_dropped);
+                    return;
+                }
+                SignalOverflow::Block => {}
+            }
+        }
+        messages.push_back(message);
+        metrics.signal_queue_depth.store(messages.len() as u64, Ordering::Relaxed);
+        let _ = self.wakeup.0.try_send(());
+    }
+
+    fn pop(&self, metrics: &Metrics) -> Option<RpcMessage> {
+        let mut messages = self.messages.lock().unwrap();
+        let message = messages.pop_front();
+        metrics.signal_queue_depth.store(messages.len() as u64, Ordering::Relaxed);
+        drop(messages);
+        if message.is_some() {
+            self.space.notify(usize::MAX);
+        }
+        message
+    }
+
+    fn clear(&self) {
+        self.messages.lock().unwrap().clear();
+        self.space.notify(usize::MAX);
+    }
+
+    fn is_full(&self) -> bool {
+        self.messages.lock().unwrap().len() >= self.capacity
+    }
+
+    async fn wait_for_room(&self) {
+        loop {
+            if !self.is_full() {
+                return;
+            }
+            // Registered before the second check, a pop in between is not missed.
+            let listener = self.space.listen();
+            if !self.is_full() {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
+
+/// Signals emitted while disconnected, sent again once the connection is back.
+struct ReplayBuffer {
+    capacity: usize,
+    messages: Mutex<VecDeque<RpcMessage>>,
+}
+
+/// Signals of `state/*` nodes held back until no newer value arrives for `window`.
+struct Coalesce {
+    window: Duration,
+    pending: Mutex<BTreeMap<String, (Instant, RpcValue)>>,
+}
+
+impl Coalesce {
+    /// Replaces the held value of `path` and restarts its window.
+    fn hold(&self, path: &str, value: RpcValue, now: Instant) {
+        self.pending.lock().unwrap().insert(path.to_string(), (now + self.window, value));
+    }
+
+    /// Removes the values whose window has passed at `now`, returns them with the
+    /// deadline of the next held value.
+    fn take_due(&self, now: Instant) -> (BTreeMap<String, RpcValue>, Option<Instant>) {
+        let mut pending = self.pending.lock().unwrap();
+        let (due, rest): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut *pending).into_iter()
+            .partition(|(_, (deadline, _))| *deadline <= now);
+        *pending = rest;
+        let due = due.into_iter().map(|(path, (_, value))| (path, value)).collect();
+        (due, pending.values().map(|(deadline, _)| *deadline).min())
+    }
+}
+
+impl Signals {
+    pub(crate) fn new(opts: &Opts) -> Result<Self, String> {
+        if !(0.0..=1.0).contains(&opts.signal_duplicate_rate) {
+            return Err(format!("Signal duplicate rate {} is out of range 0..1", opts.signal_duplicate_rate));
+        }
+        let coalesce = opts.coalesce_window.as_deref()
+            .map(|window| duration_str::parse(window).map_err(|err| format!("Invalid coalesce window: {err}")))
+            .transpose()?
+            .map(|window| Coalesce { window, pending: Default::default() });
+        if opts.signal_queue_size == Some(0) {
+            return Err("Signal queue size must be positive".into());
+        }
+        if opts.consumer_rate.is_some_and(|rate| rate.is_nan() || rate <= 0.) {
+            return Err("Consumer rate must be positive".into());
+        }
+        let queue = (opts.signal_queue_size.is_some() || opts.consumer_rate.is_some() || opts.max_send_rate.is_some()).then(|| SignalQueue::new(
+            opts.signal_queue_size.unwrap_or(usize::MAX),
+            opts.consumer_rate.map(|rate| Duration::from_secs_f64(1. / rate)),
+            opts.signal_overflow,
+        ));
+        let names = opts.signal_name.iter()
+            .map(|entry| {
+                let (mount, name) = entry.split_once('=').ok_or_else(|| format!("Invalid signal name '{entry}', expected mount=name"))?;
+                let mut chars = name.chars();
+                let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !valid {
+                    return Err(format!("Invalid signal name '{name}' for {mount}, expected an identifier"));
+                }
+                Ok((mount.to_string(), name.to_string()))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            connected: Default::default(),
+            snapshot_on_connect: opts.emit_snapshot_on_connect,
+            replay: opts.replay_on_reconnect.then(|| ReplayBuffer { capacity: opts.replay_buffer_size, messages: Default::default() }),
+            order: opts.signal_order,
+            shape: opts.signal_shape,
+            duplicate_rate: opts.signal_duplicate_rate,
+            rng: Mutex::new(StdRng::seed_from_u64(opts.signal_duplicate_seed)),
+            coalesce,
+            queue,
+            timestamp: opts.timestamp_signals,
+            last_values: opts.retain_last_value.then(Default::default),
+            names,
+        })
+    }
+
+    /// Drops signals waiting in the replay buffer and coalescing window.
+    pub(crate) fn clear(&self) {
+        if let Some(replay) = &self.replay {
+            replay.messages.lock().unwrap().clear();
+        }
+        if let Some(coalesce) = &self.coalesce {
+            coalesce.pending.lock().unwrap().clear();
+        }
+        if let Some(queue) = &self.queue {
+            queue.clear();
+        }
+    }
+
+    fn duplicate(&self) -> bool {
+        self.duplicate_rate > 0. && self.rng.lock().unwrap().gen_bool(self.duplicate_rate)
+    }
+}
+
+/// Emits a `chng` signal on `path`, every node sends its signals through here.
+/// `--signal-name` replaces `chng` with another name for the given node.
+///
+/// With `--coalesce-window` signals of `state/*` nodes are only queued here, each
+/// new value replaces the queued one and restarts the window, [`flush_coalesced`]
+/// emits the last value once the window passes without a change.
+///
+/// With `--signal-duplicate-rate` a signal may be sent twice in a row. The duplicate
+/// is a copy of the final message, so it shares the fate of the original in every
+/// later stage (replay buffering included).
+pub(crate) fn emit_chng(state: &State, client_cmd_tx: &impl MessageSink, path: &str, value: RpcValue) {
+    if state.faults.availability.is_unavailable(path) {
+        return;
+    }
+    bridge::mirror(state, path, &value);
+    if let Some(coalesce) = &state.signals.coalesce {
+        if path.starts_with("state/") {
+            coalesce.hold(path, value, Instant::now());
+            return;
+        }
+    }
+    emit(state, client_cmd_tx, path, value);
+}
+
+/// With `--extra-mount` the node exists under every mount, so each of them gets its own signal.
+fn emit(state: &State, client_cmd_tx: &impl MessageSink, path: &str, value: RpcValue) {
+    if let Some(last_values) = &state.signals.last_values {
+        last_values.lock().unwrap().insert(path.to_string(), value.clone());
+    }
+    state.journal.record(state.clock.now(), path, &value);
+    let value = state.signals.shape.wrap(state.node_formats.wrap(path, value));
+    state.subscriptions.emitted(path, &value);
+    let signal = state.signals.names.get(path).map_or(SIG_CHNG, String::as_str);
+    for mount in &state.extra_mounts {
+        emit_one(state, client_cmd_tx, &format!("{mount}/{path}"), signal, value.clone());
+    }
+    emit_one(state, client_cmd_tx, path, signal, value);
+}
+
+fn emit_one(state: &State, client_cmd_tx: &impl MessageSink, path: &str, signal: &str, value: RpcValue) {
+    for message in signal_messages(state, path, signal, value) {
+        send(state, client_cmd_tx, message);
+    }
+}
+
+/// The message of one signal, followed by its copy when it is duplicated.
+fn signal_messages(state: &State, path: &str, signal: &str, value: RpcValue) -> Vec<RpcMessage> {
+    let size = estimate_size(&value) + path.len() + signal.len() + MESSAGE_OVERHEAD_BYTES;
+    state.metrics.record_message(size);
+    Metrics::inc(&state.metrics.signals_sent);
+    state.recording.signal(state.clock.now(), path, signal, &value);
+    let mut sigchng = RpcMessage::new_signal(path, signal, Some(value));
+    if state.signals.timestamp {
+        if let Some(meta) = sigchng.meta_mut() {
+            meta.insert(TIMESTAMP_TAG, state.clock.now().into());
+        }
+    }
+    if state.signals.duplicate() {
+        info!("Duplicating signal {path}:{signal}");
+        return vec![sigchng.clone(), sigchng];
+    }
+    vec![sigchng]
+}
+
+/// Announces that the child `name` of `parent` appeared or vanished, under every mount like a `chng`.
+pub(crate) fn emit_lsmod(state: &State, client_cmd_tx: &impl MessageSink, parent: &str, name: &str, exists: bool) {
+    let mut children = Map::new();
+    children.insert(name.to_string(), exists.into());
+    let value = RpcValue::from(children);
+    for mount in &state.extra_mounts {
+        emit_one(state, client_cmd_tx, &format!("{mount}/{parent}"), SIG_LSMOD, value.clone());
+    }
+    emit_one(state, client_cmd_tx, parent, SIG_LSMOD, value);
+}
+
+/// Sends a signal as recorded in a scenario trace, the value is already shaped and the path includes the mount.
+pub(crate) fn emit_recorded(state: &State, client_cmd_tx: &impl MessageSink, path: &str, signal: &str, value: RpcValue) {
+    emit_one(state, client_cmd_tx, path, signal, value);
+}
+
+/// Emits coalesced signals whose window has passed, runs for the whole device lifetime.
+pub(crate) async fn flush_coalesced(app_state: AppState<State>) {
+    let Some(coalesce) = &app_state.signals.coalesce else {
+        return;
+    };
+    loop {
+        let now = Instant::now();
+        let next_deadline = flush_due(&app_state, coalesce, app_state.connection.client_cmd_tx().as_ref(), now);
+        // New entries are due a full window from now at the earliest.
+        let delay = next_deadline.map_or(coalesce.window, |deadline| deadline.saturating_duration_since(now));
+        runtime::sleep(delay).await;
+    }
+}
+
+/// Emits the coalesced signals due at `now`, returns the next deadline. Without a
+/// connection nothing is taken, the held values go out once the device is connected again.
+fn flush_due(state: &State, coalesce: &Coalesce, client_cmd_tx: Option<&impl MessageSink>, now: Instant) -> Option<Instant> {
+    let client_cmd_tx = client_cmd_tx?;
+    let (due, next_deadline) = coalesce.take_due(now);
+    for (path, value) in due {
+        emit(state, client_cmd_tx, &path, value);
+    }
+    next_deadline
+}
+
+fn send(state: &State, client_cmd_tx: &impl MessageSink, message: RpcMessage) {
+    if let Some(replay) = &state.signals.replay {
+        if !state.signals.connected.load(Ordering::SeqCst) {
+            let mut messages = replay.messages.lock().unwrap();
+            if messages.len() == replay.capacity {
+                messages.pop_front();
+            }
+            messages.push_back(message);
+            return;
+        }
+    }
+    enqueue(state, client_cmd_tx, message);
+}
+
+/// Hands a message over to the outbound queue, or to the client directly without `--signal-queue-size`.
+fn enqueue(state: &State, client_cmd_tx: &impl MessageSink, message: RpcMessage) {
+    if state.faults.partition.drops_signals() {
+        return;
+    }
+    match &state.signals.queue {
+        Some(queue) => queue.push(&state.metrics, message),
+        None => client_cmd_tx.deliver(message),
+    }
+}
+
+/// Moves queued signals to the current client, runs for the whole device lifetime.
+/// While disconnected the signals stay queued, subject to the overflow policy.
+pub(crate) async fn drain_queue(app_state: AppState<State>) {
+    let Some(queue) = &app_state.signals.queue else {
+        return;
+    };
+    drain(&app_state, queue, |message| app_state.connection.client_cmd_tx()
+        .is_some_and(|client_cmd_tx| client_cmd_tx.send_message(message).is_ok())).await;
+}
+
+/// The loop of [`drain_queue`], `send` returns false when the message could not be sent.
+/// With `--consumer-rate` consecutive signals are at least one interval apart.
+async fn drain(state: &State, queue: &SignalQueue, send: impl Fn(RpcMessage) -> bool) {
+    loop {
+        // Held in the queue while disconnected, on_connected wakes the loop up again.
+        if !state.signals.connected.load(Ordering::SeqCst) {
+            let _ = queue.wakeup.1.recv().await;
+            continue;
+        }
+        let Some(message) = queue.pop(&state.metrics) else {
+            let _ = queue.wakeup.1.recv().await;
+            continue;
+        };
+        state.backpressure.pace().await;
+        if !send(message) {
+            // The connection went down after the check above.
+            Metrics::inc(&state.metrics.signals_dropped);
+        }
+        match queue.interval {
+            Some(interval) => runtime::sleep(interval).await,
+            None => runtime::yield_now().await,
+        }
+    }
+}
+
+/// With `--signal-overflow block` waits until the outbound queue has room, this is
+/// where emitting request handlers and generators stall while the consumer lags.
+pub(crate) async fn wait_for_room(state: &State) {
+    if let Some(queue) = &state.signals.queue {
+        if let SignalOverflow::Block = queue.overflow {
+            queue.wait_for_room().await;
+        }
+    }
+}
+
+/// Emits `chng` signals for a group of changes in the configured [`SignalOrder`].
+/// This is the only guarantee on relative order of signals the device gives.
+pub(crate) fn emit_batch(state: &State, client_cmd_tx: &impl MessageSink, mut batch: Vec<(String, RpcValue)>) {
+    state.signals.order.arrange(&mut batch);
+    for (path, value) in batch {
+        emit_chng(state, client_cmd_tx, &path, value);
+    }
+}
+
+pub(crate) fn on_disconnected(state: &State) {
+    state.signals.connected.store(false, Ordering::SeqCst);
+}
+
+/// Replays signals buffered during the disconnect and re-emits current values,
+/// so that subscribers recover a coherent view after a connection flap.
+///
+/// SHV has no retained flag and the device cannot observe new subscriptions, so
+/// `--retain-last-value` is an emulation: the last value signalled on every node is
+/// emitted again, in path order, each time the connection comes up.
+pub(crate) async fn on_connected(state: &State, client_cmd_tx: &impl MessageSink) {
+    state.signals.connected.store(true, Ordering::SeqCst);
+    if let Some(queue) = &state.signals.queue {
+        let _ = queue.wakeup.0.try_send(());
+    }
+    if let Some(replay) = &state.signals.replay {
+        let messages: Vec<_> = replay.messages.lock().unwrap().drain(..).collect();
+        if !messages.is_empty() {
+            info!("Replaying {} signals buffered while disconnected", messages.len());
+        }
+        for message in messages {
+            enqueue(state, client_cmd_tx, message);
+        }
+    }
+    if let Some(last_values) = &state.signals.last_values {
+        let values = last_values.lock().unwrap().clone();
+        for (path, value) in values {
+            if !state.faults.availability.is_unavailable(&path) {
+                emit(state, client_cmd_tx, &path, value);
+            }
+        }
+    }
+    if state.signals.snapshot_on_connect {
+        state.emit_snapshot(client_cmd_tx).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use shvproto::rpcvalue::Value;
+
+    use super::*;
+
+    /// Pushes three signals into a queue of capacity 2 that nothing drains.
+    fn overflow(policy: SignalOverflow) -> (SignalQueue, Metrics) {
+        let queue = SignalQueue::new(2, None, policy);
+        let metrics = Metrics::default();
+        for n in 1..=3 {
+            queue.push(&metrics, RpcMessage::new_signal("test/queue", SIG_CHNG, Some(n.into())));
+        }
+        (queue, metrics)
+    }
+
+    fn pop_value(queue: &SignalQueue, metrics: &Metrics) -> Option<i64> {
+        queue.pop(metrics).map(|message| message.param().unwrap().as_int())
+    }
+
+    fn drain(queue: &SignalQueue, metrics: &Metrics) -> Vec<i64> {
+        std::iter::from_fn(|| pop_value(queue, metrics)).collect()
+    }
+
+    fn batch(paths: &[&str]) -> Vec<(String, RpcValue)> {
+        paths.iter().map(|path| (path.to_string(), RpcValue::null())).collect()
+    }
+
+    fn batch_paths(batch: &[(String, RpcValue)]) -> Vec<&str> {
+        batch.iter().map(|(path, _)| path.as_str()).collect()
+    }
+
+    #[test]
+    fn path_order_sorts_batch() {
+        let mut signals = batch(&["state/text", "state/map", "state/number"]);
+        SignalOrder::Path.arrange(&mut signals);
+        assert_eq!(batch_paths(&signals), ["state/map", "state/number", "state/text"]);
+    }
+
+    #[test]
+    fn insertion_order_keeps_batch() {
+        let mut signals = batch(&["state/text", "state/map", "state/number"]);
+        SignalOrder::Insertion.arrange(&mut signals);
+        assert_eq!(batch_paths(&signals), ["state/text", "state/map", "state/number"]);
+    }
+
+    #[test]
+    fn scalar_shape_is_bare_value() {
+        assert_eq!(SignalShape::Scalar.wrap(42.into()), RpcValue::from(42));
+    }
+
+    #[test]
+    fn value_change_shape_wraps_value() {
+        let wrapped = SignalShape::ValueChange.wrap("on".into());
+        let Value::Map(map) = wrapped.value() else {
+            panic!("valueChange shape is not a Map");
+        };
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get("value"), Some(&RpcValue::from("on")));
+    }
+
+    #[test]
+    fn value_change_shape_parses_camel_case() {
+        let opts = Opts::parse_args([env!("CARGO_PKG_NAME"), "--signal-shape", "valueChange"]).unwrap();
+        assert!(matches!(opts.signal_shape, SignalShape::ValueChange));
+    }
+
+    #[test]
+    fn duplicate_rate_one_sends_every_signal_twice() {
+        let state = crate::test_state(&["--signal-duplicate-rate", "1"]);
+        for n in 0..10 {
+            let messages = signal_messages(&state, "state/number", SIG_CHNG, n.into());
+            assert_eq!(messages.len(), 2);
+            assert!(messages.iter().all(|message| message.param() == Some(&RpcValue::from(n))));
+        }
+    }
+
+    #[test]
+    fn duplicates_are_off_by_default() {
+        let state = crate::test_state(&[]);
+        for n in 0..10 {
+            assert_eq!(signal_messages(&state, "state/number", SIG_CHNG, n.into()).len(), 1);
+        }
+    }
+
+    #[test]
+    fn coalesce_emits_last_value_once() {
+        let coalesce = Coalesce { window: Duration::from_millis(100), pending: Default::default() };
+        let start = Instant::now();
+        for n in 0..5 {
+            coalesce.hold("state/number", n.into(), start + Duration::from_millis(10 * n as u64));
+        }
+        let last_deadline = start + Duration::from_millis(140);
+        let (due, next_deadline) = coalesce.take_due(start + Duration::from_millis(120));
+        assert!(due.is_empty());
+        assert_eq!(next_deadline, Some(last_deadline));
+        let (due, next_deadline) = coalesce.take_due(last_deadline);
+        assert_eq!(due.into_iter().collect::<Vec<_>>(), [("state/number".to_string(), RpcValue::from(4))]);
+        assert_eq!(next_deadline, None);
+        assert!(coalesce.take_due(last_deadline + Duration::from_secs(1)).0.is_empty());
+    }
+
+    #[test]
+    fn coalesced_signals_wait_for_connection() {
+        let state = crate::test_state(&["--coalesce-window", "10ms"]);
+        let coalesce = state.signals.coalesce.as_ref().unwrap();
+        let collected = Collected::default();
+        emit_chng(&state, &collected, "state/number", 7.into());
+        let due = Instant::now() + Duration::from_secs(1);
+        assert_eq!(flush_due(&state, coalesce, None::<&Collected>, due), None);
+        assert!(collected.take().is_empty());
+        flush_due(&state, coalesce, Some(&collected), due);
+        assert_eq!(collected.take(), [("state/number".to_string(), SIG_CHNG.to_string(), RpcValue::from(7))]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_newest() {
+        let (queue, metrics) = overflow(SignalOverflow::DropOldest);
+        assert_eq!(drain(&queue, &metrics), [2, 3]);
+        assert_eq!(metrics.signals_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drop_newest_keeps_oldest() {
+        let (queue, metrics) = overflow(SignalOverflow::DropNewest);
+        assert_eq!(drain(&queue, &metrics), [1, 2]);
+        assert_eq!(metrics.signals_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let (queue, metrics) = overflow(SignalOverflow::Block);
+        assert_eq!(metrics.signals_dropped.load(Ordering::Relaxed), 0);
+        assert!(queue.wait_for_room().now_or_never().is_none());
+        assert_eq!(pop_value(&queue, &metrics), Some(1));
+        assert!(queue.wait_for_room().now_or_never().is_none());
+        assert_eq!(pop_value(&queue, &metrics), Some(2));
+        assert!(queue.wait_for_room().now_or_never().is_some());
+        assert_eq!(drain(&queue, &metrics), [3]);
+    }
+
+    #[test]
+    fn block_wakes_waiter_on_pop() {
+        let (queue, metrics) = overflow(SignalOverflow::Block);
+        let mut waiter = Box::pin(queue.wait_for_room());
+        assert!((&mut waiter).now_or_never().is_none());
+        queue.pop(&metrics);
+        queue.pop(&metrics);
+        assert!(waiter.now_or_never().is_some());
+    }
+
+    #[test]
+    fn clear_releases_waiters() {
+        let (queue, _) = overflow(SignalOverflow::Block);
+        let mut waiter = Box::pin(queue.wait_for_room());
+        assert!((&mut waiter).now_or_never().is_none());
+        queue.clear();
+        assert!(waiter.now_or_never().is_some());
+    }
+
+    /// Sets state/number as a generator tick would.
+    fn generate(state: &State, client_cmd_tx: &Collected, value: i32) {
+        let changed = state.update_number(value).unwrap().unwrap();
+        emit_chng(state, client_cmd_tx, crate::NUMBER_MOUNT, changed);
+    }
+
+    #[test]
+    fn flap_mid_generation_replays_gap_then_snapshot() {
+        let state = crate::test_state(&["--replay-on-reconnect", "--emit-snapshot-on-connect"]);
+        let collected = Collected::default();
+        let number = |value: i32| (crate::NUMBER_MOUNT.to_string(), SIG_CHNG.to_string(), RpcValue::from(value));
+        runtime::block_on(on_connected(&state, &collected));
+        collected.take();
+        generate(&state, &collected, 1);
+        assert_eq!(collected.take(), [number(1)]);
+
+        on_disconnected(&state);
+        generate(&state, &collected, 2);
+        generate(&state, &collected, 3);
+        assert!(collected.take().is_empty());
+
+        runtime::block_on(on_connected(&state, &collected));
+        let signals = collected.take();
+        assert_eq!(signals[..2], [number(2), number(3)]);
+        assert!(signals[2..].contains(&number(3)), "snapshot lacks the current value");
+        assert!(signals[2..].iter().any(|(path, _, _)| path == crate::sim::SIM_RAMP_MOUNT));
+    }
+
+    fn timestamp_of(message: &RpcMessage) -> Option<i64> {
+        let value = message.meta()?.get(TIMESTAMP_TAG)?;
+        match value.value() {
+            Value::DateTime(time) => Some(time.epoch_msec()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn timestamp_tag_is_device_time() {
+        let state = crate::test_state(&["--timestamp-signals", "--clock-offset", "1h"]);
+        let collected = Collected::default();
+        let before = state.clock.now().epoch_msec();
+        emit_chng(&state, &collected, crate::NUMBER_MOUNT, 1.into());
+        let after = state.clock.now().epoch_msec();
+        let [message] = collected.take_messages().try_into().expect("one signal");
+        let time = timestamp_of(&message).expect("no ts tag");
+        assert!((before..=after).contains(&time), "{before} {time} {after}");
+
+        let state = crate::test_state(&[]);
+        emit_chng(&state, &collected, crate::NUMBER_MOUNT, 1.into());
+        let [message] = collected.take_messages().try_into().expect("one signal");
+        assert_eq!(timestamp_of(&message), None);
+    }
+
+    #[test]
+    fn last_values_are_re_emitted_on_reconnect() {
+        let state = crate::test_state(&["--retain-last-value"]);
+        let collected = Collected::default();
+        let chng = |path: &str, value: i32| (path.to_string(), SIG_CHNG.to_string(), RpcValue::from(value));
+        runtime::block_on(on_connected(&state, &collected));
+        emit_chng(&state, &collected, crate::NUMBER_MOUNT, 1.into());
+        emit_chng(&state, &collected, crate::NUMBER_MOUNT, 2.into());
+        emit_chng(&state, &collected, crate::counter::COUNTER_MOUNT, 7.into());
+        collected.take();
+
+        on_disconnected(&state);
+        runtime::block_on(on_connected(&state, &collected));
+        assert_eq!(collected.take(), [chng(crate::counter::COUNTER_MOUNT, 7), chng(crate::NUMBER_MOUNT, 2)]);
+    }
+
+    #[test]
+    fn signal_name_override_replaces_chng() {
+        let state = crate::test_state(&["--signal-name", "state/number=valueChanged"]);
+        let collected = Collected::default();
+        emit_chng(&state, &collected, crate::NUMBER_MOUNT, 1.into());
+        emit_chng(&state, &collected, crate::TEXT_MOUNT, "text".into());
+        assert_eq!(collected.take(), [
+            (crate::NUMBER_MOUNT.to_string(), "valueChanged".to_string(), RpcValue::from(1)),
+            (crate::TEXT_MOUNT.to_string(), SIG_CHNG.to_string(), RpcValue::from("text")),
+        ]);
+    }
+
+    #[test]
+    fn signal_name_must_be_identifier() {
+        for entry in ["state/number", "state/number=", "state/number=value changed", "state/number=1st"] {
+            let opts = Opts::parse_args([env!("CARGO_PKG_NAME"), "--signal-name", entry]).unwrap();
+            assert!(Signals::new(&opts).is_err(), "{entry}");
+        }
+    }
+
+    #[test]
+    fn consumer_rate_meters_queued_signals() {
+        let state = crate::test_state(&["--consumer-rate", "20"]);
+        let collected = Collected::default();
+        runtime::block_on(on_connected(&state, &collected));
+        collected.take();
+        for n in 0..5 {
+            emit_chng(&state, &collected, crate::NUMBER_MOUNT, n.into());
+        }
+        assert!(collected.take().is_empty(), "metered signals bypassed the queue");
+        assert_eq!(state.metrics.signal_queue_depth.load(Ordering::Relaxed), 5);
+
+        let queue = state.signals.queue.as_ref().unwrap();
+        let sent = Mutex::new(Vec::new());
+        let started = Instant::now();
+        runtime::block_on(async {
+            let drain = drain(&state, queue, |message| {
+                sent.lock().unwrap().push((started.elapsed(), message.param().unwrap().as_int()));
+                true
+            });
+            futures::future::select(Box::pin(drain), Box::pin(runtime::sleep(Duration::from_millis(500)))).await;
+        });
+        let sent = sent.into_inner().unwrap();
+        assert_eq!(sent.iter().map(|(_, value)| *value).collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        assert!(sent.windows(2).all(|pair| pair[1].0 - pair[0].0 >= Duration::from_millis(45)), "{sent:?}");
+        assert_eq!(state.metrics.signal_queue_depth.load(Ordering::Relaxed), 0);
+    }
+}
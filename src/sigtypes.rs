@@ -0,0 +1,87 @@
+//! `test/sigtypes` emits signals covering every ChainPack type, so that serialization
+//! on the broker and decoding on clients can be checked with a single trigger.
+//!
+//! Each value is sent as a signal of `test/sigtypes` named after the entry, integers come
+//! at the limits of every width to exercise all ChainPack integer encodings.
+
+use shvproto::decimal::Decimal;
+use shvproto::rpcvalue::{IMap, Map};
+use shvproto::{DateTime, RpcValue};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::{signals, State};
+
+pub(crate) const SIGTYPES_MOUNT: &str = "test/sigtypes";
+
+/// Signal name and value of every entry of the matrix, in emission order.
+fn matrix() -> Vec<(&'static str, RpcValue)> {
+    let mut map = Map::new();
+    map.insert("bool".into(), true.into());
+    map.insert("string".into(), "text".into());
+    map.insert("empty".into(), RpcValue::null());
+    let mut imap = IMap::new();
+    imap.insert(0, 0.into());
+    imap.insert(-1, "negative key".into());
+    imap.insert(i32::MAX, 1.5.into());
+    let mut nested_imap = IMap::new();
+    nested_imap.insert(1, RpcValue::from(vec![RpcValue::from(map.clone()), RpcValue::from(vec![0xffu8, 0x00])]));
+    let mut nested = Map::new();
+    nested.insert("list".into(), vec![RpcValue::from(imap.clone()), RpcValue::from(Vec::<RpcValue>::new())].into());
+    nested.insert("imap".into(), nested_imap.into());
+    nested.insert("decimal".into(), Decimal::new(-1, -3).into());
+    nested.insert("deeper".into(), vec![RpcValue::from(vec![RpcValue::from(vec![RpcValue::from(map.clone())])])].into());
+    vec![
+        ("null", RpcValue::null()),
+        ("boolTrue", true.into()),
+        ("boolFalse", false.into()),
+        ("int0", 0.into()),
+        ("int8", i64::from(i8::MIN).into()),
+        ("int16", i64::from(i16::MIN).into()),
+        ("int32", i64::from(i32::MIN).into()),
+        ("int64", i64::MIN.into()),
+        ("intMax", i64::MAX.into()),
+        ("uint8", u64::from(u8::MAX).into()),
+        ("uint16", u64::from(u16::MAX).into()),
+        ("uint32", u64::from(u32::MAX).into()),
+        ("uint64", u64::MAX.into()),
+        ("double", std::f64::consts::PI.into()),
+        ("doubleNegativeZero", (-0.0f64).into()),
+        ("doubleInfinity", f64::INFINITY.into()),
+        ("doubleNaN", f64::NAN.into()),
+        ("decimal", Decimal::new(123456, -2).into()),
+        ("decimalNegativeExponent", Decimal::new(-5, -10).into()),
+        ("decimalPositiveExponent", Decimal::new(7, 6).into()),
+        ("string", "Příliš žluťoučký kůň \"úpěl\"\n\t\\".into()),
+        ("stringEmpty", "".into()),
+        ("blob", (0..=255u8).collect::<Vec<u8>>().into()),
+        ("blobEmpty", Vec::<u8>::new().into()),
+        ("dateTime", DateTime::from_epoch_msec(1_700_000_000_123).into()),
+        ("dateTimeTz", DateTime::from_epoch_msec_tz(1_700_000_000_123, -(5 * 3600 + 30 * 60)).into()),
+        ("list", vec![RpcValue::from(1), RpcValue::from("two"), RpcValue::from(3.0), RpcValue::null()].into()),
+        ("listEmpty", Vec::<RpcValue>::new().into()),
+        ("map", map.into()),
+        ("mapEmpty", Map::new().into()),
+        ("imap", imap.into()),
+        ("imapEmpty", IMap::new().into()),
+        ("nested", nested.into()),
+    ]
+}
+
+pub(crate) fn value() -> RpcValue {
+    let map: Map = matrix().into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+    map.into()
+}
+
+/// Emits the whole matrix, or only the entry named by `param`. Returns the number of signals sent.
+pub(crate) fn emit(state: &State, client_cmd_tx: &shvclient::ClientCommandSender, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+    let only = param.filter(|param| param.is_string()).map(RpcValue::as_str);
+    let entries: Vec<_> = matrix().into_iter().filter(|(name, _)| only.is_none_or(|only| only == *name)).collect();
+    if entries.is_empty() {
+        return Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("No entry named {}", only.unwrap_or_default())));
+    }
+    let count = entries.len() as i64;
+    for (name, value) in entries {
+        signals::emit_recorded(state, client_cmd_tx, SIGTYPES_MOUNT, name, value);
+    }
+    Ok(count.into())
+}
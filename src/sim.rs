@@ -0,0 +1,81 @@
+//! `sim/clock` and `sim/ramp` nodes, steady and predictable signal sources for
+//! dashboards and broker history tests.
+//!
+//! Both values are derived from a tick count only, so a consumer can compute the
+//! expected value of every signal: the clock emits the tick count itself, the ramp the
+//! tick position within a waveform of `--sim-ramp-steps` ticks, a sawtooth rising from
+//! 0 towards 1 or a sine between -1 and 1.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use shvclient::AppState;
+use shvproto::RpcValue;
+
+use crate::signals::emit_chng;
+use crate::{runtime, State};
+
+pub(crate) const SIM_CLOCK_MOUNT: &str = "sim/clock";
+pub(crate) const SIM_RAMP_MOUNT: &str = "sim/ramp";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub(crate) enum RampShape {
+    #[default]
+    Sawtooth,
+    Sine,
+}
+
+pub(crate) struct Sim {
+    shape: RampShape,
+    steps: u64,
+    clock_ticks: AtomicU64,
+    ramp_ticks: AtomicU64,
+}
+
+impl Sim {
+    pub(crate) fn new(shape: RampShape, steps: u64) -> Result<Self, String> {
+        if steps == 0 {
+            return Err("Ramp steps must be positive".to_string());
+        }
+        Ok(Self { shape, steps, clock_ticks: Default::default(), ramp_ticks: Default::default() })
+    }
+
+    pub(crate) fn clock_value(&self) -> RpcValue {
+        (self.clock_ticks.load(Ordering::SeqCst) as i64).into()
+    }
+
+    pub(crate) fn ramp_value(&self) -> RpcValue {
+        self.ramp_at(self.ramp_ticks.load(Ordering::SeqCst)).into()
+    }
+
+    fn ramp_at(&self, tick: u64) -> f64 {
+        let phase = (tick % self.steps) as f64 / self.steps as f64;
+        match self.shape {
+            RampShape::Sawtooth => phase,
+            RampShape::Sine => (std::f64::consts::TAU * phase).sin(),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.clock_ticks.store(0, Ordering::SeqCst);
+        self.ramp_ticks.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Advances sim/clock every `period`, runs for the whole device lifetime.
+pub(crate) async fn run_clock(app_state: AppState<State>, period: Duration) {
+    loop {
+        runtime::sleep(period).await;
+        let ticks = app_state.sim.clock_ticks.fetch_add(1, Ordering::SeqCst) + 1;
+        emit_chng(&app_state, &app_state.connection, SIM_CLOCK_MOUNT, (ticks as i64).into());
+    }
+}
+
+/// Advances sim/ramp by one step every `period`, runs for the whole device lifetime.
+pub(crate) async fn run_ramp(app_state: AppState<State>, period: Duration) {
+    loop {
+        runtime::sleep(period).await;
+        let tick = app_state.sim.ramp_ticks.fetch_add(1, Ordering::SeqCst) + 1;
+        emit_chng(&app_state, &app_state.connection, SIM_RAMP_MOUNT, app_state.sim.ramp_at(tick).into());
+    }
+}
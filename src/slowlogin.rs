@@ -0,0 +1,42 @@
+//! A device that is slow at each stage of connecting, for broker connection-timeout
+//! and half-open connection tests:
+//! - `--startup-delay`: waits before the first connection attempt
+//! - `--connect-stall`: after the TCP connect, sends nothing (not even the hello) for this long
+//! - `--login-delay`: holds the login request this long after the broker answered the hello
+//!
+//! The latter two apply to every connection attempt and work on the relay of
+//! control/backpressure, which they turn on, so they need a `tcp://` broker URL (or
+//! `ssl://` with the `tls` feature). All durations take milliseconds, e.g. `250ms`.
+
+use std::time::Duration;
+
+use log::*;
+
+use crate::Opts;
+
+#[derive(Default)]
+pub(crate) struct SlowLogin {
+    pub(crate) startup_delay: Option<Duration>,
+    pub(crate) connect_stall: Option<Duration>,
+    pub(crate) login_delay: Option<Duration>,
+}
+
+impl SlowLogin {
+    pub(crate) fn new(opts: &Opts) -> Result<Self, String> {
+        let parse = |value: &Option<String>, name: &str| value.as_deref()
+            .map(|value| duration_str::parse(value).map_err(|err| format!("Invalid {name}: {err}")))
+            .transpose();
+        Ok(Self {
+            startup_delay: parse(&opts.startup_delay, "startup delay")?,
+            connect_stall: parse(&opts.connect_stall, "connect stall")?,
+            login_delay: parse(&opts.login_delay, "login delay")?,
+        })
+    }
+
+    pub(crate) async fn before_startup(&self) {
+        if let Some(delay) = self.startup_delay {
+            info!("Delaying startup by {delay:?}");
+            crate::runtime::sleep(delay).await;
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! `.stats` exposes the device's own metrics through the broker, for orchestrators that
+//! do not reach the HTTP metrics endpoint. Every child is a property with `get` and a
+//! `chng` signal, the values are sampled every `--stats-interval` and signalled when changed:
+//! - `.stats/requests`: `{"path:method": Int}` handled requests per method
+//! - `.stats/signals`: signals sent, the `chng` signals of `.stats` itself excluded
+//! - `.stats/reconnects`: connects after the first one
+//! - `.stats/avgLatencyMs`: average handler execution time, as in status/latencyHistogram
+//! - `.stats/rssBytes`: resident set size of the process, null where `/proc` is missing
+//!
+//! Like status/latencyHistogram the average latency starts over after a soft reboot.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use shvclient::AppState;
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+
+use crate::metrics;
+use crate::{runtime, signals, State};
+
+pub(crate) const REQUESTS_MOUNT: &str = ".stats/requests";
+pub(crate) const SIGNALS_MOUNT: &str = ".stats/signals";
+pub(crate) const RECONNECTS_MOUNT: &str = ".stats/reconnects";
+pub(crate) const AVG_LATENCY_MOUNT: &str = ".stats/avgLatencyMs";
+pub(crate) const RSS_MOUNT: &str = ".stats/rssBytes";
+
+const MOUNTS: [&str; 5] = [REQUESTS_MOUNT, SIGNALS_MOUNT, RECONNECTS_MOUNT, AVG_LATENCY_MOUNT, RSS_MOUNT];
+
+#[derive(Default)]
+pub(crate) struct Stats {
+    connects: AtomicU64,
+    own_signals: AtomicU64,
+    /// Last signalled value per path.
+    signalled: Mutex<BTreeMap<&'static str, RpcValue>>,
+}
+
+impl Stats {
+    pub(crate) fn on_connected(&self) {
+        self.connects.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Forgets the signalled values, the next sample signals all of them.
+    pub(crate) fn clear(&self) {
+        self.signalled.lock().unwrap().clear();
+    }
+}
+
+pub(crate) fn value(state: &State, path: &str) -> RpcValue {
+    match path {
+        REQUESTS_MOUNT => {
+            let mut map = Map::new();
+            for (path, method, count) in state.method_stats.counts() {
+                map.insert(format!("{path}:{method}"), (count as i64).into());
+            }
+            map.into()
+        }
+        SIGNALS_MOUNT => {
+            let sent = state.metrics.signals_sent.load(Ordering::Relaxed);
+            (sent.saturating_sub(state.stats.own_signals.load(Ordering::Relaxed)) as i64).into()
+        }
+        RECONNECTS_MOUNT => (state.stats.connects.load(Ordering::SeqCst).saturating_sub(1) as i64).into(),
+        AVG_LATENCY_MOUNT => {
+            let count: u64 = state.latency_histogram.buckets().iter().map(|(_, count)| count).sum();
+            let total_ms = state.latency_histogram.total().as_secs_f64() * 1000.;
+            (if count > 0 { total_ms / count as f64 } else { 0. }).into()
+        }
+        RSS_MOUNT => match metrics::resources(0).value() {
+            Value::Map(resources) => resources.get("rssBytes").cloned().unwrap_or_default(),
+            _ => RpcValue::null(),
+        },
+        _ => RpcValue::null(),
+    }
+}
+
+pub(crate) async fn emit_changes(app_state: AppState<State>, interval: Duration) {
+    let stats = &app_state.stats;
+    loop {
+        if let Some(client_cmd_tx) = app_state.connection.client_cmd_tx() {
+            for path in MOUNTS {
+                let value = value(&app_state, path);
+                if stats.signalled.lock().unwrap().get(path) == Some(&value) {
+                    continue;
+                }
+                stats.signalled.lock().unwrap().insert(path, value.clone());
+                // One signal per mount, see signals::emit_chng.
+                stats.own_signals.fetch_add(1 + app_state.extra_mounts.len() as u64, Ordering::Relaxed);
+                signals::emit_chng(&app_state, &client_cmd_tx, path, value);
+            }
+        }
+        runtime::sleep(interval).await;
+    }
+}
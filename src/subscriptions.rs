@@ -0,0 +1,285 @@
+//! Subscriber mode: the device subscribes to signals on the broker and keeps statistics
+//! of what it receives, so that one process can verify signal routing round trips.
+//!
+//! Patterns are SHV RPC RIs such as `test/device/**:*:chng`, given with `--subscribe`
+//! or added at runtime through `control/subscriptions`. Every pattern is subscribed
+//! again on each connect. `history/signals` reports the count and last value per
+//! signal source.
+//!
+//! With `--self-subscribe` the device also subscribes to the signals of its own state
+//! nodes and `status/drift` compares, per node, the last value it emitted with the last
+//! value the broker delivered back. A difference that persists means a lost or
+//! reordered signal, a signal still on its way shows up for one round trip.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use futures::StreamExt;
+use log::*;
+use shvclient::{AppState, ClientCommandSender};
+use shvproto::rpcvalue::Map;
+use shvproto::{DateTime, RpcValue};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use shvrpc::ShvRI;
+
+use crate::{tasks, State};
+
+pub(crate) const SUBSCRIPTIONS_MOUNT: &str = "control/subscriptions";
+pub(crate) const SIGNAL_HISTORY_MOUNT: &str = "history/signals";
+pub(crate) const DRIFT_MOUNT: &str = "status/drift";
+const STATE_PREFIX: &str = "state/";
+
+struct Received {
+    count: u64,
+    value: RpcValue,
+    time: DateTime,
+}
+
+/// Last emitted and last received back value of a state node.
+#[derive(Default)]
+struct RoundTrip {
+    emitted: Option<RpcValue>,
+    received: Option<RpcValue>,
+}
+
+pub(crate) struct Subscriptions {
+    patterns: Mutex<BTreeSet<String>>,
+    received: Mutex<BTreeMap<(String, String), Received>>,
+    self_subscribe: bool,
+    round_trips: Mutex<BTreeMap<String, RoundTrip>>,
+}
+
+impl Subscriptions {
+    pub(crate) fn new(patterns: &[String], self_subscribe: bool) -> Result<Self, String> {
+        for pattern in patterns {
+            ShvRI::try_from(pattern.as_str()).map_err(|err| format!("Invalid subscription '{pattern}': {err}"))?;
+        }
+        Ok(Self {
+            patterns: Mutex::new(patterns.iter().cloned().collect()),
+            received: Default::default(),
+            self_subscribe,
+            round_trips: Default::default(),
+        })
+    }
+
+    pub(crate) fn patterns(&self) -> RpcValue {
+        let patterns: Vec<RpcValue> = self.patterns.lock().unwrap().iter().map(RpcValue::from).collect();
+        patterns.into()
+    }
+
+    fn record(&self, path: &str, signal: &str, value: RpcValue, time: DateTime) {
+        let mut received = self.received.lock().unwrap();
+        let entry = received.entry((path.to_string(), signal.to_string()))
+            .or_insert_with(|| Received { count: 0, value: RpcValue::null(), time });
+        entry.count += 1;
+        entry.value = value;
+        entry.time = time;
+    }
+
+    /// Records a received signal, `mount` is the mount of the device to recognize its own signals.
+    fn receive(&self, mount: Option<&str>, path: &str, signal: &str, value: RpcValue, time: DateTime) {
+        if self.self_subscribe {
+            if let Some(local) = local_path(mount, path).filter(|local| local.starts_with(STATE_PREFIX)) {
+                self.round_trips.lock().unwrap().entry(local.to_string()).or_default().received = Some(value.clone());
+            }
+        }
+        self.record(path, signal, value, time);
+    }
+
+    /// Remembers the value of a signal the device sent on a state node, as it went out.
+    pub(crate) fn emitted(&self, path: &str, value: &RpcValue) {
+        if self.self_subscribe && path.starts_with(STATE_PREFIX) {
+            self.round_trips.lock().unwrap().entry(path.to_string()).or_default().emitted = Some(value.clone());
+        }
+    }
+
+    /// State nodes whose last emitted and last received value differ, as
+    /// `{path: {"emitted": value, "received": value}}`, a side never seen is missing.
+    pub(crate) fn drift(&self) -> RpcValue {
+        let map: Map = self.round_trips.lock().unwrap().iter()
+            .filter(|(_, round_trip)| round_trip.emitted != round_trip.received)
+            .map(|(path, round_trip)| {
+                let mut map = Map::new();
+                if let Some(emitted) = &round_trip.emitted {
+                    map.insert("emitted".into(), emitted.clone());
+                }
+                if let Some(received) = &round_trip.received {
+                    map.insert("received".into(), received.clone());
+                }
+                (path.clone(), map.into())
+            })
+            .collect();
+        map.into()
+    }
+
+    pub(crate) fn reset_drift(&self) {
+        self.round_trips.lock().unwrap().clear();
+    }
+
+    pub(crate) fn clear_history(&self) {
+        self.received.lock().unwrap().clear();
+    }
+
+    /// One entry per received path and signal, sorted by path and signal.
+    pub(crate) fn history(&self) -> RpcValue {
+        let list: Vec<RpcValue> = self.received.lock().unwrap().iter()
+            .map(|((path, signal), received)| {
+                let mut map = Map::new();
+                map.insert("path".into(), path.as_str().into());
+                map.insert("signal".into(), signal.as_str().into());
+                map.insert("count".into(), (received.count as i64).into());
+                map.insert("lastValue".into(), received.value.clone());
+                map.insert("lastTs".into(), received.time.into());
+                RpcValue::from(map)
+            })
+            .collect();
+        list.into()
+    }
+}
+
+fn task_name(pattern: &str) -> String {
+    format!("subscription:{pattern}")
+}
+
+/// Path of a received signal relative to the device mount, None for signals of other devices.
+fn local_path<'a>(mount: Option<&str>, path: &'a str) -> Option<&'a str> {
+    match mount.filter(|mount| !mount.is_empty()) {
+        Some(mount) => path.strip_prefix(mount)?.strip_prefix('/'),
+        None => Some(path),
+    }
+}
+
+/// Pattern of the signals of the device's own state nodes.
+fn self_pattern(mount: Option<&str>) -> String {
+    match mount.filter(|mount| !mount.is_empty()) {
+        Some(mount) => format!("{mount}/{STATE_PREFIX}**:*:*"),
+        None => format!("{STATE_PREFIX}**:*:*"),
+    }
+}
+
+/// Subscribes all patterns, called on every connect.
+pub(crate) async fn on_connected(app_state: &AppState<State>, client_cmd_tx: &ClientCommandSender) {
+    let mut patterns: Vec<String> = app_state.subscriptions.patterns.lock().unwrap().iter().cloned().collect();
+    if app_state.subscriptions.self_subscribe {
+        let mount = app_state.client_config.lock().unwrap().mount.clone();
+        patterns.push(self_pattern(mount.as_deref()));
+    }
+    for pattern in patterns {
+        subscribe(app_state, client_cmd_tx, pattern).await;
+    }
+}
+
+/// Adds a pattern and subscribes it right away, returns false if it is already subscribed.
+pub(crate) async fn add(app_state: &AppState<State>, client_cmd_tx: &ClientCommandSender, pattern: String) -> Result<bool, RpcError> {
+    ShvRI::try_from(pattern.as_str())
+        .map_err(|err| RpcError::new(RpcErrorCode::InvalidParam, &format!("Invalid subscription '{pattern}': {err}")))?;
+    if !app_state.subscriptions.patterns.lock().unwrap().insert(pattern.clone()) {
+        return Ok(false);
+    }
+    subscribe(app_state, client_cmd_tx, pattern).await;
+    Ok(true)
+}
+
+/// Removes a pattern, its subscription ends with the task holding it. Returns false for an unknown pattern.
+pub(crate) async fn remove(app_state: &AppState<State>, pattern: &str) -> bool {
+    if !app_state.subscriptions.patterns.lock().unwrap().remove(pattern) {
+        return false;
+    }
+    let _ = app_state.tasks.cancel(&task_name(pattern)).await;
+    info!("Unsubscribed {pattern}");
+    true
+}
+
+/// The subscription lasts as long as its task, a previous task of the pattern is replaced.
+async fn subscribe(app_state: &AppState<State>, client_cmd_tx: &ClientCommandSender, pattern: String) {
+    let name = task_name(&pattern);
+    let _ = app_state.tasks.cancel(&name).await;
+    let ri = match ShvRI::try_from(pattern.as_str()) {
+        Ok(ri) => ri,
+        Err(err) => {
+            warn!("Invalid subscription '{pattern}': {err}");
+            return;
+        }
+    };
+    let mut subscriber = match client_cmd_tx.subscribe(ri).await {
+        Ok(subscriber) => subscriber,
+        Err(err) => {
+            warn!("Cannot subscribe {pattern}: {err}");
+            return;
+        }
+    };
+    info!("Subscribed {pattern}");
+    let state = app_state.clone();
+    let mount = app_state.client_config.lock().unwrap().mount.clone();
+    tasks::spawn(app_state, &name, async move {
+        while let Some(frame) = subscriber.next().await {
+            let signal = match frame.to_rpcmesage() {
+                Ok(signal) => signal,
+                Err(err) => {
+                    warn!("Invalid notification for {pattern}: {err}");
+                    continue;
+                }
+            };
+            let path = signal.shv_path().unwrap_or_default();
+            let name = signal.method().unwrap_or_default();
+            debug!("Received {path}:{name} {}", signal.param().map(RpcValue::to_cpon).unwrap_or_default());
+            state.subscriptions.receive(mount.as_deref(), path, name, signal.param().cloned().unwrap_or_default(), state.clock.now());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signals::{emit_chng, Collected};
+
+    /// Delivers the collected signals back to the device the way the broker would, under the mount.
+    fn round_trip(state: &State, collected: &Collected) {
+        let mount = state.client_config.lock().unwrap().mount.clone();
+        for (path, signal, value) in collected.take() {
+            let path = match mount.as_deref().filter(|mount| !mount.is_empty()) {
+                Some(mount) => format!("{mount}/{path}"),
+                None => path,
+            };
+            state.subscriptions.receive(mount.as_deref(), &path, &signal, value, state.clock.now());
+        }
+    }
+
+    #[test]
+    fn round_trip_reports_no_drift() {
+        let state = crate::test_state(&["--self-subscribe"]);
+        let collected = Collected::default();
+        for n in 0..3 {
+            emit_chng(&state, &collected, "state/number", n.into());
+            emit_chng(&state, &collected, "state/text", n.to_string().into());
+        }
+        round_trip(&state, &collected);
+        assert_eq!(state.subscriptions.drift(), RpcValue::from(Map::new()));
+    }
+
+    #[test]
+    fn lost_signal_is_drift_until_reset() {
+        let state = crate::test_state(&["--self-subscribe"]);
+        let collected = Collected::default();
+        emit_chng(&state, &collected, "state/number", 1.into());
+        round_trip(&state, &collected);
+        emit_chng(&state, &collected, "state/number", 2.into());
+        collected.take();
+        let mut expected = Map::new();
+        expected.insert("emitted".into(), 2.into());
+        expected.insert("received".into(), 1.into());
+        let mut drift = Map::new();
+        drift.insert("state/number".into(), expected.into());
+        assert_eq!(state.subscriptions.drift(), RpcValue::from(drift));
+        state.subscriptions.reset_drift();
+        assert_eq!(state.subscriptions.drift(), RpcValue::from(Map::new()));
+    }
+
+    #[test]
+    fn own_signals_are_recognized_under_the_mount() {
+        assert_eq!(local_path(Some("test/device"), "test/device/state/number"), Some("state/number"));
+        assert_eq!(local_path(Some("test/device"), "test/device2/state/number"), None);
+        assert_eq!(local_path(None, "state/number"), Some("state/number"));
+        assert_eq!(self_pattern(Some("test/device")), "test/device/state/**:*:*");
+    }
+}
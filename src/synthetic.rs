@@ -0,0 +1,523 @@
+//! Property nodes declared in a `--nodes-file` instead of compiled in.
+//!
+//! The file is a CPON Map of mount path to node description:
+//! `{"plant/boiler/temperature": {"type": "Double", "value": 21.5, "writable": true}}`.
+//! `type` is an SHV type name, `value` the initial value (Null by default) and
+//! `writable` enables `set` (false by default). Every node has `get`, `set` when
+//! writable, and `describe`, other methods cannot be declared.
+//!
+//! The declared nodes are served by a dynamic node mounted at the first segment of
+//! their paths (`plant` above), which answers `ls` and `dir` itself from the declared
+//! and the compiled-in nodes. Optional method metadata, e.g. `"methods": {"get":
+//! {"description": "Boiler temperature", "unit": "°C", "typeHint": "Double", "flags": 8}}`,
+//! is part of the `dir` descriptors and is returned by the node's `describe` method as
+//! well: a List of method descriptors in the SHV RPC 3 `dir` format, with `flags`
+//! added to the method flags and the other keys in the descriptor's extra Map.
+//!
+//! `control/nodes` adds and removes nodes at runtime while the device stays connected.
+//! The change is announced with `lsmod` on the parent of the topmost node that appears
+//! or vanishes, e.g. `plant/boiler:lsmod {"pressure": true}` when creating
+//! `plant/boiler/pressure`. A node whose first path segment is not mounted with the
+//! current connection takes a reconnect, which the broker sees as the device leaving
+//! and mounting again with the new tree.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use shvclient::{AppState, ClientCommandSender};
+use shvproto::rpcvalue::{IMap, Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use shvrpc::RpcMessage;
+
+use crate::anyvalue::type_name;
+use crate::signals::MessageSink;
+use crate::{dispatch, signals, State};
+
+pub(crate) const NODES_MOUNT: &str = "control/nodes";
+
+const DIR_NAME: i32 = 1;
+const DIR_FLAGS: i32 = 2;
+const DIR_PARAM: i32 = 3;
+const DIR_RESULT: i32 = 4;
+const DIR_ACCESS: i32 = 5;
+const DIR_SIGNALS: i32 = 6;
+const DIR_EXTRA: i32 = 63;
+const FLAG_IS_GETTER: i64 = 2;
+const FLAG_IS_SETTER: i64 = 4;
+const ACCESS_BROWSE: i64 = 1;
+const ACCESS_READ: i64 = 8;
+const ACCESS_WRITE: i64 = 16;
+const METADATA_KEYS: &[&str] = &["description", "unit", "typeHint"];
+
+struct Property {
+    type_name: String,
+    writable: bool,
+    initial: RpcValue,
+    value: RpcValue,
+    methods: BTreeMap<String, Map>,
+}
+
+impl Property {
+    fn parse(path: &str, node: &RpcValue) -> Result<Self, String> {
+        if path.is_empty() || path.split('/').any(str::is_empty) {
+            return Err(format!("invalid path '{path}'"));
+        }
+        let Value::Map(node) = node.value() else {
+            return Err(format!("node {path} is not a Map"));
+        };
+        let Some(Value::String(ty)) = node.get("type").map(RpcValue::value) else {
+            return Err(format!("node {path} has no type"));
+        };
+        let initial = node.get("value").cloned().unwrap_or_default();
+        if !initial.is_null() && type_name(&initial) != ty.as_str() {
+            return Err(format!("initial value of {path} is not {ty}"));
+        }
+        let writable = node.get("writable").is_some_and(RpcValue::as_bool);
+        let methods = match node.get("methods").map(RpcValue::value) {
+            None => BTreeMap::new(),
+            Some(Value::Map(methods)) => methods.iter()
+                .map(|(method, metadata)| Ok((method.clone(), parse_metadata(path, method, writable, metadata)?)))
+                .collect::<Result<_, String>>()?,
+            Some(_) => return Err(format!("methods of node {path} is not a Map")),
+        };
+        Ok(Self {
+            type_name: ty.to_string(),
+            writable,
+            value: initial.clone(),
+            initial,
+            methods,
+        })
+    }
+
+    /// Descriptors of the node's own methods, with the declared metadata.
+    fn descriptors(&self) -> Vec<IMap> {
+        let mut get = descriptor("get", FLAG_IS_GETTER, "Null", &self.type_name, ACCESS_READ);
+        let mut signals = Map::new();
+        signals.insert("chng".into(), self.type_name.as_str().into());
+        get.insert(DIR_SIGNALS, signals.into());
+        let mut methods = vec![("get", get)];
+        if self.writable {
+            methods.push(("set", descriptor("set", FLAG_IS_SETTER, &self.type_name, "Null", ACCESS_WRITE)));
+        }
+        methods.into_iter()
+            .map(|(name, mut descriptor)| {
+                if let Some(metadata) = self.methods.get(name) {
+                    let mut extra = metadata.clone();
+                    if let Some(flags) = extra.remove("flags") {
+                        let flags = descriptor.get(&DIR_FLAGS).map_or(0, RpcValue::as_int) | flags.as_int();
+                        descriptor.insert(DIR_FLAGS, flags.into());
+                    }
+                    if !extra.is_empty() {
+                        descriptor.insert(DIR_EXTRA, extra.into());
+                    }
+                }
+                descriptor
+            })
+            .collect()
+    }
+
+    fn describe(&self) -> RpcValue {
+        let descriptors: Vec<RpcValue> = self.descriptors().into_iter().map(RpcValue::from).collect();
+        descriptors.into()
+    }
+}
+
+fn descriptor(name: &str, flags: i64, param: &str, result: &str, access: i64) -> IMap {
+    let mut descriptor = IMap::new();
+    descriptor.insert(DIR_NAME, name.into());
+    descriptor.insert(DIR_FLAGS, flags.into());
+    descriptor.insert(DIR_PARAM, param.into());
+    descriptor.insert(DIR_RESULT, result.into());
+    descriptor.insert(DIR_ACCESS, access.into());
+    descriptor
+}
+
+fn parse_metadata(path: &str, method: &str, writable: bool, metadata: &RpcValue) -> Result<Map, String> {
+    if method != "get" && !(method == "set" && writable) {
+        return Err(format!("node {path} has no method {method}"));
+    }
+    let Value::Map(metadata) = metadata.value() else {
+        return Err(format!("metadata of {path}:{method} is not a Map"));
+    };
+    for (key, value) in metadata.iter() {
+        let valid = match key.as_str() {
+            "flags" => value.is_int() && value.as_int() >= 0,
+            key if METADATA_KEYS.contains(&key) => value.is_string(),
+            _ => return Err(format!("unknown metadata {key} of {path}:{method}")),
+        };
+        if !valid {
+            return Err(format!("invalid metadata {key} of {path}:{method}"));
+        }
+    }
+    Ok(metadata.as_ref().clone())
+}
+
+/// How a change of the declared nodes reaches the broker.
+#[derive(Debug, PartialEq)]
+pub(crate) enum TreeChange {
+    /// `lsmod` of `parent` telling that its child `name` appeared or vanished.
+    Lsmod { parent: String, name: String, exists: bool },
+    /// The node is outside the tree mounted with the current connection.
+    Remount,
+}
+
+/// The tree mounted with the current connection, see [`SyntheticNodes::mount_roots`].
+#[derive(Default)]
+struct Mounted {
+    roots: BTreeSet<String>,
+    fixed: Vec<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct SyntheticNodes {
+    nodes: Mutex<BTreeMap<String, Property>>,
+    mounted: Mutex<Mounted>,
+}
+
+impl SyntheticNodes {
+    pub(crate) fn load(file: Option<&str>) -> Result<Self, String> {
+        let Some(file) = file else {
+            return Ok(Self::default());
+        };
+        let content = std::fs::read_to_string(file).map_err(|err| format!("Cannot read nodes file {file}: {err}"))?;
+        let invalid = |msg: String| format!("Invalid nodes file {file}: {msg}");
+        let tree = RpcValue::from_cpon(&content).map_err(|err| invalid(err.to_string()))?;
+        let Value::Map(tree) = tree.value() else {
+            return Err(invalid("expected a Map of path to node".into()));
+        };
+        let nodes = tree.iter()
+            .map(|(path, node)| Ok((path.clone(), Property::parse(path, node).map_err(invalid)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Self { nodes: Mutex::new(nodes), mounted: Default::default() })
+    }
+
+    /// Replaces all declared nodes by those of a reloaded nodes file. A node declared the
+    /// same way as before keeps its current value. Returns true if the set of paths changed,
+    /// which takes effect with the next connection.
+    pub(crate) fn replace(&self, reloaded: SyntheticNodes) -> bool {
+        let mut reloaded = reloaded.nodes.into_inner().unwrap();
+        let mut nodes = self.nodes.lock().unwrap();
+        for (path, property) in reloaded.iter_mut() {
+            if let Some(old) = nodes.get(path) {
+                if old.type_name == property.type_name && old.writable == property.writable && old.initial == property.initial {
+                    property.value = old.value.clone();
+                }
+            }
+        }
+        let changed = !nodes.keys().eq(reloaded.keys());
+        *nodes = reloaded;
+        changed
+    }
+
+    /// Returns the paths to mount [`handle`] at for a new connection. `fixed` are the paths
+    /// of the compiled-in nodes mounted with it, which `ls` lists next to the declared ones.
+    pub(crate) fn mount_roots(&self, fixed: Vec<String>) -> Vec<String> {
+        let roots: BTreeSet<String> = self.nodes.lock().unwrap().keys().map(|path| root(path).to_string()).collect();
+        *self.mounted.lock().unwrap() = Mounted { roots: roots.clone(), fixed };
+        roots.into_iter().collect()
+    }
+
+    /// Declares a node at runtime, param is a node description with an additional `path` key.
+    pub(crate) fn create(&self, param: Option<&RpcValue>) -> Result<Option<TreeChange>, RpcError> {
+        let invalid = |msg: String| RpcError::new(RpcErrorCode::InvalidParam, &msg);
+        let Some((node, Value::Map(map))) = param.map(|node| (node, node.value())) else {
+            return Err(invalid("Expected {path, type, value, writable}".into()));
+        };
+        let Some(Value::String(path)) = map.get("path").map(RpcValue::value) else {
+            return Err(invalid("Node has no path".into()));
+        };
+        let property = Property::parse(path, node).map_err(invalid)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(path.as_str()) {
+            return Err(invalid(format!("Node {path} already exists")));
+        }
+        let change = self.tree_change(&nodes, path, true);
+        nodes.insert(path.to_string(), property);
+        Ok(change)
+    }
+
+    /// Drops a node declared by the nodes file or control/nodes:create.
+    pub(crate) fn remove(&self, param: Option<&RpcValue>) -> Result<Option<TreeChange>, RpcError> {
+        let Some(path) = param.filter(|param| param.is_string()).map(RpcValue::as_str) else {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected node path"));
+        };
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.remove(path).is_none() {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("No declared node {path}")));
+        }
+        Ok(self.tree_change(&nodes, path, false))
+    }
+
+    /// How adding (`exists`) or dropping the node at `path` shows in the mounted tree,
+    /// `nodes` are the other declared nodes. None if no node appears or vanishes, as
+    /// when `path` is the parent of other nodes as well.
+    fn tree_change(&self, nodes: &BTreeMap<String, Property>, path: &str, exists: bool) -> Option<TreeChange> {
+        let mounted = self.mounted.lock().unwrap();
+        if !mounted.roots.contains(root(path)) {
+            return exists.then_some(TreeChange::Remount);
+        }
+        let present = |node: &str| nodes.keys().chain(&mounted.fixed).any(|other| is_within(other, node));
+        // The root stays mounted until the next connection.
+        path.match_indices('/').map(|(end, _)| &path[..end]).chain([path]).skip(1)
+            .find(|node| !present(node))
+            .and_then(|node| node.rsplit_once('/'))
+            .map(|(parent, name)| TreeChange::Lsmod { parent: parent.to_string(), name: name.to_string(), exists })
+    }
+
+    /// Names of the children of `path` in the mounted tree, None if there is no node at `path`.
+    fn children(&self, path: &str) -> Option<Vec<String>> {
+        let nodes = self.nodes.lock().unwrap();
+        let mounted = self.mounted.lock().unwrap();
+        let children: BTreeSet<&str> = nodes.keys().chain(&mounted.fixed)
+            .filter_map(|other| other.strip_prefix(path)?.strip_prefix('/'))
+            .map(|rest| rest.split_once('/').map_or(rest, |(child, _)| child))
+            .collect();
+        let exists = !children.is_empty() || nodes.contains_key(path) || mounted.roots.contains(path);
+        exists.then(|| children.into_iter().map(str::to_string).collect())
+    }
+
+    pub(crate) fn ls(&self, path: &str, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        let children = self.children(path).ok_or_else(not_found)?;
+        match param.map(RpcValue::value) {
+            None | Some(Value::Null) => {
+                let children: Vec<RpcValue> = children.into_iter().map(RpcValue::from).collect();
+                Ok(children.into())
+            }
+            Some(Value::String(name)) => Ok(children.iter().any(|child| child == name.as_str()).into()),
+            Some(_) => Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected Null or a child name")),
+        }
+    }
+
+    pub(crate) fn dir(&self, path: &str, param: Option<&RpcValue>) -> Result<RpcValue, RpcError> {
+        self.children(path).ok_or_else(not_found)?;
+        let mut descriptors = vec![
+            descriptor("dir", 0, "idir", "odir", ACCESS_BROWSE),
+            descriptor("ls", 0, "ils", "ols", ACCESS_BROWSE),
+        ];
+        if let Some(property) = self.nodes.lock().unwrap().get(path) {
+            descriptors.extend(property.descriptors());
+            descriptors.push(descriptor("describe", 0, "Null", "List", ACCESS_BROWSE));
+        }
+        match param.map(RpcValue::value) {
+            None | Some(Value::Null | Value::Bool(_)) => {
+                let descriptors: Vec<RpcValue> = descriptors.into_iter().map(RpcValue::from).collect();
+                Ok(descriptors.into())
+            }
+            Some(Value::String(name)) => Ok(descriptors.into_iter()
+                .find(|descriptor| descriptor.get(&DIR_NAME).is_some_and(|method| method.as_str() == name.as_str()))
+                .map_or_else(RpcValue::null, RpcValue::from)),
+            Some(_) => Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected Null, Bool or a method name")),
+        }
+    }
+
+    pub(crate) fn paths(&self) -> Vec<String> {
+        self.nodes.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub(crate) fn list(&self) -> RpcValue {
+        let paths: Vec<RpcValue> = self.paths().into_iter().map(RpcValue::from).collect();
+        paths.into()
+    }
+
+    pub(crate) fn get(&self, path: Option<&str>) -> Result<RpcValue, RpcError> {
+        let nodes = self.nodes.lock().unwrap();
+        let property = path.and_then(|path| nodes.get(path)).ok_or_else(not_declared)?;
+        Ok(property.value.clone())
+    }
+
+    pub(crate) fn describe(&self, path: Option<&str>) -> Result<RpcValue, RpcError> {
+        let nodes = self.nodes.lock().unwrap();
+        let property = path.and_then(|path| nodes.get(path)).ok_or_else(not_declared)?;
+        Ok(property.describe())
+    }
+
+    /// Returns the signal value if it changed.
+    pub(crate) fn set(&self, path: Option<&str>, value: RpcValue) -> Result<Option<RpcValue>, RpcError> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let property = path.and_then(|path| nodes.get_mut(path)).ok_or_else(not_declared)?;
+        if !property.writable {
+            return Err(RpcError::new(RpcErrorCode::MethodCallException, "Node is read-only"));
+        }
+        if type_name(&value) != property.type_name {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Expected {}, got {}", property.type_name, type_name(&value))));
+        }
+        if property.value == value {
+            return Ok(None);
+        }
+        property.value = value.clone();
+        Ok(Some(value))
+    }
+
+    pub(crate) fn values(&self) -> Vec<(String, RpcValue)> {
+        self.nodes.lock().unwrap().iter().map(|(path, property)| (path.clone(), property.value.clone())).collect()
+    }
+
+    pub(crate) fn reset(&self) {
+        for property in self.nodes.lock().unwrap().values_mut() {
+            property.value = property.initial.clone();
+        }
+    }
+}
+
+fn not_declared() -> RpcError {
+    RpcError::new(RpcErrorCode::MethodNotFound, "Not a declared node")
+}
+
+fn not_found() -> RpcError {
+    RpcError::new(RpcErrorCode::MethodNotFound, "Path not found")
+}
+
+fn root(path: &str) -> &str {
+    path.split_once('/').map_or(path, |(root, _)| root)
+}
+
+/// Whether `path` is `node` or one of its descendants.
+fn is_within(path: &str, node: &str) -> bool {
+    path.strip_prefix(node).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Serves the declared nodes below a path returned by [`SyntheticNodes::mount_roots`].
+/// Methods the nodes do not have are answered according to `--unknown-method`.
+pub(crate) async fn handle(app_state: AppState<State>, request: RpcMessage, client_cmd_tx: impl MessageSink) {
+    let path = app_state.base_path(request.shv_path().unwrap_or_default());
+    let result = match request.method().unwrap_or_default() {
+        "ls" => Some(app_state.synthetic.ls(path, request.param())),
+        "dir" => Some(app_state.synthetic.dir(path, request.param())),
+        "get" => dispatch::handle(&app_state, &request, "get", "Null", async {
+            Some(app_state.synthetic.get(Some(path)))
+        }).await,
+        "set" => dispatch::handle(&app_state, &request, "set", "RpcValue", async {
+            let changed = app_state.synthetic.set(Some(path), request.param().cloned().unwrap_or_default());
+            Some(changed.map(|changed| {
+                if let Some(value) = changed {
+                    signals::emit_chng(&app_state, &client_cmd_tx, path, value);
+                }
+                ().into()
+            }))
+        }).await,
+        "describe" => dispatch::handle(&app_state, &request, "describe", "Null", async {
+            Some(app_state.synthetic.describe(Some(path)))
+        }).await,
+        method => dispatch::handle(&app_state, &request, method, "RpcValue", async {
+            Some(app_state.unknown_method.respond(path, method))
+        }).await,
+    };
+    let Some(result) = result else {
+        return;
+    };
+    let mut response = request.prepare_response().unwrap_or_default();
+    match result {
+        Ok(value) => response.set_result(value),
+        Err(err) => response.set_error(err),
+    };
+    client_cmd_tx.deliver(response);
+}
+
+/// Announces a control/nodes change, see the module doc.
+pub(crate) fn announce(app_state: &AppState<State>, client_cmd_tx: &ClientCommandSender, change: Option<TreeChange>) {
+    match change {
+        Some(TreeChange::Lsmod { parent, name, exists }) => signals::emit_lsmod(app_state, client_cmd_tx, &parent, &name, exists),
+        Some(TreeChange::Remount) => {
+            crate::lifecycle::request_reconnect(app_state);
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime;
+    use crate::signals::Collected;
+
+    fn declared(paths: &[&str]) -> SyntheticNodes {
+        let nodes = SyntheticNodes::default();
+        for path in paths {
+            nodes.create(Some(&node(path))).unwrap();
+        }
+        nodes
+    }
+
+    fn node(path: &str) -> RpcValue {
+        RpcValue::from_cpon(&format!(r#"{{"path": "{path}", "type": "Int"}}"#)).unwrap()
+    }
+
+    fn lsmod(parent: &str, name: &str, exists: bool) -> Option<TreeChange> {
+        Some(TreeChange::Lsmod { parent: parent.to_string(), name: name.to_string(), exists })
+    }
+
+    fn names(names: &[&str]) -> RpcValue {
+        let names: Vec<RpcValue> = names.iter().map(|name| RpcValue::from(*name)).collect();
+        names.into()
+    }
+
+    /// Calls `method` on a declared node and returns the response result.
+    fn call_unknown(mode: &str, method: &str) -> Result<RpcValue, RpcError> {
+        let state = crate::test_state(&["--unknown-method", mode]);
+        let node = RpcValue::from_cpon(r#"{"path": "test/declared", "type": "Int", "value": 7}"#).unwrap();
+        state.synthetic.create(Some(&node)).unwrap();
+        let collected = Collected::default();
+        let request = RpcMessage::new_request("test/declared", method, None);
+        runtime::block_on(handle(state, request, &collected));
+        let [response] = collected.take_messages().try_into().expect("one response");
+        response.result().cloned()
+    }
+
+    #[test]
+    fn unknown_method_error_is_method_not_found() {
+        let err = call_unknown("error", "frobnicate").unwrap_err();
+        assert_eq!(err.code as i32, RpcErrorCode::MethodNotFound as i32);
+    }
+
+    #[test]
+    fn unknown_method_null_returns_null() {
+        assert_eq!(call_unknown("null", "frobnicate").unwrap(), RpcValue::null());
+    }
+
+    #[test]
+    fn unknown_method_echo_returns_method_name() {
+        assert_eq!(call_unknown("echo", "frobnicate").unwrap(), RpcValue::from("frobnicate"));
+    }
+
+    #[test]
+    fn known_methods_ignore_unknown_method_mode() {
+        assert_eq!(call_unknown("echo", "get").unwrap(), RpcValue::from(7));
+    }
+
+    #[test]
+    fn create_announces_topmost_new_node() {
+        let nodes = declared(&["plant/boiler/temperature"]);
+        assert_eq!(nodes.mount_roots(vec!["state/number".to_string()]), ["plant"]);
+        assert_eq!(nodes.create(Some(&node("plant/boiler/pressure"))).unwrap(), lsmod("plant/boiler", "pressure", true));
+        assert_eq!(nodes.create(Some(&node("plant/pump/speed"))).unwrap(), lsmod("plant", "pump", true));
+        assert_eq!(nodes.create(Some(&node("plant/pump"))).unwrap(), None);
+        assert_eq!(nodes.ls("plant", None).unwrap(), names(&["boiler", "pump"]));
+    }
+
+    #[test]
+    fn create_outside_mounted_roots_remounts() {
+        let nodes = declared(&["plant/boiler/temperature"]);
+        nodes.mount_roots(Vec::new());
+        assert_eq!(nodes.create(Some(&node("other/node"))).unwrap(), Some(TreeChange::Remount));
+    }
+
+    #[test]
+    fn remove_announces_topmost_vanished_node() {
+        let nodes = declared(&["plant/boiler/temperature", "plant/pump/speed"]);
+        nodes.mount_roots(Vec::new());
+        let path = RpcValue::from("plant/boiler/temperature");
+        assert_eq!(nodes.remove(Some(&path)).unwrap(), lsmod("plant", "boiler", false));
+        assert_eq!(nodes.ls("plant", None).unwrap(), names(&["pump"]));
+        assert!(nodes.ls("plant/boiler", None).is_err());
+    }
+
+    #[test]
+    fn ls_lists_compiled_in_nodes_next_to_declared() {
+        let nodes = declared(&["state/extra"]);
+        nodes.mount_roots(vec!["state/number".to_string(), "state/text".to_string()]);
+        assert_eq!(nodes.ls("state", None).unwrap(), names(&["extra", "number", "text"]));
+        assert_eq!(nodes.ls("state", Some(&"number".into())).unwrap(), true.into());
+        assert_eq!(nodes.ls("state/extra", None).unwrap(), names(&[]));
+    }
+}
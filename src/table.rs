@@ -0,0 +1,160 @@
+//! `state/table` node holding a list of typed records.
+//!
+//! Columns are declared with `--table-column name=Type`, every row must hold exactly
+//! the declared columns with values of their type. Without declared columns rows are
+//! untyped Maps. Each row gets an id on append that never changes and is not reused.
+//!
+//! Row changes emit `chng` on `state/table/<id>` carrying only the difference:
+//! the whole row on append, the changed columns on update and Null on delete.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use shvproto::rpcvalue::{Map, Value};
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::anyvalue::type_name;
+use crate::mapnode::TYPE_NAMES;
+
+pub(crate) const TABLE_MOUNT: &str = "state/table";
+const ID_KEY: &str = "id";
+
+#[derive(Default)]
+struct Rows {
+    next_id: i64,
+    rows: BTreeMap<i64, Map>,
+}
+
+pub(crate) struct Table {
+    columns: Vec<(String, String)>,
+    rows: Mutex<Rows>,
+}
+
+impl Table {
+    pub(crate) fn new(columns: &[String]) -> Result<Self, String> {
+        let columns = columns.iter()
+            .map(|column| {
+                let (name, ty) = column.split_once('=').ok_or_else(|| format!("Invalid table column '{column}', expected name=Type"))?;
+                if name.is_empty() || name == ID_KEY {
+                    return Err(format!("Invalid table column name '{name}'"));
+                }
+                if !TYPE_NAMES.contains(&ty) {
+                    return Err(format!("Unknown type of table column '{name}': {ty}"));
+                }
+                Ok((name.to_string(), ty.to_string()))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self { columns, rows: Default::default() })
+    }
+
+    pub(crate) fn reset(&self) {
+        *self.rows.lock().unwrap() = Default::default();
+    }
+
+    /// All rows in id order, each with its `id` added.
+    pub(crate) fn rows(&self) -> RpcValue {
+        let rows: Vec<RpcValue> = self.rows.lock().unwrap().rows.iter()
+            .map(|(id, row)| {
+                let mut row = row.clone();
+                row.insert(ID_KEY.into(), (*id).into());
+                row.into()
+            })
+            .collect();
+        rows.into()
+    }
+
+    /// Returns the new row id with the signal path and value.
+    ///
+    /// `commit` here and in the other row changes runs once the change is known to
+    /// modify the table, its error rejects the change.
+    pub(crate) fn append_row(&self, param: Option<&RpcValue>, commit: impl FnOnce() -> Result<(), RpcError>) -> Result<(i64, String, RpcValue), RpcError> {
+        let Some(Value::Map(row)) = param.map(RpcValue::value) else {
+            return Err(RpcError::new(RpcErrorCode::InvalidParam, "Expected Map"));
+        };
+        self.validate(row, true)?;
+        let mut rows = self.rows.lock().unwrap();
+        commit()?;
+        let id = rows.next_id;
+        rows.next_id += 1;
+        rows.rows.insert(id, row.as_ref().clone());
+        Ok((id, row_path(id), row.as_ref().clone().into()))
+    }
+
+    /// `[id, {column: value, ...}]` changes the given columns, returns the signal path
+    /// and value when any of them changed.
+    pub(crate) fn update_row(&self, param: Option<&RpcValue>, commit: impl FnOnce() -> Result<(), RpcError>) -> Result<Option<(String, RpcValue)>, RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidParam, "Expected [id, Map]");
+        let Some(Value::List(list)) = param.map(RpcValue::value) else {
+            return Err(invalid());
+        };
+        let [id, update] = list.as_slice() else {
+            return Err(invalid());
+        };
+        let (true, Value::Map(update)) = (id.is_int(), update.value()) else {
+            return Err(invalid());
+        };
+        self.validate(update, false)?;
+        let id = id.as_int();
+        let mut rows = self.rows.lock().unwrap();
+        let row = rows.rows.get_mut(&id).ok_or_else(|| no_row(id))?;
+        let diff: Map = update.iter()
+            .filter(|(column, value)| row.get(*column) != Some(*value))
+            .map(|(column, value)| (column.clone(), value.clone()))
+            .collect();
+        if diff.is_empty() {
+            return Ok(None);
+        }
+        commit()?;
+        row.extend(diff.clone());
+        Ok(Some((row_path(id), diff.into())))
+    }
+
+    /// Returns the signal path of the deleted row.
+    pub(crate) fn delete_row(&self, id: i64, commit: impl FnOnce() -> Result<(), RpcError>) -> Result<String, RpcError> {
+        let mut rows = self.rows.lock().unwrap();
+        if !rows.rows.contains_key(&id) {
+            return Err(no_row(id));
+        }
+        commit()?;
+        rows.rows.remove(&id);
+        Ok(row_path(id))
+    }
+
+    /// A complete row must hold every column, an update only known ones.
+    fn validate(&self, row: &Map, complete: bool) -> Result<(), RpcError> {
+        if self.columns.is_empty() {
+            if row.contains_key(ID_KEY) {
+                return Err(RpcError::new(RpcErrorCode::InvalidParam, "Column 'id' is reserved"));
+            }
+            return Ok(());
+        }
+        let mut errors: Vec<String> = row.iter()
+            .filter_map(|(column, value)| match self.columns.iter().find(|(name, _)| name == column) {
+                None => Some(format!("unknown column '{column}'")),
+                Some((_, expected)) if type_name(value) != expected.as_str() => {
+                    Some(format!("column '{column}' is {}, expected {expected}", type_name(value)))
+                }
+                Some(_) => None,
+            })
+            .collect();
+        if complete {
+            errors.extend(self.columns.iter()
+                .filter(|(name, _)| !row.contains_key(name))
+                .map(|(name, _)| format!("missing column '{name}'")));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("Row does not match the table columns: {}", errors.join(", "))))
+        }
+    }
+}
+
+fn row_path(id: i64) -> String {
+    format!("{TABLE_MOUNT}/{id}")
+}
+
+fn no_row(id: i64) -> RpcError {
+    RpcError::new(RpcErrorCode::InvalidParam, &format!("No row with id {id}"))
+}
@@ -0,0 +1,68 @@
+//! Registry of named background tasks, so that tests can tear down generators.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use shvclient::AppState;
+use shvproto::RpcValue;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::runtime::{self, JoinHandle};
+use crate::State;
+
+pub(crate) const TASKS_MOUNT: &str = "status/tasks";
+
+#[derive(Default)]
+pub(crate) struct Tasks {
+    next_id: AtomicU64,
+    tasks: Mutex<BTreeMap<String, (u64, JoinHandle<()>)>>,
+}
+
+impl Tasks {
+    pub(crate) fn names(&self) -> RpcValue {
+        let names: Vec<RpcValue> = self.tasks.lock().unwrap().keys().map(RpcValue::from).collect();
+        names.into()
+    }
+
+    pub(crate) async fn cancel(&self, name: &str) -> Result<(), RpcError> {
+        let task = self.tasks.lock().unwrap().remove(name);
+        match task {
+            Some((_, handle)) => {
+                handle.cancel().await;
+                Ok(())
+            }
+            None => Err(RpcError::new(RpcErrorCode::InvalidParam, &format!("No active task named '{name}'"))),
+        }
+    }
+
+    pub(crate) async fn cancel_all(&self) {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for (_, handle) in tasks.into_values() {
+            handle.cancel().await;
+        }
+    }
+}
+
+/// Spawns a task that is listed in `status/tasks` until it finishes or is cancelled.
+/// A numeric suffix is appended when a task with the same name is already running.
+pub(crate) fn spawn<F>(app_state: &AppState<State>, name: &str, future: F) -> String
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let id = app_state.tasks.next_id.fetch_add(1, Ordering::SeqCst);
+    let mut tasks = app_state.tasks.tasks.lock().unwrap();
+    let name = if tasks.contains_key(name) { format!("{name}#{id}") } else { name.to_string() };
+    let registry = app_state.clone();
+    let task_name = name.clone();
+    let handle = runtime::spawn(async move {
+        future.await;
+        let mut tasks = registry.tasks.tasks.lock().unwrap();
+        if tasks.get(&task_name).is_some_and(|(task_id, _)| *task_id == id) {
+            tasks.remove(&task_name);
+        }
+    });
+    tasks.insert(name.clone(), (id, handle));
+    name
+}
@@ -0,0 +1,117 @@
+//! `ssl://` broker connections through a local TLS relay, enabled by the `tls` feature.
+//!
+//! The client library connects over plain TCP to a relay listening on the loopback
+//! interface, the relay opens a TLS connection to the broker for every accepted
+//! connection and copies the bytes in both directions. This keeps certificate handling
+//! (`--tls-ca`, `--tls-cert`, `--tls-key`, `--insecure`) in the device, independent of
+//! the TLS support of the client library. `--disconnect-style abrupt` resets the broker
+//! socket without a TLS close_notify when the client closes its side.
+
+use async_native_tls::{Certificate, Identity, TlsConnector};
+use async_std::net::{TcpListener, TcpStream};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::StreamExt;
+use log::*;
+use url::Url;
+
+use crate::connection::DisconnectStyle;
+use crate::{runtime, Opts};
+
+/// Default port of SHV over TLS.
+pub(crate) const DEFAULT_PORT: u16 = 3756;
+
+pub(crate) struct Relay {
+    local_port: u16,
+}
+
+impl Relay {
+    /// Starts a relay when `url` is an `ssl://` URL, returns None for other schemes.
+    pub(crate) async fn start(url: &str, opts: &Opts) -> Result<Option<Self>, String> {
+        let url = Url::parse(url).map_err(|err| format!("Invalid broker URL: {err}"))?;
+        if url.scheme() != "ssl" {
+            return Ok(None);
+        }
+        let host = url.host_str().ok_or("Broker URL has no host")?.to_string();
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let connector = connector(opts)?;
+        let disconnect_style = opts.disconnect_style;
+        let listener = TcpListener::bind("127.0.0.1:0").await.map_err(|err| format!("Cannot start TLS relay: {err}"))?;
+        let local_port = listener.local_addr().map_err(|err| format!("Cannot start TLS relay: {err}"))?.port();
+        debug!("TLS relay on 127.0.0.1:{local_port} to {host}:{port}");
+        runtime::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                match stream {
+                    Ok(local) => {
+                        runtime::spawn(relay(connector.clone(), local, host.clone(), port, disconnect_style));
+                    }
+                    Err(err) => warn!("TLS relay accept failed: {err}"),
+                }
+            }
+        });
+        Ok(Some(Self { local_port }))
+    }
+
+    /// The URL the client library connects to, `url` with the scheme, host and port of
+    /// the relay and its query (credentials included) kept.
+    pub(crate) fn local_url(&self, url: &str) -> String {
+        let Ok(mut url) = Url::parse(url) else {
+            return url.to_string();
+        };
+        let _ = url.set_scheme("tcp");
+        let _ = url.set_host(Some("127.0.0.1"));
+        let _ = url.set_port(Some(self.local_port));
+        url.to_string()
+    }
+}
+
+fn connector(opts: &Opts) -> Result<TlsConnector, String> {
+    let read = |path: &str| std::fs::read(path).map_err(|err| format!("Cannot read {path}: {err}"));
+    let mut connector = TlsConnector::new();
+    if let Some(ca) = &opts.tls_ca {
+        let ca = Certificate::from_pem(&read(ca)?).map_err(|err| format!("Invalid CA certificate {ca}: {err}"))?;
+        connector = connector.add_root_certificate(ca);
+    }
+    match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => {
+            let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?).map_err(|err| format!("Invalid client certificate {cert}: {err}"))?;
+            connector = connector.identity(identity);
+        }
+        (None, None) => {}
+        _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
+    }
+    if opts.insecure {
+        warn!("TLS certificate verification is disabled");
+        connector = connector.danger_accept_invalid_certs(true).danger_accept_invalid_hostnames(true);
+    }
+    Ok(connector)
+}
+
+async fn relay(connector: TlsConnector, local: TcpStream, host: String, port: u16, disconnect_style: DisconnectStyle) {
+    let socket = match TcpStream::connect((host.as_str(), port)).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!("TLS relay cannot connect to {host}:{port}: {err}");
+            return;
+        }
+    };
+    disconnect_style.prepare(&socket);
+    let remote = match connector.connect(&host, socket.clone()).await {
+        Ok(remote) => remote,
+        Err(err) => {
+            warn!("TLS handshake with {host}:{port} failed: {err}");
+            return;
+        }
+    };
+    let (remote_read, mut remote_write) = remote.split();
+    let (local_read, mut local_write) = (&local, &local);
+    let upstream = async {
+        let _ = futures::io::copy(local_read, &mut remote_write).await;
+        disconnect_style.close(&socket, &mut remote_write).await;
+    };
+    let downstream = async {
+        let _ = futures::io::copy(remote_read, &mut local_write).await;
+        let _ = local_write.close().await;
+    };
+    futures::future::join(upstream, downstream).await;
+}
@@ -0,0 +1,73 @@
+use std::sync::Mutex;
+
+use async_std::net::ToSocketAddrs;
+use log::*;
+use shvproto::rpcvalue::Map;
+use shvproto::RpcValue;
+use url::Url;
+
+use crate::connection::redact_url;
+
+pub(crate) const TRANSPORT_MOUNT: &str = "status/transport";
+
+/// Transport of the current broker connection.
+///
+/// The client library does not expose the connected socket, so the details are
+/// derived from the configured URL and refreshed on every connect.
+#[derive(Default)]
+pub(crate) struct Transport {
+    info: Mutex<Map>,
+}
+
+impl Transport {
+    pub(crate) fn value(&self) -> RpcValue {
+        self.info.lock().unwrap().clone().into()
+    }
+
+    pub(crate) async fn refresh(&self, url: &str) {
+        let info = match Url::parse(url) {
+            Ok(url) => describe(&url).await,
+            Err(err) => {
+                warn!("Cannot parse broker URL {url}: {err}");
+                Map::new()
+            }
+        };
+        *self.info.lock().unwrap() = info;
+    }
+}
+
+async fn describe(url: &Url) -> Map {
+    let scheme = url.scheme();
+    let peer = match (url.host_str(), url.port_or_known_default().or(default_port(scheme))) {
+        (Some(host), Some(port)) => match (host, port).to_socket_addrs().await.map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr.to_string(),
+            _ => format!("{host}:{port}"),
+        },
+        (Some(host), None) => host.to_string(),
+        (None, _) => url.path().to_string(),
+    };
+    let mut map = Map::new();
+    map.insert("scheme".into(), scheme.into());
+    map.insert("peer".into(), peer.into());
+    map.insert("tls".into(), matches!(scheme, "ssl" | "ssls" | "wss").into());
+    map
+}
+
+/// Rejects WebSocket URLs up front when the client library was built without WebSocket support.
+pub(crate) fn check_url(url: &str) -> Result<(), String> {
+    let Ok(parsed) = Url::parse(url) else {
+        return Ok(());
+    };
+    if matches!(parsed.scheme(), "ws" | "wss") && !cfg!(feature = "websocket") {
+        return Err(format!("Cannot connect to {}, build with the websocket feature for ws:// and wss:// URLs", redact_url(url)));
+    }
+    Ok(())
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "tcp" => Some(3755),
+        "ssl" => Some(3756),
+        _ => None,
+    }
+}
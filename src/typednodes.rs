@@ -0,0 +1,117 @@
+//! Reference property nodes for the SHV value types not covered by state/number and
+//! state/text. Every node has `get` and `set`, a `set` of a different value emits `chng`:
+//! - `state/timestamp`: DateTime, the Unix epoch initially
+//! - `state/decimal`: Decimal, 0 initially
+//! - `state/intList`: List of Int in the i32 range, empty initially
+//! - `state/mode`: String out of the `--mode-values`, the first of them initially
+//!
+//! A value of another type, a list item out of range or a mode not in the allowed set
+//! is rejected with InvalidParam.
+
+use std::sync::Mutex;
+
+use shvproto::decimal::Decimal;
+use shvproto::rpcvalue::Value;
+use shvproto::{DateTime, RpcValue};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::anyvalue::type_name;
+
+pub(crate) const TIMESTAMP_MOUNT: &str = "state/timestamp";
+pub(crate) const DECIMAL_MOUNT: &str = "state/decimal";
+pub(crate) const INT_LIST_MOUNT: &str = "state/intList";
+pub(crate) const MODE_MOUNT: &str = "state/mode";
+
+const PATHS: [&str; 4] = [TIMESTAMP_MOUNT, DECIMAL_MOUNT, INT_LIST_MOUNT, MODE_MOUNT];
+
+pub(crate) struct TypedNodes {
+    modes: Vec<String>,
+    timestamp: Mutex<RpcValue>,
+    decimal: Mutex<RpcValue>,
+    int_list: Mutex<RpcValue>,
+    mode: Mutex<RpcValue>,
+}
+
+impl TypedNodes {
+    /// `modes` is the comma separated `--mode-values` list.
+    pub(crate) fn new(modes: &str) -> Result<Self, String> {
+        let modes: Vec<String> = modes.split(',').map(str::trim).filter(|mode| !mode.is_empty()).map(String::from).collect();
+        if modes.is_empty() {
+            return Err("At least one mode value is required".to_string());
+        }
+        let nodes = Self {
+            modes,
+            timestamp: Default::default(),
+            decimal: Default::default(),
+            int_list: Default::default(),
+            mode: Default::default(),
+        };
+        nodes.reset();
+        Ok(nodes)
+    }
+
+    fn node(&self, path: &str) -> Option<&Mutex<RpcValue>> {
+        match path {
+            TIMESTAMP_MOUNT => Some(&self.timestamp),
+            DECIMAL_MOUNT => Some(&self.decimal),
+            INT_LIST_MOUNT => Some(&self.int_list),
+            MODE_MOUNT => Some(&self.mode),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get(&self, path: &str) -> RpcValue {
+        self.node(path).map(|value| value.lock().unwrap().clone()).unwrap_or_default()
+    }
+
+    /// Returns the signal value if it changed. `commit` runs once the write is known to
+    /// change the node, its error rejects the write.
+    pub(crate) fn set(&self, path: &str, value: RpcValue, commit: impl FnOnce() -> Result<(), RpcError>) -> Result<Option<RpcValue>, RpcError> {
+        let invalid = |msg: String| RpcError::new(RpcErrorCode::InvalidParam, &msg);
+        let expected = match path {
+            TIMESTAMP_MOUNT => "DateTime",
+            DECIMAL_MOUNT => "Decimal",
+            INT_LIST_MOUNT => "List",
+            MODE_MOUNT => "String",
+            _ => return Err(invalid(format!("Not a typed node: {path}"))),
+        };
+        if type_name(&value) != expected {
+            return Err(invalid(format!("Expected {expected}, got {}", type_name(&value))));
+        }
+        match value.value() {
+            Value::List(items) => {
+                if let Some((n, item)) = items.iter().enumerate().find(|(_, item)| !item.is_int() || i32::try_from(item.as_int()).is_err()) {
+                    return Err(invalid(format!("Item {n} is not an Int in the i32 range: {}", item.to_cpon())));
+                }
+            }
+            Value::String(mode) if !self.modes.iter().any(|allowed| allowed == mode.as_str()) => {
+                return Err(invalid(format!("Invalid mode '{mode}', allowed: {}", self.modes.join(", "))));
+            }
+            _ => {}
+        }
+        let mut current = self.node(path).expect("typed node path").lock().unwrap();
+        if *current == value {
+            return Ok(None);
+        }
+        commit()?;
+        *current = value.clone();
+        Ok(Some(value))
+    }
+
+    /// The allowed values of state/mode.
+    pub(crate) fn modes(&self) -> RpcValue {
+        let modes: Vec<RpcValue> = self.modes.iter().map(|mode| mode.as_str().into()).collect();
+        modes.into()
+    }
+
+    pub(crate) fn values(&self) -> Vec<(String, RpcValue)> {
+        PATHS.iter().map(|path| (path.to_string(), self.get(path))).collect()
+    }
+
+    pub(crate) fn reset(&self) {
+        *self.timestamp.lock().unwrap() = DateTime::from_epoch_msec(0).into();
+        *self.decimal.lock().unwrap() = Decimal::new(0, 0).into();
+        *self.int_list.lock().unwrap() = Vec::<RpcValue>::new().into();
+        *self.mode.lock().unwrap() = self.modes[0].as_str().into();
+    }
+}
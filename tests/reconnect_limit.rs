@@ -0,0 +1,21 @@
+//! `--max-reconnect-attempts` against a broker address nothing listens on.
+
+use std::net::TcpListener;
+use std::process::Command;
+
+/// Exit code of the binary when the reconnect attempts are exhausted.
+const EXIT_RECONNECT_LIMIT: i32 = 3;
+
+#[test]
+fn exits_after_max_reconnect_attempts() {
+    // Bound and released right away, so that connecting is refused.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let output = Command::new(env!("CARGO_BIN_EXE_shvbrokertestingdevice"))
+        .args(["--url", &format!("tcp://127.0.0.1:{port}?user=test&password=test")])
+        .args(["--reconnect-interval", "10ms", "--max-reconnect-attempts", "2"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(EXIT_RECONNECT_LIMIT), "{stderr}");
+    assert!(stderr.contains("Giving up after 2 failed connection attempts"), "{stderr}");
+}